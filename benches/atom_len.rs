@@ -0,0 +1,59 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use klvmr::allocator::{Allocator, NodePtr};
+
+const NUM_ATOMS: u32 = 1_000_000;
+
+// a mix of small atoms and heap atoms of various lengths
+fn make_atoms(a: &mut Allocator) -> Vec<NodePtr> {
+    let buf = [0x42_u8; 100];
+    (0..NUM_ATOMS)
+        .map(|i| {
+            if i % 2 == 0 {
+                a.new_small_number(i).unwrap()
+            } else {
+                a.new_atom(&buf[..(i % 100) as usize]).unwrap()
+            }
+        })
+        .collect()
+}
+
+fn atom_len_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("atom_len");
+    let mut a = Allocator::new();
+    let atoms = make_atoms(&mut a);
+
+    group.bench_function("atom().len()", |b| {
+        b.iter(|| {
+            let mut total = 0;
+            for n in &atoms {
+                total += a.atom(*n).as_ref().len();
+            }
+            black_box(total)
+        })
+    });
+
+    group.bench_function("atom_len()", |b| {
+        b.iter(|| {
+            let mut total = 0;
+            for n in &atoms {
+                total += a.atom_len(*n);
+            }
+            black_box(total)
+        })
+    });
+
+    group.bench_function("try_atom_len()", |b| {
+        b.iter(|| {
+            let mut total = 0;
+            for n in &atoms {
+                total += a.try_atom_len(*n).unwrap_or(0);
+            }
+            black_box(total)
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(atom_len, atom_len_benchmark);
+criterion_main!(atom_len);
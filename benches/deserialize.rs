@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, Criterion};
-use klvmr::allocator::Allocator;
+use klvmr::allocator::{Allocator, ConstantTree};
 use klvmr::serde::{
     node_from_bytes, node_from_bytes_backrefs, node_to_bytes_backrefs,
     serialized_length_from_bytes, serialized_length_from_bytes_trusted, tree_hash_from_stream,
@@ -77,6 +77,38 @@ fn deserialize_benchmark(c: &mut Criterion) {
         })
     });
 
+    // this is the setup cost of making the block available in a new
+    // allocator, when it's shared as a ConstantTree rather than deserialized
+    let rom = ConstantTree::from_bytes(block).expect("ConstantTree::from_bytes");
+    group.bench_function("import_constant", |b| {
+        b.iter(|| {
+            let mut a = Allocator::new();
+            let start = Instant::now();
+            a.import_constant(&rom).unwrap();
+            start.elapsed()
+        })
+    });
+
+    // loading the block from a snapshot, compared to node_from_bytes and
+    // node_from_bytes_backrefs above
+    for (bl, name_suffix) in &[
+        (block as &[u8], ""),
+        (compressed_block.as_ref(), "-compressed"),
+    ] {
+        let snapshot = {
+            let mut a = Allocator::new();
+            let root = node_from_bytes_backrefs(&mut a, bl).expect("node_from_bytes_backrefs");
+            a.snapshot(&[root]).expect("snapshot")
+        };
+        group.bench_function(format!("from_snapshot{name_suffix}"), |b| {
+            b.iter(|| {
+                let start = Instant::now();
+                Allocator::from_snapshot(&snapshot).expect("from_snapshot");
+                start.elapsed()
+            })
+        });
+    }
+
     group.finish();
 }
 
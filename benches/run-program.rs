@@ -1,6 +1,12 @@
 use criterion::{criterion_group, criterion_main, Criterion, SamplingMode};
 use klvmr::allocator::{Allocator, NodePtr};
 use klvmr::chik_dialect::ChikDialect;
+use klvmr::core_ops::{op_eq, op_first, op_listp};
+use klvmr::cost::Cost;
+use klvmr::dialect::{Dialect, OperatorSet};
+use klvmr::f_table::{f_lookup_for_flags, OpFn};
+use klvmr::more_ops::{op_add, op_any, op_logxor, op_not, op_strlen};
+use klvmr::reduction::Response;
 use klvmr::serde::node_from_bytes;
 use std::fs::read_to_string;
 use std::time::Instant;
@@ -244,5 +250,258 @@ fn run_program_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(run_program, run_program_benchmark);
+// measures the overhead of dispatching operators, by calling cheap arithmetic
+// operators directly on the dialect
+fn dispatch_benchmark(c: &mut Criterion) {
+    let mut a = Allocator::new();
+    let dialect = ChikDialect::new(0);
+
+    let mut group = c.benchmark_group("dispatch");
+
+    let args = single_value::<1337>(&mut a);
+    let args = a.new_pair(a.one(), args).expect("new_pair");
+    let checkpoint = a.checkpoint();
+    for (name, opcode) in [("add", 16_u32), ("logxor", 26), ("any", 33)] {
+        let op = a.new_small_number(opcode).expect("new_small_number");
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                a.restore_checkpoint(&checkpoint);
+                let start = Instant::now();
+                for _ in 0..1000 {
+                    dialect
+                        .op(&mut a, op, args, 11000000000, OperatorSet::Default)
+                        .expect("operator failed");
+                }
+                start.elapsed()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+// looks up the base cost of a mix of operators 1000 times, which the
+// interpreter does before applying every operator, outside of any softfork
+// guard and inside the BLS guard
+fn min_cost_benchmark(c: &mut Criterion) {
+    let mut a = Allocator::new();
+    let dialect = ChikDialect::new(0);
+
+    let mut group = c.benchmark_group("op_min_cost");
+
+    let ops: Vec<NodePtr> = [5_u32, 11, 16, 26, 48, 60, 0x13d61f00]
+        .iter()
+        .map(|op| a.new_number((*op).into()).expect("new_number"))
+        .collect();
+    for (name, extension) in [("default", OperatorSet::Default), ("bls", OperatorSet::BLS)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let start = Instant::now();
+                let mut total: Cost = 0;
+                for i in 0..1000 {
+                    total += dialect.op_min_cost(&a, ops[i % ops.len()], extension);
+                }
+                std::hint::black_box(total);
+                start.elapsed()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+// the operators of the table-vs-match benchmark, dispatched through a match
+// on the opcode. This is what the table in ChikDialect replaces
+fn match_dispatch(a: &mut Allocator, op: u8, args: NodePtr, max_cost: Cost) -> Response {
+    let f: OpFn = match op {
+        5 => op_first,
+        7 => op_listp,
+        9 => op_eq,
+        13 => op_strlen,
+        16 => op_add,
+        26 => op_logxor,
+        32 => op_not,
+        33 => op_any,
+        _ => panic!("unexpected operator {op}"),
+    };
+    f(a, args, max_cost)
+}
+
+// applies a mix of cheap operators 1000 times, dispatching them through the
+// same 256 entry table ChikDialect uses, and through a match on the opcode
+fn dispatch_table_benchmark(c: &mut Criterion) {
+    let mut a = Allocator::new();
+    let table = f_lookup_for_flags(0);
+
+    let mut group = c.benchmark_group("dispatch-table");
+
+    let list = a.new_pair(a.one(), a.nil()).expect("new_pair");
+    let one_arg = single_value::<1337>(&mut a);
+    let atom_args = a.new_pair(a.one(), one_arg).expect("new_pair");
+    let list_args = a.new_pair(list, a.nil()).expect("new_pair");
+    let ops: [(u8, NodePtr); 8] = [
+        (5, list_args),
+        (7, list_args),
+        (9, atom_args),
+        (13, one_arg),
+        (16, atom_args),
+        (26, atom_args),
+        (32, one_arg),
+        (33, atom_args),
+    ];
+    let checkpoint = a.checkpoint();
+
+    group.bench_function("table", |b| {
+        b.iter(|| {
+            a.restore_checkpoint(&checkpoint);
+            let start = Instant::now();
+            for i in 0..1000 {
+                let (op, args) = ops[i % ops.len()];
+                let f = table[op as usize].expect("unknown operator");
+                f(&mut a, args, 11000000000).expect("operator failed");
+            }
+            start.elapsed()
+        })
+    });
+
+    group.bench_function("match", |b| {
+        b.iter(|| {
+            a.restore_checkpoint(&checkpoint);
+            let start = Instant::now();
+            for i in 0..1000 {
+                let (op, args) = ops[i % ops.len()];
+                match_dispatch(&mut a, op, args, 11000000000).expect("operator failed");
+            }
+            start.elapsed()
+        })
+    });
+
+    group.finish();
+}
+
+// concatenates 1000 atoms of various sizes, by calling the operator directly
+fn concat_benchmark(c: &mut Criterion) {
+    let mut a = Allocator::new();
+    let dialect = ChikDialect::new(0);
+
+    let mut group = c.benchmark_group("concat");
+
+    let mut args = a.nil();
+    for i in 0..1000_usize {
+        let item = a
+            .new_atom(&b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789"[..i % 62])
+            .expect("new_atom");
+        args = a.new_pair(item, args).expect("new_pair");
+    }
+    let op = a.new_small_number(14).expect("new_small_number");
+    let checkpoint = a.checkpoint();
+    group.bench_function("concat-1000", |b| {
+        b.iter(|| {
+            a.restore_checkpoint(&checkpoint);
+            let start = Instant::now();
+            dialect
+                .op(&mut a, op, args, 11000000000, OperatorSet::Default)
+                .expect("concat failed");
+            start.elapsed()
+        })
+    });
+
+    group.finish();
+}
+
+// adds, subtracts and multiplies pairs of values. Coin amounts fit in 8 bytes
+// and take the i128 fast path. u64::MAX needs 9 bytes (because of the sign
+// bit) and 32 byte values don't fit either
+fn arithmetic_benchmark(c: &mut Criterion) {
+    let mut a = Allocator::new();
+    let dialect = ChikDialect::new(0);
+
+    let mut group = c.benchmark_group("arithmetic");
+
+    let values: [(&str, &[u8]); 3] = [
+        ("amount", &[0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00]),
+        (
+            "u64",
+            &[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        ),
+        ("bytes32", &[0x42; 32]),
+    ];
+    for (name, value) in values {
+        let mut args = a.nil();
+        for _ in 0..2 {
+            let item = a.new_atom(value).expect("new_atom");
+            args = a.new_pair(item, args).expect("new_pair");
+        }
+        let checkpoint = a.checkpoint();
+        for (op_name, opcode) in [("add", 16_u32), ("subtract", 17), ("multiply", 18)] {
+            let op = a.new_small_number(opcode).expect("new_small_number");
+            group.bench_function(format!("{op_name}-{name}"), |b| {
+                b.iter(|| {
+                    a.restore_checkpoint(&checkpoint);
+                    let start = Instant::now();
+                    for _ in 0..1000 {
+                        dialect
+                            .op(&mut a, op, args, 11000000000, OperatorSet::Default)
+                            .expect("operator failed");
+                    }
+                    start.elapsed()
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+// compares creating a new allocator for every program run, to resetting a
+// single allocator between runs
+fn allocator_reset_benchmark(c: &mut Criterion) {
+    let dialect = ChikDialect::new(0);
+    // (sha256 (q . "foobar"))
+    let prg = hex::decode("ff0bffff0186666f6f62617280").expect("invalid hex");
+
+    let mut group = c.benchmark_group("allocator");
+
+    group.bench_function("fresh-allocator-1000", |b| {
+        b.iter(|| {
+            let start = Instant::now();
+            for _ in 0..1000 {
+                let mut a = Allocator::new();
+                let program = node_from_bytes(&mut a, &prg).expect("node_from_bytes");
+                let env = a.nil();
+                klvmr::run_program(&mut a, &dialect, program, env, 11000000000)
+                    .expect("program failed");
+            }
+            start.elapsed()
+        })
+    });
+
+    let mut a = Allocator::new();
+    group.bench_function("reset-allocator-1000", |b| {
+        b.iter(|| {
+            let start = Instant::now();
+            for _ in 0..1000 {
+                a.reset();
+                let program = node_from_bytes(&mut a, &prg).expect("node_from_bytes");
+                let env = a.nil();
+                klvmr::run_program(&mut a, &dialect, program, env, 11000000000)
+                    .expect("program failed");
+            }
+            start.elapsed()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(
+    run_program,
+    run_program_benchmark,
+    dispatch_benchmark,
+    min_cost_benchmark,
+    dispatch_table_benchmark,
+    concat_benchmark,
+    arithmetic_benchmark,
+    allocator_reset_benchmark
+);
 criterion_main!(run_program);
@@ -0,0 +1,34 @@
+// runs the generator in benches/block_af9c3d98.bin, with the tracing feature
+// enabled, printing all spans to stdout:
+//
+// cargo run --release --example trace --features=tracing
+//
+// Use a different tracing subscriber (e.g. tracing-flame) to produce
+// flamegraph-compatible output.
+
+use klvmr::serde::node_from_bytes_backrefs;
+use klvmr::{run_program, Allocator, ChikDialect, MEMPOOL_MODE};
+use tracing_subscriber::fmt::format::FmtSpan;
+
+fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+
+    let block = include_bytes!("../benches/block_af9c3d98.bin");
+    let mut a = Allocator::new();
+    let generator = node_from_bytes_backrefs(&mut a, block).expect("invalid generator");
+    let env = a.nil();
+
+    match run_program(
+        &mut a,
+        &ChikDialect::new(MEMPOOL_MODE),
+        generator,
+        env,
+        11000000000,
+    ) {
+        Ok(reduction) => println!("cost: {}", reduction.0),
+        Err(e) => println!("error: {}", e.display(&a)),
+    }
+}
@@ -0,0 +1,69 @@
+#![no_main]
+mod fuzzing_utils;
+
+use libfuzzer_sys::fuzz_target;
+
+use klvmr::allocator::Allocator;
+use klvmr::chik_dialect::{
+    ChikDialect, ENABLE_BLS_OPS_OUTSIDE_GUARD, ENABLE_BLS_VERIFY_BASIC, ENABLE_FIXED_DIV,
+    ENABLE_SHA256_TAGGED, MEMPOOL_MODE,
+};
+use klvmr::dialect::OperatorSet;
+use klvmr::reduction::{EvalErr, Reduction};
+use klvmr::serde::node_from_bytes;
+
+// the first byte is the opcode and the rest are the arguments. Seed inputs
+// (from generate-fuzz-corpus) hold the serialized arguments, anything else is
+// turned into an arbitrary tree. Either way, the operator is called directly,
+// without going through the interpreter first
+fuzz_target!(|data: &[u8]| {
+    let Some((opcode, args)) = data.split_first() else {
+        return;
+    };
+
+    let mut a = Allocator::new();
+    let args = match node_from_bytes(&mut a, args) {
+        Ok(r) => r,
+        Err(_) => {
+            let mut cursor = fuzzing_utils::BitCursor::new(args);
+            fuzzing_utils::make_tree(&mut a, &mut cursor, true)
+        }
+    };
+
+    let checkpoint = a.checkpoint();
+    let pairs = a.pair_count();
+    let heap_size = a.heap_size();
+
+    let all_ops = ENABLE_BLS_OPS_OUTSIDE_GUARD
+        | ENABLE_FIXED_DIV
+        | ENABLE_BLS_VERIFY_BASIC
+        | ENABLE_SHA256_TAGGED;
+    for flags in [0, MEMPOOL_MODE, all_ops, MEMPOOL_MODE | all_ops] {
+        let dialect = ChikDialect::new(flags);
+        for extension in [OperatorSet::Default, OperatorSet::BLS] {
+            for max_cost in [11000000000, 11000000, 110000, 10, 1, 0] {
+                a.restore_checkpoint(&checkpoint);
+                match dialect.apply_opcode(&mut a, *opcode as u32, args, max_cost, extension) {
+                    Err(EvalErr(n, msg)) => {
+                        assert!(!msg.contains("internal error"));
+                        // make sure n is a valid node in the allocator
+                        a.sexp(n);
+                    }
+                    Ok(Reduction(cost, n)) => {
+                        assert!(cost <= max_cost);
+                        // make sure n is a valid node in the allocator
+                        a.sexp(n);
+                    }
+                }
+                // operators only ever add to the allocator
+                assert!(a.pair_count() >= pairs);
+                assert!(a.heap_size() >= heap_size);
+            }
+        }
+    }
+
+    // and the checkpoint undoes all of it
+    a.restore_checkpoint(&checkpoint);
+    assert_eq!(a.pair_count(), pairs);
+    assert_eq!(a.heap_size(), heap_size);
+});
@@ -2,17 +2,13 @@
 use libfuzzer_sys::fuzz_target;
 
 use klvmr::allocator::{Allocator, NodePtr};
-use klvmr::bls_ops::{
-    op_bls_g1_multiply, op_bls_g1_negate, op_bls_g1_subtract, op_bls_g2_add, op_bls_g2_multiply,
-    op_bls_g2_negate, op_bls_g2_subtract, op_bls_map_to_g1, op_bls_map_to_g2,
-    op_bls_pairing_identity, op_bls_verify,
-};
+use klvmr::chik_dialect::CHIK_EXTENSIONS;
 use klvmr::core_ops::{op_cons, op_eq, op_first, op_if, op_listp, op_raise, op_rest};
 use klvmr::cost::Cost;
 use klvmr::more_ops::{
-    op_add, op_all, op_any, op_ash, op_coinid, op_concat, op_div, op_divmod, op_gr, op_gr_bytes,
-    op_logand, op_logior, op_lognot, op_logxor, op_lsh, op_mod, op_modpow, op_multiply, op_not,
-    op_point_add, op_pubkey_for_exp, op_sha256, op_strlen, op_substr, op_subtract,
+    op_add, op_all, op_any, op_ash, op_concat, op_div, op_divmod, op_gr, op_gr_bytes, op_logand,
+    op_logior, op_lognot, op_logxor, op_lsh, op_multiply, op_not, op_point_add, op_pubkey_for_exp,
+    op_sha256, op_strlen, op_substr, op_subtract,
 };
 use klvmr::reduction::{EvalErr, Response};
 use klvmr::secp_ops::{op_secp256k1_verify, op_secp256r1_verify};
@@ -20,7 +16,8 @@ use klvmr::serde::node_from_bytes;
 
 type Opf = fn(&mut Allocator, NodePtr, Cost) -> Response;
 
-const FUNS: [Opf; 45] = [
+// the operators of the softfork extensions are added from CHIK_EXTENSIONS
+const FUNS: [Opf; 31] = [
     op_if as Opf,
     op_cons as Opf,
     op_first as Opf,
@@ -50,21 +47,6 @@ const FUNS: [Opf; 45] = [
     op_not as Opf,
     op_any as Opf,
     op_all as Opf,
-    // the BLS extension
-    op_coinid as Opf,
-    op_bls_g1_subtract as Opf,
-    op_bls_g1_multiply as Opf,
-    op_bls_g1_negate as Opf,
-    op_bls_g2_add as Opf,
-    op_bls_g2_subtract as Opf,
-    op_bls_g2_multiply as Opf,
-    op_bls_g2_negate as Opf,
-    op_bls_map_to_g1 as Opf,
-    op_bls_map_to_g2 as Opf,
-    op_bls_pairing_identity as Opf,
-    op_bls_verify as Opf,
-    op_mod as Opf,
-    op_modpow as Opf,
     // Secp operators
     op_secp256k1_verify as Opf,
     op_secp256r1_verify as Opf,
@@ -82,7 +64,10 @@ fuzz_target!(|data: &[u8]| {
 
     let allocator_checkpoint = allocator.checkpoint();
 
-    for op in FUNS {
+    let extension_ops = CHIK_EXTENSIONS
+        .iter()
+        .flat_map(|ext| ext.ops.iter().map(|op| op.f));
+    for op in FUNS.into_iter().chain(extension_ops) {
         for max_cost in [11000000, 1100000, 110000, 10, 1, 0] {
             allocator.restore_checkpoint(&allocator_checkpoint);
             match op(&mut allocator, args, max_cost) {
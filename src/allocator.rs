@@ -1,16 +1,43 @@
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use crate::bls_ops::{validate_g1_bytes, validate_g2_bytes, PointError};
+use crate::bytes::Bytes32;
+use crate::collections::{HashMap, HashSet};
 use crate::err_utils::err;
-use crate::number::{node_from_number, number_from_u8, Number};
+use crate::number::{node_from_number, number_from_u8, IntDecodeErr, Number};
 use crate::reduction::EvalErr;
+use crate::serde::{structural_hash, ObjectCache, SExpTree};
+use alloc::borrow::Cow;
+use alloc::sync::Arc;
+#[cfg(feature = "std")]
 use chik_bls::{G1Element, G2Element};
-use std::hash::Hash;
-use std::hash::Hasher;
+use core::cmp::min;
+use core::fmt;
+use core::hash::Hash;
+use core::hash::Hasher;
 
-const MAX_NUM_ATOMS: usize = 62500000;
-const MAX_NUM_PAIRS: usize = 62500000;
+pub const MAX_NUM_ATOMS: usize = 62500000;
+pub const MAX_NUM_PAIRS: usize = 62500000;
 const NODE_PTR_IDX_BITS: u32 = 26;
 const NODE_PTR_IDX_MASK: u32 = (1 << NODE_PTR_IDX_BITS) - 1;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+// the index of a ConstPair or ConstBytes node is split into the ID of the
+// ConstantTree (the top bits) and the index into that tree's arena
+const CONST_TREE_BITS: u32 = 4;
+const CONST_IDX_BITS: u32 = NODE_PTR_IDX_BITS - CONST_TREE_BITS;
+const CONST_IDX_MASK: u32 = (1 << CONST_IDX_BITS) - 1;
+
+/// The max number of different ConstantTrees an Allocator can import
+pub const MAX_CONSTANT_TREES: usize = 1 << CONST_TREE_BITS;
+
+// the max number of pairs new_small_pair() remembers for reuse
+const MAX_SMALL_PAIRS: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct NodePtr(u32);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -21,9 +48,28 @@ enum ObjectType {
     Bytes,
     // The low bits are the atom itself (unsigned integer, 26 bits)
     SmallAtom,
+    // The low bits identify one of the ConstantTrees imported into the
+    // allocator, and an index into its pair_vec
+    ConstPair,
+    // The low bits identify one of the ConstantTrees imported into the
+    // allocator, and an index into its atom_vec
+    ConstBytes,
+    // The low bits form an index into the atom_vec, holding the 32 byte tree
+    // hash of a sub tree that isn't present. See new_hash_stub()
+    HashStub,
 }
 
 // The top 6 bits of the NodePtr indicate what type of object it is
+// Nodes that live in a ConstantTree (constant-space) use their own object
+// types, ConstPair and ConstBytes. Their index refers to the arena owned by
+// a ConstantTree rather than the Allocator's own (local-space) arena. An
+// allocator can import several ConstantTrees, so the top 4 bits of the index
+// are the tree ID (the order in which it was imported) and the low 22 bits are
+// the index into that tree's arena. Small atoms don't refer to any arena, so
+// they are the same in both spaces.
+// Pairs in a ConstantTree store their children as local-space pointers into
+// the ConstantTree's arena. These are translated into constant-space when
+// they're returned from the Allocator.
 impl NodePtr {
     pub const NIL: Self = Self::new(ObjectType::SmallAtom, 0);
 
@@ -35,12 +81,37 @@ impl NodePtr {
     pub fn is_atom(self) -> bool {
         matches!(
             self.object_type(),
-            ObjectType::Bytes | ObjectType::SmallAtom
+            ObjectType::Bytes
+                | ObjectType::SmallAtom
+                | ObjectType::ConstBytes
+                | ObjectType::HashStub
         )
     }
 
     pub fn is_pair(self) -> bool {
-        self.object_type() == ObjectType::Pair
+        matches!(self.object_type(), ObjectType::Pair | ObjectType::ConstPair)
+    }
+
+    /// Returns true if this is a hash stub, created by
+    /// `Allocator::new_hash_stub()`
+    pub fn is_hash_stub(self) -> bool {
+        self.object_type() == ObjectType::HashStub
+    }
+
+    // returns true if this is a (local) pair at index idx or later
+    fn is_pair_from(self, idx: usize) -> bool {
+        self.object_type() == ObjectType::Pair && self.index() as usize >= idx
+    }
+
+    // returns true if this node, a child of the pair `parent`, is a pair that
+    // wasn't created before `parent`. Pairs only refer to pairs created before
+    // them (i.e. with a lower index in the same arena), so this is the only way
+    // a traversal could get back to `parent`. Traversals check this to fail
+    // rather than loop forever, should the allocator ever hold a cycle
+    pub(crate) fn may_cycle_to(self, parent: NodePtr) -> bool {
+        self.is_pair()
+            && self.object_type() == parent.object_type()
+            && self.index() >= parent.index()
     }
 
     fn object_type(self) -> ObjectType {
@@ -48,10 +119,34 @@ impl NodePtr {
             0 => ObjectType::Pair,
             1 => ObjectType::Bytes,
             2 => ObjectType::SmallAtom,
+            3 => ObjectType::ConstPair,
+            4 => ObjectType::ConstBytes,
+            5 => ObjectType::HashStub,
             _ => unreachable!(),
         }
     }
 
+    // translate a node from the arena of the ConstantTree with the specified
+    // ID into constant-space
+    fn to_const(self, tree: usize) -> Self {
+        let index = (tree << CONST_IDX_BITS) | self.index() as usize;
+        match self.object_type() {
+            ObjectType::Pair => Self::new(ObjectType::ConstPair, index),
+            ObjectType::Bytes => Self::new(ObjectType::ConstBytes, index),
+            _ => self,
+        }
+    }
+
+    // the ID of the ConstantTree a ConstPair or ConstBytes node belongs to
+    fn const_tree(self) -> usize {
+        (self.index() >> CONST_IDX_BITS) as usize
+    }
+
+    // the index of a ConstPair or ConstBytes node in its ConstantTree's arena
+    fn const_index(self) -> usize {
+        (self.index() & CONST_IDX_MASK) as usize
+    }
+
     fn index(self) -> u32 {
         self.0 & NODE_PTR_IDX_MASK
     }
@@ -63,6 +158,23 @@ impl Default for NodePtr {
     }
 }
 
+// the kind of node and its index, e.g. NodePtr(pair:1042). Small atoms are
+// printed with their value instead, e.g. NodePtr(small:5). This format is
+// relied on by log parsers, so don't change it
+impl fmt::Debug for NodePtr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.object_type() {
+            ObjectType::Pair => "pair",
+            ObjectType::Bytes => "atom",
+            ObjectType::SmallAtom => "small",
+            ObjectType::ConstPair => "const_pair",
+            ObjectType::ConstBytes => "const_atom",
+            ObjectType::HashStub => "hash_stub",
+        };
+        write!(f, "NodePtr({kind}:{})", self.index())
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub enum SExp {
     Atom,
@@ -95,6 +207,10 @@ pub struct Checkpoint {
     pairs: usize,
     atoms: usize,
     small_atoms: usize,
+    reused_pairs: usize,
+    // the number of ConstantTrees that had been imported
+    constants: usize,
+    hash_stubs: usize,
 }
 
 pub enum NodeVisitor<'a> {
@@ -130,12 +246,219 @@ impl<'a> AsRef<[u8]> for Atom<'a> {
     }
 }
 
+impl Atom<'_> {
+    /// The atom as a string, or None if it's not valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(self.as_ref()).ok()
+    }
+}
+
 impl<'a> Borrow<[u8]> for Atom<'a> {
     fn borrow(&self) -> &[u8] {
         self.as_ref()
     }
 }
 
+// iterates over the elements of a proper list. See Allocator::list_iter()
+pub struct ListIter<'a> {
+    allocator: &'a Allocator,
+    node: NodePtr,
+    remaining: usize,
+}
+
+impl Iterator for ListIter<'_> {
+    type Item = NodePtr;
+
+    fn next(&mut self) -> Option<NodePtr> {
+        let (first, rest) = self.allocator.next(self.node)?;
+        self.node = rest;
+        self.remaining -= 1;
+        Some(first)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for ListIter<'_> {}
+
+/// A position in a list that doesn't borrow the Allocator, to process a long
+/// list (e.g. the conditions returned by a generator) in chunks, yielding to
+/// other work in between. Elements are returned in list order.
+///
+/// The allocator passed to next_chunk() must be the one the list was created
+/// in, and it must not have been reset (or restored to an earlier checkpoint)
+/// since the cursor was created. In debug builds, the allocator's generation
+/// is checked to catch resets. Passing a different allocator is not detected.
+/// Allocating new nodes while the cursor is alive is fine.
+#[derive(Debug, Clone)]
+pub struct ListCursor {
+    generation: u64,
+    node: NodePtr,
+    index: usize,
+}
+
+impl ListCursor {
+    pub fn new(a: &Allocator, list: NodePtr) -> Self {
+        Self {
+            generation: a.generation(),
+            node: list,
+            index: 0,
+        }
+    }
+
+    /// Returns the next (up to) `n` elements of the list. Fewer than `n`
+    /// elements are returned once the end of the list is reached, and no
+    /// elements after that. Use improper_tail() to tell whether the list was
+    /// terminated by nil
+    pub fn next_chunk(&mut self, a: &Allocator, n: usize) -> Vec<NodePtr> {
+        debug_assert_eq!(
+            self.generation,
+            a.generation(),
+            "the allocator was reset while a ListCursor was in use"
+        );
+        let mut ret = Vec::new();
+        while ret.len() < n {
+            let Some((first, rest)) = a.next(self.node) else {
+                break;
+            };
+            ret.push(first);
+            self.node = rest;
+        }
+        self.index += ret.len();
+        ret
+    }
+
+    /// The number of elements returned so far
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Whether all elements of the list have been returned
+    pub fn is_done(&self, a: &Allocator) -> bool {
+        a.next(self.node).is_none()
+    }
+
+    /// Once the end of the list has been reached, returns the terminator if
+    /// it's not nil. Returns None for a proper list, or if there are more
+    /// elements to return
+    pub fn improper_tail(&self, a: &Allocator) -> Option<NodePtr> {
+        if self.is_done(a) && a.atom_len(self.node) != 0 {
+            Some(self.node)
+        } else {
+            None
+        }
+    }
+}
+
+// yields the atoms of a tree, left to right. See Allocator::atoms_iter()
+pub struct AtomsIter<'a> {
+    allocator: &'a Allocator,
+    stack: Vec<NodePtr>,
+}
+
+impl<'a> Iterator for AtomsIter<'a> {
+    type Item = Atom<'a>;
+
+    fn next(&mut self) -> Option<Atom<'a>> {
+        loop {
+            let node = self.stack.pop()?;
+            match self.allocator.sexp(node) {
+                SExp::Pair(first, rest) => {
+                    self.stack.push(rest);
+                    self.stack.push(first);
+                }
+                SExp::Atom => {
+                    return Some(self.allocator.atom(node));
+                }
+            }
+        }
+    }
+}
+
+type AtomPredicate<'a> = dyn Fn(&[u8]) -> bool + 'a;
+
+/// Yields the atoms of a tree matching a predicate, left to right. See
+/// `find_atoms()`
+pub struct AtomIter<'a> {
+    allocator: &'a Allocator,
+    stack: Vec<NodePtr>,
+    pred: Box<AtomPredicate<'a>>,
+    // when set, the nodes we've already traversed. Any node (pair or atom)
+    // that's reachable more than once is only traversed the first time
+    visited: Option<HashSet<NodePtr>>,
+}
+
+impl AtomIter<'_> {
+    /// Skip subtrees that have already been traversed. With this set, each
+    /// NodePtr is yielded at most once. Note that small atoms with the same
+    /// value are the same NodePtr, so they are only yielded once too
+    pub fn skip_visited(mut self, skip: bool) -> Self {
+        self.visited = if skip { Some(HashSet::new()) } else { None };
+        self
+    }
+}
+
+impl Iterator for AtomIter<'_> {
+    type Item = NodePtr;
+
+    fn next(&mut self) -> Option<NodePtr> {
+        loop {
+            let node = self.stack.pop()?;
+            if let Some(visited) = &mut self.visited {
+                if !visited.insert(node) {
+                    continue;
+                }
+            }
+            match self.allocator.sexp(node) {
+                SExp::Pair(first, rest) => {
+                    self.stack.push(rest);
+                    self.stack.push(first);
+                }
+                SExp::Atom => {
+                    if (self.pred)(self.allocator.atom(node).as_ref()) {
+                        return Some(node);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns an iterator over the atoms in the tree at `root` for which `pred`
+/// returns true, in left-to-right order (nil terminators included). The
+/// traversal uses an explicit stack, so deep trees don't overflow the call
+/// stack. Shared subtrees are traversed every time they're reached, unless
+/// `skip_visited(true)` is set on the returned iterator
+pub fn find_atoms<'a>(
+    a: &'a Allocator,
+    root: NodePtr,
+    pred: impl Fn(&[u8]) -> bool + 'a,
+) -> AtomIter<'a> {
+    AtomIter {
+        allocator: a,
+        stack: vec![root],
+        pred: Box::new(pred),
+        visited: None,
+    }
+}
+
+/// Returns an iterator over the atoms of length `len` in the tree at `root`.
+/// e.g. `find_atoms_of_len(a, root, 32)` finds all candidate puzzle hashes and
+/// coin IDs
+pub fn find_atoms_of_len(a: &Allocator, root: NodePtr, len: usize) -> AtomIter<'_> {
+    find_atoms(a, root, move |atom| atom.len() == len)
+}
+
+// the capacity of the buffers of a new allocator
+const INITIAL_HEAP_CAPACITY: usize = 1024 * 1024;
+const INITIAL_NODE_CAPACITY: usize = 256;
+
+// The allocator is an arena. All atom bytes live in one contiguous buffer
+// (u8_vec), and atoms and pairs are fixed size entries (8 bytes each) in their
+// own vectors. A NodePtr is a 32 bit value, tagging an index into one of them.
+// Atoms small enough to fit in the NodePtr itself don't use any storage.
 #[derive(Debug)]
 pub struct Allocator {
     // this is effectively a grow-only stack where atoms are allocated. Atoms
@@ -154,9 +477,125 @@ pub struct Allocator {
     // the atom_vec may not grow past this
     heap_limit: usize,
 
+    // the max number of atoms (including small atoms) and pairs that may be
+    // allocated
+    atom_limit: usize,
+    pair_limit: usize,
+
     // the number of small atoms we've allocated. We keep track of these to ensure the limit on the
     // number of atoms is identical to what it was before the small-atom optimization
     small_atoms: usize,
+
+    // pairs whose children are both small atoms, e.g. the (quotient .
+    // remainder) returned by divmod, indexed by their children. See
+    // new_small_pair()
+    small_pairs: HashMap<(NodePtr, NodePtr), NodePtr>,
+
+    // the number of times new_small_pair() returned an existing pair. These
+    // count towards the pair limit, to keep the limit identical to what it was
+    // before pairs were reused
+    reused_pairs: usize,
+
+    // atoms allocated by intern_atom(), indexed by their contents
+    interned_atoms: HashMap<Vec<u8>, NodePtr>,
+
+    // the immutable trees referenced by ConstPair and ConstBytes nodes,
+    // indexed by tree ID. See import_constant()
+    constants: Vec<ConstantTree>,
+
+    // incremented every time nodes are removed, by reset() or
+    // restore_checkpoint(). See generation()
+    generation: u64,
+
+    // the number of hash stubs allocated by new_hash_stub()
+    hash_stubs: usize,
+}
+
+// An immutable KLVM structure that can be shared by any number of
+// allocators, e.g. the generator ROM or standard puzzles that are used in
+// every block. Importing it into an Allocator is constant time, its nodes are
+// referenced in-place, not copied. Cloning a ConstantTree is cheap, the clones
+// share the same arena.
+#[derive(Debug, Clone)]
+pub struct ConstantTree {
+    arena: Arc<Allocator>,
+    root: NodePtr,
+}
+
+impl ConstantTree {
+    pub fn from_bytes(b: &[u8]) -> crate::io::Result<Self> {
+        let mut allocator = Allocator::new();
+        let root = crate::serde::node_from_bytes(&mut allocator, b)?;
+        // the tree ID takes up some of the index bits of constant nodes
+        if allocator.atom_vec.len() > CONST_IDX_MASK as usize
+            || allocator.pair_vec.len() > CONST_IDX_MASK as usize
+        {
+            return Err(crate::io::Error::new(
+                crate::io::ErrorKind::InvalidData,
+                "constant tree too large",
+            ));
+        }
+        allocator.u8_vec.shrink_to_fit();
+        allocator.atom_vec.shrink_to_fit();
+        allocator.pair_vec.shrink_to_fit();
+        Ok(Self {
+            arena: Arc::new(allocator),
+            root,
+        })
+    }
+}
+
+// the arena of the ConstantTree a ConstPair or ConstBytes node refers to. These
+// take the imported trees rather than the Allocator, so that an atom can be
+// copied from a constant arena into the allocator's own heap
+fn const_arena(constants: &[ConstantTree], node: NodePtr) -> &Allocator {
+    &constants
+        .get(node.const_tree())
+        .expect("constant node without a ConstantTree")
+        .arena
+}
+
+fn const_atom_buffer(constants: &[ConstantTree], node: NodePtr) -> &[u8] {
+    let arena = const_arena(constants, node);
+    let atom = arena.atom_vec[node.const_index()];
+    &arena.u8_vec[atom.start as usize..atom.end as usize]
+}
+
+// The snapshot format is a dump of the allocator's arenas, so loading it
+// doesn't involve any parsing of the KLVM serialization, and it preserves
+// shared structure. It's only meant to be read back by the same version of
+// this library. The layout is:
+// magic (8 bytes), version (u32), then the number of heap bytes, atoms,
+// small atoms, pairs and roots (u32 each), followed by the heap bytes, the
+// atoms (start, end), the pairs (first, rest) and the roots. All integers are
+// little endian.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"KLVMSNAP";
+const SNAPSHOT_VERSION: u32 = 1;
+
+fn snapshot_err<T>(msg: &str) -> crate::io::Result<T> {
+    Err(crate::io::Error::new(
+        crate::io::ErrorKind::InvalidData,
+        msg,
+    ))
+}
+
+struct SnapshotReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    fn bytes(&mut self, len: usize) -> crate::io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return snapshot_err("snapshot truncated");
+        }
+        let (ret, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(ret)
+    }
+
+    fn u32(&mut self) -> crate::io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
 }
 
 impl Default for Allocator {
@@ -212,6 +651,13 @@ impl Allocator {
     }
 
     pub fn new_limited(heap_limit: usize) -> Self {
+        Self::new_with_limits(heap_limit, MAX_NUM_ATOMS, MAX_NUM_PAIRS)
+    }
+
+    // like new_limited(), but also allows lowering the max number of atoms and
+    // pairs. These can't be raised above MAX_NUM_ATOMS and MAX_NUM_PAIRS
+    // respectively, since NodePtr can't address more than that
+    pub fn new_with_limits(heap_limit: usize, atom_limit: usize, pair_limit: usize) -> Self {
         // we have a maximum of 4 GiB heap, because pointers are 32 bit unsigned
         assert!(heap_limit <= u32::MAX as usize);
 
@@ -221,13 +667,21 @@ impl Allocator {
             atom_vec: Vec::new(),
             // subtract 1 to compensate for the one() we used to allocate unconfitionally
             heap_limit: heap_limit - 1,
+            atom_limit: min(atom_limit, MAX_NUM_ATOMS),
+            pair_limit: min(pair_limit, MAX_NUM_PAIRS),
             // initialize this to 2 to behave as if we had allocated atoms for
             // nil() and one(), like we used to
             small_atoms: 2,
+            small_pairs: HashMap::new(),
+            reused_pairs: 0,
+            interned_atoms: HashMap::new(),
+            constants: Vec::new(),
+            generation: 0,
+            hash_stubs: 0,
         };
-        r.u8_vec.reserve(1024 * 1024);
-        r.atom_vec.reserve(256);
-        r.pair_vec.reserve(256);
+        r.u8_vec.reserve(INITIAL_HEAP_CAPACITY);
+        r.atom_vec.reserve(INITIAL_NODE_CAPACITY);
+        r.pair_vec.reserve(INITIAL_NODE_CAPACITY);
         r
     }
 
@@ -240,6 +694,9 @@ impl Allocator {
             pairs: self.pair_vec.len(),
             atoms: self.atom_vec.len(),
             small_atoms: self.small_atoms,
+            reused_pairs: self.reused_pairs,
+            constants: self.constants.len(),
+            hash_stubs: self.hash_stubs,
         }
     }
 
@@ -255,6 +712,114 @@ impl Allocator {
         self.pair_vec.truncate(cp.pairs);
         self.atom_vec.truncate(cp.atoms);
         self.small_atoms = cp.small_atoms;
+        self.reused_pairs = cp.reused_pairs;
+        self.small_pairs
+            .retain(|_, pair| (pair.index() as usize) < cp.pairs);
+        self.interned_atoms
+            .retain(|_, atom| (atom.index() as usize) < cp.atoms);
+        // any node referring to a ConstantTree imported after the checkpoint
+        // is gone now
+        assert!(self.constants.len() >= cp.constants);
+        self.constants.truncate(cp.constants);
+        self.hash_stubs = cp.hash_stubs;
+        self.generation += 1;
+    }
+
+    /// Remove all nodes from the allocator, returning it to the state of a
+    /// newly created one (with the same limits). Unlike creating a new
+    /// allocator, this keeps the capacity of the internal buffers, so an
+    /// allocator can be reused, e.g. once per block, without allocating memory
+    /// every time.
+    ///
+    /// Any NodePtr into this allocator becomes invalid, except nil() and
+    /// one(). There's no room in a NodePtr to tell which allocator state it
+    /// belongs to, so using a stale NodePtr is not detected, unless it's out
+    /// of bounds (which panics). It may refer to an unrelated node allocated
+    /// after the reset.
+    pub fn reset(&mut self) {
+        self.u8_vec.clear();
+        self.pair_vec.clear();
+        self.atom_vec.clear();
+        self.small_atoms = 2;
+        self.small_pairs.clear();
+        self.reused_pairs = 0;
+        self.interned_atoms.clear();
+        self.constants.clear();
+        self.hash_stubs = 0;
+        self.generation += 1;
+    }
+
+    /// A counter that changes every time nodes are removed from the allocator,
+    /// by reset() or restore_checkpoint(). A NodePtr held across calls is only
+    /// known to still be valid if the generation is the same as when it was
+    /// created. See ListCursor
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The number of bytes reserved by the internal buffers, whether they are
+    /// in use or not. This grows as nodes are allocated, and is kept by
+    /// reset() and restore_checkpoint()
+    pub fn buffer_capacity(&self) -> usize {
+        self.u8_vec.capacity()
+            + self.pair_vec.capacity() * core::mem::size_of::<IntPair>()
+            + self.atom_vec.capacity() * core::mem::size_of::<AtomBuf>()
+    }
+
+    /// Release the memory of the internal buffers that isn't in use, keeping
+    /// the capacity of a newly created allocator. This is typically called
+    /// after reset(), when an allocator that's being reused has grown larger
+    /// than it's expected to need again
+    pub fn shrink_buffers(&mut self) {
+        self.u8_vec.shrink_to(INITIAL_HEAP_CAPACITY);
+        self.atom_vec.shrink_to(INITIAL_NODE_CAPACITY);
+        self.pair_vec.shrink_to(INITIAL_NODE_CAPACITY);
+        self.small_pairs.shrink_to_fit();
+        self.interned_atoms.shrink_to_fit();
+    }
+
+    // make the nodes of a ConstantTree available in this allocator, and
+    // return its root. The nodes are not copied and they don't count towards
+    // the allocator's limits. They can be used like any other node, including
+    // as children of new pairs. Up to MAX_CONSTANT_TREES different trees can
+    // be imported (e.g. the generator ROM and the standard puzzles), importing
+    // the same tree again returns the same root. Importing more trees than
+    // that fails.
+    pub fn import_constant(&mut self, c: &ConstantTree) -> Result<NodePtr, EvalErr> {
+        let tree = match self
+            .constants
+            .iter()
+            .position(|t| Arc::ptr_eq(&t.arena, &c.arena))
+        {
+            Some(tree) => tree,
+            None => {
+                if self.constants.len() >= MAX_CONSTANT_TREES {
+                    return err(self.nil(), "too many constant trees");
+                }
+                self.constants.push(c.clone());
+                self.constants.len() - 1
+            }
+        };
+        Ok(c.root.to_const(tree))
+    }
+
+    // the arena of the ConstantTree a ConstPair or ConstBytes node refers to.
+    // Those nodes can only be created by import_constant()
+    fn const_arena(&self, node: NodePtr) -> &Allocator {
+        const_arena(&self.constants, node)
+    }
+
+    // the buffer of an atom that's stored in either the local or a constant
+    // arena
+    fn atom_buffer(&self, node: NodePtr) -> &[u8] {
+        match node.object_type() {
+            ObjectType::Bytes | ObjectType::HashStub => {
+                let atom = self.atom_vec[node.index() as usize];
+                &self.u8_vec[atom.start as usize..atom.end as usize]
+            }
+            ObjectType::ConstBytes => const_atom_buffer(&self.constants, node),
+            _ => panic!("expected atom buffer"),
+        }
     }
 
     pub fn new_atom(&mut self, v: &[u8]) -> Result<NodePtr, EvalErr> {
@@ -275,6 +840,57 @@ impl Allocator {
         }
     }
 
+    /// Like `new_atom()`, but if an atom with the same contents was allocated
+    /// by `intern_atom()` earlier, that atom is returned instead of allocating
+    /// a new one. This is meant for values repeated many times, like 32 byte
+    /// hashes. Unlike `new_small_pair()`, returning an existing atom does not
+    /// count towards the atom limit.
+    pub fn intern_atom(&mut self, v: &[u8]) -> Result<NodePtr, EvalErr> {
+        if fits_in_small_atom(v).is_some() {
+            return self.new_atom(v);
+        }
+        if let Some(atom) = self.interned_atoms.get(v) {
+            return Ok(*atom);
+        }
+        let atom = self.new_atom(v)?;
+        self.interned_atoms.insert(v.to_vec(), atom);
+        Ok(atom)
+    }
+
+    /// Replace the contents of an atom. If `node` is a heap atom of the same
+    /// length as `bytes`, its bytes are overwritten in place and `node` is
+    /// returned. Otherwise a new atom is allocated, just like `new_atom()`.
+    ///
+    /// This is meant for building a tree, before it's shared. The bytes are
+    /// overwritten for every reference to `node`, and for any atom sharing
+    /// them (e.g. substrings created by `new_substr()`). Callers must not rely
+    /// on the original bytes afterwards, not even by restoring a checkpoint
+    /// taken before the call.
+    pub fn rewrite_atom(&mut self, node: NodePtr, bytes: &[u8]) -> Result<NodePtr, EvalErr> {
+        if node.is_pair() {
+            return err(node, "expected atom");
+        }
+        if node.object_type() != ObjectType::Bytes
+            || self.atom_len(node) != bytes.len()
+            || fits_in_small_atom(bytes).is_some()
+        {
+            return self.new_atom(bytes);
+        }
+        let atom = self.atom_vec[node.index() as usize];
+        let buf = &mut self.u8_vec[atom.start as usize..atom.end as usize];
+        // an interned atom no longer has the contents it was interned by
+        if self.interned_atoms.get(&buf[..]) == Some(&node) {
+            self.interned_atoms.remove(&buf[..]);
+        }
+        buf.copy_from_slice(bytes);
+        Ok(node)
+    }
+
+    /// Allocate an atom holding the UTF-8 encoding of `s`
+    pub fn new_str(&mut self, s: &str) -> Result<NodePtr, EvalErr> {
+        self.new_atom(s.as_bytes())
+    }
+
     pub fn new_small_number(&mut self, v: u32) -> Result<NodePtr, EvalErr> {
         debug_assert!(v <= NODE_PTR_IDX_MASK);
         self.check_atom_limit()?;
@@ -292,23 +908,156 @@ impl Allocator {
         node_from_number(self, &v)
     }
 
+    #[cfg(feature = "std")]
     pub fn new_g1(&mut self, g1: G1Element) -> Result<NodePtr, EvalErr> {
         self.new_atom(&g1.to_bytes())
     }
 
+    #[cfg(feature = "std")]
     pub fn new_g2(&mut self, g2: G2Element) -> Result<NodePtr, EvalErr> {
         self.new_atom(&g2.to_bytes())
     }
 
+    pub fn new_atom_b32(&mut self, v: &Bytes32) -> Result<NodePtr, EvalErr> {
+        self.new_atom(v.as_ref())
+    }
+
+    /// Allocate a hash stub, a leaf standing in for a sub tree that isn't
+    /// present, whose tree hash is `hash`. The tree hash of any tree
+    /// containing the stub is the same as if the full sub tree was there,
+    /// which allows computing the hash of a large tree from partial data.
+    /// Since the sub tree isn't known, a tree containing a stub can't be
+    /// serialized, and run_program() fails with "descend into hash stub" if
+    /// it needs to look inside one. Everywhere else, a stub looks like a 32
+    /// byte atom holding the hash, except that it's only equal to stubs of
+    /// the same hash
+    pub fn new_hash_stub(&mut self, hash: Bytes32) -> Result<NodePtr, EvalErr> {
+        let start = self.u8_vec.len() as u32;
+        if (self.heap_limit - start as usize) < hash.len() {
+            return err(self.nil(), "out of memory");
+        }
+        let idx = self.atom_vec.len();
+        self.check_atom_limit()?;
+        self.u8_vec.extend_from_slice(hash.as_ref());
+        let end = self.u8_vec.len() as u32;
+        self.atom_vec.push(AtomBuf { start, end });
+        self.hash_stubs += 1;
+        Ok(NodePtr::new(ObjectType::HashStub, idx))
+    }
+
+    /// The tree hash a hash stub stands in for, or None if `node` isn't a
+    /// hash stub (or is a lazy environment, which doesn't have one)
+    pub fn hash_stub(&self, node: NodePtr) -> Option<Bytes32> {
+        if !node.is_hash_stub() {
+            return None;
+        }
+        self.atom_buffer(node).try_into().ok()
+    }
+
+    /// Returns true if any hash stubs have been allocated (and not removed by
+    /// reset() or restore_checkpoint())
+    pub fn has_hash_stubs(&self) -> bool {
+        self.hash_stubs > 0
+    }
+
+    /// Allocate a lazy environment, a leaf standing in for a sub tree of a
+    /// program's environment that's only produced when the program looks
+    /// into it, by the provider passed to `run_program_with_env_provider()`.
+    /// It's a hash stub without a hash, so everything that fails on a hash
+    /// stub fails on a lazy environment too. Its tree hash is unknown, and is
+    /// computed as if it was nil
+    pub fn new_lazy_env(&mut self) -> Result<NodePtr, EvalErr> {
+        let idx = self.atom_vec.len();
+        self.check_atom_limit()?;
+        let start = self.u8_vec.len() as u32;
+        self.atom_vec.push(AtomBuf { start, end: start });
+        self.hash_stubs += 1;
+        Ok(NodePtr::new(ObjectType::HashStub, idx))
+    }
+
+    /// Returns true if `node` was allocated by `new_lazy_env()`
+    pub fn is_lazy_env(&self, node: NodePtr) -> bool {
+        node.is_hash_stub() && self.atom_buffer(node).is_empty()
+    }
+
     pub fn new_pair(&mut self, first: NodePtr, rest: NodePtr) -> Result<NodePtr, EvalErr> {
         let idx = self.pair_vec.len();
-        if idx == MAX_NUM_PAIRS {
+        if idx + self.reused_pairs >= self.pair_limit {
             return err(self.nil(), "too many pairs");
         }
+        // a pair may only refer to pairs created before it. This guarantees the
+        // structure is a DAG, and it's the invariant the cycle checks in tree
+        // hashing and serialization rely on (see NodePtr::may_cycle_to()).
+        // Only a stale NodePtr, e.g. from before restoring a checkpoint, can
+        // violate this
+        if first.is_pair_from(idx) || rest.is_pair_from(idx) {
+            return err(self.nil(), "pair would create a cycle");
+        }
         self.pair_vec.push(IntPair { first, rest });
         Ok(NodePtr::new(ObjectType::Pair, idx))
     }
 
+    // overwrite the children of an existing pair. Pairs are immutable, this is
+    // only used by tests to build a cyclic structure, which the allocator
+    // otherwise rules out
+    #[cfg(test)]
+    pub(crate) fn set_pair(&mut self, pair: NodePtr, first: NodePtr, rest: NodePtr) {
+        assert_eq!(pair.object_type(), ObjectType::Pair);
+        self.pair_vec[pair.index() as usize] = IntPair { first, rest };
+    }
+
+    /// Build the structure `(item0 item1 ... . tail)`, right to left. With a
+    /// nil `tail`, this is a proper list. Either all pairs are allocated, or
+    /// none are. Returns `tail` if `items` is empty.
+    pub fn new_pair_many(&mut self, items: &[NodePtr], tail: NodePtr) -> Result<NodePtr, EvalErr> {
+        let first_idx = self.pair_vec.len();
+        if self
+            .pair_limit
+            .saturating_sub(first_idx + self.reused_pairs)
+            < items.len()
+        {
+            return err(self.nil(), "too many pairs");
+        }
+        if tail.is_pair_from(first_idx) || items.iter().any(|n| n.is_pair_from(first_idx)) {
+            return err(self.nil(), "pair would create a cycle");
+        }
+        self.pair_vec.reserve(items.len());
+        let mut rest = tail;
+        for first in items.iter().rev() {
+            let idx = self.pair_vec.len();
+            self.pair_vec.push(IntPair {
+                first: *first,
+                rest,
+            });
+            rest = NodePtr::new(ObjectType::Pair, idx);
+        }
+        Ok(rest)
+    }
+
+    /// Like `new_pair()`, but if both `first` and `rest` are small atoms, a
+    /// pair allocated earlier with the same children may be returned instead.
+    /// Pairs are immutable, so this is only observable through `pair_count()`.
+    /// The pair limit is enforced as if a new pair was allocated.
+    pub fn new_small_pair(&mut self, first: NodePtr, rest: NodePtr) -> Result<NodePtr, EvalErr> {
+        if first.object_type() != ObjectType::SmallAtom
+            || rest.object_type() != ObjectType::SmallAtom
+        {
+            return self.new_pair(first, rest);
+        }
+        if let Some(pair) = self.small_pairs.get(&(first, rest)) {
+            if self.pair_vec.len() + self.reused_pairs >= self.pair_limit {
+                return err(self.nil(), "too many pairs");
+            }
+            self.reused_pairs += 1;
+            return Ok(*pair);
+        }
+        let pair = self.new_pair(first, rest)?;
+        if self.small_pairs.len() < MAX_SMALL_PAIRS {
+            self.small_pairs.insert((first, rest), pair);
+        }
+        Ok(pair)
+    }
+
     pub fn new_substr(&mut self, node: NodePtr, start: u32, end: u32) -> Result<NodePtr, EvalErr> {
         self.check_atom_limit()?;
 
@@ -326,8 +1075,29 @@ impl Allocator {
         }
 
         match node.object_type() {
-            ObjectType::Pair => err(node, "(internal error) substr expected atom, got pair"),
-            ObjectType::Bytes => {
+            ObjectType::Pair | ObjectType::ConstPair => {
+                err(node, "(internal error) substr expected atom, got pair")
+            }
+            ObjectType::ConstBytes => {
+                // the constant arena can't be referenced by local atoms, so
+                // the substring is copied
+                bounds_check(node, start, end, self.atom_len(node) as u32)?;
+                let len = (end - start) as usize;
+                let new_start = self.u8_vec.len();
+                if self.heap_limit - new_start < len {
+                    return err(self.nil(), "out of memory");
+                }
+                let buf = const_atom_buffer(&self.constants, node);
+                self.u8_vec
+                    .extend_from_slice(&buf[start as usize..end as usize]);
+                let idx = self.atom_vec.len();
+                self.atom_vec.push(AtomBuf {
+                    start: new_start as u32,
+                    end: (new_start + len) as u32,
+                });
+                Ok(NodePtr::new(ObjectType::Bytes, idx))
+            }
+            ObjectType::Bytes | ObjectType::HashStub => {
                 let atom = self.atom_vec[node.index() as usize];
                 let atom_len = atom.end - atom.start;
                 bounds_check(node, start, end, atom_len)?;
@@ -372,42 +1142,50 @@ impl Allocator {
         // TODO: maybe it would make sense to have a special case where
         // nodes.len() == 1. We can just return the same node
 
-        self.u8_vec.reserve(new_size);
-
+        // validate all terms before writing anything. This way the destination
+        // is reserved once, each term is copied straight into place and there
+        // is no partially written atom to roll back
         let mut counter: usize = 0;
+        for node in nodes {
+            if node.is_pair() {
+                return err(*node, "(internal error) concat expected atom, got pair");
+            }
+            counter += self.atom_len(*node);
+            if counter > new_size {
+                return err(*node, "(internal error) concat passed invalid new_size");
+            }
+        }
+        if counter != new_size {
+            return err(
+                self.nil(),
+                "(internal error) concat passed invalid new_size",
+            );
+        }
+
+        self.u8_vec.reserve(new_size);
         for node in nodes {
             match node.object_type() {
-                ObjectType::Pair => {
-                    self.u8_vec.truncate(start);
-                    return err(*node, "(internal error) concat expected atom, got pair");
+                ObjectType::Pair | ObjectType::ConstPair => {
+                    unreachable!("concat terms were validated");
+                }
+                ObjectType::ConstBytes => {
+                    self.u8_vec
+                        .extend_from_slice(const_atom_buffer(&self.constants, *node));
                 }
-                ObjectType::Bytes => {
+                ObjectType::Bytes | ObjectType::HashStub => {
                     let term = self.atom_vec[node.index() as usize];
-                    if counter + term.len() > new_size {
-                        self.u8_vec.truncate(start);
-                        return err(*node, "(internal error) concat passed invalid new_size");
-                    }
                     self.u8_vec
                         .extend_from_within(term.start as usize..term.end as usize);
-                    counter += term.len();
                 }
                 ObjectType::SmallAtom => {
                     let val = node.index();
-                    let len = len_for_value(val) as u32;
+                    let len = len_for_value(val);
                     let buf: [u8; 4] = val.to_be_bytes();
-                    let buf = &buf[4 - len as usize..];
-                    self.u8_vec.extend_from_slice(buf);
-                    counter += len as usize;
+                    self.u8_vec.extend_from_slice(&buf[4 - len..]);
                 }
             }
         }
-        if counter != new_size {
-            self.u8_vec.truncate(start);
-            return err(
-                self.nil(),
-                "(internal error) concat passed invalid new_size",
-            );
-        }
+        debug_assert_eq!(self.u8_vec.len(), start + new_size);
         let end = self.u8_vec.len() as u32;
         let idx = self.atom_vec.len();
         self.atom_vec.push(AtomBuf {
@@ -422,35 +1200,70 @@ impl Allocator {
         let rhs_type = rhs.object_type();
 
         match (lhs_type, rhs_type) {
-            (ObjectType::Pair, _) | (_, ObjectType::Pair) => {
+            (ObjectType::Pair | ObjectType::ConstPair, _)
+            | (_, ObjectType::Pair | ObjectType::ConstPair) => {
                 panic!("atom_eq() called on pair");
             }
-            (ObjectType::Bytes, ObjectType::Bytes) => {
-                let lhs = self.atom_vec[lhs.index() as usize];
-                let rhs = self.atom_vec[rhs.index() as usize];
-                self.u8_vec[lhs.start as usize..lhs.end as usize]
-                    == self.u8_vec[rhs.start as usize..rhs.end as usize]
+            (ObjectType::HashStub, ObjectType::HashStub) => {
+                self.atom_buffer(lhs) == self.atom_buffer(rhs)
             }
+            // a stub stands in for a sub tree, not an atom
+            (ObjectType::HashStub, _) | (_, ObjectType::HashStub) => false,
             (ObjectType::SmallAtom, ObjectType::SmallAtom) => lhs.index() == rhs.index(),
-            (ObjectType::SmallAtom, ObjectType::Bytes) => {
-                self.bytes_eq_int(self.atom_vec[rhs.index() as usize], lhs.index())
+            (ObjectType::SmallAtom, _) => Self::bytes_eq_int(self.atom_buffer(rhs), lhs.index()),
+            (_, ObjectType::SmallAtom) => Self::bytes_eq_int(self.atom_buffer(lhs), rhs.index()),
+            _ => self.atom_buffer(lhs) == self.atom_buffer(rhs),
+        }
+    }
+
+    /// A fast, non-cryptographic hash of the structure and atoms of the tree
+    /// at `node`. Equal trees hash equally, regardless of how they were
+    /// allocated. It's not collision resistant, so when used to look up sub
+    /// trees, e.g. to de-duplicate them, a match must be confirmed with
+    /// `structural_eq()`. Shared sub trees are only hashed once.
+    pub fn structural_hash(&self, node: NodePtr) -> u64 {
+        let mut cache = ObjectCache::new(self, structural_hash);
+        *cache
+            .get_or_calculate(&node)
+            .expect("structural_hash is defined for all nodes")
+    }
+
+    /// Returns true if the trees at `lhs` and `rhs` have the same structure
+    /// and atoms
+    pub fn structural_eq(&self, lhs: NodePtr, rhs: NodePtr) -> bool {
+        let mut stack = vec![(lhs, rhs)];
+        while let Some((lhs, rhs)) = stack.pop() {
+            if lhs == rhs {
+                continue;
             }
-            (ObjectType::Bytes, ObjectType::SmallAtom) => {
-                self.bytes_eq_int(self.atom_vec[lhs.index() as usize], rhs.index())
+            match (self.sexp(lhs), self.sexp(rhs)) {
+                (SExp::Pair(lhs_first, lhs_rest), SExp::Pair(rhs_first, rhs_rest)) => {
+                    stack.push((lhs_rest, rhs_rest));
+                    stack.push((lhs_first, rhs_first));
+                }
+                (SExp::Atom, SExp::Atom) => {
+                    if !self.atom_eq(lhs, rhs) {
+                        return false;
+                    }
+                }
+                _ => {
+                    return false;
+                }
             }
         }
+        true
     }
 
-    fn bytes_eq_int(&self, atom: AtomBuf, val: u32) -> bool {
-        let len = len_for_value(val) as u32;
-        if (atom.end - atom.start) != len {
+    fn bytes_eq_int(atom: &[u8], val: u32) -> bool {
+        let len = len_for_value(val);
+        if atom.len() != len {
             return false;
         }
         if val == 0 {
             return true;
         }
 
-        if self.u8_vec[atom.start as usize] & 0x80 != 0 {
+        if atom[0] & 0x80 != 0 {
             // SmallAtom only represents positive values
             // if the byte buffer is negative, they can't match
             return false;
@@ -459,9 +1272,9 @@ impl Allocator {
         // since we know the value of atom is small, we can turn it into a u32 and compare
         // against val
         let mut atom_val: u32 = 0;
-        for i in atom.start..atom.end {
+        for b in atom {
             atom_val <<= 8;
-            atom_val |= self.u8_vec[i as usize] as u32;
+            atom_val |= *b as u32;
         }
         val == atom_val
     }
@@ -479,25 +1292,55 @@ impl Allocator {
                 let bytes = index.to_be_bytes();
                 Atom::U32(bytes, len)
             }
+            ObjectType::ConstBytes | ObjectType::HashStub => Atom::Borrowed(self.atom_buffer(node)),
             _ => panic!("expected atom, got pair"),
         }
     }
 
+    // copies the atom bytes into an owned vector, to outlive the borrow of the
+    // allocator. Returns None for pairs
+    pub fn atom_owned(&self, node: NodePtr) -> Option<Vec<u8>> {
+        match self.sexp(node) {
+            SExp::Atom => Some(self.atom(node).as_ref().to_vec()),
+            SExp::Pair(..) => None,
+        }
+    }
+
+    /// Returns the atom as a string, or None if it's a pair or not valid
+    /// UTF-8. Small atoms aren't stored on the heap, so they are returned as
+    /// an owned string.
+    pub fn atom_as_str(&self, node: NodePtr) -> Option<Cow<'_, str>> {
+        if node.is_pair() {
+            return None;
+        }
+        match self.atom(node) {
+            Atom::Borrowed(buf) => core::str::from_utf8(buf).ok().map(Cow::Borrowed),
+            atom => atom.as_str().map(|s| Cow::Owned(s.to_string())),
+        }
+    }
+
     pub fn atom_len(&self, node: NodePtr) -> usize {
         let index = node.index();
 
         match node.object_type() {
-            ObjectType::Bytes => {
-                let atom = self.atom_vec[index as usize];
-                (atom.end - atom.start) as usize
-            }
+            ObjectType::Bytes => self.atom_vec[index as usize].len(),
             ObjectType::SmallAtom => len_for_value(index),
+            ObjectType::ConstBytes | ObjectType::HashStub => self.atom_buffer(node).len(),
             _ => {
                 panic!("expected atom, got pair");
             }
         }
     }
 
+    /// the length of the atom, or None if `node` is a pair. Unlike `atom()`,
+    /// this doesn't need to look at the atom's bytes
+    pub fn try_atom_len(&self, node: NodePtr) -> Option<usize> {
+        if node.is_pair() {
+            return None;
+        }
+        Some(self.atom_len(node))
+    }
+
     pub fn small_number(&self, node: NodePtr) -> Option<u32> {
         match node.object_type() {
             ObjectType::SmallAtom => Some(node.index()),
@@ -506,6 +1349,7 @@ impl Allocator {
                 let buf = &self.u8_vec[atom.start as usize..atom.end as usize];
                 fits_in_small_atom(buf)
             }
+            ObjectType::ConstBytes => fits_in_small_atom(self.atom_buffer(node)),
             _ => None,
         }
     }
@@ -519,56 +1363,73 @@ impl Allocator {
                 number_from_u8(&self.u8_vec[atom.start as usize..atom.end as usize])
             }
             ObjectType::SmallAtom => Number::from(index),
+            ObjectType::ConstBytes | ObjectType::HashStub => number_from_u8(self.atom_buffer(node)),
             _ => {
                 panic!("number() calld on pair");
             }
         }
     }
 
+    /// decode the atom as a signed, big endian, integer of type T (e.g. u8 or
+    /// i64). The empty atom is 0. Unlike `number()`, this doesn't panic on
+    /// pairs, and values that don't fit in T are reported as Overflow, rather
+    /// than being truncated
+    pub fn as_int<T: TryFrom<Number>>(&self, node: NodePtr) -> Result<T, IntDecodeErr> {
+        if node.is_pair() {
+            return Err(IntDecodeErr::NotAnAtom);
+        }
+        T::try_from(self.number(node)).map_err(|_| IntDecodeErr::Overflow)
+    }
+
+    /// the atom as a `Bytes32`, or None if it's a pair or not 32 bytes
+    pub fn atom_b32(&self, node: NodePtr) -> Option<Bytes32> {
+        if self.try_atom_len(node)? != 32 {
+            return None;
+        }
+        self.atom(node).as_ref().try_into().ok()
+    }
+
+    #[cfg(feature = "std")]
     pub fn g1(&self, node: NodePtr) -> Result<G1Element, EvalErr> {
-        let idx = match node.object_type() {
-            ObjectType::Bytes => node.index(),
+        let buf = match node.object_type() {
+            ObjectType::Bytes | ObjectType::ConstBytes | ObjectType::HashStub => {
+                self.atom_buffer(node)
+            }
             ObjectType::SmallAtom => {
                 return err(node, "atom is not G1 size, 48 bytes");
             }
-            ObjectType::Pair => {
+            ObjectType::Pair | ObjectType::ConstPair => {
                 return err(node, "pair found, expected G1 point");
             }
         };
-        let atom = self.atom_vec[idx as usize];
-        if atom.end - atom.start != 48 {
-            return err(node, "atom is not G1 size, 48 bytes");
-        }
-
-        let array: &[u8; 48] = &self.u8_vec[atom.start as usize..atom.end as usize]
-            .try_into()
-            .expect("atom size is not 48 bytes");
-        G1Element::from_bytes(array)
-            .map_err(|_| EvalErr(node, "atom is not a G1 point".to_string()))
+        validate_g1_bytes(buf, true).map_err(|e| match e {
+            PointError::BadLength => {
+                EvalErr::new(node, "atom is not G1 size, 48 bytes".to_string())
+            }
+            _ => EvalErr::new(node, "atom is not a G1 point".to_string()),
+        })
     }
 
+    #[cfg(feature = "std")]
     pub fn g2(&self, node: NodePtr) -> Result<G2Element, EvalErr> {
-        let idx = match node.object_type() {
-            ObjectType::Bytes => node.index(),
+        let buf = match node.object_type() {
+            ObjectType::Bytes | ObjectType::ConstBytes | ObjectType::HashStub => {
+                self.atom_buffer(node)
+            }
             ObjectType::SmallAtom => {
                 return err(node, "atom is not G2 size, 96 bytes");
             }
-            ObjectType::Pair => {
+            ObjectType::Pair | ObjectType::ConstPair => {
                 return err(node, "pair found, expected G2 point");
             }
         };
 
-        let atom = self.atom_vec[idx as usize];
-        if atom.end - atom.start != 96 {
-            return err(node, "atom is not G2 size, 96 bytes");
-        }
-
-        let array: &[u8; 96] = &self.u8_vec[atom.start as usize..atom.end as usize]
-            .try_into()
-            .expect("atom size is not 96 bytes");
-
-        G2Element::from_bytes(array)
-            .map_err(|_| EvalErr(node, "atom is not a G2 point".to_string()))
+        validate_g2_bytes(buf, true).map_err(|e| match e {
+            PointError::BadLength => {
+                EvalErr::new(node, "atom is not G2 size, 96 bytes".to_string())
+            }
+            _ => EvalErr::new(node, "atom is not a G2 point".to_string()),
+        })
     }
 
     pub fn node(&self, node: NodePtr) -> NodeVisitor {
@@ -585,16 +1446,32 @@ impl Allocator {
                 let pair = self.pair_vec[index as usize];
                 NodeVisitor::Pair(pair.first, pair.rest)
             }
+            ObjectType::ConstBytes | ObjectType::HashStub => {
+                NodeVisitor::Buffer(self.atom_buffer(node))
+            }
+            ObjectType::ConstPair => {
+                let tree = node.const_tree();
+                let pair = self.const_arena(node).pair_vec[node.const_index()];
+                NodeVisitor::Pair(pair.first.to_const(tree), pair.rest.to_const(tree))
+            }
         }
     }
 
     pub fn sexp(&self, node: NodePtr) -> SExp {
         match node.object_type() {
-            ObjectType::Bytes | ObjectType::SmallAtom => SExp::Atom,
+            ObjectType::Bytes
+            | ObjectType::SmallAtom
+            | ObjectType::ConstBytes
+            | ObjectType::HashStub => SExp::Atom,
             ObjectType::Pair => {
                 let pair = self.pair_vec[node.index() as usize];
                 SExp::Pair(pair.first, pair.rest)
             }
+            ObjectType::ConstPair => {
+                let tree = node.const_tree();
+                let pair = self.const_arena(node).pair_vec[node.const_index()];
+                SExp::Pair(pair.first.to_const(tree), pair.rest.to_const(tree))
+            }
         }
     }
 
@@ -610,17 +1487,338 @@ impl Allocator {
         }
     }
 
-    pub fn nil(&self) -> NodePtr {
-        NodePtr::new(ObjectType::SmallAtom, 0)
-    }
-
+    // returns the number of elements in the list, or None if it's not a proper
+    // list (i.e. not terminated by nil)
+    pub fn list_len(&self, mut node: NodePtr) -> Option<usize> {
+        let mut len = 0;
+        while let Some((_, rest)) = self.next(node) {
+            len += 1;
+            node = rest;
+        }
+        if self.atom_len(node) == 0 {
+            Some(len)
+        } else {
+            None
+        }
+    }
+
+    // returns an iterator over the elements of a proper list, which knows its
+    // length. Returns None if the list is not terminated by nil
+    pub fn list_iter(&self, node: NodePtr) -> Option<ListIter<'_>> {
+        let remaining = self.list_len(node)?;
+        Some(ListIter {
+            allocator: self,
+            node,
+            remaining,
+        })
+    }
+
+    // returns an iterator over all atoms in the tree, including nil terminators,
+    // in left-to-right order. The traversal is iterative and doesn't allocate
+    // any nodes
+    pub fn atoms_iter(&self, node: NodePtr) -> AtomsIter<'_> {
+        AtomsIter {
+            allocator: self,
+            stack: vec![node],
+        }
+    }
+
+    /// Copies the tree at `node` into an owned `SExpTree`, detached from the
+    /// allocator. The traversal is iterative, so deep trees don't overflow the
+    /// stack. Shared sub trees are copied once per reference
+    pub fn to_owned_tree(&self, node: NodePtr) -> SExpTree {
+        enum Op {
+            Visit(NodePtr),
+            Cons,
+        }
+        let mut ops = vec![Op::Visit(node)];
+        let mut values = Vec::<SExpTree>::new();
+        while let Some(op) = ops.pop() {
+            match op {
+                Op::Visit(node) => match self.sexp(node) {
+                    SExp::Pair(first, rest) => {
+                        ops.push(Op::Cons);
+                        ops.push(Op::Visit(rest));
+                        ops.push(Op::Visit(first));
+                    }
+                    SExp::Atom => {
+                        values.push(SExpTree::Atom(self.atom(node).as_ref().to_vec()));
+                    }
+                },
+                Op::Cons => {
+                    let rest = values.pop().expect("internal error, empty stack");
+                    let first = values.pop().expect("internal error, empty stack");
+                    values.push(SExpTree::Pair(Box::new(first), Box::new(rest)));
+                }
+            }
+        }
+        values.pop().expect("internal error, empty stack")
+    }
+
+    /// Returns the tree at `node` with every atom for which `f` returns a node
+    /// replaced by that node. Atoms for which `f` returns `None` are kept.
+    /// Only the pairs above replaced atoms are rebuilt, sub trees without any
+    /// replacements are shared with the original tree (so if nothing is
+    /// replaced, `node` itself is returned). The traversal is iterative and
+    /// shared sub trees are only visited once
+    pub fn replace_atoms<F>(&mut self, node: NodePtr, f: F) -> Result<NodePtr, EvalErr>
+    where
+        F: Fn(&[u8]) -> Option<NodePtr>,
+    {
+        enum Op {
+            Visit(NodePtr),
+            Cons(NodePtr),
+        }
+        // maps nodes we've already visited to their replacement
+        let mut done = HashMap::<NodePtr, NodePtr>::new();
+        let mut ops = vec![Op::Visit(node)];
+        let mut values = Vec::<NodePtr>::new();
+        while let Some(op) = ops.pop() {
+            match op {
+                Op::Visit(node) => {
+                    if let Some(replacement) = done.get(&node) {
+                        values.push(*replacement);
+                        continue;
+                    }
+                    match self.sexp(node) {
+                        SExp::Atom => {
+                            let replacement = f(self.atom(node).as_ref()).unwrap_or(node);
+                            done.insert(node, replacement);
+                            values.push(replacement);
+                        }
+                        SExp::Pair(left, right) => {
+                            ops.push(Op::Cons(node));
+                            ops.push(Op::Visit(right));
+                            ops.push(Op::Visit(left));
+                        }
+                    }
+                }
+                Op::Cons(node) => {
+                    let right = values.pop().expect("internal error, empty stack");
+                    let left = values.pop().expect("internal error, empty stack");
+                    let SExp::Pair(old_left, old_right) = self.sexp(node) else {
+                        unreachable!();
+                    };
+                    let replacement = if left == old_left && right == old_right {
+                        node
+                    } else {
+                        self.new_pair(left, right)?
+                    };
+                    done.insert(node, replacement);
+                    values.push(replacement);
+                }
+            }
+        }
+        Ok(values.pop().expect("internal error, empty stack"))
+    }
+
+    // builds a new, nil-terminated, list by applying f() to each element of the
+    // list. An improper tail is dropped
+    pub fn map_list<F>(&mut self, node: NodePtr, mut f: F) -> Result<NodePtr, EvalErr>
+    where
+        F: FnMut(&mut Self, NodePtr) -> Result<NodePtr, EvalErr>,
+    {
+        let mut items = Vec::<NodePtr>::new();
+        let mut node = node;
+        while let Some((first, rest)) = self.next(node) {
+            items.push(first);
+            node = rest;
+        }
+        for item in &mut items {
+            *item = f(self, *item)?;
+        }
+        let mut ret = self.nil();
+        for item in items.into_iter().rev() {
+            ret = self.new_pair(item, ret)?;
+        }
+        Ok(ret)
+    }
+
+    // like map_list(), but returns None if the list is not terminated by nil
+    pub fn try_map_list<F>(&mut self, node: NodePtr, f: F) -> Result<Option<NodePtr>, EvalErr>
+    where
+        F: FnMut(&mut Self, NodePtr) -> Result<NodePtr, EvalErr>,
+    {
+        if self.list_len(node).is_none() {
+            return Ok(None);
+        }
+        self.map_list(node, f).map(Some)
+    }
+
+    // returns the list with head added to the front, i.e. (head . list). The
+    // list itself is shared
+    pub fn prepend(&mut self, list: NodePtr, head: NodePtr) -> Result<NodePtr, EvalErr> {
+        self.new_pair(head, list)
+    }
+
+    // builds a new, nil-terminated, list with item added to the end. All pairs
+    // of the list have to be copied, so this is O(n). An improper tail is
+    // dropped
+    pub fn append(&mut self, list: NodePtr, item: NodePtr) -> Result<NodePtr, EvalErr> {
+        let mut items = Vec::<NodePtr>::new();
+        let mut node = list;
+        while let Some((first, rest)) = self.next(node) {
+            items.push(first);
+            node = rest;
+        }
+        items.push(item);
+        let nil = self.nil();
+        self.new_pair_many(&items, nil)
+    }
+
+    // like append(), but returns None if the list is not terminated by nil
+    pub fn try_append(&mut self, list: NodePtr, item: NodePtr) -> Result<Option<NodePtr>, EvalErr> {
+        if self.list_len(list).is_none() {
+            return Ok(None);
+        }
+        self.append(list, item).map(Some)
+    }
+
+    // serialize the allocator along with the specified roots, to be loaded by
+    // from_snapshot(). This is a lot faster to load than the KLVM
+    // serialization, and it preserves shared structure. Allocators that have
+    // imported a ConstantTree can't be snapshotted.
+    pub fn snapshot(&self, roots: &[NodePtr]) -> crate::io::Result<Vec<u8>> {
+        if !self.constants.is_empty() {
+            return Err(crate::io::Error::new(
+                crate::io::ErrorKind::Unsupported,
+                "can't snapshot an allocator referencing a constant tree",
+            ));
+        }
+        if self.hash_stubs > 0 {
+            return Err(crate::io::Error::new(
+                crate::io::ErrorKind::Unsupported,
+                "can't snapshot an allocator with hash stubs",
+            ));
+        }
+        let mut ret = Vec::with_capacity(
+            SNAPSHOT_MAGIC.len()
+                + 4 * 6
+                + self.u8_vec.len()
+                + 8 * (self.atom_vec.len() + self.pair_vec.len())
+                + 4 * roots.len(),
+        );
+        ret.extend_from_slice(SNAPSHOT_MAGIC);
+        for v in [
+            SNAPSHOT_VERSION,
+            self.u8_vec.len() as u32,
+            self.atom_vec.len() as u32,
+            self.small_atoms as u32,
+            self.pair_vec.len() as u32,
+            roots.len() as u32,
+        ] {
+            ret.extend_from_slice(&v.to_le_bytes());
+        }
+        ret.extend_from_slice(&self.u8_vec);
+        for atom in &self.atom_vec {
+            ret.extend_from_slice(&atom.start.to_le_bytes());
+            ret.extend_from_slice(&atom.end.to_le_bytes());
+        }
+        for pair in &self.pair_vec {
+            ret.extend_from_slice(&pair.first.0.to_le_bytes());
+            ret.extend_from_slice(&pair.rest.0.to_le_bytes());
+        }
+        for root in roots {
+            ret.extend_from_slice(&root.0.to_le_bytes());
+        }
+        Ok(ret)
+    }
+
+    // load an allocator from a snapshot created by snapshot(). Returns the
+    // allocator and the roots passed to snapshot(). The snapshot is validated,
+    // so it's safe to load untrusted input, but snapshots created by a
+    // different version of the format are rejected.
+    pub fn from_snapshot(buf: &[u8]) -> crate::io::Result<(Allocator, Vec<NodePtr>)> {
+        let mut r = SnapshotReader { buf };
+        if r.bytes(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return snapshot_err("not a snapshot");
+        }
+        if r.u32()? != SNAPSHOT_VERSION {
+            return snapshot_err("unsupported snapshot version");
+        }
+        let num_u8s = r.u32()? as usize;
+        let num_atoms = r.u32()? as usize;
+        let small_atoms = r.u32()? as usize;
+        let num_pairs = r.u32()? as usize;
+        let num_roots = r.u32()? as usize;
+        if num_atoms + small_atoms > MAX_NUM_ATOMS || num_pairs > MAX_NUM_PAIRS {
+            return snapshot_err("snapshot exceeds allocator limits");
+        }
+
+        let mut a = Allocator::new();
+        a.u8_vec = r.bytes(num_u8s)?.to_vec();
+        a.small_atoms = small_atoms;
+
+        // make sure we have enough input before reserving space for the atoms
+        // and pairs
+        let atoms = r.bytes(num_atoms * 8)?;
+        a.atom_vec = Vec::with_capacity(num_atoms);
+        for atom in atoms.chunks_exact(8) {
+            let start = u32::from_le_bytes(atom[0..4].try_into().unwrap());
+            let end = u32::from_le_bytes(atom[4..8].try_into().unwrap());
+            if start > end || end as usize > num_u8s {
+                return snapshot_err("invalid atom in snapshot");
+            }
+            a.atom_vec.push(AtomBuf { start, end });
+        }
+
+        // nodes may only refer to pairs that were allocated before them,
+        // which rules out cycles
+        let check_node = |v: u32, num_pairs: usize| -> crate::io::Result<NodePtr> {
+            let node = NodePtr(v);
+            let valid = match v >> NODE_PTR_IDX_BITS {
+                0 => (node.index() as usize) < num_pairs,
+                1 => (node.index() as usize) < num_atoms,
+                2 => true,
+                _ => false,
+            };
+            if valid {
+                Ok(node)
+            } else {
+                snapshot_err("invalid node in snapshot")
+            }
+        };
+
+        let pairs = r.bytes(num_pairs * 8)?;
+        a.pair_vec = Vec::with_capacity(num_pairs);
+        for (idx, pair) in pairs.chunks_exact(8).enumerate() {
+            let first = check_node(u32::from_le_bytes(pair[0..4].try_into().unwrap()), idx)?;
+            let rest = check_node(u32::from_le_bytes(pair[4..8].try_into().unwrap()), idx)?;
+            a.pair_vec.push(IntPair { first, rest });
+        }
+
+        let roots = r.bytes(num_roots * 4)?;
+        let roots = roots
+            .chunks_exact(4)
+            .map(|v| check_node(u32::from_le_bytes(v.try_into().unwrap()), num_pairs))
+            .collect::<crate::io::Result<Vec<NodePtr>>>()?;
+        if !r.buf.is_empty() {
+            return snapshot_err("trailing bytes in snapshot");
+        }
+        Ok((a, roots))
+    }
+
+    /// The empty atom. This never allocates, doesn't count towards the atom
+    /// limit and always returns the same NodePtr, so it's cheap to call
+    /// repeatedly
+    pub fn nil(&self) -> NodePtr {
+        NodePtr::new(ObjectType::SmallAtom, 0)
+    }
+
+    /// The atom 1. Just like `nil()`, this never allocates and always returns
+    /// the same NodePtr
     pub fn one(&self) -> NodePtr {
         NodePtr::new(ObjectType::SmallAtom, 1)
     }
 
+    /// The quote operator of the Chik dialect, i.e. the same atom as `one()`
+    pub fn quote_atom(&self) -> NodePtr {
+        self.one()
+    }
+
     #[inline]
     fn check_atom_limit(&self) -> Result<(), EvalErr> {
-        if self.atom_vec.len() + self.small_atoms == MAX_NUM_ATOMS {
+        if self.atom_vec.len() + self.small_atoms >= self.atom_limit {
             err(self.nil(), "too many atoms")
         } else {
             Ok(())
@@ -637,180 +1835,1158 @@ impl Allocator {
         self.small_atoms
     }
 
-    #[cfg(feature = "counters")]
+    /// the number of pairs currently allocated. This only decreases when
+    /// restoring a checkpoint
     pub fn pair_count(&self) -> usize {
         self.pair_vec.len()
     }
 
-    #[cfg(feature = "counters")]
+    /// the number of bytes of atom storage currently allocated. This only
+    /// decreases when restoring a checkpoint
     pub fn heap_size(&self) -> usize {
         self.u8_vec.len()
     }
 }
 
 #[test]
-fn test_atom_eq_1() {
-    // these are a bunch of different representations of 1
-    // make sure they all compare equal
+fn test_atom_eq_1() {
+    // these are a bunch of different representations of 1
+    // make sure they all compare equal
+    let mut a = Allocator::new();
+    let a0 = a.one();
+    let a1 = a.new_atom(&[1]).unwrap();
+    let a2 = {
+        let tmp = a.new_atom(&[0x01, 0xff]).unwrap();
+        a.new_substr(tmp, 0, 1).unwrap()
+    };
+    let a3 = a.new_substr(a2, 0, 1).unwrap();
+    let a4 = a.new_number(1.into()).unwrap();
+    let a5 = a.new_small_number(1).unwrap();
+
+    assert!(a.atom_eq(a0, a0));
+    assert!(a.atom_eq(a0, a1));
+    assert!(a.atom_eq(a0, a2));
+    assert!(a.atom_eq(a0, a3));
+    assert!(a.atom_eq(a0, a4));
+    assert!(a.atom_eq(a0, a5));
+
+    assert!(a.atom_eq(a1, a0));
+    assert!(a.atom_eq(a1, a1));
+    assert!(a.atom_eq(a1, a2));
+    assert!(a.atom_eq(a1, a3));
+    assert!(a.atom_eq(a1, a4));
+    assert!(a.atom_eq(a1, a5));
+
+    assert!(a.atom_eq(a2, a0));
+    assert!(a.atom_eq(a2, a1));
+    assert!(a.atom_eq(a2, a2));
+    assert!(a.atom_eq(a2, a3));
+    assert!(a.atom_eq(a2, a4));
+    assert!(a.atom_eq(a2, a5));
+
+    assert!(a.atom_eq(a3, a0));
+    assert!(a.atom_eq(a3, a1));
+    assert!(a.atom_eq(a3, a2));
+    assert!(a.atom_eq(a3, a3));
+    assert!(a.atom_eq(a3, a4));
+    assert!(a.atom_eq(a3, a5));
+
+    assert!(a.atom_eq(a4, a0));
+    assert!(a.atom_eq(a4, a1));
+    assert!(a.atom_eq(a4, a2));
+    assert!(a.atom_eq(a4, a3));
+    assert!(a.atom_eq(a4, a4));
+    assert!(a.atom_eq(a4, a5));
+
+    assert!(a.atom_eq(a5, a0));
+    assert!(a.atom_eq(a5, a1));
+    assert!(a.atom_eq(a5, a2));
+    assert!(a.atom_eq(a5, a3));
+    assert!(a.atom_eq(a5, a4));
+    assert!(a.atom_eq(a5, a5));
+}
+
+#[test]
+fn test_atom_eq_minus_1() {
+    // these are a bunch of different representations of -1
+    // make sure they all compare equal
+    let mut a = Allocator::new();
+    let a0 = a.new_atom(&[0xff]).unwrap();
+    let a1 = a.new_number((-1).into()).unwrap();
+    let a2 = {
+        let tmp = a.new_atom(&[0x01, 0xff]).unwrap();
+        a.new_substr(tmp, 1, 2).unwrap()
+    };
+    let a3 = a.new_substr(a0, 0, 1).unwrap();
+
+    assert!(a.atom_eq(a0, a0));
+    assert!(a.atom_eq(a0, a1));
+    assert!(a.atom_eq(a0, a2));
+    assert!(a.atom_eq(a0, a3));
+
+    assert!(a.atom_eq(a1, a0));
+    assert!(a.atom_eq(a1, a1));
+    assert!(a.atom_eq(a1, a2));
+    assert!(a.atom_eq(a1, a3));
+
+    assert!(a.atom_eq(a2, a0));
+    assert!(a.atom_eq(a2, a1));
+    assert!(a.atom_eq(a2, a2));
+    assert!(a.atom_eq(a2, a3));
+
+    assert!(a.atom_eq(a3, a0));
+    assert!(a.atom_eq(a3, a1));
+    assert!(a.atom_eq(a3, a2));
+    assert!(a.atom_eq(a3, a3));
+}
+
+#[test]
+fn test_atom_eq() {
+    let mut a = Allocator::new();
+    let a0 = a.nil();
+    let a1 = a.one();
+    let a2 = a.new_atom(&[1]).unwrap();
+    let a3 = a.new_atom(&[0xfa, 0xc7]).unwrap();
+    let a4 = a.new_small_number(1).unwrap();
+    let a5 = a.new_number((-1337).into()).unwrap();
+
+    assert!(a.atom_eq(a0, a0));
+    assert!(!a.atom_eq(a0, a1));
+    assert!(!a.atom_eq(a0, a2));
+    assert!(!a.atom_eq(a0, a3));
+    assert!(!a.atom_eq(a0, a4));
+    assert!(!a.atom_eq(a0, a5));
+
+    assert!(!a.atom_eq(a1, a0));
+    assert!(a.atom_eq(a1, a1));
+    assert!(a.atom_eq(a1, a2));
+    assert!(!a.atom_eq(a1, a3));
+    assert!(a.atom_eq(a1, a4));
+    assert!(!a.atom_eq(a1, a5));
+
+    assert!(!a.atom_eq(a2, a0));
+    assert!(a.atom_eq(a2, a1));
+    assert!(a.atom_eq(a2, a2));
+    assert!(!a.atom_eq(a2, a3));
+    assert!(a.atom_eq(a2, a4));
+    assert!(!a.atom_eq(a2, a5));
+
+    assert!(!a.atom_eq(a3, a0));
+    assert!(!a.atom_eq(a3, a1));
+    assert!(!a.atom_eq(a3, a2));
+    assert!(a.atom_eq(a3, a3));
+    assert!(!a.atom_eq(a3, a4));
+    assert!(a.atom_eq(a3, a5));
+
+    assert!(!a.atom_eq(a4, a0));
+    assert!(a.atom_eq(a4, a1));
+    assert!(a.atom_eq(a4, a2));
+    assert!(!a.atom_eq(a4, a3));
+    assert!(a.atom_eq(a4, a4));
+    assert!(!a.atom_eq(a4, a5));
+}
+
+#[test]
+#[should_panic]
+fn test_atom_eq_pair1() {
+    let mut a = Allocator::new();
+    let a0 = a.nil();
+    let pair = a.new_pair(a0, a0).unwrap();
+    a.atom_eq(pair, a0);
+}
+
+#[test]
+#[should_panic]
+fn test_atom_eq_pair2() {
+    let mut a = Allocator::new();
+    let a0 = a.nil();
+    let pair = a.new_pair(a0, a0).unwrap();
+    a.atom_eq(a0, pair);
+}
+
+#[cfg(test)]
+#[rstest]
+#[case(0)]
+#[case(1)]
+#[case(5)]
+fn test_list_len(#[case] len: u32) {
+    let mut a = Allocator::new();
+    let mut list = a.nil();
+    for i in (0..len).rev() {
+        let item = a.new_small_number(i).unwrap();
+        list = a.new_pair(item, list).unwrap();
+    }
+    assert_eq!(a.list_len(list), Some(len as usize));
+
+    let iter = a.list_iter(list).unwrap();
+    assert_eq!(iter.size_hint(), (len as usize, Some(len as usize)));
+    assert_eq!(iter.len(), len as usize);
+    let items: Vec<NodePtr> = a.list_iter(list).unwrap().collect();
+    assert_eq!(items.len(), len as usize);
+    for (i, item) in items.into_iter().enumerate() {
+        assert_eq!(a.small_number(item), Some(i as u32));
+    }
+
+    // the length is updated as we iterate
+    let mut iter = a.list_iter(list).unwrap();
+    if len > 0 {
+        iter.next();
+        assert_eq!(iter.len(), len as usize - 1);
+    }
+}
+
+#[test]
+fn test_list_len_improper() {
+    let mut a = Allocator::new();
+    let one = a.one();
+    let list = a.new_pair(one, one).unwrap();
+    let list = a.new_pair(one, list).unwrap();
+    assert_eq!(a.list_len(list), None);
+    assert!(a.list_iter(list).is_none());
+
+    // an atom other than nil is not a list
+    assert_eq!(a.list_len(one), None);
+}
+
+#[test]
+fn test_reset() {
+    let mut a = Allocator::new_with_limits(1000, 10, 10);
+    let fresh = a.checkpoint();
+    let nil = a.nil();
+    let one = a.one();
+    for _ in 0..3 {
+        let atom = a.new_atom(&[0x42; 100]).unwrap();
+        let pair = a.new_pair(atom, nil).unwrap();
+        a.new_pair(one, nil).unwrap();
+        a.intern_atom(&[0x42; 100]).unwrap();
+        let u8_capacity = a.u8_vec.capacity();
+        let pair_capacity = a.pair_vec.capacity();
+        assert_eq!(a.pair_count(), 2);
+        assert_eq!(a.heap_size(), 200);
+
+        a.reset();
+        assert_eq!(a.pair_count(), 0);
+        assert_eq!(a.heap_size(), 0);
+        assert_eq!(a.atom_vec.len(), 0);
+        assert_eq!(a.small_atoms, fresh.small_atoms);
+        assert!(a.small_pairs.is_empty());
+        assert!(a.interned_atoms.is_empty());
+        // the buffers are retained
+        assert_eq!(a.u8_vec.capacity(), u8_capacity);
+        assert_eq!(a.pair_vec.capacity(), pair_capacity);
+        // the limits are unchanged
+        assert_eq!(a.heap_limit, 999);
+        assert_eq!(a.pair_limit, 10);
+
+        // the same nodes are allocated again
+        assert_eq!(a.new_atom(&[0x42; 100]).unwrap(), atom);
+        assert_eq!(a.new_pair(atom, nil).unwrap(), pair);
+        a.reset();
+    }
+
+    // nil and one stay valid
+    assert!(a.atom(nil).as_ref().is_empty());
+    assert_eq!(a.atom(one).as_ref(), [1]);
+}
+
+#[test]
+fn test_atoms_iter() {
+    use crate::test_ops::parse_exp;
+    let mut a = Allocator::new();
+    let (tree, _) = parse_exp(&mut a, "(1 (\"foo\" . 0x1337) ((() 2)) . 3)");
+    let atoms: Vec<Vec<u8>> = a
+        .atoms_iter(tree)
+        .map(|atom| atom.as_ref().to_vec())
+        .collect();
+    let expected: [&[u8]; 8] = [&[1], b"foo", &[0x13, 0x37], &[], &[2], &[], &[], &[3]];
+    assert_eq!(atoms, expected);
+
+    // a single atom
+    let one = a.one();
+    assert_eq!(a.atoms_iter(one).count(), 1);
+
+    // a deep tree doesn't recurse
+    let mut deep = one;
+    for _ in 0..1000000 {
+        deep = a.new_pair(deep, one).unwrap();
+    }
+    assert_eq!(a.atoms_iter(deep).count(), 1000001);
+}
+
+#[test]
+fn test_find_atoms() {
+    use crate::test_ops::parse_exp;
+    let mut a = Allocator::new();
+    let (tree, _) = parse_exp(&mut a, "(0x1337 (\"foo\" . 0x4242) ((() 0xcafe)) . 0xbeef)");
+    let found: Vec<Vec<u8>> = find_atoms_of_len(&a, tree, 2)
+        .map(|n| a.atom(n).as_ref().to_vec())
+        .collect();
+    assert_eq!(
+        found,
+        [[0x13, 0x37], [0x42, 0x42], [0xca, 0xfe], [0xbe, 0xef]]
+    );
+
+    // nil terminators are atoms too
+    assert_eq!(find_atoms_of_len(&a, tree, 0).count(), 3);
+    assert_eq!(find_atoms(&a, tree, |_| true).count(), 8);
+    assert_eq!(find_atoms(&a, tree, |_| false).count(), 0);
+    let found: Vec<NodePtr> = find_atoms(&a, tree, |atom| atom == b"foo").collect();
+    assert_eq!(found.len(), 1);
+    assert_eq!(a.atom(found[0]).as_ref(), b"foo");
+
+    // the root itself may be an atom
+    let hash = a.new_atom(&[0x42; 32]).unwrap();
+    let found: Vec<NodePtr> = find_atoms_of_len(&a, hash, 32).collect();
+    assert_eq!(found, [hash]);
+    assert_eq!(find_atoms_of_len(&a, hash, 31).count(), 0);
+}
+
+#[test]
+fn test_find_atoms_shared() {
+    let mut a = Allocator::new();
+    let hash1 = a.new_atom(&[1; 32]).unwrap();
+    let hash2 = a.new_atom(&[2; 32]).unwrap();
+    let shared = a.new_pair(hash1, hash2).unwrap();
+    let tree = a.new_pair(shared, shared).unwrap();
+    let hash3 = a.new_atom(&[3; 32]).unwrap();
+    let tree = a.new_pair(tree, hash3).unwrap();
+
+    let found: Vec<NodePtr> = find_atoms_of_len(&a, tree, 32).collect();
+    assert_eq!(found, [hash1, hash2, hash1, hash2, hash3]);
+    let found: Vec<NodePtr> = find_atoms_of_len(&a, tree, 32).skip_visited(true).collect();
+    assert_eq!(found, [hash1, hash2, hash3]);
+
+    // the same atom reachable from two different pairs is only yielded once
+    let other = a.new_pair(hash3, hash1).unwrap();
+    let tree = a.new_pair(tree, other).unwrap();
+    let found: Vec<NodePtr> = find_atoms_of_len(&a, tree, 32).skip_visited(true).collect();
+    assert_eq!(found, [hash1, hash2, hash3]);
+
+    // 2^64 paths to the leaves, but only 64 pairs
+    let mut dag = hash1;
+    for _ in 0..64 {
+        dag = a.new_pair(dag, dag).unwrap();
+    }
+    let found: Vec<NodePtr> = find_atoms(&a, dag, |_| true).skip_visited(true).collect();
+    assert_eq!(found, [hash1]);
+    assert_eq!(find_atoms(&a, dag, |_| true).take(1000).count(), 1000);
+}
+
+#[test]
+fn test_find_atoms_large() {
+    let mut a = Allocator::new();
+    let hash = a.new_atom(&[0x42; 32]).unwrap();
+    let nil = a.nil();
+
+    // a deep tree, nested in the first element, doesn't recurse
+    let mut deep = hash;
+    for _ in 0..3000000 {
+        deep = a.new_pair(deep, nil).unwrap();
+    }
+    let found: Vec<NodePtr> = find_atoms_of_len(&a, deep, 32).collect();
+    assert_eq!(found, [hash]);
+
+    // a long list of distinct 32 byte atoms is found in order
+    let mut list = nil;
+    let mut atoms = Vec::new();
+    for i in 0..1000000_u32 {
+        let mut buf = [0; 32];
+        buf[..4].copy_from_slice(&i.to_be_bytes());
+        let atom = a.new_atom(&buf).unwrap();
+        atoms.push(atom);
+        let item = a.new_pair(atom, nil).unwrap();
+        list = a.new_pair(item, list).unwrap();
+    }
+    atoms.reverse();
+    let found: Vec<NodePtr> = find_atoms_of_len(&a, list, 32).collect();
+    assert_eq!(found, atoms);
+    let found: Vec<NodePtr> = find_atoms_of_len(&a, list, 32).skip_visited(true).collect();
+    assert_eq!(found, atoms);
+}
+
+#[cfg(test)]
+fn plus_one(a: &mut Allocator, n: NodePtr) -> Result<NodePtr, EvalErr> {
+    let v = a.number(n) + 1;
+    a.new_number(v)
+}
+
+#[test]
+fn test_new_pair_many() {
+    let mut a = Allocator::new();
+    let items: Vec<NodePtr> = (1..=5).map(|v| a.new_small_number(v).unwrap()).collect();
+
+    // a proper list
+    let nil = a.nil();
+    let list = a.new_pair_many(&items, nil).unwrap();
+    assert_eq!(a.list_iter(list).unwrap().collect::<Vec<_>>(), items);
+    assert_eq!(a.pair_vec.len(), 5);
+
+    // a dotted tail
+    let tail = a.new_atom(b"foobar").unwrap();
+    let node = a.new_pair_many(&items, tail).unwrap();
+    let mut elements = Vec::new();
+    let mut rest = node;
+    while let Some((first, next)) = a.next(rest) {
+        elements.push(first);
+        rest = next;
+    }
+    assert_eq!(elements, items);
+    assert_eq!(rest, tail);
+
+    // the tail may be a pair, and items may be pairs
+    let node = a.new_pair_many(&[list, list], list).unwrap();
+    assert_eq!(a.list_len(node), Some(7));
+    assert_eq!(a.next(node).unwrap().0, list);
+
+    // no items
+    assert_eq!(a.new_pair_many(&[], tail).unwrap(), tail);
+}
+
+#[test]
+fn test_new_pair_many_limit() {
+    let mut a = Allocator::new_with_limits(500000000, 62500000, 3);
+    let one = a.one();
+    let nil = a.nil();
+    assert_eq!(
+        a.new_pair_many(&[one; 4], nil).unwrap_err().1,
+        "too many pairs"
+    );
+    // nothing was allocated
+    assert_eq!(a.pair_vec.len(), 0);
+    let list = a.new_pair_many(&[one; 3], nil).unwrap();
+    assert_eq!(a.list_len(list), Some(3));
+}
+
+#[test]
+fn test_new_small_pair() {
+    let mut a = Allocator::new();
+    let one = a.one();
+    let two = a.new_small_number(2).unwrap();
+    let p1 = a.new_small_pair(one, two).unwrap();
+    assert_eq!(a.sexp(p1), SExp::Pair(one, two));
+    assert_eq!(a.new_small_pair(one, two).unwrap(), p1);
+    assert_eq!(a.pair_vec.len(), 1);
+
+    // the order of the children matters
+    let p2 = a.new_small_pair(two, one).unwrap();
+    assert_ne!(p2, p1);
+    assert_eq!(a.pair_vec.len(), 2);
+
+    // pairs and heap atoms are not reused
+    let big = a.new_atom(&[0xff; 10]).unwrap();
+    assert_ne!(
+        a.new_small_pair(one, big).unwrap(),
+        a.new_small_pair(one, big).unwrap()
+    );
+    assert_ne!(
+        a.new_small_pair(p1, two).unwrap(),
+        a.new_small_pair(p1, two).unwrap()
+    );
+    assert_eq!(a.pair_vec.len(), 6);
+
+    // pairs allocated after a checkpoint are forgotten when it's restored
+    let cp = a.checkpoint();
+    let three = a.new_small_number(3).unwrap();
+    let p3 = a.new_small_pair(three, three).unwrap();
+    a.restore_checkpoint(&cp);
+    a.new_pair(one, one).unwrap();
+    let p4 = a.new_small_pair(three, three).unwrap();
+    assert_ne!(p3, p4);
+    assert_eq!(a.sexp(p4), SExp::Pair(three, three));
+    assert_eq!(a.new_small_pair(one, two).unwrap(), p1);
+}
+
+#[test]
+fn test_new_small_pair_limit() {
+    // reused pairs count towards the pair limit
+    let mut a = Allocator::new_with_limits(500000000, 62500000, 3);
+    let one = a.one();
+    let nil = a.nil();
+    for _ in 0..3 {
+        a.new_small_pair(one, one).unwrap();
+    }
+    assert_eq!(a.pair_vec.len(), 1);
+    assert_eq!(a.new_small_pair(one, one).unwrap_err().1, "too many pairs");
+    assert_eq!(a.new_pair(one, one).unwrap_err().1, "too many pairs");
+    assert_eq!(
+        a.new_pair_many(&[one], nil).unwrap_err().1,
+        "too many pairs"
+    );
+}
+
+#[test]
+fn test_intern_atom() {
+    let mut a = Allocator::new();
+    let hash = [0x42; 32];
+    let atom = a.intern_atom(&hash).unwrap();
+    assert_eq!(a.atom(atom).as_ref(), hash);
+    for _ in 0..1000 {
+        assert_eq!(a.intern_atom(&hash).unwrap(), atom);
+    }
+    assert_eq!(a.atom_vec.len(), 1);
+    assert_eq!(a.heap_size(), 32);
+
+    // new_atom() always allocates, and its atoms aren't interned
+    let other = a.new_atom(&[0x43; 32]).unwrap();
+    assert_ne!(a.new_atom(&hash).unwrap(), atom);
+    assert_ne!(a.intern_atom(&[0x43; 32]).unwrap(), other);
+    assert_eq!(a.atom_vec.len(), 4);
+
+    // small atoms are not stored at all
+    let small = a.intern_atom(&[1, 2, 3]).unwrap();
+    assert_eq!(a.small_number(small), Some(0x010203));
+    assert_eq!(a.atom_vec.len(), 4);
+
+    // atoms interned after a checkpoint are forgotten when it's restored
+    let cp = a.checkpoint();
+    let before = a.intern_atom(&[0x44; 32]).unwrap();
+    a.restore_checkpoint(&cp);
+    a.new_atom(&[0x45; 10]).unwrap();
+    let after = a.intern_atom(&[0x44; 32]).unwrap();
+    assert_ne!(before, after);
+    assert_eq!(a.atom(after).as_ref(), [0x44; 32]);
+    assert_eq!(a.intern_atom(&hash).unwrap(), atom);
+}
+
+#[test]
+fn test_atom_as_str() {
+    use crate::serde::{node_from_bytes, node_to_bytes};
+
+    let mut a = Allocator::new();
+    for s in [
+        "",
+        "a",
+        "ab",
+        "foobar",
+        "a\0b",
+        "\0\0\0\0\0",
+        "\u{e9}",
+        "caf\u{e9} \u{2603} \u{1f980}",
+        "\u{65e5}\u{672c}\u{8a9e}\u{306e}\u{30e1}\u{30e2}",
+    ] {
+        let atom = a.new_str(s).unwrap();
+        assert_eq!(a.atom(atom).as_ref(), s.as_bytes());
+        assert_eq!(a.atom_as_str(atom).as_deref(), Some(s));
+        assert_eq!(a.atom(atom).as_str(), Some(s));
+
+        // the string survives a round-trip through serialization
+        let buf = node_to_bytes(&a, atom).unwrap();
+        let atom = node_from_bytes(&mut a, &buf).unwrap();
+        assert_eq!(a.atom_as_str(atom).as_deref(), Some(s));
+    }
+
+    // "ab" fits in a small atom
+    let atom = a.new_str("ab").unwrap();
+    assert_eq!(atom.object_type(), ObjectType::SmallAtom);
+    assert!(matches!(a.atom_as_str(atom), Some(Cow::Owned(_))));
+    let atom = a.new_str("foobar").unwrap();
+    assert!(matches!(a.atom_as_str(atom), Some(Cow::Borrowed(_))));
+
+    for invalid in [
+        &[0xff_u8][..],
+        &[0x80],
+        &[0xc3],
+        &[0xc3, 0x28],
+        &[0xe2, 0x28, 0xa1],
+        &[0xf0, 0x9f, 0xa6],
+        // an overlong encoding of '/'
+        &[0xc0, 0xaf],
+        // a surrogate
+        &[0xed, 0xa0, 0x80],
+        &[b'f', b'o', b'o', 0, 0xfe],
+    ] {
+        let atom = a.new_atom(invalid).unwrap();
+        assert_eq!(a.atom_as_str(atom), None);
+        assert_eq!(a.atom(atom).as_str(), None);
+    }
+
+    let nil = a.nil();
+    let pair = a.new_pair(nil, nil).unwrap();
+    assert_eq!(a.atom_as_str(pair), None);
+}
+
+#[test]
+fn test_structural_hash() {
+    use crate::test_ops::parse_exp;
+    let mut a = Allocator::new();
+    let trees = [
+        "()",
+        "1",
+        "0x1337",
+        "\"abcdefghijklmnop\"",
+        "\"abcdefghijklmnopq\"",
+        "(1 . 2)",
+        "(2 . 1)",
+        "(1 2)",
+        "((1 . 2))",
+        "(\"foo\" (\"bar\" . 0x1337) . 3)",
+    ];
+    let mut hashes = Vec::new();
+    for tree in trees {
+        // equal trees, allocated separately, hash equally
+        let (lhs, _) = parse_exp(&mut a, tree);
+        let (rhs, _) = parse_exp(&mut a, tree);
+        assert!(a.structural_eq(lhs, rhs));
+        let hash = a.structural_hash(lhs);
+        assert_eq!(hash, a.structural_hash(rhs));
+        hashes.push((lhs, hash));
+    }
+    for (i, (lhs, lhs_hash)) in hashes.iter().enumerate() {
+        for (rhs, rhs_hash) in &hashes[i + 1..] {
+            assert!(!a.structural_eq(*lhs, *rhs));
+            assert_ne!(lhs_hash, rhs_hash);
+        }
+    }
+
+    // a deep tree doesn't overflow the stack
+    let mut deep = a.nil();
+    let mut deep2 = a.nil();
+    for _ in 0..100000 {
+        deep = a.new_pair(deep, deep).unwrap();
+        deep2 = a.new_pair(deep2, deep2).unwrap();
+    }
+    assert_eq!(a.structural_hash(deep), a.structural_hash(deep2));
+    let one = a.one();
+    let other = a.new_pair(one, deep).unwrap();
+    assert_ne!(a.structural_hash(deep), a.structural_hash(other));
+}
+
+#[test]
+fn test_structural_hash_dedup() {
+    use crate::collections::HashMap;
+    use crate::test_ops::parse_exp;
+    let mut a = Allocator::new();
+    // the sub tree (c (q . 1) 1) is repeated, but not shared
+    let (tree, _) = parse_exp(&mut a, "(a (c (q . 1) 1) (c (q . 1) 1) (q . 1))");
+
+    // rebuild the tree bottom-up, replacing every sub tree by the first one
+    // that's equal to it
+    let mut table = HashMap::<u64, Vec<NodePtr>>::new();
+    let mut dedup = |a: &Allocator, node: NodePtr| -> NodePtr {
+        let candidates = table.entry(a.structural_hash(node)).or_default();
+        match candidates.iter().find(|c| a.structural_eq(**c, node)) {
+            Some(c) => *c,
+            None => {
+                candidates.push(node);
+                node
+            }
+        }
+    };
+    let mut items = Vec::new();
+    for item in a.list_iter(tree).unwrap().collect::<Vec<_>>() {
+        items.push(dedup(&a, item));
+    }
+    let nil = a.nil();
+    let deduped = a.new_pair_many(&items, nil).unwrap();
+
+    assert!(a.structural_eq(tree, deduped));
+    assert_eq!(a.structural_hash(tree), a.structural_hash(deduped));
+    let items: Vec<NodePtr> = a.list_iter(deduped).unwrap().collect();
+    assert_eq!(items[1], items[2]);
+    assert_ne!(items[0], items[3]);
+    let original: Vec<NodePtr> = a.list_iter(tree).unwrap().collect();
+    assert_ne!(original[1], original[2]);
+}
+
+#[test]
+fn test_map_list() {
+    let mut a = Allocator::new();
+    let mut list = a.nil();
+    for v in [3, 2, 1] {
+        let v = a.new_small_number(v).unwrap();
+        list = a.new_pair(v, list).unwrap();
+    }
+    let mapped = a.map_list(list, plus_one).unwrap();
+    let values: Vec<u32> = a
+        .list_iter(mapped)
+        .unwrap()
+        .map(|n| a.small_number(n).unwrap())
+        .collect();
+    assert_eq!(values, [2, 3, 4]);
+    // the original list is unchanged
+    assert_eq!(a.list_len(list), Some(3));
+    assert_eq!(a.small_number(a.next(list).unwrap().0), Some(1));
+
+    let mapped = a.try_map_list(list, plus_one).unwrap().unwrap();
+    assert_eq!(a.list_len(mapped), Some(3));
+
+    // the empty list
+    let nil = a.nil();
+    let mapped = a.map_list(nil, plus_one).unwrap();
+    assert_eq!(a.list_len(mapped), Some(0));
+    assert_eq!(a.try_map_list(nil, plus_one).unwrap(), Some(mapped));
+
+    // errors from the closure are propagated
+    let e = a.map_list(list, |_, n| err(n, "failed")).unwrap_err();
+    assert_eq!(e.1, "failed");
+}
+
+#[test]
+fn test_replace_atoms() {
+    use crate::test_ops::{node_eq, parse_exp};
+
+    let mut a = Allocator::new();
+    let (tree, _) = parse_exp(&mut a, "((\"foo\" \"X\") (1 2 3) \"X\" (\"X\" . \"X\"))");
+    let (expected, _) = parse_exp(
+        &mut a,
+        "((\"foo\" 0x1337) (1 2 3) 0x1337 (0x1337 . 0x1337))",
+    );
+    let value = a.new_atom(&[0x13, 0x37]).unwrap();
+
+    let pairs = a.pair_count();
+    let replaced = a
+        .replace_atoms(tree, |atom| (atom == b"X").then_some(value))
+        .unwrap();
+    assert!(node_eq(&a, replaced, expected));
+
+    // the spine of the top level list (4 pairs), the pair above "X" in the
+    // first element, the first element itself and the last element are
+    // rebuilt
+    assert_eq!(a.pair_count() - pairs, 7);
+
+    // (1 2 3) is shared with the original tree
+    let second = |a: &Allocator, list: NodePtr| a.next(a.next(list).unwrap().1).unwrap().0;
+    assert_eq!(second(&a, replaced), second(&a, tree));
+    // and so is the first atom of the first element
+    let first = |a: &Allocator, list: NodePtr| a.next(a.next(list).unwrap().0).unwrap().0;
+    assert_eq!(first(&a, replaced), first(&a, tree));
+
+    // the replacement node is used as is, not copied
+    let third = a
+        .next(a.next(a.next(replaced).unwrap().1).unwrap().1)
+        .unwrap()
+        .0;
+    assert_eq!(third, value);
+}
+
+#[test]
+fn test_replace_atoms_unchanged() {
+    use crate::test_ops::parse_exp;
+
+    let mut a = Allocator::new();
+    let (tree, _) = parse_exp(&mut a, "((\"foo\" \"bar\") (1 2 3) . 4)");
+    let pairs = a.pair_count();
+
+    // nothing matches, so the tree is returned as is
+    let replaced = a
+        .replace_atoms(tree, |atom| (atom == b"X").then_some(NodePtr::NIL))
+        .unwrap();
+    assert_eq!(replaced, tree);
+    assert_eq!(a.pair_count(), pairs);
+
+    // an atom as the root
+    let one = a.one();
+    let nil = a.nil();
+    assert_eq!(a.replace_atoms(one, |_| Some(nil)).unwrap(), nil);
+    assert_eq!(a.replace_atoms(one, |_| None).unwrap(), one);
+}
+
+#[test]
+fn test_replace_atoms_shared() {
+    // a tree where every level refers to the one below it twice. It has 2^100
+    // leaves, which only works if shared sub trees are visited once
+    let mut a = Allocator::new();
+    let x = a.new_atom(b"X").unwrap();
+    let mut tree = x;
+    for _ in 0..100 {
+        tree = a.new_pair(tree, tree).unwrap();
+    }
+    let value = a.new_atom(&[0x13, 0x37]).unwrap();
+
+    let pairs = a.pair_count();
+    let mut node = a
+        .replace_atoms(tree, |atom| (atom == b"X").then_some(value))
+        .unwrap();
+    assert_eq!(a.pair_count() - pairs, 100);
+
+    // the replaced tree is shared the same way
+    for _ in 0..100 {
+        let SExp::Pair(left, right) = a.sexp(node) else {
+            panic!("expected pair");
+        };
+        assert_eq!(left, right);
+        node = left;
+    }
+    assert_eq!(node, value);
+}
+
+#[test]
+fn test_hash_stub() {
+    use crate::test_ops::parse_exp;
+
+    let tree_hash = |a: &Allocator, node: NodePtr| crate::serde::tree_hash(a, node).unwrap();
+
+    let mut a = Allocator::new();
+    let (full, _) = parse_exp(&mut a, "(1 (\"foo\" (2 3)) . 4)");
+    let (sub, _) = parse_exp(&mut a, "(\"foo\" (2 3))");
+    let hash = tree_hash(&a, sub);
+
+    // the same tree, with the sub tree replaced by a stub
+    let stub = a.new_hash_stub(hash.into()).unwrap();
+    assert!(stub.is_hash_stub());
+    assert!(a.has_hash_stubs());
+    assert_eq!(a.hash_stub(stub), Some(hash.into()));
+    assert_eq!(tree_hash(&a, stub), hash);
+    let one = a.one();
+    let four = a.new_small_number(4).unwrap();
+    let partial = a.new_pair(stub, four).unwrap();
+    let partial = a.new_pair(one, partial).unwrap();
+    assert_eq!(tree_hash(&a, partial), tree_hash(&a, full));
+
+    // a stub looks like a 32 byte atom, but it's only equal to other stubs
+    assert!(stub.is_atom());
+    assert_eq!(a.atom_len(stub), 32);
+    assert_eq!(a.atom(stub).as_ref(), hash);
+    let atom = a.new_atom(&hash).unwrap();
+    assert_eq!(a.hash_stub(atom), None);
+    assert!(!a.atom_eq(stub, atom));
+    assert!(!a.atom_eq(atom, stub));
+    let stub2 = a.new_hash_stub(hash.into()).unwrap();
+    assert!(a.atom_eq(stub, stub2));
+    assert!(!a.structural_eq(partial, full));
+
+    // the sub tree isn't known, so the allocator can't be snapshotted
+    assert!(a.snapshot(&[partial]).is_err());
+}
+
+#[test]
+fn test_hash_stub_checkpoint() {
+    let mut a = Allocator::new();
+    let checkpoint = a.checkpoint();
+    a.new_hash_stub([0x42; 32].into()).unwrap();
+    assert!(a.has_hash_stubs());
+    a.restore_checkpoint(&checkpoint);
+    assert!(!a.has_hash_stubs());
+
+    a.new_hash_stub([0x42; 32].into()).unwrap();
+    a.reset();
+    assert!(!a.has_hash_stubs());
+}
+
+#[test]
+fn test_map_list_improper() {
+    let mut a = Allocator::new();
+    let one = a.one();
+    let list = a.new_pair(one, one).unwrap();
+    let list = a.new_pair(one, list).unwrap();
+
+    // the improper tail is dropped
+    let mapped = a.map_list(list, plus_one).unwrap();
+    let values: Vec<u32> = a
+        .list_iter(mapped)
+        .unwrap()
+        .map(|n| a.small_number(n).unwrap())
+        .collect();
+    assert_eq!(values, [2, 2]);
+
+    assert_eq!(a.try_map_list(list, plus_one).unwrap(), None);
+    assert_eq!(a.try_map_list(one, plus_one).unwrap(), None);
+}
+
+#[test]
+fn test_prepend_append() {
+    let mut a = Allocator::new();
+    let values = |a: &Allocator, list: NodePtr| -> Vec<u32> {
+        a.list_iter(list)
+            .unwrap()
+            .map(|n| a.small_number(n).unwrap())
+            .collect()
+    };
+
+    let mut prepended = a.nil();
+    for v in [3, 2, 1] {
+        let v = a.new_small_number(v).unwrap();
+        prepended = a.prepend(prepended, v).unwrap();
+    }
+    assert_eq!(values(&a, prepended), [1, 2, 3]);
+
+    let mut appended = a.nil();
+    for v in [1, 2, 3] {
+        let v = a.new_small_number(v).unwrap();
+        appended = a.try_append(appended, v).unwrap().unwrap();
+    }
+    assert_eq!(values(&a, appended), [1, 2, 3]);
+    assert!(a.structural_eq(prepended, appended));
+
+    // prepending shares the list, appending copies it
+    let four = a.new_small_number(4).unwrap();
+    let pairs = a.pair_count();
+    let longer = a.prepend(appended, four).unwrap();
+    assert_eq!(a.pair_count(), pairs + 1);
+    assert_eq!(a.next(longer).unwrap().1, appended);
+    let longer = a.append(appended, four).unwrap();
+    assert_eq!(a.pair_count(), pairs + 5);
+    assert_eq!(values(&a, longer), [1, 2, 3, 4]);
+    // the original list is unchanged
+    assert_eq!(values(&a, appended), [1, 2, 3]);
+}
+
+#[test]
+fn test_append_improper() {
+    let mut a = Allocator::new();
+    let one = a.one();
+    let list = a.new_pair(one, one).unwrap();
+
+    // the improper tail is dropped
+    let appended = a.append(list, one).unwrap();
+    assert_eq!(a.list_len(appended), Some(2));
+
+    assert_eq!(a.try_append(list, one).unwrap(), None);
+    assert_eq!(a.try_append(one, one).unwrap(), None);
+
+    // prepending to an improper list is fine, it stays improper
+    let prepended = a.prepend(list, one).unwrap();
+    assert_eq!(a.list_len(prepended), None);
+}
+
+#[cfg(test)]
+fn tree_hash(a: &Allocator, node: NodePtr) -> [u8; 32] {
+    crate::serde::tree_hash(a, node).unwrap()
+}
+
+#[test]
+fn test_snapshot_roundtrip() {
+    let mut a = Allocator::new();
+    // a tree with 2^40 leaves, but only 40 pairs
+    let mut shared = a.new_atom(b"foobar").unwrap();
+    for _ in 0..40 {
+        shared = a.new_pair(shared, shared).unwrap();
+    }
+    let small = a.new_small_number(1337).unwrap();
+    let list = a.new_pair(small, shared).unwrap();
+    let roots = [shared, list, a.nil(), small];
+
+    let snapshot = a.snapshot(&roots).unwrap();
+    let (b, loaded) = Allocator::from_snapshot(&snapshot).unwrap();
+    assert_eq!(loaded, roots);
+    for (lhs, rhs) in roots.iter().zip(&loaded) {
+        assert_eq!(tree_hash(&a, *lhs), tree_hash(&b, *rhs));
+    }
+    // the structure sharing is preserved
+    assert_eq!(b.pair_vec.len(), a.pair_vec.len());
+    assert_eq!(b.snapshot(&loaded).unwrap(), snapshot);
+
+    // the loaded allocator can be used as normal
+    let mut b = b;
+    let pair = b.new_pair(loaded[0], loaded[1]).unwrap();
+    assert_eq!(b.sexp(pair), SExp::Pair(loaded[0], loaded[1]));
+}
+
+#[test]
+fn test_snapshot_invalid() {
     let mut a = Allocator::new();
-    let a0 = a.one();
-    let a1 = a.new_atom(&[1]).unwrap();
-    let a2 = {
-        let tmp = a.new_atom(&[0x01, 0xff]).unwrap();
-        a.new_substr(tmp, 0, 1).unwrap()
+    let atom = a.new_atom(b"foobar").unwrap();
+    let pair = a.new_pair(atom, atom).unwrap();
+    let snapshot = a.snapshot(&[pair]).unwrap();
+    assert!(Allocator::from_snapshot(&snapshot).is_ok());
+
+    let expect_err = |buf: &[u8], msg: &str| {
+        let e = Allocator::from_snapshot(buf).unwrap_err();
+        assert_eq!(e.to_string(), msg);
     };
-    let a3 = a.new_substr(a2, 0, 1).unwrap();
-    let a4 = a.new_number(1.into()).unwrap();
-    let a5 = a.new_small_number(1).unwrap();
 
-    assert!(a.atom_eq(a0, a0));
-    assert!(a.atom_eq(a0, a1));
-    assert!(a.atom_eq(a0, a2));
-    assert!(a.atom_eq(a0, a3));
-    assert!(a.atom_eq(a0, a4));
-    assert!(a.atom_eq(a0, a5));
+    // wrong magic
+    let mut buf = snapshot.clone();
+    buf[0] = b'X';
+    expect_err(&buf, "not a snapshot");
 
-    assert!(a.atom_eq(a1, a0));
-    assert!(a.atom_eq(a1, a1));
-    assert!(a.atom_eq(a1, a2));
-    assert!(a.atom_eq(a1, a3));
-    assert!(a.atom_eq(a1, a4));
-    assert!(a.atom_eq(a1, a5));
+    // a different version
+    let mut buf = snapshot.clone();
+    buf[8] = 2;
+    expect_err(&buf, "unsupported snapshot version");
 
-    assert!(a.atom_eq(a2, a0));
-    assert!(a.atom_eq(a2, a1));
-    assert!(a.atom_eq(a2, a2));
-    assert!(a.atom_eq(a2, a3));
-    assert!(a.atom_eq(a2, a4));
-    assert!(a.atom_eq(a2, a5));
+    // every truncation is caught
+    for len in 0..snapshot.len() {
+        assert!(Allocator::from_snapshot(&snapshot[..len]).is_err());
+    }
 
-    assert!(a.atom_eq(a3, a0));
-    assert!(a.atom_eq(a3, a1));
-    assert!(a.atom_eq(a3, a2));
-    assert!(a.atom_eq(a3, a3));
-    assert!(a.atom_eq(a3, a4));
-    assert!(a.atom_eq(a3, a5));
+    let mut buf = snapshot.clone();
+    buf.push(0);
+    expect_err(&buf, "trailing bytes in snapshot");
+
+    // the layout: header (32 bytes), heap (6 bytes), atom (8 bytes), pair (8
+    // bytes), root (4 bytes)
+    assert_eq!(snapshot.len(), 32 + 6 + 8 + 8 + 4);
+
+    // an atom pointing past the end of the heap
+    let mut buf = snapshot.clone();
+    buf[38 + 4..38 + 8].copy_from_slice(&7_u32.to_le_bytes());
+    expect_err(&buf, "invalid atom in snapshot");
+
+    // a pair referring to itself
+    let mut buf = snapshot.clone();
+    buf[46..50].copy_from_slice(&pair.0.to_le_bytes());
+    expect_err(&buf, "invalid node in snapshot");
+
+    // a root with an invalid object type
+    let mut buf = snapshot.clone();
+    buf[54..58].copy_from_slice(&u32::MAX.to_le_bytes());
+    expect_err(&buf, "invalid node in snapshot");
+
+    // allocators referencing a constant tree can't be snapshotted
+    let rom = ConstantTree::from_bytes(&[0xff, 0x01, 0x80]).unwrap();
+    let root = a.import_constant(&rom).unwrap();
+    assert!(a.snapshot(&[root]).is_err());
+}
 
-    assert!(a.atom_eq(a4, a0));
-    assert!(a.atom_eq(a4, a1));
-    assert!(a.atom_eq(a4, a2));
-    assert!(a.atom_eq(a4, a3));
-    assert!(a.atom_eq(a4, a4));
-    assert!(a.atom_eq(a4, a5));
+#[test]
+fn test_atom_owned() {
+    let buf = {
+        let mut a = Allocator::new();
+        let atom = a.new_atom(b"foobar").unwrap();
+        a.atom_owned(atom).unwrap()
+    };
+    assert_eq!(buf, b"foobar");
 
-    assert!(a.atom_eq(a5, a0));
-    assert!(a.atom_eq(a5, a1));
-    assert!(a.atom_eq(a5, a2));
-    assert!(a.atom_eq(a5, a3));
-    assert!(a.atom_eq(a5, a4));
-    assert!(a.atom_eq(a5, a5));
+    let mut a = Allocator::new();
+    let small = a.new_small_number(1337).unwrap();
+    assert_eq!(a.atom_owned(small).unwrap(), [0x05, 0x39]);
+    assert_eq!(a.atom_owned(a.nil()).unwrap(), b"");
+    let pair = a.new_pair(small, small).unwrap();
+    assert_eq!(a.atom_owned(pair), None);
 }
 
 #[test]
-fn test_atom_eq_minus_1() {
-    // these are a bunch of different representations of -1
-    // make sure they all compare equal
+#[should_panic]
+fn test_atom_len_pair() {
     let mut a = Allocator::new();
-    let a0 = a.new_atom(&[0xff]).unwrap();
-    let a1 = a.new_number((-1).into()).unwrap();
-    let a2 = {
-        let tmp = a.new_atom(&[0x01, 0xff]).unwrap();
-        a.new_substr(tmp, 1, 2).unwrap()
-    };
-    let a3 = a.new_substr(a0, 0, 1).unwrap();
-
-    assert!(a.atom_eq(a0, a0));
-    assert!(a.atom_eq(a0, a1));
-    assert!(a.atom_eq(a0, a2));
-    assert!(a.atom_eq(a0, a3));
+    let a0 = a.nil();
+    let pair = a.new_pair(a0, a0).unwrap();
+    a.atom_len(pair);
+}
 
-    assert!(a.atom_eq(a1, a0));
-    assert!(a.atom_eq(a1, a1));
-    assert!(a.atom_eq(a1, a2));
-    assert!(a.atom_eq(a1, a3));
+#[cfg(test)]
+#[rstest]
+#[case("", Ok(0))]
+#[case("00", Ok(0))]
+#[case("0000", Ok(0))]
+#[case("7f", Ok(127))]
+#[case("0080", Ok(128))]
+#[case("00ff", Ok(255))]
+#[case("0000ff", Ok(255))]
+#[case("ff", Err(IntDecodeErr::Overflow))]
+#[case("80", Err(IntDecodeErr::Overflow))]
+#[case("0100", Err(IntDecodeErr::Overflow))]
+#[case("00ffffffffffffffffff", Err(IntDecodeErr::Overflow))]
+fn test_as_int_u8(#[case] atom: &str, #[case] expected: Result<u8, IntDecodeErr>) {
+    let mut a = Allocator::new();
+    let node = a.new_atom(&hex::decode(atom).unwrap()).unwrap();
+    assert_eq!(a.as_int::<u8>(node), expected);
+}
 
-    assert!(a.atom_eq(a2, a0));
-    assert!(a.atom_eq(a2, a1));
-    assert!(a.atom_eq(a2, a2));
-    assert!(a.atom_eq(a2, a3));
+#[cfg(test)]
+#[rstest]
+#[case("", Ok(0))]
+#[case("ff", Ok(-1))]
+#[case("7fff", Ok(32767))]
+#[case("8000", Ok(-32768))]
+#[case("00007fff", Ok(32767))]
+#[case("ffff8000", Ok(-32768))]
+#[case("008000", Err(IntDecodeErr::Overflow))]
+#[case("ff7fff", Err(IntDecodeErr::Overflow))]
+#[case("010000", Err(IntDecodeErr::Overflow))]
+fn test_as_int_i16(#[case] atom: &str, #[case] expected: Result<i16, IntDecodeErr>) {
+    let mut a = Allocator::new();
+    let node = a.new_atom(&hex::decode(atom).unwrap()).unwrap();
+    assert_eq!(a.as_int::<i16>(node), expected);
+}
 
-    assert!(a.atom_eq(a3, a0));
-    assert!(a.atom_eq(a3, a1));
-    assert!(a.atom_eq(a3, a2));
-    assert!(a.atom_eq(a3, a3));
+#[test]
+fn test_as_int_pair() {
+    let mut a = Allocator::new();
+    let nil = a.nil();
+    let pair = a.new_pair(nil, nil).unwrap();
+    assert_eq!(a.as_int::<u8>(pair), Err(IntDecodeErr::NotAnAtom));
+    assert_eq!(a.as_int::<i64>(pair), Err(IntDecodeErr::NotAnAtom));
+    assert_eq!(a.as_int::<u64>(nil), Ok(0));
 }
 
 #[test]
-fn test_atom_eq() {
+fn test_rewrite_atom_in_place() {
     let mut a = Allocator::new();
-    let a0 = a.nil();
-    let a1 = a.one();
-    let a2 = a.new_atom(&[1]).unwrap();
-    let a3 = a.new_atom(&[0xfa, 0xc7]).unwrap();
-    let a4 = a.new_small_number(1).unwrap();
-    let a5 = a.new_number((-1337).into()).unwrap();
+    let atom = a.new_atom(b"foobar").unwrap();
+    let pair = a.new_pair(atom, atom).unwrap();
+    let heap_size = a.heap_size();
+    #[cfg(feature = "counters")]
+    let atom_count = a.atom_count();
+
+    let ret = a.rewrite_atom(atom, b"barfoo").unwrap();
+    assert_eq!(ret, atom);
+    assert_eq!(a.atom(atom).as_ref(), b"barfoo");
+    // every reference sees the new bytes
+    let SExp::Pair(first, rest) = a.sexp(pair) else {
+        panic!("expected pair");
+    };
+    assert_eq!(a.atom(first).as_ref(), b"barfoo");
+    assert_eq!(a.atom(rest).as_ref(), b"barfoo");
+    // nothing was allocated
+    assert_eq!(a.heap_size(), heap_size);
+    #[cfg(feature = "counters")]
+    assert_eq!(a.atom_count(), atom_count);
+}
 
-    assert!(a.atom_eq(a0, a0));
-    assert!(!a.atom_eq(a0, a1));
-    assert!(!a.atom_eq(a0, a2));
-    assert!(!a.atom_eq(a0, a3));
-    assert!(!a.atom_eq(a0, a4));
-    assert!(!a.atom_eq(a0, a5));
+#[test]
+fn test_rewrite_atom_new_allocation() {
+    let mut a = Allocator::new();
+    let atom = a.new_atom(b"foobar").unwrap();
 
-    assert!(!a.atom_eq(a1, a0));
-    assert!(a.atom_eq(a1, a1));
-    assert!(a.atom_eq(a1, a2));
-    assert!(!a.atom_eq(a1, a3));
-    assert!(a.atom_eq(a1, a4));
-    assert!(!a.atom_eq(a1, a5));
+    // a different length
+    let ret = a.rewrite_atom(atom, b"foo").unwrap();
+    assert!(ret != atom);
+    assert_eq!(a.atom(ret).as_ref(), b"foo");
+    assert_eq!(a.atom(atom).as_ref(), b"foobar");
 
-    assert!(!a.atom_eq(a2, a0));
-    assert!(a.atom_eq(a2, a1));
-    assert!(a.atom_eq(a2, a2));
-    assert!(!a.atom_eq(a2, a3));
-    assert!(a.atom_eq(a2, a4));
-    assert!(!a.atom_eq(a2, a5));
+    // small atoms are stored in the NodePtr, and can't be overwritten
+    let small = a.new_atom(&[0x42]).unwrap();
+    let ret = a.rewrite_atom(small, &[0x43]).unwrap();
+    assert!(ret != small);
+    assert_eq!(a.atom(ret).as_ref(), [0x43]);
+    assert_eq!(a.atom(small).as_ref(), [0x42]);
 
-    assert!(!a.atom_eq(a3, a0));
-    assert!(!a.atom_eq(a3, a1));
-    assert!(!a.atom_eq(a3, a2));
-    assert!(a.atom_eq(a3, a3));
-    assert!(!a.atom_eq(a3, a4));
-    assert!(a.atom_eq(a3, a5));
+    // the same length, but the new value is a small atom
+    let atom = a.new_atom(&[0x00, 0x80]).unwrap();
+    let ret = a.rewrite_atom(atom, &[0x01, 0x00]).unwrap();
+    assert_eq!(a.small_number(ret), Some(256));
+    assert_eq!(a.atom(atom).as_ref(), [0x00, 0x80]);
 
-    assert!(!a.atom_eq(a4, a0));
-    assert!(a.atom_eq(a4, a1));
-    assert!(a.atom_eq(a4, a2));
-    assert!(!a.atom_eq(a4, a3));
-    assert!(a.atom_eq(a4, a4));
-    assert!(!a.atom_eq(a4, a5));
+    let pair = a.new_pair(atom, atom).unwrap();
+    assert!(a.rewrite_atom(pair, b"foo").is_err());
 }
 
 #[test]
-#[should_panic]
-fn test_atom_eq_pair1() {
+fn test_rewrite_interned_atom() {
     let mut a = Allocator::new();
-    let a0 = a.nil();
-    let pair = a.new_pair(a0, a0).unwrap();
-    a.atom_eq(pair, a0);
+    let atom = a.intern_atom(b"foobar").unwrap();
+    assert_eq!(a.rewrite_atom(atom, b"barfoo").unwrap(), atom);
+    // interning the original contents allocates a new atom
+    let atom2 = a.intern_atom(b"foobar").unwrap();
+    assert!(atom2 != atom);
+    assert_eq!(a.atom(atom2).as_ref(), b"foobar");
 }
 
 #[test]
-#[should_panic]
-fn test_atom_eq_pair2() {
+fn test_atom_b32() {
     let mut a = Allocator::new();
-    let a0 = a.nil();
-    let pair = a.new_pair(a0, a0).unwrap();
-    a.atom_eq(a0, pair);
+    let hash = Bytes32::from([0x42; 32]);
+    let atom = a.new_atom_b32(&hash).unwrap();
+    assert_eq!(a.atom(atom).as_ref(), hash.as_ref());
+    assert_eq!(a.atom_b32(atom), Some(hash));
+
+    let short = a.new_atom(&[0x42; 31]).unwrap();
+    assert_eq!(a.atom_b32(short), None);
+    let long = a.new_atom(&[0x42; 33]).unwrap();
+    assert_eq!(a.atom_b32(long), None);
+    let pair = a.new_pair(atom, atom).unwrap();
+    assert_eq!(a.atom_b32(pair), None);
 }
 
 #[test]
-#[should_panic]
-fn test_atom_len_pair() {
+fn test_try_atom_len_pair() {
     let mut a = Allocator::new();
     let a0 = a.nil();
     let pair = a.new_pair(a0, a0).unwrap();
-    a.atom_len(pair);
+    assert_eq!(a.try_atom_len(pair), None);
+    assert_eq!(a.try_atom_len(a0), Some(0));
 }
 
 #[test]
@@ -825,7 +3001,7 @@ fn test_number_pair() {
 #[test]
 #[should_panic]
 fn test_invalid_node_ptr_type() {
-    let node = NodePtr(3 << NODE_PTR_IDX_BITS);
+    let node = NodePtr(6 << NODE_PTR_IDX_BITS);
     // unknown NodePtr type
     let _ = node.object_type();
 }
@@ -845,6 +3021,84 @@ fn test_invalid_small_number() {
     a.new_small_number(NODE_PTR_IDX_MASK + 1).unwrap();
 }
 
+#[test]
+fn test_list_cursor() {
+    let mut a = Allocator::new();
+    let items: Vec<NodePtr> = (0..1000).map(|i| a.new_number(i.into()).unwrap()).collect();
+    let nil = a.nil();
+    let list = a.new_pair_many(&items, nil).unwrap();
+
+    for chunk_size in [1, 7, 100, 999, 1000, 2000] {
+        let mut cursor = ListCursor::new(&a, list);
+        let mut all = Vec::new();
+        loop {
+            let chunk = cursor.next_chunk(&a, chunk_size);
+            assert!(chunk.len() <= chunk_size);
+            if chunk.is_empty() {
+                break;
+            }
+            all.extend(chunk);
+            assert_eq!(cursor.index(), all.len());
+            // allocating in between chunks is fine
+            a.new_atom(b"foobar").unwrap();
+        }
+        assert!(cursor.is_done(&a));
+        assert_eq!(cursor.improper_tail(&a), None);
+        assert_eq!(all, a.list_iter(list).unwrap().collect::<Vec<_>>());
+        assert_eq!(all, items);
+    }
+
+    let mut cursor = ListCursor::new(&a, nil);
+    assert!(cursor.is_done(&a));
+    assert!(cursor.next_chunk(&a, 10).is_empty());
+    assert_eq!(cursor.improper_tail(&a), None);
+}
+
+#[test]
+fn test_list_cursor_improper() {
+    let mut a = Allocator::new();
+    let items: Vec<NodePtr> = (0..10).map(|i| a.new_number(i.into()).unwrap()).collect();
+    let tail = a.new_atom(b"tail").unwrap();
+    let list = a.new_pair_many(&items, tail).unwrap();
+
+    let mut cursor = ListCursor::new(&a, list);
+    assert_eq!(cursor.next_chunk(&a, 4), items[0..4]);
+    // we haven't reached the end yet
+    assert!(!cursor.is_done(&a));
+    assert_eq!(cursor.improper_tail(&a), None);
+    assert_eq!(cursor.next_chunk(&a, 4), items[4..8]);
+    assert_eq!(cursor.next_chunk(&a, 4), items[8..10]);
+    assert!(cursor.is_done(&a));
+    assert_eq!(cursor.improper_tail(&a), Some(tail));
+}
+
+#[test]
+fn test_generation() {
+    let mut a = Allocator::new();
+    assert_eq!(a.generation(), 0);
+    let cp = a.checkpoint();
+    a.new_atom(b"foobar").unwrap();
+    assert_eq!(a.generation(), 0);
+    a.restore_checkpoint(&cp);
+    assert_eq!(a.generation(), 1);
+    a.reset();
+    assert_eq!(a.generation(), 2);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "the allocator was reset while a ListCursor was in use")]
+fn test_list_cursor_reset() {
+    let mut a = Allocator::new();
+    let one = a.one();
+    let nil = a.nil();
+    let list = a.new_pair_many(&[one, one, one], nil).unwrap();
+    let mut cursor = ListCursor::new(&a, list);
+    cursor.next_chunk(&a, 1);
+    a.reset();
+    cursor.next_chunk(&a, 1);
+}
+
 #[cfg(test)]
 #[rstest]
 #[case(0, 0)]
@@ -975,6 +3229,21 @@ fn test_allocate_pair_limit() {
     assert_eq!(a.new_pair(atom, atom).unwrap_err().1, "too many pairs");
 }
 
+#[test]
+fn test_allocate_custom_limits() {
+    // nil() and one() count towards the atom limit
+    let mut a = Allocator::new_with_limits(u32::MAX as usize, 5, 2);
+    let atom = a.new_atom(b"foo").unwrap();
+    let _ = a.new_small_number(1337).unwrap();
+    let _ = a.new_atom(b"bar").unwrap();
+    assert_eq!(a.new_atom(b"baz").unwrap_err().1, "too many atoms");
+    assert_eq!(a.new_small_number(42).unwrap_err().1, "too many atoms");
+
+    let _ = a.new_pair(atom, atom).unwrap();
+    let _ = a.new_pair(atom, atom).unwrap();
+    assert_eq!(a.new_pair(atom, atom).unwrap_err().1, "too many pairs");
+}
+
 #[test]
 fn test_substr() {
     let mut a = Allocator::new();
@@ -1239,6 +3508,219 @@ fn test_checkpoints() {
     assert_eq!(atom2, atom3);
 }
 
+#[test]
+fn test_node_sizes() {
+    // the arena layout depends on these being compact
+    assert_eq!(core::mem::size_of::<NodePtr>(), 4);
+    assert_eq!(core::mem::size_of::<Option<NodePtr>>(), 8);
+    assert_eq!(core::mem::size_of::<IntPair>(), 8);
+    assert_eq!(core::mem::size_of::<AtomBuf>(), 8);
+}
+
+#[test]
+fn test_pair_cycle() {
+    let mut a = Allocator::new();
+    let nil = a.nil();
+    let checkpoint = a.checkpoint();
+    let stale = a.new_pair(nil, nil).unwrap();
+    let stale2 = a.new_pair(stale, nil).unwrap();
+    a.restore_checkpoint(&checkpoint);
+
+    // the new pair would occupy the same slot as the stale one, and refer to
+    // itself
+    assert_eq!(
+        a.new_pair(stale, nil).unwrap_err().1,
+        "pair would create a cycle"
+    );
+    assert_eq!(
+        a.new_pair(nil, stale2).unwrap_err().1,
+        "pair would create a cycle"
+    );
+
+    // pairs created before the checkpoint are fine
+    let p = a.new_pair(nil, nil).unwrap();
+    assert_eq!(p, stale);
+    let p2 = a.new_pair(p, p).unwrap();
+    assert_eq!(a.sexp(p2), SExp::Pair(p, p));
+}
+
+#[test]
+fn test_constant_tree() {
+    use crate::serde::{node_from_bytes, node_to_bytes};
+
+    // (foobar (1 . 0x8000) bar). "foobar" is too large to be a small atom
+    let rom_bytes = {
+        let mut a = Allocator::new();
+        let foo = a.new_atom(b"foobar").unwrap();
+        let bar = a.new_atom(b"bar").unwrap();
+        let big = a.new_atom(&[0x80, 0x00]).unwrap();
+        let inner = a.new_pair(a.one(), big).unwrap();
+        let list = a.new_pair(bar, a.nil()).unwrap();
+        let list = a.new_pair(inner, list).unwrap();
+        let list = a.new_pair(foo, list).unwrap();
+        node_to_bytes(&a, list).unwrap()
+    };
+    let rom = ConstantTree::from_bytes(&rom_bytes).unwrap();
+
+    let mut a = Allocator::new();
+    let local = node_from_bytes(&mut a, &rom_bytes).unwrap();
+    let pairs = a.pair_vec.len();
+    let heap = a.u8_vec.len();
+
+    let root = a.import_constant(&rom).unwrap();
+    assert!(root.is_pair());
+    assert_eq!(root.object_type(), ObjectType::ConstPair);
+    // nothing was copied into the allocator
+    assert_eq!(a.pair_vec.len(), pairs);
+    assert_eq!(a.u8_vec.len(), heap);
+    // importing the same tree again is fine
+    assert_eq!(a.import_constant(&rom).unwrap(), root);
+    assert_eq!(a.import_constant(&rom.clone()).unwrap(), root);
+
+    assert_eq!(node_to_bytes(&a, root).unwrap(), rom_bytes);
+
+    let SExp::Pair(foo, rest) = a.sexp(root) else {
+        panic!("expected pair");
+    };
+    let SExp::Pair(inner, _) = a.sexp(rest) else {
+        panic!("expected pair");
+    };
+    let SExp::Pair(one, big) = a.sexp(inner) else {
+        panic!("expected pair");
+    };
+    assert!(foo.is_atom());
+    assert_eq!(foo.object_type(), ObjectType::ConstBytes);
+    assert_eq!(a.atom(foo).as_ref(), b"foobar");
+    assert_eq!(a.atom_len(foo), 6);
+    assert_eq!(a.small_number(one), Some(1));
+    assert_eq!(a.small_number(big), None);
+    assert_eq!(a.number(big), Number::from(-32768));
+
+    // constant atoms compare equal to their local counterparts
+    let SExp::Pair(local_foo, _) = a.sexp(local) else {
+        panic!("expected pair");
+    };
+    assert!(a.atom_eq(foo, local_foo));
+    assert!(a.atom_eq(one, a.one()));
+    assert!(!a.atom_eq(foo, big));
+
+    // cons local nodes onto constant nodes
+    let local_atom = a.new_atom(b"baz").unwrap();
+    let p1 = a.new_pair(local_atom, root).unwrap();
+    let p2 = a.new_pair(p1, foo).unwrap();
+    let mut expected = vec![0xff, 0xff, 0x83];
+    expected.extend_from_slice(b"baz");
+    expected.extend_from_slice(&rom_bytes);
+    expected.push(0x86);
+    expected.extend_from_slice(b"foobar");
+    assert_eq!(node_to_bytes(&a, p2).unwrap(), expected);
+
+    // substr and concat work across both spaces
+    let oo = a.new_substr(foo, 1, 3).unwrap();
+    assert_eq!(a.atom(oo).as_ref(), b"oo");
+    let foobarbaz = a.new_concat(9, &[foo, local_atom]).unwrap();
+    assert_eq!(a.atom(foobarbaz).as_ref(), b"foobarbaz");
+
+    // the same ConstantTree can be shared by multiple allocators
+    let mut a2 = Allocator::new();
+    let root2 = a2.import_constant(&rom).unwrap();
+    assert_eq!(node_to_bytes(&a2, root2).unwrap(), rom_bytes);
+}
+
+#[test]
+fn test_import_multiple_constants() {
+    use crate::serde::{node_from_bytes, node_to_bytes};
+
+    // the ROM is (foobar . 1337), the template is (bar (baz . 1337))
+    let rom_bytes = [
+        0xff, 0x86, b'f', b'o', b'o', b'b', b'a', b'r', 0x82, 0x05, 0x39,
+    ];
+    let template_bytes = [
+        0xff, 0x83, b'b', b'a', b'r', 0xff, 0xff, 0x83, b'b', b'a', b'z', 0x82, 0x05, 0x39, 0x80,
+    ];
+    let rom = ConstantTree::from_bytes(&rom_bytes).unwrap();
+    let template = ConstantTree::from_bytes(&template_bytes).unwrap();
+
+    let mut a = Allocator::new();
+    let rom_root = a.import_constant(&rom).unwrap();
+    let template_root = a.import_constant(&template).unwrap();
+    assert_eq!(rom_root.object_type(), ObjectType::ConstPair);
+    assert_eq!(template_root.object_type(), ObjectType::ConstPair);
+    // both trees have their root at index 0 of their own arena
+    assert_ne!(rom_root, template_root);
+    assert_eq!(a.import_constant(&rom).unwrap(), rom_root);
+    assert_eq!(a.import_constant(&template).unwrap(), template_root);
+
+    assert_eq!(node_to_bytes(&a, rom_root).unwrap(), rom_bytes);
+    assert_eq!(node_to_bytes(&a, template_root).unwrap(), template_bytes);
+
+    // atoms from different trees are distinct nodes, but compare equal to
+    // their local counterparts
+    let SExp::Pair(foobar, _) = a.sexp(rom_root) else {
+        panic!("expected pair");
+    };
+    let SExp::Pair(bar, rest) = a.sexp(template_root) else {
+        panic!("expected pair");
+    };
+    assert_ne!(foobar, bar);
+    assert_eq!(a.atom(foobar).as_ref(), b"foobar");
+    assert_eq!(a.atom(bar).as_ref(), b"bar");
+    let substr = a.new_substr(foobar, 3, 6).unwrap();
+    assert!(a.atom_eq(substr, bar));
+    let barbar = a.new_concat(6, &[bar, bar]).unwrap();
+    let foobarbar = a.new_concat(9, &[foobar, bar]).unwrap();
+    assert_eq!(a.atom(barbar).as_ref(), b"barbar");
+    assert_eq!(a.atom(foobarbar).as_ref(), b"foobarbar");
+
+    // cons local nodes onto nodes from both trees
+    let local = a.new_atom(b"local").unwrap();
+    let p1 = a.new_pair(rest, local).unwrap();
+    let p2 = a.new_pair(rom_root, p1).unwrap();
+    let mut expected = vec![0xff];
+    expected.extend_from_slice(&rom_bytes);
+    expected.push(0xff);
+    expected.extend_from_slice(&template_bytes[5..]);
+    expected.extend_from_slice(b"\x85local");
+    assert_eq!(node_to_bytes(&a, p2).unwrap(), expected);
+    let mut b = Allocator::new();
+    let copy = node_from_bytes(&mut b, &expected).unwrap();
+    assert_eq!(tree_hash(&a, p2), tree_hash(&b, copy));
+
+    // restoring a checkpoint drops the trees imported after it
+    let cp = a.checkpoint();
+    let other = ConstantTree::from_bytes(&[0xff, 0x01, 0x80]).unwrap();
+    let other_root = a.import_constant(&other).unwrap();
+    assert_eq!(a.constants.len(), 3);
+    a.restore_checkpoint(&cp);
+    assert_eq!(a.constants.len(), 2);
+    assert_eq!(a.import_constant(&other).unwrap(), other_root);
+
+    a.reset();
+    assert!(a.constants.is_empty());
+}
+
+#[test]
+fn test_import_too_many_constants() {
+    use crate::serde::node_to_bytes;
+
+    let mut a = Allocator::new();
+    let trees: Vec<ConstantTree> = (0..MAX_CONSTANT_TREES)
+        .map(|_| ConstantTree::from_bytes(&[0xff, 0x01, 0x80]).unwrap())
+        .collect();
+    for tree in &trees {
+        a.import_constant(tree).unwrap();
+    }
+    let tree = ConstantTree::from_bytes(&[0xff, 0x01, 0x80]).unwrap();
+    assert_eq!(
+        a.import_constant(&tree).unwrap_err().1,
+        "too many constant trees"
+    );
+
+    // trees that were already imported can still be imported again
+    let root = a.import_constant(&trees[0]).unwrap();
+    assert_eq!(node_to_bytes(&a, root).unwrap(), [0xff, 0x01, 0x80]);
+}
+
 #[cfg(test)]
 fn test_g1(a: &Allocator, n: NodePtr) -> EvalErr {
     a.g1(n).unwrap_err()
@@ -1370,7 +3852,7 @@ fn test_g2_roundtrip(#[case] atom: &str) {
     );
 }
 
-use std::borrow::Borrow;
+use core::borrow::Borrow;
 
 #[cfg(test)]
 type MakeFun = fn(&mut Allocator, &[u8]) -> NodePtr;
@@ -1577,6 +4059,26 @@ fn test_atom_len(#[case] buf: &[u8], #[case] expected: usize) {
     let mut a = Allocator::new();
     let atom = a.new_atom(buf).unwrap();
     assert_eq!(a.atom_len(atom), expected);
+    assert_eq!(a.try_atom_len(atom), Some(a.atom(atom).as_ref().len()));
+}
+
+#[test]
+fn test_try_atom_len() {
+    let mut a = Allocator::new();
+    assert_eq!(a.try_atom_len(a.nil()), Some(0));
+    assert_eq!(a.try_atom_len(a.one()), Some(1));
+
+    let atom = a.new_atom(b"foobar").unwrap();
+    let pair = a.new_pair(atom, atom).unwrap();
+    assert_eq!(a.try_atom_len(pair), None);
+
+    // substrings have the length of the range, whether they're copied into a
+    // small atom or point into the original buffer
+    for (start, end) in [(0, 0), (1, 2), (0, 3), (2, 6), (0, 6)] {
+        let sub = a.new_substr(atom, start, end).unwrap();
+        assert_eq!(a.try_atom_len(sub), Some((end - start) as usize));
+        assert_eq!(a.try_atom_len(sub), Some(a.atom(sub).as_ref().len()));
+    }
 }
 
 #[cfg(test)]
@@ -1593,6 +4095,7 @@ fn test_atom_len_number(#[case] value: Number, #[case] expected: usize) {
     let mut a = Allocator::new();
     let atom = a.new_number(value).unwrap();
     assert_eq!(a.atom_len(atom), expected);
+    assert_eq!(a.try_atom_len(atom), Some(a.atom(atom).as_ref().len()));
 }
 
 #[cfg(test)]
@@ -1617,6 +4120,7 @@ fn test_atom_len_g1(#[case] buffer_hex: &str, #[case] expected: usize) {
     let g1 = G1Element::from_bytes(&buffer[..].try_into().unwrap()).expect("invalid G1 point");
     let atom = a.new_g1(g1).unwrap();
     assert_eq!(a.atom_len(atom), expected);
+    assert_eq!(a.try_atom_len(atom), Some(a.atom(atom).as_ref().len()));
 }
 
 #[cfg(test)]
@@ -1648,6 +4152,7 @@ fn test_atom_len_g2(#[case] buffer_hex: &str, #[case] expected: usize) {
     let g2 = G2Element::from_bytes(&buffer[..].try_into().unwrap()).expect("invalid G2 point");
     let atom = a.new_g2(g2).unwrap();
     assert_eq!(a.atom_len(atom), expected);
+    assert_eq!(a.try_atom_len(atom), Some(a.atom(atom).as_ref().len()));
 }
 
 #[cfg(test)]
@@ -1831,3 +4336,63 @@ fn test_auto_small_number_from_buf(#[case] buf: &[u8], #[case] expect_small: boo
 fn test_fits_in_small_atom(#[case] buf: &[u8], #[case] expected: Option<u32>) {
     assert_eq!(fits_in_small_atom(buf), expected);
 }
+
+#[test]
+fn test_nil_and_one_dont_allocate() {
+    // the allocator starts out counting nil() and one() as allocated, leaving
+    // room for a single atom here. nil() and one() can still be called any
+    // number of times
+    let mut a = Allocator::new_with_limits(u32::MAX as usize, 3, MAX_NUM_PAIRS);
+    #[cfg(feature = "counters")]
+    let small_atoms = a.small_atom_count();
+    let nil = a.nil();
+    let one = a.one();
+    for _ in 0..1000 {
+        assert_eq!(a.nil(), nil);
+        assert_eq!(a.one(), one);
+        assert_eq!(a.quote_atom(), one);
+    }
+    assert_eq!(a.heap_size(), 0);
+    assert_eq!(a.pair_count(), 0);
+    #[cfg(feature = "counters")]
+    {
+        assert_eq!(a.atom_count(), 0);
+        assert_eq!(a.small_atom_count(), small_atoms);
+    }
+
+    assert!(a.atom(nil).as_ref().is_empty());
+    assert_eq!(a.atom(one).as_ref(), &[1]);
+    a.new_atom(b"foo").unwrap();
+    assert_eq!(a.new_atom(b"bar").unwrap_err().1, "too many atoms");
+}
+
+#[test]
+fn test_node_ptr_debug() {
+    let mut a = Allocator::new();
+    let atom = a.new_atom(b"foobar").unwrap();
+    let pair = a.new_pair(atom, atom).unwrap();
+    let small = a.new_small_number(1337).unwrap();
+    let stub = a.new_hash_stub([0x42; 32].into()).unwrap();
+    assert_eq!(format!("{atom:?}"), "NodePtr(atom:0)");
+    assert_eq!(format!("{pair:?}"), "NodePtr(pair:0)");
+    assert_eq!(format!("{small:?}"), "NodePtr(small:1337)");
+    assert_eq!(format!("{:?}", a.nil()), "NodePtr(small:0)");
+    assert_eq!(format!("{stub:?}"), "NodePtr(hash_stub:1)");
+
+    // nodes of a constant tree
+    let mut rom_bytes = vec![0xff, 0x86];
+    rom_bytes.extend_from_slice(b"foobar");
+    rom_bytes.push(0x80);
+    let rom = ConstantTree::from_bytes(&rom_bytes).unwrap();
+    let mut a = Allocator::new();
+    let pair = a.import_constant(&rom).unwrap();
+    let SExp::Pair(atom, _) = a.sexp(pair) else {
+        panic!("expected a pair");
+    };
+    assert_eq!(format!("{pair:?}"), "NodePtr(const_pair:0)");
+    assert_eq!(format!("{atom:?}"), "NodePtr(const_atom:0)");
+
+    // the Debug format carries over to EvalErr
+    let e = EvalErr::new(pair, "failed".to_string());
+    assert_eq!(e.to_string(), "Error at NodePtr(const_pair:0): failed");
+}
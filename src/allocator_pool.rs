@@ -0,0 +1,330 @@
+use crate::allocator::Allocator;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// By default, an allocator whose buffers have grown larger than this (in
+/// bytes) is shrunk before it's returned to the pool
+pub const DEFAULT_MAX_RETAINED: usize = 64 * 1024 * 1024;
+
+/// A pool of allocators, shared between threads. Creating a new `Allocator`
+/// for every program run means allocating (and freeing) its buffers every
+/// time. Allocators handed out by the pool keep their buffers between uses.
+///
+/// An allocator is reset when the `PooledAllocator` is dropped, so nothing
+/// allocated by one user is visible to the next. A single large program
+/// shouldn't make the pool hold on to its memory forever, so allocators whose
+/// buffers grew beyond the high-water mark (see `with_max_retained()`) are
+/// shrunk back when they're returned.
+pub struct AllocatorPool {
+    heap_limit: usize,
+    max_retained: usize,
+    idle: Mutex<Vec<Allocator>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    shrinks: AtomicU64,
+}
+
+/// Counters of how allocators have been handed out by an `AllocatorPool`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// the number of times get() reused an idle allocator
+    pub hits: u64,
+    /// the number of times get() had to create a new allocator
+    pub misses: u64,
+    /// the number of times an allocator was shrunk when it was returned
+    pub shrinks: u64,
+}
+
+impl AllocatorPool {
+    pub fn new() -> Self {
+        Self::new_limited(u32::MAX as usize)
+    }
+
+    /// The allocators created by this pool have the heap limit `heap_limit`.
+    /// See `Allocator::new_limited()`
+    pub fn new_limited(heap_limit: usize) -> Self {
+        Self {
+            heap_limit,
+            max_retained: DEFAULT_MAX_RETAINED,
+            idle: Mutex::new(Vec::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            shrinks: AtomicU64::new(0),
+        }
+    }
+
+    /// Set the high-water mark for the memory an idle allocator may keep, in
+    /// bytes. See `Allocator::buffer_capacity()`
+    pub fn with_max_retained(mut self, max_retained: usize) -> Self {
+        self.max_retained = max_retained;
+        self
+    }
+
+    /// Take an allocator from the pool, or create a new one if all of them
+    /// are in use
+    pub fn get(&self) -> PooledAllocator<'_> {
+        let idle = self.idle.lock().unwrap().pop();
+        let allocator = match idle {
+            Some(a) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                a
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Allocator::new_limited(self.heap_limit)
+            }
+        };
+        PooledAllocator {
+            pool: self,
+            allocator: Some(allocator),
+        }
+    }
+
+    /// The number of allocators in the pool that are not in use
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            shrinks: self.shrinks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for AllocatorPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An allocator borrowed from an `AllocatorPool`. It's reset and returned to
+/// the pool when dropped. NodePtrs into it must not outlive it
+pub struct PooledAllocator<'a> {
+    pool: &'a AllocatorPool,
+    // this is only None while being dropped
+    allocator: Option<Allocator>,
+}
+
+impl Deref for PooledAllocator<'_> {
+    type Target = Allocator;
+
+    fn deref(&self) -> &Allocator {
+        self.allocator.as_ref().expect("allocator already returned")
+    }
+}
+
+impl DerefMut for PooledAllocator<'_> {
+    fn deref_mut(&mut self) -> &mut Allocator {
+        self.allocator.as_mut().expect("allocator already returned")
+    }
+}
+
+impl Drop for PooledAllocator<'_> {
+    fn drop(&mut self) {
+        let Some(mut allocator) = self.allocator.take() else {
+            return;
+        };
+        allocator.reset();
+        if allocator.buffer_capacity() > self.pool.max_retained {
+            allocator.shrink_buffers();
+            self.pool.shrinks.fetch_add(1, Ordering::Relaxed);
+        }
+        // if the lock is poisoned, another thread panicked. Just drop the
+        // allocator rather than panicking again
+        if let Ok(mut idle) = self.pool.idle.lock() {
+            idle.push(allocator);
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::allocator::ConstantTree;
+#[cfg(test)]
+use crate::chik_dialect::ChikDialect;
+#[cfg(test)]
+use crate::run_program::run_program;
+#[cfg(test)]
+use crate::serde::{node_from_bytes, node_to_bytes};
+#[cfg(test)]
+use crate::test_ops::parse_exp;
+
+#[test]
+fn test_allocator_pool() {
+    let pool = AllocatorPool::new();
+    assert_eq!(pool.idle_count(), 0);
+    {
+        let mut a = pool.get();
+        let atom = a.new_atom(b"foobar").unwrap();
+        a.new_pair(atom, atom).unwrap();
+        assert_eq!(a.heap_size(), 6);
+        assert_eq!(a.pair_count(), 1);
+        assert_eq!(pool.idle_count(), 0);
+    }
+    assert_eq!(pool.idle_count(), 1);
+
+    // the allocator is reused, and restored
+    let a = pool.get();
+    assert_eq!(pool.idle_count(), 0);
+    assert_eq!(a.heap_size(), 0);
+    assert_eq!(a.pair_count(), 0);
+
+    // two allocators in use at the same time
+    let b = pool.get();
+    drop(a);
+    drop(b);
+    assert_eq!(pool.idle_count(), 2);
+    assert_eq!(
+        pool.stats(),
+        PoolStats {
+            hits: 1,
+            misses: 2,
+            shrinks: 0
+        }
+    );
+}
+
+#[test]
+fn test_allocator_pool_constants() {
+    // (1 2 3)
+    let rom = ConstantTree::from_bytes(&[0xff, 1, 0xff, 2, 0xff, 3, 0x80]).unwrap();
+    let other = ConstantTree::from_bytes(&[0xff, 4, 0x80]).unwrap();
+
+    let pool = AllocatorPool::new();
+    {
+        let mut a = pool.get();
+        a.import_constant(&rom).unwrap();
+    }
+    // the next user of the allocator doesn't see the constant tree imported
+    // by the previous one
+    let mut a = pool.get();
+    let root = a.import_constant(&other).unwrap();
+    assert_eq!(node_to_bytes(&a, root).unwrap(), [0xff, 4, 0x80]);
+}
+
+#[test]
+fn test_allocator_pool_threads() {
+    // (c 2 (c 5 (q))), run with the environment (index id)
+    let program = [
+        0xff, 4, 0xff, 2, 0xff, 0xff, 4, 0xff, 5, 0xff, 0xff, 1, 0x80, 0x80, 0x80,
+    ];
+    const THREADS: usize = 8;
+    const ROUNDS: u32 = 200;
+
+    let pool = AllocatorPool::new();
+    std::thread::scope(|s| {
+        for id in 0..THREADS {
+            let pool = &pool;
+            s.spawn(move || {
+                for i in 0..ROUNDS {
+                    let mut a = pool.get();
+                    // whatever the previous user left behind is gone
+                    assert_eq!(a.heap_size(), 0);
+                    assert_eq!(a.pair_count(), 0);
+
+                    let program = node_from_bytes(&mut a, &program).unwrap();
+                    let id_atom = a.new_atom(&[0xab; 32]).unwrap();
+                    let nil = a.nil();
+                    let id_atom = a.new_pair(id_atom, nil).unwrap();
+                    let index = a
+                        .new_number((i * THREADS as u32 + id as u32).into())
+                        .unwrap();
+                    let env = a.new_pair(index, id_atom).unwrap();
+                    let result = run_program(&mut a, &ChikDialect::new(0), program, env, 100000)
+                        .unwrap()
+                        .1;
+                    let mut expected = vec![0xff];
+                    expected.extend_from_slice(&node_to_bytes(&a, index).unwrap());
+                    expected.extend_from_slice(&[0xff, 0xa0]);
+                    expected.extend_from_slice(&[0xab; 32]);
+                    expected.extend_from_slice(&[0x80]);
+                    assert_eq!(node_to_bytes(&a, result).unwrap(), expected);
+                }
+            });
+        }
+    });
+
+    // allocators are reused, there's at most one per thread
+    assert!(pool.idle_count() >= 1);
+    assert!(pool.idle_count() <= THREADS);
+    let stats = pool.stats();
+    assert_eq!(stats.misses, pool.idle_count() as u64);
+    assert_eq!(stats.hits + stats.misses, THREADS as u64 * ROUNDS as u64);
+    let a = pool.get();
+    assert_eq!(a.heap_size(), 0);
+    assert_eq!(a.pair_count(), 0);
+}
+
+#[test]
+fn test_allocator_pool_shrink() {
+    let pool = AllocatorPool::new().with_max_retained(8 * 1024 * 1024);
+    let initial = pool.get().buffer_capacity();
+    assert!(initial < 8 * 1024 * 1024);
+
+    // a small program doesn't grow the buffers
+    {
+        let mut a = pool.get();
+        a.new_atom(&[0x42; 1000]).unwrap();
+    }
+    assert_eq!(pool.get().buffer_capacity(), initial);
+    assert_eq!(pool.stats().shrinks, 0);
+
+    // a huge one does, but the memory isn't retained
+    {
+        let mut a = pool.get();
+        let nil = a.nil();
+        let mut list = nil;
+        for _ in 0..1000 {
+            let atom = a.new_atom(&[0x42; 20000]).unwrap();
+            list = a.new_pair(atom, list).unwrap();
+        }
+        assert!(a.buffer_capacity() > 20_000_000);
+    }
+    assert_eq!(pool.stats().shrinks, 1);
+    assert_eq!(pool.idle_count(), 1);
+    let a = pool.get();
+    assert_eq!(a.buffer_capacity(), initial);
+    assert_eq!(a.heap_size(), 0);
+    drop(a);
+
+    // with a high enough limit, the buffers are kept
+    let pool = AllocatorPool::new().with_max_retained(usize::MAX);
+    {
+        let mut a = pool.get();
+        a.new_atom(&[0x42; 20_000_000]).unwrap();
+    }
+    assert!(pool.get().buffer_capacity() > 20_000_000);
+    assert_eq!(pool.stats().shrinks, 0);
+}
+
+// builds the list of the squares of (n n-1 ... 1)
+#[cfg(test)]
+const SQUARES: &str = "(a (q . (a 2 (c 2 (c 5 ())))) (c (q . (a (i 5 (q . (c (* 5 5) (a 2 (c 2 (c (- 5 (q . 1)) ()))))) (q . ())) 1)) 1))";
+
+#[cfg(test)]
+fn run_squares(a: &mut Allocator, n: u32) -> (u64, Vec<u8>) {
+    let (program, _) = parse_exp(a, SQUARES);
+    let n = a.new_number(n.into()).unwrap();
+    let nil = a.nil();
+    let env = a.new_pair(n, nil).unwrap();
+    let r = run_program(a, &ChikDialect::new(0), program, env, 11_000_000_000).unwrap();
+    (r.0, node_to_bytes(a, r.1).unwrap())
+}
+
+#[test]
+fn test_allocator_pool_reuse_results() {
+    // every allocator is shrunk when it's returned
+    let pool = AllocatorPool::new().with_max_retained(0);
+    for n in [100, 10, 1000, 0, 50] {
+        let expected = run_squares(&mut Allocator::new(), n);
+        assert_eq!(run_squares(&mut pool.get(), n), expected);
+        let mut a = pool.get();
+        assert_eq!(run_squares(&mut a, n), expected);
+        a.reset();
+        assert_eq!(run_squares(&mut a, n), expected);
+    }
+    assert_eq!(pool.stats().shrinks, 10);
+}
@@ -0,0 +1,774 @@
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use crate::collections::HashSet;
+
+use crate::allocator::{Allocator, NodePtr, SExp};
+use crate::bytes::Bytes32;
+use crate::chik_dialect::ChikDialect;
+use crate::conditions::{parse_conditions, Condition};
+use crate::cost::Cost;
+use crate::err_utils::err;
+use crate::op_utils::uint_atom;
+use crate::reduction::{EvalErr, Reduction};
+use crate::run_program::run_program;
+use crate::serde::{node_from_bytes_backrefs, tree_hash};
+use crate::sha2::Sha256;
+
+// A block generator is a program that, when run, returns the list of coin
+// spends in a block, as its first element:
+//
+//   ((parent_coin_id puzzle amount solution ...) ...)
+//
+// It's passed a single argument, the list of block references (the generators
+// of previous blocks, as atoms). Each puzzle is then run with its solution to
+// produce the spend's conditions.
+
+/// Deserialize (with back references) and run a block generator. The result is
+/// the generator's output, i.e. the list of spends is its first element. The
+/// puzzles are not run.
+pub fn run_block_generator<R: AsRef<[u8]>>(
+    a: &mut Allocator,
+    generator: &[u8],
+    block_refs: &[R],
+    max_cost: Cost,
+    flags: u32,
+) -> Result<Reduction, EvalErr> {
+    let program = match node_from_bytes_backrefs(a, generator) {
+        Ok(program) => program,
+        Err(e) => {
+            return err(a.nil(), &format!("invalid generator: {e}"));
+        }
+    };
+    let mut refs = Vec::with_capacity(block_refs.len());
+    for r in block_refs {
+        refs.push(a.new_atom(r.as_ref())?);
+    }
+    let nil = a.nil();
+    let refs = a.new_pair_many(&refs, nil)?;
+    let env = a.new_pair(refs, nil)?;
+    run_program(a, &ChikDialect::new(flags), program, env, max_cost)
+}
+
+/// The cost of a generator's bytes, i.e. the size of the generator and its
+/// block references times `cost_per_byte`. It only depends on the sizes, so a
+/// generator can be rejected by this alone, before it's deserialized or run.
+/// Like `run_program()`, a `max_cost` of 0 means no limit.
+pub fn validate_generator_cost<R: AsRef<[u8]>>(
+    generator: &[u8],
+    block_refs: &[R],
+    cost_per_byte: Cost,
+    max_cost: Cost,
+) -> Result<Cost, EvalErr> {
+    let num_bytes = block_refs.iter().fold(generator.len() as Cost, |acc, r| {
+        acc.saturating_add(r.as_ref().len() as Cost)
+    });
+    let cost = num_bytes.saturating_mul(cost_per_byte);
+    if max_cost != 0 && cost > max_cost {
+        return err(NodePtr::NIL, "cost exceeded");
+    }
+    Ok(cost)
+}
+
+/// Like `run_block_generator()`, but `byte_cost`, as computed by
+/// `validate_generator_cost()`, is charged first. The generator runs with
+/// what's left of `max_cost`, and the returned cost includes `byte_cost`. If
+/// `byte_cost` alone exceeds `max_cost`, the generator isn't deserialized.
+pub fn run_block_generator_with_byte_cost<R: AsRef<[u8]>>(
+    a: &mut Allocator,
+    generator: &[u8],
+    block_refs: &[R],
+    byte_cost: Cost,
+    max_cost: Cost,
+    flags: u32,
+) -> Result<Reduction, EvalErr> {
+    let max_cost = if max_cost == 0 {
+        0
+    } else {
+        // a limit of 0 would mean no limit
+        match max_cost.checked_sub(byte_cost) {
+            Some(remaining) if remaining > 0 => remaining,
+            _ => {
+                return err(a.nil(), "cost exceeded");
+            }
+        }
+    };
+    let Reduction(cost, result) = run_block_generator(a, generator, block_refs, max_cost, flags)?;
+    Ok(Reduction(byte_cost + cost, result))
+}
+
+/// A coin spend in a block, and what running its puzzle cost and returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendInfo {
+    pub coin_id: [u8; 32],
+    pub puzzle_hash: [u8; 32],
+    pub amount: u64,
+    pub conditions: Vec<Condition>,
+    /// the cost of running the puzzle with its solution
+    pub execution_cost: Cost,
+}
+
+impl SpendInfo {
+    pub fn create_coin_count(&self) -> usize {
+        self.conditions
+            .iter()
+            .filter(|c| c.is_create_coin())
+            .count()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockAnalysis {
+    /// the cost of running the generator itself, to produce the list of spends
+    pub generator_cost: Cost,
+    /// the generator cost plus the execution cost of all spends
+    pub total_cost: Cost,
+    pub spends: Vec<SpendInfo>,
+}
+
+fn coin_id(parent_coin_id: &[u8], puzzle_hash: &[u8; 32], amount: u64) -> [u8; 32] {
+    // the amount is hashed in its canonical atom form. i.e. without leading
+    // zeros, unless one is needed to keep the value positive
+    let bytes = amount.to_be_bytes();
+    let start = bytes.iter().take_while(|b| **b == 0).count();
+    let mut hasher = Sha256::new();
+    hasher.update(parent_coin_id);
+    hasher.update(puzzle_hash);
+    if bytes.get(start).is_some_and(|b| (b & 0x80) != 0) {
+        hasher.update([0_u8]);
+    }
+    hasher.update(&bytes[start..]);
+    hasher.finalize()
+}
+
+/// Run a block generator, and each of its spends, and attribute the cost to
+/// each spend. Every puzzle is run separately, with its solution, so the
+/// execution cost of a spend is exactly the cost of its puzzle. Any work done
+/// by the generator itself, e.g. decompressing puzzles from block references,
+/// is counted in `generator_cost`, not charged to any spend.
+///
+/// `max_cost` is the limit for the block as a whole. Like `run_program()`, 0
+/// means no limit.
+pub fn analyze_block_generator<R: AsRef<[u8]>>(
+    a: &mut Allocator,
+    generator: &[u8],
+    block_refs: &[R],
+    max_cost: Cost,
+    flags: u32,
+) -> Result<BlockAnalysis, EvalErr> {
+    let max_cost = if max_cost == 0 { Cost::MAX } else { max_cost };
+    let Reduction(generator_cost, result) =
+        run_block_generator(a, generator, block_refs, max_cost, flags)?;
+    let Some((spends, _)) = a.next(result) else {
+        return err(result, "generator must return a list of spends");
+    };
+    let Some(spend_list) = a.list_iter(spends) else {
+        return err(spends, "generator must return a list of spends");
+    };
+    let spend_list: Vec<NodePtr> = spend_list.collect();
+
+    let dialect = ChikDialect::new(flags);
+    let mut total_cost = generator_cost;
+    let mut spends = Vec::with_capacity(spend_list.len());
+    for spend in spend_list {
+        let mut items = a.list_iter(spend).into_iter().flatten();
+        let (Some(parent), Some(puzzle), Some(amount), Some(solution)) =
+            (items.next(), items.next(), items.next(), items.next())
+        else {
+            return err(spend, "invalid spend");
+        };
+        if !parent.is_atom() || a.atom_len(parent) != 32 {
+            return err(parent, "invalid parent coin id");
+        }
+        let amount = uint_atom::<8>(a, amount, "spend amount")?;
+        let puzzle_hash = tree_hash(a, puzzle).map_err(|e| EvalErr::new(puzzle, e.to_string()))?;
+        let coin_id = coin_id(a.atom(parent).as_ref(), &puzzle_hash, amount);
+
+        // a limit of 0 would mean no limit
+        let remaining = max_cost - total_cost;
+        if remaining == 0 {
+            return err(spend, "cost exceeded");
+        }
+        let Reduction(execution_cost, conditions) =
+            run_program(a, &dialect, puzzle, solution, remaining)?;
+        total_cost += execution_cost;
+        spends.push(SpendInfo {
+            coin_id,
+            puzzle_hash,
+            amount,
+            conditions: parse_conditions(a, conditions)?,
+            execution_cost,
+        });
+    }
+    Ok(BlockAnalysis {
+        generator_cost,
+        total_cost,
+        spends,
+    })
+}
+
+/// A coin created by a CREATE_COIN condition, `(51 puzzle_hash amount (hint
+/// ...))`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewCoin {
+    /// the ID of the coin whose spend created this coin
+    pub parent_coin_id: Bytes32,
+    pub puzzle_hash: Bytes32,
+    pub amount: u64,
+    /// the first memo, if it's 32 bytes
+    pub hint: Option<Bytes32>,
+}
+
+impl NewCoin {
+    pub fn coin_id(&self) -> Bytes32 {
+        coin_id(self.parent_coin_id.as_ref(), &self.puzzle_hash, self.amount).into()
+    }
+}
+
+// the hint of a CREATE_COIN condition is its first memo, if it's 32 bytes.
+// Anything else in the memo position is ignored
+fn hint(a: &Allocator, args: &[NodePtr]) -> Option<Bytes32> {
+    let SExp::Pair(first, _) = a.sexp(*args.get(2)?) else {
+        return None;
+    };
+    if !first.is_atom() {
+        return None;
+    }
+    Bytes32::try_from(a.atom(first).as_ref()).ok()
+}
+
+/// Run a block generator, and all of its spends, and return the coins created
+/// by CREATE_COIN conditions, in the order of the spends and conditions. See
+/// `analyze_block_generator()`. Creating the same coin twice (i.e. the same
+/// puzzle hash and amount from the same spend) is an error.
+pub fn additions_from_generator<R: AsRef<[u8]>>(
+    a: &mut Allocator,
+    generator: &[u8],
+    block_refs: &[R],
+    max_cost: Cost,
+    flags: u32,
+) -> Result<Vec<NewCoin>, EvalErr> {
+    let analysis = analyze_block_generator(a, generator, block_refs, max_cost, flags)?;
+    let mut additions = Vec::new();
+    let mut seen = HashSet::<Bytes32>::new();
+    for spend in &analysis.spends {
+        for c in spend.conditions.iter().filter(|c| c.is_create_coin()) {
+            let (Some(puzzle_hash), Some(amount)) = (c.args.first(), c.args.get(1)) else {
+                return err(c.node, "invalid CREATE_COIN condition");
+            };
+            let ph = match a.sexp(*puzzle_hash) {
+                SExp::Atom => Bytes32::try_from(a.atom(*puzzle_hash).as_ref()).ok(),
+                SExp::Pair(..) => None,
+            };
+            let Some(puzzle_hash) = ph else {
+                return err(*puzzle_hash, "invalid CREATE_COIN puzzle hash");
+            };
+            let coin = NewCoin {
+                parent_coin_id: spend.coin_id.into(),
+                puzzle_hash,
+                amount: uint_atom::<8>(a, *amount, "CREATE_COIN amount")?,
+                hint: hint(a, &c.args),
+            };
+            if !seen.insert(coin.coin_id()) {
+                return err(c.node, "duplicate output");
+            }
+            additions.push(coin);
+        }
+    }
+    Ok(additions)
+}
+
+#[cfg(test)]
+use crate::serde::node_to_bytes;
+#[cfg(test)]
+use crate::test_ops::parse_exp;
+
+// a generator with two spends. The first puzzle is 1, i.e. it returns its
+// solution, the second one quotes its conditions
+#[cfg(test)]
+fn two_spend_generator() -> Vec<u8> {
+    let mut a = Allocator::new();
+    let parent1 = format!("0x{}", hex::encode([1; 32]));
+    let parent2 = format!("0x{}", hex::encode([2; 32]));
+    let ph1 = format!("0x{}", hex::encode([0x22; 32]));
+    let ph2 = format!("0x{}", hex::encode([0x33; 32]));
+    let ph3 = format!("0x{}", hex::encode([0x44; 32]));
+    let (generator, _) = parse_exp(
+        &mut a,
+        &format!(
+            "(q (({parent1} 1 100 ((51 {ph1} 100) (51 {ph2} 200))) \
+             ({parent2} (q (60 \"msg\") (51 {ph3} 1)) 1 ())))"
+        ),
+    );
+    node_to_bytes(&a, generator).unwrap()
+}
+
+#[test]
+fn test_analyze_block_generator() {
+    let generator = two_spend_generator();
+    let no_refs: [&[u8]; 0] = [];
+    let mut a = Allocator::new();
+    let analysis = analyze_block_generator(&mut a, &generator, &no_refs, 11000000000, 0).unwrap();
+
+    // quoting the list of spends
+    assert_eq!(analysis.generator_cost, 20);
+    assert_eq!(analysis.spends.len(), 2);
+
+    let spend = &analysis.spends[0];
+    assert_eq!(
+        hex::encode(spend.puzzle_hash),
+        "9dcf97a184f32623d11a73124ceb99a5709b083721e878a16d78f596718ba7b2"
+    );
+    assert_eq!(
+        hex::encode(spend.coin_id),
+        "d56758f00cdc23cbb91927f0e45ebefb9acdecd03f78318bb504ffdf83041bfc"
+    );
+    assert_eq!(spend.amount, 100);
+    // looking up the solution (path 1)
+    assert_eq!(spend.execution_cost, 44);
+    assert_eq!(spend.conditions.len(), 2);
+    assert_eq!(spend.create_coin_count(), 2);
+    assert_eq!(
+        a.atom(spend.conditions[1].args[0]).as_ref(),
+        [0x33; 32].as_slice()
+    );
+
+    let spend = &analysis.spends[1];
+    assert_eq!(
+        hex::encode(spend.puzzle_hash),
+        "53acc2d5d480909b0aa2eef282dee830d84f64ae9530a2e317427296e84112a6"
+    );
+    assert_eq!(
+        hex::encode(spend.coin_id),
+        "99e580c8a4d276359464470063ee813cbcf036fd414da343bbeb321cc06280c0"
+    );
+    assert_eq!(spend.amount, 1);
+    // quote
+    assert_eq!(spend.execution_cost, 20);
+    assert_eq!(spend.conditions.len(), 2);
+    assert_eq!(spend.conditions[0].opcode, Some(60));
+    assert_eq!(spend.create_coin_count(), 1);
+
+    assert_eq!(analysis.total_cost, 20 + 44 + 20);
+
+    // the generator output is the same as run_block_generator()'s
+    let mut a = Allocator::new();
+    let Reduction(cost, result) =
+        run_block_generator(&mut a, &generator, &no_refs, 11000000000, 0).unwrap();
+    assert_eq!(cost, analysis.generator_cost);
+    assert_eq!(a.list_len(result), Some(1));
+}
+
+#[test]
+fn test_analyze_block_generator_cost_limit() {
+    let generator = two_spend_generator();
+    let no_refs: [&[u8]; 0] = [];
+    // enough for the generator and the first spend only
+    for max_cost in [20, 63, 64, 83] {
+        let mut a = Allocator::new();
+        let e = analyze_block_generator(&mut a, &generator, &no_refs, max_cost, 0).unwrap_err();
+        assert_eq!(e.1, "cost exceeded");
+    }
+    let mut a = Allocator::new();
+    let analysis = analyze_block_generator(&mut a, &generator, &no_refs, 84, 0).unwrap();
+    assert_eq!(analysis.total_cost, 84);
+    // no limit
+    let mut a = Allocator::new();
+    let analysis = analyze_block_generator(&mut a, &generator, &no_refs, 0, 0).unwrap();
+    assert_eq!(analysis.total_cost, 84);
+}
+
+#[test]
+fn test_analyze_block_generator_refs() {
+    // the generator passes the list of block references as the solution to
+    // (c (c (q . 60) 1) (q)), which turns it into a single condition
+    let mut a = Allocator::new();
+    let parent = hex::encode([3; 32]);
+    let (generator, _) = parse_exp(
+        &mut a,
+        &format!(
+            "(c (c (c (q . 0x{parent}) (c (q c (c (q . 60) 1) (q)) (c (q . 1) (c 2 (q))))) (q)) (q))"
+        ),
+    );
+    let generator = node_to_bytes(&a, generator).unwrap();
+    let block_ref = [0xff, 0x01, 0x80];
+
+    let mut a = Allocator::new();
+    let analysis = analyze_block_generator(&mut a, &generator, &[block_ref], 0, 0).unwrap();
+    assert_eq!(analysis.spends.len(), 1);
+    let spend = &analysis.spends[0];
+    assert_eq!(spend.amount, 1);
+    assert_eq!(spend.create_coin_count(), 0);
+    assert_eq!(spend.conditions.len(), 1);
+    assert_eq!(spend.conditions[0].opcode, Some(60));
+    assert_eq!(a.atom(spend.conditions[0].args[0]).as_ref(), block_ref);
+    assert_eq!(
+        analysis.total_cost,
+        analysis.generator_cost + spend.execution_cost
+    );
+}
+
+#[test]
+fn test_analyze_block_generator_invalid() {
+    let no_refs: [&[u8]; 0] = [];
+    let parent = format!("0x{}", hex::encode([1; 32]));
+    let cases: [(String, &str); 8] = [
+        (
+            "(q . 1)".to_string(),
+            "generator must return a list of spends",
+        ),
+        (
+            "(q 1)".to_string(),
+            "generator must return a list of spends",
+        ),
+        ("(q (1))".to_string(), "invalid spend"),
+        (format!("(q (({parent} 1 100)))"), "invalid spend"),
+        (
+            "(q ((0x1337 1 100 ())))".to_string(),
+            "invalid parent coin id",
+        ),
+        (
+            format!("(q (({parent} 1 -1 ())))"),
+            "spend amount requires positive int arg",
+        ),
+        (format!("(q (({parent} (x) 1 ())))"), "klvm raise"),
+        (format!("(q (({parent} 1 1 (1))))"), "invalid condition"),
+    ];
+    for (generator, expected) in cases {
+        let mut a = Allocator::new();
+        let (generator, _) = parse_exp(&mut a, &generator);
+        let generator = node_to_bytes(&a, generator).unwrap();
+        let mut a = Allocator::new();
+        let e = analyze_block_generator(&mut a, &generator, &no_refs, 0, 0).unwrap_err();
+        assert_eq!(e.1, expected);
+    }
+
+    let mut a = Allocator::new();
+    let e = analyze_block_generator(&mut a, &[0xff, 0x01], &no_refs, 0, 0).unwrap_err();
+    assert!(e.1.starts_with("invalid generator"));
+}
+
+#[test]
+fn test_additions_from_generator() {
+    let parent1 = format!("0x{}", hex::encode([1; 32]));
+    let parent2 = format!("0x{}", hex::encode([2; 32]));
+    let ph1 = format!("0x{}", hex::encode([0x22; 32]));
+    let ph2 = format!("0x{}", hex::encode([0x33; 32]));
+    let ph3 = format!("0x{}", hex::encode([0x44; 32]));
+    let hint = format!("0x{}", hex::encode([0x55; 32]));
+    let mut a = Allocator::new();
+    let (generator, _) = parse_exp(
+        &mut a,
+        &format!(
+            "(q (({parent1} 1 100 ((51 {ph1} 100 ({hint} 0x1337)) (51 {ph2} 200 (0x1337 {hint})) \
+             (60 \"msg\") (51 {ph3} 0))) \
+             ({parent2} (q (60 \"msg\") (51 {ph3} 1 {hint})) 1 ())))"
+        ),
+    );
+    let generator = node_to_bytes(&a, generator).unwrap();
+
+    let no_refs: [&[u8]; 0] = [];
+    let mut a = Allocator::new();
+    let additions = additions_from_generator(&mut a, &generator, &no_refs, 0, 0).unwrap();
+
+    // the parent coin IDs are the IDs of the spent coins. The first spend is
+    // the same as in test_analyze_block_generator(), the second one's puzzle
+    // also has a hint, so it has a different puzzle hash
+    let p1 = "d56758f00cdc23cbb91927f0e45ebefb9acdecd03f78318bb504ffdf83041bfc";
+    let p2 = "04e94bedcdf8759b0371be1e3a0af6e230f50b2c1cd17b2ca75c6ebb7f691d36";
+    let expected = [
+        (
+            p1,
+            [0x22; 32],
+            100,
+            Some([0x55; 32]),
+            "b24b1a85aa6ea68af4adaea91c074f692205373130d041b19904d89182a3f4bb",
+        ),
+        (
+            p1,
+            [0x33; 32],
+            200,
+            None,
+            "bc134d40e123d7f20ee527396f245629aa32509c27a22123e0e56c625d0d22ed",
+        ),
+        (
+            p1,
+            [0x44; 32],
+            0,
+            None,
+            "19ea914fe949a6ca4f453881c24b8497f2f6a4be4a3f8d67a3cddd5ea3c174df",
+        ),
+        // the memos aren't a list, so there's no hint
+        (
+            p2,
+            [0x44; 32],
+            1,
+            None,
+            "03d3f7795d6fbaa749da26a7cd8538ef8e772f8c9241d96425e4adfafedd6e67",
+        ),
+    ];
+    assert_eq!(additions.len(), expected.len());
+    for (coin, (parent, puzzle_hash, amount, hint, coin_id)) in additions.iter().zip(expected) {
+        assert_eq!(coin.parent_coin_id.to_string(), parent);
+        assert_eq!(coin.puzzle_hash, puzzle_hash);
+        assert_eq!(coin.amount, amount);
+        assert_eq!(coin.hint, hint.map(Bytes32::from));
+        assert_eq!(coin.coin_id().to_string(), coin_id);
+    }
+}
+
+#[test]
+fn test_additions_from_generator_invalid() {
+    let no_refs: [&[u8]; 0] = [];
+    let parent = format!("0x{}", hex::encode([1; 32]));
+    let ph = format!("0x{}", hex::encode([0x22; 32]));
+    let cases: [(String, &str); 6] = [
+        // the same coin twice, even with different memos
+        (
+            format!("(q (({parent} 1 100 ((51 {ph} 100) (51 {ph} 100 ({ph}))))))"),
+            "duplicate output",
+        ),
+        (
+            format!("(q (({parent} 1 100 ((51 {ph})))))"),
+            "invalid CREATE_COIN condition",
+        ),
+        (
+            format!("(q (({parent} 1 100 ((51 0x1337 1)))))"),
+            "invalid CREATE_COIN puzzle hash",
+        ),
+        (
+            format!("(q (({parent} 1 100 ((51 ({ph}) 1)))))"),
+            "invalid CREATE_COIN puzzle hash",
+        ),
+        (
+            format!("(q (({parent} 1 100 ((51 {ph} -1)))))"),
+            "CREATE_COIN amount requires positive int arg",
+        ),
+        (
+            format!("(q (({parent} 1 100 ((51 {ph} 0x010000000000000000)))))"),
+            "CREATE_COIN amount requires u64 arg",
+        ),
+    ];
+    for (generator, expected) in cases {
+        let mut a = Allocator::new();
+        let (generator, _) = parse_exp(&mut a, &generator);
+        let generator = node_to_bytes(&a, generator).unwrap();
+        let mut a = Allocator::new();
+        let e = additions_from_generator(&mut a, &generator, &no_refs, 0, 0).unwrap_err();
+        assert_eq!(e.1, expected);
+    }
+
+    // the same puzzle hash and amount from different spends are different
+    // coins
+    let parent2 = format!("0x{}", hex::encode([2; 32]));
+    let mut a = Allocator::new();
+    let (generator, _) = parse_exp(
+        &mut a,
+        &format!("(q (({parent} 1 100 ((51 {ph} 100))) ({parent2} 1 100 ((51 {ph} 100)))))"),
+    );
+    let generator = node_to_bytes(&a, generator).unwrap();
+    let mut a = Allocator::new();
+    let additions = additions_from_generator(&mut a, &generator, &no_refs, 0, 0).unwrap();
+    assert_eq!(additions.len(), 2);
+    assert!(additions[0].coin_id() != additions[1].coin_id());
+}
+
+// benches/block_af9c3d98.bin is a real, compressed, block generator. It
+// deserializes its puzzles from a block reference, with the old deserializer
+// module, neither of which is available here. All 532 spends in it are of the
+// standard puzzle though, curried with a public key, so this builds the
+// equivalent uncompressed generator from the block's list of spends:
+//
+//   (a (q . decompressor) (c (q . prefix) (c (q . suffix) (c (q . start)
+//       (c (q . end) (c (q . (((parent amount) (public_key solution)) ...)) 1))))))
+#[cfg(test)]
+fn expanded_block_af9c3d98() -> Vec<u8> {
+    use crate::serde::{node_from_bytes, node_to_bytes_backrefs};
+
+    let mut a = Allocator::new();
+    let block = include_bytes!("../benches/block_af9c3d98.bin");
+    let program = node_from_bytes_backrefs(&mut a, block).unwrap();
+    let arg = |a: &Allocator, n: NodePtr, i: usize| a.list_iter(n).unwrap().nth(i).unwrap();
+    let mut env = arg(&a, program, 2);
+    for _ in 0..4 {
+        env = arg(&a, env, 2);
+    }
+    let (_, compressed_spends) = a.next(arg(&a, env, 1)).unwrap();
+
+    let standard_puzzle = hex::decode(
+        include_str!("../tests/puzzles/p2_delegated_puzzle_or_hidden_puzzle.hex").trim(),
+    )
+    .unwrap();
+    let standard_puzzle = node_from_bytes(&mut a, &standard_puzzle).unwrap();
+    let nil = a.nil();
+    let one = a.one();
+    let quoted_mod = a.new_pair(one, standard_puzzle).unwrap();
+    let op_a = a.new_small_number(2).unwrap();
+    let op_c = a.new_small_number(4).unwrap();
+
+    let mut spends = Vec::new();
+    for spend in a.list_iter(compressed_spends).unwrap().collect::<Vec<_>>() {
+        let coin = arg(&a, spend, 0);
+        let key_solution = arg(&a, spend, 1);
+        let (parent, amount) = (arg(&a, coin, 0), arg(&a, coin, 1));
+        let (public_key, solution) = (arg(&a, key_solution, 0), arg(&a, key_solution, 1));
+        // (a (q . standard_puzzle) (c (q . public_key) 1))
+        let quoted_key = a.new_pair(one, public_key).unwrap();
+        let curry = a.new_pair_many(&[op_c, quoted_key, one], nil).unwrap();
+        let puzzle = a.new_pair_many(&[op_a, quoted_mod, curry], nil).unwrap();
+        spends.push(
+            a.new_pair_many(&[parent, puzzle, amount, solution], nil)
+                .unwrap(),
+        );
+    }
+    let spends = a.new_pair_many(&spends, nil).unwrap();
+    let result = a.new_pair(spends, nil).unwrap();
+    let generator = a.new_pair(one, result).unwrap();
+    node_to_bytes_backrefs(&a, generator).unwrap()
+}
+
+#[test]
+fn test_additions_from_real_block() {
+    let generator = expanded_block_af9c3d98();
+    let no_refs: [&[u8]; 0] = [];
+    let mut a = Allocator::new();
+    let additions = additions_from_generator(&mut a, &generator, &no_refs, 11000000000, 0).unwrap();
+
+    // the expected additions were computed independently of this crate, from
+    // the CREATE_COIN conditions in the block and the tree hash of the
+    // standard puzzle (e9aaa49f...) curried with each spend's public key
+    assert_eq!(additions.len(), 792);
+    let total: u64 = additions.iter().map(|c| c.amount).sum();
+    assert_eq!(total, 51626942946623);
+    // none of the spends in this block use hints
+    assert!(additions.iter().all(|c| c.hint.is_none()));
+
+    let expected = [
+        (
+            &additions[0],
+            "331d9380b88ee1d25d2c2f046de7f24bbb1fa291b085c138cdd1fda3770eaafa",
+            "f11c396146ed4177328c17c45151d8878af97707ae77eaeddbe34c3dcc86c732",
+            1,
+            "ed4a055de0fd83fbd4c1b126406639114361d36c35a352e1ff0ec495859a0646",
+        ),
+        (
+            &additions[791],
+            "c704a87c512c2aa8f1882aa6dfce944073ddaf0b6110c964f6a10ad91bd68537",
+            "96fc6da0a97403e7819cf87c9bab527e732016334f4ce3e0a7820cfe9ddac523",
+            0x3d0900,
+            "7dbf7b335b13e39774df0c2aa4c48f482a8e3b0baf478485d3d66e626a215b36",
+        ),
+    ];
+    for (coin, parent, puzzle_hash, amount, coin_id) in expected {
+        assert_eq!(coin.parent_coin_id.to_string(), parent);
+        assert_eq!(coin.puzzle_hash.to_string(), puzzle_hash);
+        assert_eq!(coin.amount, amount);
+        assert_eq!(coin.coin_id().to_string(), coin_id);
+    }
+
+    // the hash of all the coin IDs, in order
+    let mut hasher = Sha256::new();
+    for coin in &additions {
+        hasher.update(coin.coin_id());
+    }
+    assert_eq!(
+        hex::encode(hasher.finalize()),
+        "c46b6552d6e863d19b0299bdee51857c076ec5645709e8e41b5ea9d0e64d8e00"
+    );
+}
+
+#[test]
+fn test_run_block_generator_with_byte_cost() {
+    let no_refs: [&[u8]; 0] = [];
+    let mut a = Allocator::new();
+    let (refs_generator, _) = parse_exp(&mut a, "(c 2 (q))");
+    let fixtures: [(Vec<u8>, &[&[u8]]); 3] = [
+        (two_spend_generator(), &no_refs),
+        (
+            node_to_bytes(&a, refs_generator).unwrap(),
+            &[&[0xff, 0x01, 0x80]],
+        ),
+        (
+            node_to_bytes(&a, refs_generator).unwrap(),
+            &[&[0x80], &[0x13, 0x37], &[]],
+        ),
+    ];
+    for (generator, refs) in fixtures {
+        for cost_per_byte in [0, 1, 12000] {
+            let num_bytes = generator.len() + refs.iter().map(|r| r.len()).sum::<usize>();
+            let byte_cost = validate_generator_cost(&generator, refs, cost_per_byte, 0).unwrap();
+            assert_eq!(byte_cost, num_bytes as Cost * cost_per_byte);
+
+            // the combined cost is the byte cost plus the cost of running the
+            // generator
+            let mut a = Allocator::new();
+            let Reduction(run_cost, expected) =
+                run_block_generator(&mut a, &generator, refs, 0, 0).unwrap();
+            let total = byte_cost + run_cost;
+
+            let mut a2 = Allocator::new();
+            let Reduction(cost, result) =
+                run_block_generator_with_byte_cost(&mut a2, &generator, refs, byte_cost, 0, 0)
+                    .unwrap();
+            assert_eq!(cost, total);
+            assert_eq!(a2.to_owned_tree(result), a.to_owned_tree(expected));
+
+            // and the limit applies to the sum
+            let mut a = Allocator::new();
+            let Reduction(cost, _) =
+                run_block_generator_with_byte_cost(&mut a, &generator, refs, byte_cost, total, 0)
+                    .unwrap();
+            assert_eq!(cost, total);
+            let mut a = Allocator::new();
+            let e = run_block_generator_with_byte_cost(
+                &mut a,
+                &generator,
+                refs,
+                byte_cost,
+                total - 1,
+                0,
+            )
+            .unwrap_err();
+            assert_eq!(e.1, "cost exceeded");
+        }
+    }
+}
+
+#[test]
+fn test_validate_generator_cost_reject() {
+    let no_refs: [&[u8]; 0] = [];
+    // not a valid generator, but that's never found out
+    let blob = vec![0xff; 1000000];
+    let max_cost = 11000000000;
+    let e = validate_generator_cost(&blob, &no_refs, 12000, max_cost).unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+    let e = validate_generator_cost(&blob[..10], &[&blob[..]], 12000, max_cost).unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+    assert_eq!(
+        validate_generator_cost(&blob, &no_refs, 11000, max_cost).unwrap(),
+        max_cost
+    );
+    assert_eq!(
+        validate_generator_cost(&blob, &no_refs, Cost::MAX, 0).unwrap(),
+        Cost::MAX
+    );
+
+    // the generator isn't deserialized when its byte cost is over the limit
+    let byte_cost = validate_generator_cost(&blob, &no_refs, 12000, 0).unwrap();
+    let mut a = Allocator::new();
+    let (pairs, heap_size) = (a.pair_count(), a.heap_size());
+    #[cfg(feature = "counters")]
+    let atoms = a.atom_count();
+    let e = run_block_generator_with_byte_cost(&mut a, &blob, &no_refs, byte_cost, max_cost, 0)
+        .unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+    assert_eq!((a.pair_count(), a.heap_size()), (pairs, heap_size));
+    #[cfg(feature = "counters")]
+    assert_eq!(a.atom_count(), atoms);
+
+    // which it otherwise is, and fails
+    let e =
+        run_block_generator_with_byte_cost(&mut a, &blob, &no_refs, 0, max_cost, 0).unwrap_err();
+    assert!(e.1.starts_with("invalid generator"));
+}
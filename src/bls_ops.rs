@@ -1,21 +1,132 @@
+// chik-bls requires std. Without it, the operators below don't exist (and
+// neither does ChikDialect, see lib.rs), and the cost constants and helpers
+// they use are unused
+#![cfg_attr(not(feature = "std"), allow(dead_code, unused_imports))]
+
 use crate::allocator::{Allocator, Atom, NodePtr};
+#[cfg(feature = "std")]
+use crate::collections::HashSet;
 use crate::cost::{check_cost, Cost};
 use crate::err_utils::err;
+#[cfg(feature = "std")]
+use crate::op_utils::mod_group_order;
 use crate::op_utils::{
-    atom, first, get_args, get_varargs, int_atom, mod_group_order, new_atom_and_cost, nilp, rest,
+    atom, first, get_args, get_varargs, int_atom, new_atom_and_cost, nilp, rest,
     MALLOC_COST_PER_BYTE,
 };
 use crate::reduction::{EvalErr, Reduction, Response};
+#[cfg(feature = "std")]
 use chik_bls::{
     aggregate_pairing, aggregate_verify, hash_to_g1_with_dst, hash_to_g2_with_dst, G1Element,
     G2Element, PublicKey,
 };
+use core::fmt;
+use hex_literal::hex;
+
+// the BLS12-381 base field modulus. Every coordinate of a compressed point must
+// be less than this
+const FIELD_MODULUS: [u8; 48] = hex!("1a0111ea397fe69a4b1ba7b6434bacd764774b84f38512bf6730d2a0f6b0f6241eabfffeb153ffffb9feffffffffaaab");
+
+/// The reasons a compressed G1 or G2 point can be rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointError {
+    /// not 48 (G1) or 96 (G2) bytes
+    BadLength,
+    /// the flag bits are invalid, the point at infinity isn't encoded
+    /// canonically (0xc0 followed by zeros), or a coordinate is not a field
+    /// element
+    BadEncoding,
+    NotOnCurve,
+    /// on the curve, but not in the prime order subgroup
+    NotInSubgroup,
+    /// the point at infinity, where it's not allowed
+    Infinity,
+}
+
+impl fmt::Display for PointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::BadLength => "bad length",
+            Self::BadEncoding => "bad encoding",
+            Self::NotOnCurve => "not on curve",
+            Self::NotInSubgroup => "not in subgroup",
+            Self::Infinity => "point at infinity",
+        })
+    }
+}
+
+impl core::error::Error for PointError {}
+
+// check the flag bits and the coordinates of a compressed point, without
+// decompressing it. Returns true if it's the point at infinity
+fn check_point_encoding(buf: &[u8]) -> Result<bool, PointError> {
+    if (buf[0] & 0x80) == 0 {
+        return Err(PointError::BadEncoding);
+    }
+    if (buf[0] & 0x40) != 0 {
+        return if buf[0] == 0xc0 && buf[1..].iter().all(|b| *b == 0) {
+            Ok(true)
+        } else {
+            Err(PointError::BadEncoding)
+        };
+    }
+    for (i, coordinate) in buf.chunks(48).enumerate() {
+        let mut x: [u8; 48] = coordinate.try_into().unwrap();
+        if i == 0 {
+            // the top 3 bits are the flags
+            x[0] &= 0x1f;
+        }
+        if x >= FIELD_MODULUS {
+            return Err(PointError::BadEncoding);
+        }
+    }
+    Ok(false)
+}
+
+/// Validate and decompress a G1 point, with the rules the BLS operators use.
+/// The point at infinity is only accepted if `allow_infinity` is set. The
+/// operators allow it.
+#[cfg(feature = "std")]
+pub fn validate_g1_bytes(buf: &[u8], allow_infinity: bool) -> Result<G1Element, PointError> {
+    let buf: &[u8; 48] = buf.try_into().map_err(|_| PointError::BadLength)?;
+    if check_point_encoding(buf)? && !allow_infinity {
+        return Err(PointError::Infinity);
+    }
+    // the encoding has been checked, except that the x-coordinate 0 is not
+    // allowed (even though (0, 2) is on the curve)
+    let point = G1Element::from_bytes_unchecked(buf).map_err(|e| match e {
+        chik_bls::Error::InvalidPublicKey(_) => PointError::NotOnCurve,
+        _ => PointError::BadEncoding,
+    })?;
+    if !point.is_valid() {
+        return Err(PointError::NotInSubgroup);
+    }
+    Ok(point)
+}
+
+/// Validate and decompress a G2 point, with the rules the BLS operators use.
+/// The point at infinity is only accepted if `allow_infinity` is set. The
+/// operators allow it.
+#[cfg(feature = "std")]
+pub fn validate_g2_bytes(buf: &[u8], allow_infinity: bool) -> Result<G2Element, PointError> {
+    let buf: &[u8; 96] = buf.try_into().map_err(|_| PointError::BadLength)?;
+    if check_point_encoding(buf)? && !allow_infinity {
+        return Err(PointError::Infinity);
+    }
+    // the encoding has been checked, so failing to decompress means there's no
+    // point with this x-coordinate
+    let point = G2Element::from_bytes_unchecked(buf).map_err(|_| PointError::NotOnCurve)?;
+    if !point.is_valid() {
+        return Err(PointError::NotInSubgroup);
+    }
+    Ok(point)
+}
 
 // the same cost as point_add (aka g1_add)
-const BLS_G1_SUBTRACT_BASE_COST: Cost = 101094;
+pub(crate) const BLS_G1_SUBTRACT_BASE_COST: Cost = 101094;
 const BLS_G1_SUBTRACT_COST_PER_ARG: Cost = 1343980;
 
-const BLS_G1_MULTIPLY_BASE_COST: Cost = 705500;
+pub(crate) const BLS_G1_MULTIPLY_BASE_COST: Cost = 705500;
 const BLS_G1_MULTIPLY_COST_PER_BYTE: Cost = 10;
 
 // this is the same cost as XORing the top bit (minus the heap allocation of the
@@ -23,31 +134,36 @@ const BLS_G1_MULTIPLY_COST_PER_BYTE: Cost = 10;
 const BLS_G1_NEGATE_BASE_COST: Cost = 1396 - 480;
 
 // g2_add and g2_subtract have the same cost
-const BLS_G2_ADD_BASE_COST: Cost = 80000;
+pub(crate) const BLS_G2_ADD_BASE_COST: Cost = 80000;
 const BLS_G2_ADD_COST_PER_ARG: Cost = 1950000;
-const BLS_G2_SUBTRACT_BASE_COST: Cost = 80000;
+pub(crate) const BLS_G2_SUBTRACT_BASE_COST: Cost = 80000;
 const BLS_G2_SUBTRACT_COST_PER_ARG: Cost = 1950000;
 
-const BLS_G2_MULTIPLY_BASE_COST: Cost = 2100000;
+pub(crate) const BLS_G2_MULTIPLY_BASE_COST: Cost = 2100000;
 const BLS_G2_MULTIPLY_COST_PER_BYTE: Cost = 5;
 
 // this is the same cost as XORing the top bit (minus the heap allocation of the
 // return value, which the operator is adding back)
 const BLS_G2_NEGATE_BASE_COST: Cost = 2164 - 960;
 
-const BLS_MAP_TO_G1_BASE_COST: Cost = 195000;
+pub(crate) const BLS_MAP_TO_G1_BASE_COST: Cost = 195000;
 const BLS_MAP_TO_G1_COST_PER_BYTE: Cost = 4;
 const BLS_MAP_TO_G1_COST_PER_DST_BYTE: Cost = 4;
 
-const BLS_MAP_TO_G2_BASE_COST: Cost = 815000;
+pub(crate) const BLS_MAP_TO_G2_BASE_COST: Cost = 815000;
 const BLS_MAP_TO_G2_COST_PER_BYTE: Cost = 4;
 const BLS_MAP_TO_G2_COST_PER_DST_BYTE: Cost = 4;
 
-const BLS_PAIRING_BASE_COST: Cost = 3000000;
+pub(crate) const BLS_PAIRING_BASE_COST: Cost = 3000000;
 const BLS_PAIRING_COST_PER_ARG: Cost = 1200000;
 
 const DST_G2: &[u8; 43] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_AUG_";
 
+// the domain separation tag of the basic scheme. It has the same length as the
+// AUG tag, so the costs of bls_verify and bls_verify_basic are the same
+const DST_G2_BASIC: &[u8; 43] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_NUL_";
+
+#[cfg(feature = "std")]
 pub fn op_bls_g1_subtract(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Response {
     let mut cost = BLS_G1_SUBTRACT_BASE_COST;
     check_cost(a, cost, max_cost)?;
@@ -71,6 +187,7 @@ pub fn op_bls_g1_subtract(a: &mut Allocator, mut input: NodePtr, max_cost: Cost)
     ))
 }
 
+#[cfg(feature = "std")]
 pub fn op_bls_g1_multiply(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
     let [point, scalar] = get_args::<2>(a, input, "g1_multiply")?;
 
@@ -91,17 +208,16 @@ pub fn op_bls_g1_multiply(a: &mut Allocator, input: NodePtr, max_cost: Cost) ->
     ))
 }
 
+#[cfg(feature = "std")]
 pub fn op_bls_g1_negate(a: &mut Allocator, input: NodePtr, _max_cost: Cost) -> Response {
     let [point] = get_args::<1>(a, input, "g1_negate")?;
 
     let blob = atom(a, point, "G1 atom")?;
     // this is here to validate the point
-    let _g1 = G1Element::from_bytes(
-        blob.as_ref()
-            .try_into()
-            .map_err(|_| EvalErr(point, "atom is not G1 size, 48 bytes".to_string()))?,
-    )
-    .map_err(|_| EvalErr(point, "atom is not a valid G1 point".to_string()))?;
+    validate_g1_bytes(blob.as_ref(), true).map_err(|e| match e {
+        PointError::BadLength => EvalErr::new(point, "atom is not G1 size, 48 bytes".to_string()),
+        _ => EvalErr::new(point, "atom is not a valid G1 point".to_string()),
+    })?;
 
     if (blob.as_ref()[0] & 0xe0) == 0xc0 {
         // This is compressed infinity. negating it is a no-op
@@ -118,6 +234,7 @@ pub fn op_bls_g1_negate(a: &mut Allocator, input: NodePtr, _max_cost: Cost) -> R
     }
 }
 
+#[cfg(feature = "std")]
 pub fn op_bls_g2_add(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Response {
     let mut cost = BLS_G2_ADD_BASE_COST;
     check_cost(a, cost, max_cost)?;
@@ -135,6 +252,7 @@ pub fn op_bls_g2_add(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> R
     ))
 }
 
+#[cfg(feature = "std")]
 pub fn op_bls_g2_subtract(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Response {
     let mut cost = BLS_G2_SUBTRACT_BASE_COST;
     check_cost(a, cost, max_cost)?;
@@ -158,6 +276,7 @@ pub fn op_bls_g2_subtract(a: &mut Allocator, mut input: NodePtr, max_cost: Cost)
     ))
 }
 
+#[cfg(feature = "std")]
 pub fn op_bls_g2_multiply(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
     let [point, scalar] = get_args::<2>(a, input, "g2_multiply")?;
 
@@ -178,6 +297,7 @@ pub fn op_bls_g2_multiply(a: &mut Allocator, input: NodePtr, max_cost: Cost) ->
     ))
 }
 
+#[cfg(feature = "std")]
 pub fn op_bls_g2_negate(a: &mut Allocator, input: NodePtr, _max_cost: Cost) -> Response {
     let [point] = get_args::<1>(a, input, "g2_negate")?;
 
@@ -187,12 +307,10 @@ pub fn op_bls_g2_negate(a: &mut Allocator, input: NodePtr, _max_cost: Cost) -> R
     let blob = blob_atom.as_ref();
 
     // this is here to validate the point
-    let _g2 = G2Element::from_bytes(
-        blob.as_ref()
-            .try_into()
-            .map_err(|_| EvalErr(point, "atom is not G2 size, 96 bytes".to_string()))?,
-    )
-    .map_err(|_| EvalErr(point, "atom is not a valid G2 point".to_string()))?;
+    validate_g2_bytes(blob, true).map_err(|e| match e {
+        PointError::BadLength => EvalErr::new(point, "atom is not G2 size, 96 bytes".to_string()),
+        _ => EvalErr::new(point, "atom is not a valid G2 point".to_string()),
+    })?;
 
     if (blob[0] & 0xe0) == 0xc0 {
         // This is compressed infinity. negating it is a no-op
@@ -209,6 +327,7 @@ pub fn op_bls_g2_negate(a: &mut Allocator, input: NodePtr, _max_cost: Cost) -> R
     }
 }
 
+#[cfg(feature = "std")]
 pub fn op_bls_map_to_g1(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
     let ([msg, dst], argc) = get_varargs::<2>(a, input, "g1_map")?;
     if !(1..=2).contains(&argc) {
@@ -237,6 +356,7 @@ pub fn op_bls_map_to_g1(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Re
     ))
 }
 
+#[cfg(feature = "std")]
 pub fn op_bls_map_to_g2(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
     let ([msg, dst], argc) = get_varargs::<2>(a, input, "g2_map")?;
     if !(1..=2).contains(&argc) {
@@ -269,6 +389,7 @@ pub fn op_bls_map_to_g2(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Re
 // It performs a low-level pairing operation of the (G1, G2)-pairs
 // and returns if the resulting Gt point is the
 // identity, otherwise terminates the program with a validation error.
+#[cfg(feature = "std")]
 pub fn op_bls_pairing_identity(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
     let mut cost = BLS_PAIRING_BASE_COST;
     check_cost(a, cost, max_cost)?;
@@ -292,11 +413,24 @@ pub fn op_bls_pairing_identity(a: &mut Allocator, input: NodePtr, max_cost: Cost
     }
 }
 
-// expects: G2 G1 msg G1 msg ...
+// the cost, signature and public key/message pairs of a bls_verify call
+#[cfg(feature = "std")]
+type VerifyArgs<'a> = (Cost, G2Element, Vec<(PublicKey, Atom<'a>)>);
+
+// parses the arguments to bls_verify and bls_verify_basic, and computes the
+// cost. Both operators have the same argument shape and cost:
+// G2 G1 msg G1 msg ...
 // G2 is the signature
 // G1 is a public key
 // the G1 and its corresponding message must be passed in pairs.
-pub fn op_bls_verify(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
+// msg_name is used in the error for a message that's not an atom
+#[cfg(feature = "std")]
+fn verify_args<'a>(
+    a: &'a Allocator,
+    input: NodePtr,
+    max_cost: Cost,
+    msg_name: &'static str,
+) -> Result<VerifyArgs<'a>, EvalErr> {
     let mut cost = BLS_PAIRING_BASE_COST;
     check_cost(a, cost, max_cost)?;
 
@@ -312,7 +446,7 @@ pub fn op_bls_verify(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Respo
     while !nilp(a, args) {
         let pk = a.g1(first(a, args)?)?;
         args = rest(a, args)?;
-        let msg = atom(a, first(a, args)?, "bls_verify message")?;
+        let msg = atom(a, first(a, args)?, msg_name)?;
         args = rest(a, args)?;
 
         cost += BLS_PAIRING_COST_PER_ARG;
@@ -322,6 +456,15 @@ pub fn op_bls_verify(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Respo
 
         items.push((pk, msg));
     }
+    Ok((cost, signature, items))
+}
+
+// expects: G2 G1 msg G1 msg ...
+// verifies the signature using the augmented scheme (AUG). i.e. every message
+// is prefixed by its public key before being hashed
+#[cfg(feature = "std")]
+pub fn op_bls_verify(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
+    let (cost, signature, items) = verify_args(a, input, max_cost, "bls_verify message")?;
 
     if !aggregate_verify(&signature, items) {
         err(input, "bls_verify failed")
@@ -329,3 +472,336 @@ pub fn op_bls_verify(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Respo
         Ok(Reduction(cost, a.nil()))
     }
 }
+
+// basic scheme verification:
+// e(G1 generator, sig) == e(pk1, H(msg1)) * e(pk2, H(msg2)) ...
+// which is checked as the product of the pairings (including the negated
+// generator) being the identity
+#[cfg(feature = "std")]
+fn verify_basic(signature: &G2Element, items: &[(PublicKey, Atom)]) -> bool {
+    // the basic scheme is only secure against rogue key attacks if all
+    // messages are distinct. Public keys must not be the point at infinity
+    let mut seen = HashSet::<&[u8]>::new();
+    for (pk, msg) in items {
+        if pk.is_inf() || !seen.insert(msg.as_ref()) {
+            return false;
+        }
+    }
+    let mut g1 = PublicKey::generator();
+    g1.negate();
+    let mut pairs = vec![(g1, signature.clone())];
+    for (pk, msg) in items {
+        pairs.push((*pk, hash_to_g2_with_dst(msg.as_ref(), DST_G2_BASIC)));
+    }
+    aggregate_pairing(pairs)
+}
+
+// expects: G2 G1 msg G1 msg ...
+// the same arguments and cost as bls_verify, but verifies the signature using
+// the basic scheme (NUL). i.e. messages are hashed as-is, and must be distinct
+#[cfg(feature = "std")]
+pub fn op_bls_verify_basic(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
+    let (cost, signature, items) = verify_args(a, input, max_cost, "bls_verify_basic message")?;
+
+    if !verify_basic(&signature, &items) {
+        err(input, "bls_verify_basic failed")
+    } else {
+        Ok(Reduction(cost, a.nil()))
+    }
+}
+
+#[cfg(test)]
+use chik_bls::{sign, SecretKey};
+
+// returns the argument list (signature pk1 msg1 pk2 msg2 ...)
+#[cfg(test)]
+fn verify_arg_list(a: &mut Allocator, sig: &G2Element, items: &[(PublicKey, &[u8])]) -> NodePtr {
+    let mut args = a.nil();
+    for (pk, msg) in items.iter().rev() {
+        let msg = a.new_atom(msg).unwrap();
+        args = a.new_pair(msg, args).unwrap();
+        let pk = a.new_g1(*pk).unwrap();
+        args = a.new_pair(pk, args).unwrap();
+    }
+    let sig = a.new_g2(sig.clone()).unwrap();
+    a.new_pair(sig, args).unwrap()
+}
+
+#[cfg(test)]
+fn sign_basic(sk: &SecretKey, msg: &[u8]) -> G2Element {
+    let mut sig = hash_to_g2_with_dst(msg, DST_G2_BASIC);
+    sig.scalar_multiply(&sk.to_bytes());
+    sig
+}
+
+#[test]
+fn test_bls_verify_schemes() {
+    let mut a = Allocator::new();
+    let sk1 = SecretKey::from_seed(&[1; 32]);
+    let sk2 = SecretKey::from_seed(&[2; 32]);
+    let items: [(PublicKey, &[u8]); 2] = [
+        (sk1.public_key(), b"foobar"),
+        (sk2.public_key(), b"the quick brown fox"),
+    ];
+
+    let mut basic_sig = sign_basic(&sk1, items[0].1);
+    basic_sig.aggregate(&sign_basic(&sk2, items[1].1));
+    let mut aug_sig = sign(&sk1, items[0].1);
+    aug_sig.aggregate(&sign(&sk2, items[1].1));
+
+    let basic = verify_arg_list(&mut a, &basic_sig, &items);
+    let aug = verify_arg_list(&mut a, &aug_sig, &items);
+
+    // each signature verifies under its own scheme, with the same cost
+    let Reduction(basic_cost, _) = op_bls_verify_basic(&mut a, basic, 11000000000).unwrap();
+    let Reduction(aug_cost, _) = op_bls_verify(&mut a, aug, 11000000000).unwrap();
+    assert_eq!(basic_cost, aug_cost);
+
+    // and fails under the other
+    let e = op_bls_verify(&mut a, basic, 11000000000).unwrap_err();
+    assert_eq!(e.1, "bls_verify failed");
+    let e = op_bls_verify_basic(&mut a, aug, 11000000000).unwrap_err();
+    assert_eq!(e.1, "bls_verify_basic failed");
+}
+
+// known answers from blspy. With the secret key 1, the public key is the G1
+// generator and a basic scheme signature is just the message hashed to G2 (the
+// g2_map test cases in op-tests/test-blspy-hash.txt). The augmented scheme
+// case is AugSchemeMPL.sign() of "foobar" with the secret key below
+#[cfg(test)]
+const G1_GENERATOR: [u8; 48] = hex!("97f1d3a73197d7942695638c4fa9ac0fc3688c4f9774b905a14e3a3f171bac586c55e83ff97a1aeffb3af00adb22c6bb");
+
+#[cfg(test)]
+#[rstest]
+#[case::basic_1(true, G1_GENERATOR, &hex!("c5b195624fffecb9"), hex!("855c2ba154f74ba1da3b7f57273518574834046f2c9b60d3306b228e811231beaf55170a77d23098b14617d329cc17160fd4ee2f3e6f738868454a57cf5984f4a1ca053f121b008062bab8b394800e9c006c812b8b0cf10a7e13332bfbb86c42"))]
+#[case::basic_2(true, G1_GENERATOR, &hex!("5aa353e8109f"), hex!("8cc614dc8dc1f0c4314784ceb04cbf504ff05c002441d7f9ef7924c15e3cdd22b7b45cc0bacdc8f799870d32d7c4373e12f7f373edd0b9096b3c4090dd96da6eb9b6ba4205c3f7f5e270d547a952adc36de85c328482d724e5087141fed46659"))]
+#[case::basic_3(true, G1_GENERATOR, b"abcdef0123456789", hex!("8ee1ff66094b8975401c86ad424076d97fed9c2025db5f9dfde6ed455c7bff34b55e96379c1f9ee3c173633587f425e50aed3e807c6c7cd7bed35d40542eee99891955b2ea5321ebde37172e2c01155138494c2d725b03c02765828679bf011e"))]
+#[case::aug(false, SecretKey::from_bytes(&hex!("52d75c4707e39595b27314547f9723e5530c01198af3fc5849d9a7af65631efb")).unwrap().public_key().to_bytes(), b"foobar", hex!("b45825c0ee7759945c0189b4c38b7e54231ebadc83a851bec3bb7cf954a124ae0cc8e8e5146558332ea152f63bf8846e04826185ef60e817f271f8d500126561319203f9acb95809ed20c193757233454be1562a5870570941a84605bd2c9c9a"))]
+fn test_bls_verify_known_answers(
+    #[case] basic: bool,
+    #[case] pk: [u8; 48],
+    #[case] msg: &[u8],
+    #[case] sig: [u8; 96],
+) {
+    let mut a = Allocator::new();
+    let pk = PublicKey::from_bytes(&pk).unwrap();
+    let sig = G2Element::from_bytes(&sig).unwrap();
+    let args = verify_arg_list(&mut a, &sig, &[(pk, msg)]);
+
+    // the signature only verifies under its own scheme
+    let basic_result = op_bls_verify_basic(&mut a, args, 11000000000);
+    let aug_result = op_bls_verify(&mut a, args, 11000000000);
+    assert_eq!(basic_result.is_ok(), basic);
+    assert_eq!(aug_result.is_ok(), !basic);
+}
+
+#[test]
+fn test_bls_verify_message_not_atom() {
+    let mut a = Allocator::new();
+    let pk = a.new_g1(PublicKey::default()).unwrap();
+    let msg = a.new_pair(a.nil(), a.nil()).unwrap();
+    let sig = a.new_g2(G2Element::default()).unwrap();
+    let args = a.new_pair(msg, a.nil()).unwrap();
+    let args = a.new_pair(pk, args).unwrap();
+    let args = a.new_pair(sig, args).unwrap();
+
+    let e = op_bls_verify(&mut a, args, 11000000000).unwrap_err();
+    assert_eq!(e.1, "bls_verify message on list");
+    let e = op_bls_verify_basic(&mut a, args, 11000000000).unwrap_err();
+    assert_eq!(e.1, "bls_verify_basic message on list");
+}
+
+#[test]
+fn test_bls_verify_basic_distinct_messages() {
+    let mut a = Allocator::new();
+    let sk1 = SecretKey::from_seed(&[1; 32]);
+    let sk2 = SecretKey::from_seed(&[2; 32]);
+    let msg: &[u8] = b"foobar";
+
+    // a valid aggregate signature over the same message is still rejected,
+    // since the basic scheme requires all messages to be distinct
+    let mut sig = sign_basic(&sk1, msg);
+    sig.aggregate(&sign_basic(&sk2, msg));
+    let args = verify_arg_list(
+        &mut a,
+        &sig,
+        &[(sk1.public_key(), msg), (sk2.public_key(), msg)],
+    );
+    let e = op_bls_verify_basic(&mut a, args, 11000000000).unwrap_err();
+    assert_eq!(e.1, "bls_verify_basic failed");
+
+    // the identity public key with the identity signature
+    let args = verify_arg_list(
+        &mut a,
+        &G2Element::default(),
+        &[(PublicKey::default(), msg)],
+    );
+    let e = op_bls_verify_basic(&mut a, args, 11000000000).unwrap_err();
+    assert_eq!(e.1, "bls_verify_basic failed");
+
+    // with no keys, the signature must be the identity
+    let args = verify_arg_list(&mut a, &G2Element::default(), &[]);
+    op_bls_verify_basic(&mut a, args, 11000000000).unwrap();
+}
+
+#[test]
+fn test_bls_verify_aggregate() {
+    let mut a = Allocator::new();
+    // more pairs than the fuzzer and op-tests cover
+    let keys: Vec<SecretKey> = (0..20_u8).map(|i| SecretKey::from_seed(&[i; 32])).collect();
+    let messages: Vec<Vec<u8>> = (0..20_u8).map(|i| vec![i; i as usize]).collect();
+    let mut items: Vec<(PublicKey, &[u8])> = keys
+        .iter()
+        .zip(&messages)
+        .map(|(sk, msg)| (sk.public_key(), msg.as_slice()))
+        .collect();
+    let mut sig = G2Element::default();
+    for (sk, msg) in keys.iter().zip(&messages) {
+        sig.aggregate(&sign(sk, msg));
+    }
+
+    let args = verify_arg_list(&mut a, &sig, &items);
+    let Reduction(cost, result) = op_bls_verify(&mut a, args, 11000000000).unwrap();
+    assert_eq!(result, a.nil());
+    let msg_bytes: usize = messages.iter().map(Vec::len).sum();
+    assert_eq!(
+        cost,
+        BLS_PAIRING_BASE_COST
+            + 20 * BLS_PAIRING_COST_PER_ARG
+            + msg_bytes as Cost * BLS_MAP_TO_G2_COST_PER_BYTE
+            + 20 * DST_G2.len() as Cost * BLS_MAP_TO_G2_COST_PER_DST_BYTE
+    );
+
+    // a single tampered message
+    items[7].1 = b"tampered";
+    let args = verify_arg_list(&mut a, &sig, &items);
+    let e = op_bls_verify(&mut a, args, 11000000000).unwrap_err();
+    assert_eq!(e.1, "bls_verify failed");
+
+    // signatures of the wrong length, or not on the curve, raise before any
+    // pairing is computed
+    let nil = a.nil();
+    let short = a.new_atom(&sig.to_bytes()[..95]).unwrap();
+    let args = a.new_pair(short, nil).unwrap();
+    let e = op_bls_verify(&mut a, args, 11000000000).unwrap_err();
+    assert_eq!(e.0, short);
+    assert_eq!(e.1, "atom is not G2 size, 96 bytes");
+    let bad_point = a.new_atom(&test_point(96, 0x80, 1)).unwrap();
+    let args = a.new_pair(bad_point, nil).unwrap();
+    let e = op_bls_verify(&mut a, args, 11000000000).unwrap_err();
+    assert_eq!(e.0, bad_point);
+    assert_eq!(e.1, "atom is not a G2 point");
+}
+
+#[cfg(test)]
+use rstest::rstest;
+
+// a compressed point of `len` bytes, starting with `first` and ending with
+// `last`, and zeros in between
+#[cfg(test)]
+fn test_point(len: usize, first: u8, last: u8) -> Vec<u8> {
+    let mut ret = vec![0; len];
+    ret[0] |= first;
+    ret[len - 1] |= last;
+    ret
+}
+
+#[cfg(test)]
+#[rstest]
+// the canonical point at infinity
+#[case(test_point(48, 0xc0, 0), Ok(true))]
+// non-canonical encodings of the point at infinity
+#[case(test_point(48, 0xe0, 0), Err(PointError::BadEncoding))]
+#[case(test_point(48, 0xc0, 1), Err(PointError::BadEncoding))]
+#[case(test_point(48, 0x40, 0), Err(PointError::BadEncoding))]
+#[case(test_point(48, 0, 0), Err(PointError::BadEncoding))]
+// x = 0 is not a valid encoding, even though (0, 2) is on the curve
+#[case(test_point(48, 0x80, 0), Err(PointError::BadEncoding))]
+// the compression bit is required
+#[case(test_point(48, 0, 4), Err(PointError::BadEncoding))]
+// x = 1 has no y
+#[case(test_point(48, 0x80, 1), Err(PointError::NotOnCurve))]
+// x = 4 is on the curve, but not in the subgroup
+#[case(test_point(48, 0x80, 4), Err(PointError::NotInSubgroup))]
+// x = p
+#[case(hex!("9a0111ea397fe69a4b1ba7b6434bacd764774b84f38512bf6730d2a0f6b0f6241eabfffeb153ffffb9feffffffffaaab").to_vec(), Err(PointError::BadEncoding))]
+#[case(test_point(47, 0xc0, 0), Err(PointError::BadLength))]
+#[case(test_point(49, 0xc0, 0), Err(PointError::BadLength))]
+#[case(vec![], Err(PointError::BadLength))]
+fn test_validate_g1_bytes(#[case] buf: Vec<u8>, #[case] expected: Result<bool, PointError>) {
+    // Ok(true) means the point at infinity
+    let res = validate_g1_bytes(&buf, true);
+    assert_eq!(
+        res.as_ref().map(|p| p.is_inf()),
+        expected.as_ref().map(|i| *i)
+    );
+    if expected == Ok(true) {
+        assert_eq!(
+            validate_g1_bytes(&buf, false).err(),
+            Some(PointError::Infinity)
+        );
+    } else {
+        assert_eq!(validate_g1_bytes(&buf, false).err(), expected.err());
+    }
+    // this must agree with what chik_bls accepts
+    if let Ok(b) = <&[u8; 48]>::try_from(buf.as_slice()) {
+        assert_eq!(G1Element::from_bytes(b).is_ok(), res.is_ok());
+    }
+
+    // and the negate operator
+    let mut a = Allocator::new();
+    let point = a.new_atom(&buf).unwrap();
+    let nil = a.nil();
+    let args = a.new_pair(point, nil).unwrap();
+    assert_eq!(op_bls_g1_negate(&mut a, args, 10000).is_ok(), res.is_ok());
+}
+
+#[cfg(test)]
+#[rstest]
+// the canonical point at infinity
+#[case(test_point(96, 0xc0, 0), Ok(true))]
+// non-canonical encodings of the point at infinity
+#[case(test_point(96, 0xe0, 0), Err(PointError::BadEncoding))]
+#[case(test_point(96, 0xc0, 1), Err(PointError::BadEncoding))]
+#[case(test_point(96, 0x40, 0), Err(PointError::BadEncoding))]
+#[case(test_point(96, 0, 0), Err(PointError::BadEncoding))]
+// the compression bit is required
+#[case(test_point(96, 0, 2), Err(PointError::BadEncoding))]
+// x = 1 has no y
+#[case(test_point(96, 0x80, 1), Err(PointError::NotOnCurve))]
+// x = 2 is on the curve, but not in the subgroup
+#[case(test_point(96, 0x80, 2), Err(PointError::NotInSubgroup))]
+// the first half of x is p
+#[case([hex!("9a0111ea397fe69a4b1ba7b6434bacd764774b84f38512bf6730d2a0f6b0f6241eabfffeb153ffffb9feffffffffaaab").as_slice(), &[0; 48]].concat(), Err(PointError::BadEncoding))]
+// the second half of x is p
+#[case([[0x80].as_slice(), &[0; 47], &FIELD_MODULUS].concat(), Err(PointError::BadEncoding))]
+#[case(test_point(95, 0xc0, 0), Err(PointError::BadLength))]
+#[case(test_point(48, 0xc0, 0), Err(PointError::BadLength))]
+#[case(vec![], Err(PointError::BadLength))]
+fn test_validate_g2_bytes(#[case] buf: Vec<u8>, #[case] expected: Result<bool, PointError>) {
+    // Ok(true) means the point at infinity
+    let res = validate_g2_bytes(&buf, true);
+    assert_eq!(
+        res.as_ref().map(|p| *p == G2Element::default()),
+        expected.as_ref().map(|i| *i)
+    );
+    if expected == Ok(true) {
+        assert_eq!(
+            validate_g2_bytes(&buf, false).err(),
+            Some(PointError::Infinity)
+        );
+    } else {
+        assert_eq!(validate_g2_bytes(&buf, false).err(), expected.err());
+    }
+    // this must agree with what chik_bls accepts
+    if let Ok(b) = <&[u8; 96]>::try_from(buf.as_slice()) {
+        assert_eq!(G2Element::from_bytes(b).is_ok(), res.is_ok());
+    }
+
+    // and the negate operator
+    let mut a = Allocator::new();
+    let point = a.new_atom(&buf).unwrap();
+    let nil = a.nil();
+    let args = a.new_pair(point, nil).unwrap();
+    assert_eq!(op_bls_g2_negate(&mut a, args, 10000).is_ok(), res.is_ok());
+}
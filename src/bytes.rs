@@ -0,0 +1,182 @@
+use core::array::TryFromSliceError;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::Deref;
+
+// the conversions and formatting shared by all the fixed size byte types.
+// Equality is implemented separately
+macro_rules! fixed_bytes {
+    ($name:ident, $len:expr) => {
+        #[derive(Clone, Copy)]
+        pub struct $name([u8; $len]);
+
+        impl $name {
+            pub const LEN: usize = $len;
+
+            pub const fn new(bytes: [u8; $len]) -> Self {
+                Self(bytes)
+            }
+
+            pub fn to_bytes(self) -> [u8; $len] {
+                self.0
+            }
+        }
+
+        impl From<[u8; $len]> for $name {
+            fn from(bytes: [u8; $len]) -> Self {
+                Self(bytes)
+            }
+        }
+
+        impl From<&[u8; $len]> for $name {
+            fn from(bytes: &[u8; $len]) -> Self {
+                Self(*bytes)
+            }
+        }
+
+        impl From<$name> for [u8; $len] {
+            fn from(bytes: $name) -> Self {
+                bytes.0
+            }
+        }
+
+        impl TryFrom<&[u8]> for $name {
+            type Error = TryFromSliceError;
+
+            fn try_from(buf: &[u8]) -> Result<Self, TryFromSliceError> {
+                Ok(Self(buf.try_into()?))
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = [u8; $len];
+
+            fn deref(&self) -> &[u8; $len] {
+                &self.0
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl PartialEq<[u8; $len]> for $name {
+            fn eq(&self, other: &[u8; $len]) -> bool {
+                *self == Self(*other)
+            }
+        }
+
+        impl Hash for $name {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.0.hash(state)
+            }
+        }
+
+        impl fmt::Display for $name {
+            /// lower case hex
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                for b in self.0 {
+                    write!(f, "{b:02x}")?;
+                }
+                Ok(())
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({self})", stringify!($name))
+            }
+        }
+    };
+}
+
+fixed_bytes!(Bytes32, 32);
+fixed_bytes!(Bytes48, 48);
+fixed_bytes!(Bytes96, 96);
+
+/// Bytes32 is used for hashes, some of which are secret (or derived from
+/// secrets). The comparison always looks at every byte, to not leak the
+/// position of the first difference through timing
+impl PartialEq for Bytes32 {
+    fn eq(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .fold(0, |acc, (l, r)| acc | (l ^ r))
+            == 0
+    }
+}
+
+impl PartialEq for Bytes48 {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl PartialEq for Bytes96 {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+#[cfg(test)]
+use rstest::rstest;
+
+#[test]
+fn test_bytes32() {
+    let mut buf = [0_u8; 32];
+    buf[0] = 0x01;
+    buf[31] = 0xfe;
+    let b = Bytes32::from(buf);
+    assert_eq!(b.to_bytes(), buf);
+    assert_eq!(b.as_ref(), &buf[..]);
+    assert_eq!(b, buf);
+    assert_eq!(Bytes32::try_from(&buf[..]).unwrap(), b);
+    assert_eq!(
+        b.to_string(),
+        "01000000000000000000000000000000000000000000000000000000000000fe"
+    );
+    assert_eq!(
+        format!("{b:?}"),
+        "Bytes32(01000000000000000000000000000000000000000000000000000000000000fe)"
+    );
+
+    // a difference in the first and in the last byte
+    let mut other = buf;
+    other[0] = 0;
+    assert!(b != Bytes32::from(other));
+    let mut other = buf;
+    other[31] = 0;
+    assert!(b != Bytes32::from(other));
+}
+
+#[test]
+fn test_bytes48_96() {
+    let b = Bytes48::from([0x42; 48]);
+    assert_eq!(b.to_string(), "42".repeat(48));
+    assert_eq!(Bytes48::try_from(&[0x42_u8; 48][..]).unwrap(), b);
+    let b = Bytes96::from([0x42; 96]);
+    assert_eq!(b.to_string(), "42".repeat(96));
+    assert_eq!(Bytes96::try_from(&[0x42_u8; 96][..]).unwrap(), b);
+}
+
+#[cfg(test)]
+#[rstest]
+#[case(0)]
+#[case(1)]
+#[case(31)]
+#[case(33)]
+#[case(47)]
+#[case(49)]
+#[case(95)]
+#[case(97)]
+fn test_bytes_wrong_length(#[case] len: usize) {
+    let buf = vec![0_u8; len];
+    assert!(Bytes32::try_from(buf.as_slice()).is_err());
+    assert!(Bytes48::try_from(buf.as_slice()).is_err());
+    assert!(Bytes96::try_from(buf.as_slice()).is_err());
+}
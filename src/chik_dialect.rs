@@ -1,20 +1,30 @@
+// ChikDialect and its BLS extension need the BLS operators, which need std.
+// Without it they are left out, see lib.rs
+#![cfg_attr(not(feature = "std"), allow(dead_code, unused_imports))]
+
+use alloc::vec::Vec;
+
 use crate::allocator::{Allocator, NodePtr};
+#[cfg(feature = "std")]
 use crate::bls_ops::{
     op_bls_g1_multiply, op_bls_g1_negate, op_bls_g1_subtract, op_bls_g2_add, op_bls_g2_multiply,
     op_bls_g2_negate, op_bls_g2_subtract, op_bls_map_to_g1, op_bls_map_to_g2,
-    op_bls_pairing_identity, op_bls_verify,
+    op_bls_pairing_identity, op_bls_verify, op_bls_verify_basic, BLS_G1_MULTIPLY_BASE_COST,
+    BLS_G1_SUBTRACT_BASE_COST, BLS_G2_ADD_BASE_COST, BLS_G2_MULTIPLY_BASE_COST,
+    BLS_G2_SUBTRACT_BASE_COST, BLS_MAP_TO_G1_BASE_COST, BLS_MAP_TO_G2_BASE_COST,
+    BLS_PAIRING_BASE_COST,
 };
-use crate::core_ops::{op_cons, op_eq, op_first, op_if, op_listp, op_raise, op_rest};
-use crate::cost::Cost;
+use crate::cost::{Cost, CostTable};
 use crate::dialect::{Dialect, OperatorSet};
 use crate::err_utils::err;
+#[cfg(feature = "std")]
+use crate::f_table::{base_cost_with_extensions, f_lookup_with_extensions};
+use crate::f_table::{CostLookup, FLookup, OpFn};
 use crate::more_ops::{
-    op_add, op_all, op_any, op_ash, op_coinid, op_concat, op_div, op_div_fixed, op_divmod, op_gr,
-    op_gr_bytes, op_logand, op_logior, op_lognot, op_logxor, op_lsh, op_mod, op_modpow,
-    op_multiply, op_not, op_point_add, op_pubkey_for_exp, op_sha256, op_strlen, op_substr,
-    op_subtract, op_unknown,
+    op_coinid, op_mod, op_modpow, op_sha256_tagged, op_unknown, COINID_COST, DIV_BASE_COST,
+    MODPOW_BASE_COST, SHA256_BASE_COST,
 };
-use crate::reduction::Response;
+use crate::reduction::{Reduction, Response};
 use crate::secp_ops::{op_secp256k1_verify, op_secp256r1_verify};
 
 // unknown operators are disallowed
@@ -33,11 +43,21 @@ pub const ENABLE_BLS_OPS_OUTSIDE_GUARD: u32 = 0x0020;
 // division operator
 pub const ENABLE_FIXED_DIV: u32 = 0x0080;
 
+// enables the bls_verify_basic operator (62), verifying signatures using the
+// basic scheme rather than the augmented one. It's part of the BLS operator
+// set, so it's only available where the BLS ops are
+pub const ENABLE_BLS_VERIFY_BASIC: u32 = 0x0100;
+
+// enables the sha256_tagged operator (63), hashing a domain separation tag
+// followed by the data. Just like bls_verify_basic, it's only available where
+// the BLS ops are
+pub const ENABLE_SHA256_TAGGED: u32 = 0x0200;
+
 // The default mode when running grnerators in mempool-mode (i.e. the stricter
 // mode)
 pub const MEMPOOL_MODE: u32 = NO_UNKNOWN_OPS | LIMIT_HEAP;
 
-fn unknown_operator(
+pub(crate) fn unknown_operator(
     allocator: &mut Allocator,
     o: NodePtr,
     args: NodePtr,
@@ -51,16 +71,372 @@ fn unknown_operator(
     }
 }
 
+/// A single-byte operator added by a softfork extension
+#[derive(Clone, Copy)]
+pub struct OpDescriptor {
+    pub opcode: u8,
+    /// the name of the operator in the op-tests
+    pub name: &'static str,
+    pub f: OpFn,
+    /// the cost the operator charges regardless of its arguments (see
+    /// `Dialect::op_min_cost()`). 0 if there isn't a significant one
+    pub base_cost: Cost,
+    /// the operator is only available when all of these flags are set
+    pub flags: u32,
+}
+
+/// The operators added by a softfork extension. Inside a softfork guard
+/// passing `id` as the extension, they are available in addition to the
+/// default operators. Once the extension has been activated outside of the
+/// guard (a hard fork), by setting `outside_guard_flag`, they are available
+/// everywhere.
+#[derive(Clone, Copy)]
+pub struct ExtensionTable {
+    pub id: u32,
+    /// the operator set passed to `Dialect::op()` inside the guard
+    pub operator_set: OperatorSet,
+    pub outside_guard_flag: u32,
+    pub ops: &'static [OpDescriptor],
+}
+
+impl ExtensionTable {
+    /// the operators of this extension that are available with `flags`
+    pub fn enabled_ops(&self, flags: u32) -> impl Iterator<Item = &OpDescriptor> {
+        self.ops
+            .iter()
+            .filter(move |op| (flags & op.flags) == op.flags)
+    }
+
+    pub fn lookup(&self, opcode: u8) -> Option<&OpDescriptor> {
+        self.ops.iter().find(|op| op.opcode == opcode)
+    }
+
+    /// add the operators of this extension that are available with `flags`
+    /// to a dispatch table
+    pub fn add_to(&self, f_lookup: &mut FLookup, flags: u32) {
+        for op in self.enabled_ops(flags) {
+            f_lookup[op.opcode as usize] = Some(op.f);
+        }
+    }
+
+    /// add the base costs of the operators of this extension that are
+    /// available with `flags` to a base cost table
+    pub fn add_costs_to(&self, base_cost: &mut CostLookup, flags: u32) {
+        for op in self.enabled_ops(flags) {
+            base_cost[op.opcode as usize] = op.base_cost;
+        }
+    }
+}
+
+/// extension 0, the BLS and coin ID operators
+#[cfg(feature = "std")]
+pub const BLS_EXTENSION: ExtensionTable = ExtensionTable {
+    id: 0,
+    operator_set: OperatorSet::BLS,
+    outside_guard_flag: ENABLE_BLS_OPS_OUTSIDE_GUARD,
+    ops: &[
+        OpDescriptor {
+            opcode: 48,
+            name: "coinid",
+            f: op_coinid,
+            base_cost: COINID_COST,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 49,
+            name: "g1_subtract",
+            f: op_bls_g1_subtract,
+            base_cost: BLS_G1_SUBTRACT_BASE_COST,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 50,
+            name: "g1_multiply",
+            f: op_bls_g1_multiply,
+            base_cost: BLS_G1_MULTIPLY_BASE_COST,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 51,
+            name: "g1_negate",
+            f: op_bls_g1_negate,
+            base_cost: 0,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 52,
+            name: "g2_add",
+            f: op_bls_g2_add,
+            base_cost: BLS_G2_ADD_BASE_COST,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 53,
+            name: "g2_subtract",
+            f: op_bls_g2_subtract,
+            base_cost: BLS_G2_SUBTRACT_BASE_COST,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 54,
+            name: "g2_multiply",
+            f: op_bls_g2_multiply,
+            base_cost: BLS_G2_MULTIPLY_BASE_COST,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 55,
+            name: "g2_negate",
+            f: op_bls_g2_negate,
+            base_cost: 0,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 56,
+            name: "g1_map",
+            f: op_bls_map_to_g1,
+            base_cost: BLS_MAP_TO_G1_BASE_COST,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 57,
+            name: "g2_map",
+            f: op_bls_map_to_g2,
+            base_cost: BLS_MAP_TO_G2_BASE_COST,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 58,
+            name: "bls_pairing_identity",
+            f: op_bls_pairing_identity,
+            base_cost: BLS_PAIRING_BASE_COST,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 59,
+            name: "bls_verify",
+            f: op_bls_verify,
+            base_cost: 0,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 60,
+            name: "modpow",
+            f: op_modpow,
+            base_cost: MODPOW_BASE_COST,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 61,
+            name: "%",
+            f: op_mod,
+            base_cost: DIV_BASE_COST,
+            flags: 0,
+        },
+        OpDescriptor {
+            opcode: 62,
+            name: "bls_verify_basic",
+            f: op_bls_verify_basic,
+            base_cost: 0,
+            flags: ENABLE_BLS_VERIFY_BASIC,
+        },
+        OpDescriptor {
+            opcode: 63,
+            name: "sha256_tagged",
+            f: op_sha256_tagged,
+            base_cost: SHA256_BASE_COST,
+            flags: ENABLE_SHA256_TAGGED,
+        },
+    ],
+};
+
+/// The softfork extensions of the Chik dialect. Adding an extension only
+/// takes a new entry here (and its operators)
+#[cfg(feature = "std")]
+pub static CHIK_EXTENSIONS: [ExtensionTable; 1] = [BLS_EXTENSION];
+
+#[cfg(feature = "std")]
 pub struct ChikDialect {
     flags: u32,
+    // the dispatch table for single-byte operators outside of any softfork
+    // guard
+    f_lookup: FLookup,
+    // the dispatch tables inside a guard enabling each of the extensions, in
+    // the same order as `extensions`
+    f_lookup_ext: Vec<FLookup>,
+    // the base costs of the operators in the dispatch tables above, scaled by
+    // cost_table. These are looked up before every operator is applied
+    base_cost: CostLookup,
+    base_cost_ext: Vec<CostLookup>,
+    extensions: &'static [ExtensionTable],
+    // re-prices the single-byte operators. None means they all have their
+    // regular cost
+    cost_table: Option<CostTable>,
 }
 
+#[cfg(feature = "std")]
 impl ChikDialect {
     pub fn new(flags: u32) -> ChikDialect {
-        ChikDialect { flags }
+        Self::with_extensions(flags, &CHIK_EXTENSIONS)
+    }
+
+    /// Like `new()`, but with a different set of softfork extensions than
+    /// `CHIK_EXTENSIONS`
+    pub fn with_extensions(flags: u32, extensions: &'static [ExtensionTable]) -> ChikDialect {
+        let f_lookup = f_lookup_with_extensions(flags, extensions);
+        let f_lookup_ext = extensions
+            .iter()
+            .map(|ext| {
+                let mut f_lookup = f_lookup;
+                ext.add_to(&mut f_lookup, flags);
+                f_lookup
+            })
+            .collect();
+        let base_cost = base_cost_with_extensions(flags, extensions);
+        let base_cost_ext = extensions
+            .iter()
+            .map(|ext| {
+                let mut base_cost = base_cost;
+                ext.add_costs_to(&mut base_cost, flags);
+                base_cost
+            })
+            .collect();
+        ChikDialect {
+            flags,
+            f_lookup,
+            f_lookup_ext,
+            base_cost,
+            base_cost_ext,
+            extensions,
+            cost_table: None,
+        }
+    }
+
+    /// The operators enabled by softfork extension `n`, if it exists
+    pub fn extension(&self, n: u32) -> Option<&ExtensionTable> {
+        self.extensions.iter().find(|ext| ext.id == n)
+    }
+
+    // the index of the extension enabling `extension` in `extensions`, or None
+    // for the default operators
+    fn extension_idx(&self, extension: OperatorSet) -> Option<usize> {
+        if extension == OperatorSet::Default {
+            return None;
+        }
+        self.extensions
+            .iter()
+            .position(|ext| ext.operator_set == extension)
+    }
+
+    // the dispatch table for single-byte operators, with the operators of
+    // `extension` enabled
+    fn f_lookup(&self, extension: OperatorSet) -> &FLookup {
+        match self.extension_idx(extension) {
+            Some(idx) => &self.f_lookup_ext[idx],
+            None => &self.f_lookup,
+        }
+    }
+
+    // the base costs of the operators in f_lookup(extension)
+    fn base_cost(&self, extension: OperatorSet) -> &CostLookup {
+        match self.extension_idx(extension) {
+            Some(idx) => &self.base_cost_ext[idx],
+            None => &self.base_cost,
+        }
+    }
+
+    /// Like `new()`, but the costs of the single-byte operators are scaled by
+    /// `cost_table`
+    pub fn with_cost_table(flags: u32, cost_table: CostTable) -> ChikDialect {
+        let mut dialect = Self::new(flags);
+        if cost_table == CostTable::default() {
+            return dialect;
+        }
+        for base_cost in
+            core::iter::once(&mut dialect.base_cost).chain(dialect.base_cost_ext.iter_mut())
+        {
+            for (op, cost) in base_cost.iter_mut().enumerate() {
+                *cost = cost_table.scale(op as u8, *cost);
+            }
+        }
+        dialect.cost_table = Some(cost_table);
+        dialect
+    }
+
+    /// The dialect in effect at block `height`, according to `schedule`
+    pub fn for_height(height: u32, schedule: &ConsensusCostSchedule) -> ChikDialect {
+        let (flags, cost_table) = schedule.at_height(height);
+        Self::with_cost_table(flags, cost_table.clone())
+    }
+
+    /// Apply the operator `opcode` to `args` directly, without evaluating a
+    /// program. The operator is dispatched like the interpreter does it, with
+    /// `extension` enabled, and fails with "cost exceeded" where the
+    /// interpreter would, i.e. if the operator can't or doesn't fit in
+    /// `max_cost`
+    pub fn apply_opcode(
+        &self,
+        allocator: &mut Allocator,
+        opcode: u32,
+        args: NodePtr,
+        max_cost: Cost,
+        extension: OperatorSet,
+    ) -> Response {
+        let o = allocator.new_number(opcode.into())?;
+        if self.op_min_cost(allocator, o, extension) > max_cost {
+            return err(o, "cost exceeded");
+        }
+        let r = self.op(allocator, o, args, max_cost, extension)?;
+        if r.0 > max_cost {
+            return err(o, "cost exceeded");
+        }
+        Ok(r)
+    }
+}
+
+/// The flags and operator costs of the Chik dialect over the history of the
+/// chain. Each activation replaces the flags and cost table in effect from its
+/// height onwards.
+#[derive(Debug, Clone)]
+pub struct ConsensusCostSchedule {
+    initial_flags: u32,
+    initial_cost_table: CostTable,
+    // (height, flags, cost table), sorted by height
+    activations: Vec<(u32, u32, CostTable)>,
+}
+
+impl ConsensusCostSchedule {
+    /// the flags and cost table in effect from the genesis block
+    pub fn new(flags: u32, cost_table: CostTable) -> Self {
+        Self {
+            initial_flags: flags,
+            initial_cost_table: cost_table,
+            activations: Vec::new(),
+        }
+    }
+
+    /// add a consensus change, activating at `height`. Activations may be
+    /// added in any order, adding one at the same height as an existing one
+    /// replaces it
+    pub fn activate(mut self, height: u32, flags: u32, cost_table: CostTable) -> Self {
+        match self.activations.binary_search_by_key(&height, |a| a.0) {
+            Ok(idx) => self.activations[idx] = (height, flags, cost_table),
+            Err(idx) => self.activations.insert(idx, (height, flags, cost_table)),
+        }
+        self
+    }
+
+    /// the flags and cost table in effect at `height`
+    pub fn at_height(&self, height: u32) -> (u32, &CostTable) {
+        let idx = self.activations.partition_point(|a| a.0 <= height);
+        match idx.checked_sub(1) {
+            Some(idx) => (self.activations[idx].1, &self.activations[idx].2),
+            None => (self.initial_flags, &self.initial_cost_table),
+        }
     }
 }
 
+#[cfg(feature = "std")]
 impl Dialect for ChikDialect {
     fn op(
         &self,
@@ -70,11 +446,6 @@ impl Dialect for ChikDialect {
         max_cost: Cost,
         extension: OperatorSet,
     ) -> Response {
-        let flags = self.flags
-            | match extension {
-                OperatorSet::BLS => ENABLE_BLS_OPS_OUTSIDE_GUARD,
-                _ => 0,
-            };
         let op_len = allocator.atom_len(o);
         if op_len == 4 {
             // these are unknown operators with assigned cost
@@ -98,84 +469,32 @@ impl Dialect for ChikDialect {
                 0x13d61f00 => op_secp256k1_verify,
                 0x1c3a8f00 => op_secp256r1_verify,
                 _ => {
-                    return unknown_operator(allocator, o, argument_list, flags, max_cost);
+                    return unknown_operator(allocator, o, argument_list, self.flags, max_cost);
                 }
             };
             return f(allocator, argument_list, max_cost);
         }
         if op_len != 1 {
-            return unknown_operator(allocator, o, argument_list, flags, max_cost);
+            return unknown_operator(allocator, o, argument_list, self.flags, max_cost);
         }
-        let Some(op) = allocator.small_number(o) else {
-            return unknown_operator(allocator, o, argument_list, flags, max_cost);
+        let f_lookup = self.f_lookup(extension);
+        // atoms with the top bit set are negative and the atom 0x00 is not
+        // canonical. None of them are in the table
+        let op = allocator.atom(o).as_ref()[0];
+        let Some(f) = f_lookup[op as usize] else {
+            return unknown_operator(allocator, o, argument_list, self.flags, max_cost);
         };
-        let f = match op {
-            // 1 = quote
-            // 2 = apply
-            3 => op_if,
-            4 => op_cons,
-            5 => op_first,
-            6 => op_rest,
-            7 => op_listp,
-            8 => op_raise,
-            9 => op_eq,
-            10 => op_gr_bytes,
-            11 => op_sha256,
-            12 => op_substr,
-            13 => op_strlen,
-            14 => op_concat,
-            // 15 ---
-            16 => op_add,
-            17 => op_subtract,
-            18 => op_multiply,
-            19 => {
-                if (flags & ENABLE_FIXED_DIV) != 0 {
-                    op_div_fixed
-                } else {
-                    op_div
-                }
+        match &self.cost_table {
+            None => f(allocator, argument_list, max_cost),
+            Some(table) => {
+                let Reduction(cost, result) = f(
+                    allocator,
+                    argument_list,
+                    table.unscaled_max_cost(op, max_cost),
+                )?;
+                Ok(Reduction(table.scale(op, cost), result))
             }
-            20 => op_divmod,
-            21 => op_gr,
-            22 => op_ash,
-            23 => op_lsh,
-            24 => op_logand,
-            25 => op_logior,
-            26 => op_logxor,
-            27 => op_lognot,
-            // 28 ---
-            29 => op_point_add,
-            30 => op_pubkey_for_exp,
-            // 31 ---
-            32 => op_not,
-            33 => op_any,
-            34 => op_all,
-            // 35 ---
-            // 36 = softfork
-            48..=61 if (flags & ENABLE_BLS_OPS_OUTSIDE_GUARD) != 0 => match op {
-                48 => op_coinid,
-                49 => op_bls_g1_subtract,
-                50 => op_bls_g1_multiply,
-                51 => op_bls_g1_negate,
-                52 => op_bls_g2_add,
-                53 => op_bls_g2_subtract,
-                54 => op_bls_g2_multiply,
-                55 => op_bls_g2_negate,
-                56 => op_bls_map_to_g1,
-                57 => op_bls_map_to_g2,
-                58 => op_bls_pairing_identity,
-                59 => op_bls_verify,
-                60 => op_modpow,
-                61 => op_mod,
-                _ => {
-                    unreachable!();
-                }
-            },
-            _ => {
-                return unknown_operator(allocator, o, argument_list, flags, max_cost);
-            }
-        };
-        f(allocator, argument_list, max_cost)
+        }
     }
 
     fn quote_kw(&self) -> u32 {
@@ -191,14 +510,306 @@ impl Dialect for ChikDialect {
     // interpret the extension argument passed to the softfork operator, and
     // return the Operators it enables (or None) if we don't know what it means
     fn softfork_extension(&self, ext: u32) -> OperatorSet {
-        match ext {
-            0 => OperatorSet::BLS,
-            // new extensions go here
-            _ => OperatorSet::Default,
-        }
+        self.extension(ext)
+            .map_or(OperatorSet::Default, |ext| ext.operator_set)
     }
 
     fn allow_unknown_ops(&self) -> bool {
         (self.flags & NO_UNKNOWN_OPS) == 0
     }
+
+    fn op_min_cost(&self, allocator: &Allocator, o: NodePtr, extension: OperatorSet) -> Cost {
+        if allocator.atom_len(o) != 1 {
+            return 0;
+        }
+        let op = allocator.atom(o).as_ref()[0];
+        self.base_cost(extension)[op as usize]
+    }
+}
+
+#[cfg(test)]
+use crate::run_program::run_program;
+#[cfg(test)]
+use crate::test_ops::parse_exp;
+
+// sha256 costs half as much from height 1000, where unknown operators are
+// also disallowed
+#[cfg(test)]
+fn toy_schedule() -> ConsensusCostSchedule {
+    let mut cheap_sha256 = CostTable::default();
+    cheap_sha256.set_percent(11, 50);
+    ConsensusCostSchedule::new(0, CostTable::default()).activate(1000, NO_UNKNOWN_OPS, cheap_sha256)
+}
+
+#[test]
+fn test_for_height() {
+    let schedule = toy_schedule();
+    let mut a = Allocator::new();
+    let nil = a.nil();
+    let (program, _) = parse_exp(&mut a, "(sha256 (q . \"foo\"))");
+    let (other, _) = parse_exp(&mut a, "(+ (q . 1) (q . 2))");
+
+    // 1 for the operator and 20 for the quoted argument, the rest is sha256
+    let sha256_cost = 87 + 134 + 3 * 2 + 32 * 10;
+    for (height, expected_cost, unknown_ops) in [
+        (0, 21 + sha256_cost, true),
+        (999, 21 + sha256_cost, true),
+        (1000, 21 + sha256_cost / 2, false),
+        (u32::MAX, 21 + sha256_cost / 2, false),
+    ] {
+        let dialect = ChikDialect::for_height(height, &schedule);
+        assert_eq!(dialect.allow_unknown_ops(), unknown_ops);
+        let Reduction(cost, result) =
+            run_program(&mut a, &dialect, program, nil, 11000000000).unwrap();
+        assert_eq!(cost, expected_cost);
+        assert_eq!(a.atom_len(result), 32);
+
+        // other operators are not affected
+        let Reduction(cost, _) = run_program(&mut a, &dialect, other, nil, 11000000000).unwrap();
+        assert_eq!(cost, 1 + 40 + 99 + 2 * 320 + 2 * 3 + 10);
+    }
+}
+
+#[test]
+fn test_cost_table_max_cost() {
+    let schedule = toy_schedule();
+    let mut a = Allocator::new();
+    let nil = a.nil();
+    let (program, _) = parse_exp(&mut a, "(sha256 (q . \"foo\"))");
+    let discounted = 21 + (87 + 134 + 3 * 2 + 32 * 10) / 2;
+
+    // the discounted cost is what counts towards the limit
+    let dialect = ChikDialect::for_height(1000, &schedule);
+    run_program(&mut a, &dialect, program, nil, discounted).unwrap();
+    let e = run_program(&mut a, &dialect, program, nil, discounted - 1).unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+
+    let dialect = ChikDialect::for_height(999, &schedule);
+    let e = run_program(&mut a, &dialect, program, nil, discounted).unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+}
+
+#[test]
+fn test_schedule_activations() {
+    let mut table = CostTable::default();
+    table.set_percent(11, 200);
+    let schedule = toy_schedule()
+        .activate(500, ENABLE_FIXED_DIV, table.clone())
+        .activate(2000, 0, CostTable::default());
+    assert_eq!(schedule.at_height(499), (0, &CostTable::default()));
+    assert_eq!(schedule.at_height(500), (ENABLE_FIXED_DIV, &table));
+    assert_eq!(schedule.at_height(1999).0, NO_UNKNOWN_OPS);
+    assert_eq!(schedule.at_height(2000), (0, &CostTable::default()));
+
+    // replacing an activation
+    let schedule = schedule.activate(500, LIMIT_HEAP, CostTable::default());
+    assert_eq!(schedule.at_height(600), (LIMIT_HEAP, &CostTable::default()));
+
+    assert_eq!(table.scale(11, 3), 6);
+    assert_eq!(table.unscaled_max_cost(11, 7), 3);
+    table.set_percent(11, 0);
+    assert_eq!(table.scale(11, 1000), 0);
+    assert_eq!(table.unscaled_max_cost(11, 0), Cost::MAX);
+    table.set_percent(11, 30);
+    // 33 * 0.3 = 9.9, which rounds down to 9
+    assert_eq!(table.unscaled_max_cost(11, 9), 33);
+    assert_eq!(table.scale(11, 33), 9);
+    assert_eq!(table.scale(11, 34), 10);
+}
+
+// a hypothetical extension 2, with a single operator (64), that fails unless
+// its argument is 42
+#[cfg(test)]
+const ENABLE_TOY_OPS_OUTSIDE_GUARD: u32 = 0x8000_0000;
+
+#[cfg(test)]
+fn op_toy(a: &mut Allocator, args: NodePtr, _max_cost: Cost) -> Response {
+    let [n] = crate::op_utils::get_args::<1>(a, args, "toy")?;
+    if a.small_number(n) != Some(42) {
+        return err(n, "expected 42");
+    }
+    Ok(Reduction(10, a.nil()))
+}
+
+#[cfg(test)]
+static TOY_EXTENSIONS: [ExtensionTable; 2] = [
+    BLS_EXTENSION,
+    ExtensionTable {
+        id: 2,
+        operator_set: OperatorSet::Extension(2),
+        outside_guard_flag: ENABLE_TOY_OPS_OUTSIDE_GUARD,
+        ops: &[OpDescriptor {
+            opcode: 64,
+            name: "toy",
+            f: op_toy,
+            base_cost: 0,
+            flags: 0,
+        }],
+    },
+];
+
+#[cfg(test)]
+fn run_toy(dialect: &ChikDialect, program: &str) -> Result<Cost, String> {
+    let mut a = Allocator::new();
+    let nil = a.nil();
+    let (program, _) = parse_exp(&mut a, program);
+    run_program(&mut a, dialect, program, nil, 11000000000)
+        .map(|Reduction(cost, _)| cost)
+        .map_err(|e| e.1)
+}
+
+#[test]
+fn test_extension_table() {
+    let dialect = ChikDialect::new(0);
+    let bls = dialect.extension(0).unwrap();
+    assert!(bls.operator_set == OperatorSet::BLS);
+    assert_eq!(bls.lookup(48).unwrap().name, "coinid");
+    // the flags enabling individual operators
+    assert_eq!(bls.enabled_ops(0).count(), 14);
+    assert_eq!(
+        bls.enabled_ops(ENABLE_BLS_VERIFY_BASIC | ENABLE_SHA256_TAGGED)
+            .count(),
+        16
+    );
+    assert!(dialect.extension(1).is_none());
+    assert!(dialect.extension(2).is_none());
+    assert!(dialect.softfork_extension(2) == OperatorSet::Default);
+}
+
+#[test]
+fn test_op_min_cost_table() {
+    let mut a = Allocator::new();
+    let mut min_cost = |dialect: &ChikDialect, op: u32, extension: OperatorSet| {
+        let o = a.new_number(op.into()).unwrap();
+        dialect.op_min_cost(&a, o, extension)
+    };
+
+    let dialect = ChikDialect::new(0);
+    assert_eq!(
+        min_cost(&dialect, 11, OperatorSet::Default),
+        SHA256_BASE_COST
+    );
+    assert_eq!(min_cost(&dialect, 11, OperatorSet::BLS), SHA256_BASE_COST);
+    // coinid is only available inside the BLS guard
+    assert_eq!(min_cost(&dialect, 48, OperatorSet::Default), 0);
+    assert_eq!(min_cost(&dialect, 48, OperatorSet::BLS), COINID_COST);
+    // operators enabled by a flag
+    assert_eq!(min_cost(&dialect, 63, OperatorSet::BLS), 0);
+    // unknown and multi-byte operators
+    for op in [0, 0x7f, 0x80, 0xff, 0x100, 0x13d61f00] {
+        for extension in [OperatorSet::Default, OperatorSet::BLS] {
+            assert_eq!(min_cost(&dialect, op, extension), 0);
+        }
+    }
+
+    let dialect = ChikDialect::new(ENABLE_BLS_OPS_OUTSIDE_GUARD | ENABLE_SHA256_TAGGED);
+    assert_eq!(min_cost(&dialect, 48, OperatorSet::Default), COINID_COST);
+    assert_eq!(min_cost(&dialect, 63, OperatorSet::BLS), SHA256_BASE_COST);
+
+    // the base costs are scaled by the cost table
+    let mut table = CostTable::default();
+    table.set_percent(11, 50);
+    table.set_percent(48, 200);
+    let dialect = ChikDialect::with_cost_table(0, table);
+    assert_eq!(
+        min_cost(&dialect, 11, OperatorSet::Default),
+        SHA256_BASE_COST / 2
+    );
+    assert_eq!(min_cost(&dialect, 48, OperatorSet::BLS), COINID_COST * 2);
+    assert_eq!(min_cost(&dialect, 19, OperatorSet::BLS), DIV_BASE_COST);
+}
+
+#[test]
+fn test_toy_extension() {
+    let dialect = ChikDialect::with_extensions(NO_UNKNOWN_OPS, &TOY_EXTENSIONS);
+    assert_eq!(dialect.extension(2).unwrap().ops[0].name, "toy");
+    assert!(dialect.softfork_extension(2) == OperatorSet::Extension(2));
+    assert!(dialect.softfork_extension(0) == OperatorSet::BLS);
+
+    // 140 for the guard, 1 for the operator, 20 for the quoted argument and 10
+    // for the toy operator itself. On top of that, 81 for the softfork
+    // operator and its quoted arguments
+    assert_eq!(
+        run_toy(
+            &dialect,
+            "(softfork (q . 171) (q . 2) (q 64 (q . 42)) (q . ()))"
+        ),
+        Ok(252)
+    );
+    assert_eq!(
+        run_toy(
+            &dialect,
+            "(softfork (q . 171) (q . 2) (q 64 (q . 41)) (q . ()))"
+        ),
+        Err("expected 42".to_string())
+    );
+    // the operator isn't available in other extensions, or outside the guard
+    assert_eq!(
+        run_toy(
+            &dialect,
+            "(softfork (q . 171) (q . 0) (q 64 (q . 42)) (q . ()))"
+        ),
+        Err("unimplemented operator".to_string())
+    );
+    assert_eq!(
+        run_toy(&dialect, "(64 (q . 42))"),
+        Err("unimplemented operator".to_string())
+    );
+    // and neither are the BLS operators inside its guard
+    assert_eq!(
+        run_toy(
+            &dialect,
+            "(softfork (q . 171) (q . 2) (q 48 (q . 42)) (q . ()))"
+        ),
+        Err("unimplemented operator".to_string())
+    );
+
+    // once activated outside the guard, it's available everywhere
+    let dialect = ChikDialect::with_extensions(
+        NO_UNKNOWN_OPS | ENABLE_TOY_OPS_OUTSIDE_GUARD,
+        &TOY_EXTENSIONS,
+    );
+    assert_eq!(run_toy(&dialect, "(64 (q . 42))"), Ok(31));
+    assert_eq!(
+        run_toy(&dialect, "(64 (q . 41))"),
+        Err("expected 42".to_string())
+    );
+
+    // in consensus mode, it's an unknown operator outside the guard
+    let dialect = ChikDialect::with_extensions(0, &TOY_EXTENSIONS);
+    assert!(run_toy(&dialect, "(64 (q . 41))").is_ok());
+}
+
+#[test]
+fn test_apply_opcode() {
+    let dialect = ChikDialect::new(NO_UNKNOWN_OPS);
+    let mut a = Allocator::new();
+    let (args, _) = parse_exp(&mut a, "(1 2)");
+
+    // 99 base cost, 320 per argument, 3 per argument byte and 10 per byte of
+    // the result
+    let add_cost = 99 + 2 * 320 + 2 * 3 + 10;
+    let Reduction(cost, result) = dialect
+        .apply_opcode(&mut a, 16, args, add_cost, OperatorSet::Default)
+        .unwrap();
+    assert_eq!(cost, add_cost);
+    assert_eq!(a.small_number(result), Some(3));
+
+    // the operator doesn't fit, or can't even start
+    for max_cost in [add_cost - 1, 98] {
+        let e = dialect
+            .apply_opcode(&mut a, 16, args, max_cost, OperatorSet::Default)
+            .unwrap_err();
+        assert_eq!(e.1, "cost exceeded");
+    }
+
+    // the BLS operators are only available with their extension
+    let e = dialect
+        .apply_opcode(&mut a, 48, args, 11000000000, OperatorSet::Default)
+        .unwrap_err();
+    assert_eq!(e.1, "unimplemented operator");
+    let e = dialect
+        .apply_opcode(&mut a, 48, args, 11000000000, OperatorSet::BLS)
+        .unwrap_err();
+    assert_ne!(e.1, "unimplemented operator");
 }
@@ -0,0 +1,492 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+use crate::allocator::{Allocator, Atom, NodePtr, SExp};
+use crate::err_utils::err;
+use crate::number::Number;
+use crate::reduction::EvalErr;
+use crate::serde::write_atom::atom_prefix;
+use crate::serde::{serialized_length, ObjectCache};
+
+// compare the serialized forms of two trees, as if by comparing the output of
+// node_to_bytes(), but without serializing them. Both trees are walked in lock
+// step, skipping sub trees they share, until their serializations differ
+fn cmp_serialized(a: &Allocator, lhs: NodePtr, rhs: NodePtr) -> Ordering {
+    let mut stack = vec![(lhs, rhs)];
+    while let Some((lhs, rhs)) = stack.pop() {
+        if lhs == rhs {
+            continue;
+        }
+        match (a.sexp(lhs), a.sexp(rhs)) {
+            (SExp::Pair(lhs_first, lhs_rest), SExp::Pair(rhs_first, rhs_rest)) => {
+                stack.push((lhs_rest, rhs_rest));
+                stack.push((lhs_first, rhs_first));
+            }
+            // a pair serializes as 0xff, which is greater than the first byte
+            // of any atom
+            (SExp::Pair(..), SExp::Atom) => return Ordering::Greater,
+            (SExp::Atom, SExp::Pair(..)) => return Ordering::Less,
+            (SExp::Atom, SExp::Atom) => {
+                let lhs = a.atom(lhs);
+                let rhs = a.atom(rhs);
+                let (lhs_prefix, lhs_len) = atom_prefix(lhs.as_ref());
+                let (rhs_prefix, rhs_len) = atom_prefix(rhs.as_ref());
+                // atom serializations are prefix free, so if they differ,
+                // they do so before the shorter one ends
+                let ord = lhs_prefix[..lhs_len]
+                    .iter()
+                    .chain(lhs.as_ref())
+                    .cmp(rhs_prefix[..rhs_len].iter().chain(rhs.as_ref()));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+        }
+    }
+    Ordering::Equal
+}
+
+// the opcode of a condition, which is the first part of its sort key
+fn opcode(a: &Allocator, condition: NodePtr) -> Result<Number, EvalErr> {
+    match a.sexp(condition) {
+        SExp::Pair(op, _) if op.is_atom() => Ok(a.number(op)),
+        _ => err(condition, "invalid condition"),
+    }
+}
+
+// the conditions in a proper list. They're ordered by their serialization,
+// so none of them may contain a hash stub, which doesn't have one
+fn collect_conditions(a: &Allocator, conditions: NodePtr) -> Result<Vec<NodePtr>, EvalErr> {
+    let Some(iter) = a.list_iter(conditions) else {
+        return err(conditions, "conditions must be a proper list");
+    };
+    let items: Vec<NodePtr> = iter.collect();
+    // sub trees shared between conditions are only visited once
+    let mut lengths = ObjectCache::new(a, serialized_length);
+    for c in &items {
+        match lengths.get_or_calculate(c) {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                return err(*c, "can't serialize hash stub");
+            }
+            Err(_) => {
+                return err(*c, "cycle detected");
+            }
+        }
+    }
+    Ok(items)
+}
+
+/// Sort a list of conditions into canonical order. i.e. by opcode and then by
+/// serialized form. The sort is stable, conditions that compare equal retain
+/// their relative order. The returned list is newly allocated, nil-terminated
+/// and refers to the same condition nodes as the input.
+pub fn sort_conditions(a: &mut Allocator, conditions: NodePtr) -> Result<NodePtr, EvalErr> {
+    let items = collect_conditions(a, conditions)?;
+    let mut items = items
+        .into_iter()
+        .map(|c| Ok((opcode(a, c)?, c)))
+        .collect::<Result<Vec<_>, EvalErr>>()?;
+    // sort_by() is stable
+    items.sort_by(|(lhs_op, lhs), (rhs_op, rhs)| {
+        lhs_op
+            .cmp(rhs_op)
+            .then_with(|| cmp_serialized(a, *lhs, *rhs))
+    });
+
+    let items: Vec<NodePtr> = items.into_iter().map(|(_, c)| c).collect();
+    let nil = a.nil();
+    a.new_pair_many(&items, nil)
+}
+
+/// Returns true if the list of conditions is already in the canonical order
+/// produced by `sort_conditions()`. This is cheaper than sorting, since every
+/// condition is only compared to the one before it.
+pub fn are_conditions_sorted(a: &Allocator, conditions: NodePtr) -> Result<bool, EvalErr> {
+    let mut prev: Option<(Number, NodePtr)> = None;
+    for c in collect_conditions(a, conditions)? {
+        let op = opcode(a, c)?;
+        if let Some((prev_op, prev)) = &prev {
+            if prev_op.cmp(&op).then_with(|| cmp_serialized(a, *prev, c)) == Ordering::Greater {
+                return Ok(false);
+            }
+        }
+        prev = Some((op, c));
+    }
+    Ok(true)
+}
+
+const CREATE_COIN: u32 = 51;
+
+/// A memo of a CREATE_COIN condition. Memos are arbitrary atoms, but are often
+/// UTF-8 strings, e.g. CAT memos and NFT metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Memo<'a> {
+    pub node: NodePtr,
+    atom: Atom<'a>,
+}
+
+impl Memo<'_> {
+    pub fn as_bytes(&self) -> &[u8] {
+        self.atom.as_ref()
+    }
+
+    /// The memo as a string, or None if it's not valid UTF-8. This is the same
+    /// check as `Allocator::atom_as_str()`
+    pub fn as_str(&self) -> Option<&str> {
+        self.atom.as_str()
+    }
+}
+
+/// Returns the memos of a CREATE_COIN condition, `(51 puzzle_hash amount
+/// (memo ...))`. A condition without the memo list has no memos. The memo list
+/// must be a proper list of atoms.
+pub fn create_coin_memos(a: &Allocator, condition: NodePtr) -> Result<Vec<Memo<'_>>, EvalErr> {
+    let mut args = match a.list_iter(condition) {
+        Some(iter) => iter,
+        None => {
+            return err(condition, "invalid condition");
+        }
+    };
+    match args.next() {
+        Some(op) if op.is_atom() && a.small_number(op) == Some(CREATE_COIN) => {}
+        _ => {
+            return err(condition, "expected CREATE_COIN condition");
+        }
+    }
+    let Some(memos) = args.nth(2) else {
+        return Ok(Vec::new());
+    };
+    let Some(memos) = a.list_iter(memos) else {
+        return err(memos, "memos must be a proper list");
+    };
+    memos
+        .map(|node| {
+            if node.is_pair() {
+                return err(node, "memo must be an atom");
+            }
+            Ok(Memo {
+                node,
+                atom: a.atom(node),
+            })
+        })
+        .collect()
+}
+
+/// A condition, `(opcode arg ...)`, as returned by a puzzle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub node: NodePtr,
+    /// None if the opcode doesn't fit in a small, canonical integer. Such
+    /// conditions are unknown, and ignored by consensus
+    pub opcode: Option<u32>,
+    pub args: Vec<NodePtr>,
+}
+
+impl Condition {
+    pub fn is_create_coin(&self) -> bool {
+        self.opcode == Some(CREATE_COIN)
+    }
+}
+
+/// Parse a list of conditions. The list, and each condition, must be a proper
+/// list, and the opcode of each condition must be an atom. The arguments are
+/// not validated.
+pub fn parse_conditions(a: &Allocator, conditions: NodePtr) -> Result<Vec<Condition>, EvalErr> {
+    collect_conditions(a, conditions)?
+        .into_iter()
+        .map(|node| {
+            let mut items = match a.list_iter(node) {
+                Some(iter) => iter,
+                None => {
+                    return err(node, "invalid condition");
+                }
+            };
+            let opcode = match items.next() {
+                Some(op) if op.is_atom() => a.small_number(op),
+                _ => {
+                    return err(node, "invalid condition");
+                }
+            };
+            Ok(Condition {
+                node,
+                opcode,
+                args: items.collect(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+use crate::test_ops::{node_eq, parse_exp};
+
+#[cfg(test)]
+fn collect(a: &Allocator, list: NodePtr) -> Vec<NodePtr> {
+    a.list_iter(list).unwrap().collect()
+}
+
+#[test]
+fn test_sort_conditions() {
+    let mut a = Allocator::new();
+    let (conditions, _) = parse_exp(
+        &mut a,
+        "((51 0x1337 100) (60 \"foo\") (51 0x1337 20) (73 1) (51 0x1234 100) (60 \"bar\"))",
+    );
+    assert!(!are_conditions_sorted(&a, conditions).unwrap());
+    let sorted = sort_conditions(&mut a, conditions).unwrap();
+    let (expected, _) = parse_exp(
+        &mut a,
+        "((51 0x1234 100) (51 0x1337 20) (51 0x1337 100) (60 \"bar\") (60 \"foo\") (73 1))",
+    );
+    assert!(node_eq(&a, sorted, expected));
+    assert!(are_conditions_sorted(&a, sorted).unwrap());
+
+    // sorting is idempotent
+    let sorted2 = sort_conditions(&mut a, sorted).unwrap();
+    assert_eq!(collect(&a, sorted), collect(&a, sorted2));
+
+    // the empty list and single conditions are sorted
+    let nil = a.nil();
+    assert!(are_conditions_sorted(&a, nil).unwrap());
+    assert_eq!(sort_conditions(&mut a, nil).unwrap(), nil);
+    let (single, _) = parse_exp(&mut a, "((51 0x1337 100))");
+    assert!(are_conditions_sorted(&a, single).unwrap());
+}
+
+#[test]
+fn test_sort_conditions_stable() {
+    let mut a = Allocator::new();
+    // three distinct nodes that compare equal, interleaved with a condition
+    // that sorts before them
+    let (c1, _) = parse_exp(&mut a, "(51 0x1337 100)");
+    let (c2, _) = parse_exp(&mut a, "(51 0x1337 100)");
+    let (c3, _) = parse_exp(&mut a, "(51 0x1337 100)");
+    let (c4, _) = parse_exp(&mut a, "(50 0x1337 100)");
+    let mut list = a.nil();
+    for c in [c3, c4, c2, c1] {
+        list = a.new_pair(c, list).unwrap();
+    }
+    let sorted = sort_conditions(&mut a, list).unwrap();
+    assert_eq!(collect(&a, sorted), [c4, c1, c2, c3]);
+    assert!(are_conditions_sorted(&a, sorted).unwrap());
+}
+
+#[test]
+fn test_sort_conditions_invalid() {
+    let mut a = Allocator::new();
+
+    // improper list
+    let (list, _) = parse_exp(&mut a, "((51 0x1337 100) . 1)");
+    let e = sort_conditions(&mut a, list).unwrap_err();
+    assert_eq!(e.1, "conditions must be a proper list");
+    let e = are_conditions_sorted(&a, list).unwrap_err();
+    assert_eq!(e.1, "conditions must be a proper list");
+
+    // a condition that's an atom
+    let (list, _) = parse_exp(&mut a, "((51 0x1337 100) 1)");
+    let e = sort_conditions(&mut a, list).unwrap_err();
+    assert_eq!(e.1, "invalid condition");
+    let e = are_conditions_sorted(&a, list).unwrap_err();
+    assert_eq!(e.1, "invalid condition");
+
+    // a condition whose opcode is a pair
+    let (list, _) = parse_exp(&mut a, "(((51) 0x1337 100))");
+    let e = sort_conditions(&mut a, list).unwrap_err();
+    assert_eq!(e.1, "invalid condition");
+}
+
+#[test]
+fn test_sort_conditions_hash_stub() {
+    let mut a = Allocator::new();
+    let (c1, _) = parse_exp(&mut a, "(51 0x1337 100)");
+    let stub = a.new_hash_stub([0x42; 32].into()).unwrap();
+    let nil = a.nil();
+    let memos = a.new_pair(stub, nil).unwrap();
+    let memos = a.new_pair(memos, nil).unwrap();
+    let items: Vec<NodePtr> = a.list_iter(c1).unwrap().collect();
+    let c2 = a.new_pair_many(&items, memos).unwrap();
+    for c in [c2, stub] {
+        let list = a.new_pair_many(&[c1, c], nil).unwrap();
+        let e = sort_conditions(&mut a, list).unwrap_err();
+        assert_eq!(e, EvalErr::new(c, "can't serialize hash stub".to_string()));
+        let e = are_conditions_sorted(&a, list).unwrap_err();
+        assert_eq!(e, EvalErr::new(c, "can't serialize hash stub".to_string()));
+    }
+}
+
+#[test]
+fn test_sort_many_conditions() {
+    // many conditions sharing the same puzzle hash node
+    let mut a = Allocator::new();
+    let puzzle_hash = a.new_atom(&[0x42; 32]).unwrap();
+    let opcode = a.new_small_number(51).unwrap();
+    let nil = a.nil();
+    let mut list = nil;
+    for i in 0..50000 {
+        let amount = a.new_small_number((i * 7919) % 50000).unwrap();
+        let c = a.new_pair(amount, nil).unwrap();
+        let c = a.new_pair(puzzle_hash, c).unwrap();
+        let c = a.new_pair(opcode, c).unwrap();
+        list = a.new_pair(c, list).unwrap();
+    }
+    assert!(!are_conditions_sorted(&a, list).unwrap());
+    let sorted = sort_conditions(&mut a, list).unwrap();
+    assert!(are_conditions_sorted(&a, sorted).unwrap());
+    assert_eq!(a.list_len(sorted), Some(50000));
+}
+
+#[test]
+fn test_cmp_serialized() {
+    use crate::serde::node_to_bytes;
+
+    let mut a = Allocator::new();
+    let long = format!("0x{}", "cc".repeat(100));
+    let mut nodes = Vec::new();
+    for exp in [
+        "()",
+        "0",
+        "1",
+        "0x7f",
+        "0x80",
+        "0xff",
+        "0x0000",
+        "0xffff",
+        long.as_str(),
+        "(1)",
+        "(())",
+        "(1 . 2)",
+        "((1) 2)",
+        "(1 (2))",
+        "(0x1337 100)",
+        "(0x1337 . 100)",
+        "(51 0x1337 100 (\"foo\"))",
+        "(51 0x1337 100 (\"foo\" \"bar\"))",
+    ] {
+        nodes.push(parse_exp(&mut a, exp).0);
+    }
+    // the same tree as a different node
+    nodes.push(parse_exp(&mut a, "(51 0x1337 100 (\"foo\"))").0);
+    for lhs in &nodes {
+        for rhs in &nodes {
+            let expected = node_to_bytes(&a, *lhs)
+                .unwrap()
+                .cmp(&node_to_bytes(&a, *rhs).unwrap());
+            assert_eq!(cmp_serialized(&a, *lhs, *rhs), expected);
+        }
+    }
+}
+
+#[test]
+fn test_sort_many_memos() {
+    // conditions with many memos, that only differ in the last one. Comparing
+    // them must not require serializing them
+    let mut a = Allocator::new();
+    let (prefix, _) = parse_exp(&mut a, "(51 0x1337 100)");
+    let mut list = a.nil();
+    for last in [3, 1, 2] {
+        let nil = a.nil();
+        let last = a.new_small_number(last).unwrap();
+        let mut memos = a.new_pair(last, nil).unwrap();
+        for _ in 0..10000 {
+            let memo = a.new_atom(&[0x42; 32]).unwrap();
+            memos = a.new_pair(memo, memos).unwrap();
+        }
+        let memos = a.new_pair(memos, nil).unwrap();
+        let items: Vec<NodePtr> = a.list_iter(prefix).unwrap().collect();
+        let c = a.new_pair_many(&items, memos).unwrap();
+        list = a.new_pair(c, list).unwrap();
+    }
+    let sorted = sort_conditions(&mut a, list).unwrap();
+    assert!(are_conditions_sorted(&a, sorted).unwrap());
+    let last_memos: Vec<NodePtr> = collect(&a, sorted)
+        .into_iter()
+        .map(|c| *collect(&a, collect(&a, c)[3]).last().unwrap())
+        .collect();
+    let last_memos: Vec<Option<u32>> = last_memos.iter().map(|n| a.small_number(*n)).collect();
+    assert_eq!(last_memos, [Some(1), Some(2), Some(3)]);
+}
+
+#[test]
+fn test_create_coin_memos() {
+    let mut a = Allocator::new();
+    let (condition, _) = parse_exp(
+        &mut a,
+        "(51 0x1337 100 (0xcafe \"foobar\" 0x636166c3a9 0x610062 0xc328))",
+    );
+    let memos = create_coin_memos(&a, condition).unwrap();
+    let bytes: Vec<&[u8]> = memos.iter().map(|m| m.as_bytes()).collect();
+    let expected: [&[u8]; 5] = [
+        &[0xca, 0xfe],
+        b"foobar",
+        &[0x63, 0x61, 0x66, 0xc3, 0xa9],
+        b"a\0b",
+        &[0xc3, 0x28],
+    ];
+    assert_eq!(bytes, expected);
+    let strings: Vec<Option<&str>> = memos.iter().map(|m| m.as_str()).collect();
+    assert_eq!(
+        strings,
+        [None, Some("foobar"), Some("caf\u{e9}"), Some("a\0b"), None]
+    );
+    for m in &memos {
+        assert_eq!(a.atom_as_str(m.node).as_deref(), m.as_str());
+    }
+
+    // no memos
+    for c in ["(51 0x1337 100)", "(51 0x1337 100 ())"] {
+        let (condition, _) = parse_exp(&mut a, c);
+        assert!(create_coin_memos(&a, condition).unwrap().is_empty());
+    }
+
+    for (c, msg) in [
+        ("(51 0x1337 100 . 1)", "invalid condition"),
+        (
+            "(52 0x1337 100 (\"foobar\"))",
+            "expected CREATE_COIN condition",
+        ),
+        (
+            "((51) 0x1337 100 (\"foobar\"))",
+            "expected CREATE_COIN condition",
+        ),
+        ("(51 0x1337 100 \"foobar\")", "memos must be a proper list"),
+        (
+            "(51 0x1337 100 (\"foobar\" . 1))",
+            "memos must be a proper list",
+        ),
+        ("(51 0x1337 100 ((\"foobar\")))", "memo must be an atom"),
+    ] {
+        let (condition, _) = parse_exp(&mut a, c);
+        assert_eq!(create_coin_memos(&a, condition).unwrap_err().1, msg);
+    }
+}
+
+#[test]
+fn test_parse_conditions() {
+    let mut a = Allocator::new();
+    let (conditions, _) = parse_exp(
+        &mut a,
+        "((51 0x1337 100 (\"memo\")) (60 \"msg\") (0x0100000000 1 2))",
+    );
+    let parsed = parse_conditions(&a, conditions).unwrap();
+    assert_eq!(parsed.len(), 3);
+    assert_eq!(parsed[0].opcode, Some(51));
+    assert!(parsed[0].is_create_coin());
+    assert_eq!(parsed[0].args.len(), 3);
+    assert_eq!(a.atom(parsed[0].args[0]).as_ref(), [0x13, 0x37]);
+    assert_eq!(parsed[1].opcode, Some(60));
+    assert!(!parsed[1].is_create_coin());
+    assert_eq!(parsed[1].args.len(), 1);
+    // the opcode doesn't fit in a u32
+    assert_eq!(parsed[2].opcode, None);
+    assert_eq!(parsed[2].args.len(), 2);
+    assert_eq!(collect(&a, conditions)[2], parsed[2].node);
+
+    let nil = a.nil();
+    assert!(parse_conditions(&a, nil).unwrap().is_empty());
+
+    for invalid in ["((51 . 1))", "(51)", "((() 1) . 1)", "(((51) 1))"] {
+        let (conditions, _) = parse_exp(&mut a, invalid);
+        assert!(parse_conditions(&a, conditions).is_err());
+    }
+}
@@ -1,6 +1,7 @@
+use alloc::format;
+
 use crate::allocator::{Allocator, NodePtr, SExp};
 use crate::cost::Cost;
-use crate::err_utils::err;
 use crate::op_utils::{first, get_args, nilp, rest};
 use crate::reduction::{EvalErr, Reduction, Response};
 
@@ -59,14 +60,14 @@ pub fn op_raise(a: &mut Allocator, input: NodePtr, _max_cost: Cost) -> Response
         input
     };
 
-    err(throw_value, "klvm raise")
+    Err(EvalErr::raise(throw_value))
 }
 
 fn ensure_atom(a: &Allocator, n: NodePtr, op: &str) -> Result<(), EvalErr> {
     if let SExp::Atom = a.sexp(n) {
         Ok(())
     } else {
-        Err(EvalErr(n, format!("{op} on list")))
+        Err(EvalErr::new(n, format!("{op} on list")))
     }
 }
 
@@ -1,11 +1,69 @@
 use crate::allocator::Allocator;
 use crate::reduction::EvalErr;
+use core::cmp::min;
 
 pub type Cost = u64;
 
+/// Passing this as `max_cost` to `run_program()` (and the other entry points)
+/// disables the cost limit. A `max_cost` of 0 means the same thing. Costs
+/// still can't exceed `Cost::MAX`; a program whose cost would is reported as
+/// "cost exceeded", rather than wrapping around.
+pub const UNLIMITED_COST: Cost = Cost::MAX;
+
+/// Re-prices the single-byte operators, e.g. to evaluate blocks under the cost
+/// model in effect before (or after) a consensus change. The cost of each
+/// operator is scaled by a percentage, 100 means the operator's own cost
+/// applies unchanged. Scaled costs are rounded down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostTable {
+    percent: [u16; 256],
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        Self {
+            percent: [100; 256],
+        }
+    }
+}
+
+impl CostTable {
+    pub fn set_percent(&mut self, op: u8, percent: u16) {
+        self.percent[op as usize] = percent;
+    }
+
+    pub fn percent(&self, op: u8) -> u16 {
+        self.percent[op as usize]
+    }
+
+    /// the cost of running `op`, given the cost of the operator itself
+    pub fn scale(&self, op: u8, cost: Cost) -> Cost {
+        let percent = self.percent(op);
+        if percent == 100 {
+            return cost;
+        }
+        min(cost as u128 * percent as u128 / 100, Cost::MAX as u128) as Cost
+    }
+
+    /// the max cost to pass to the operator itself, such that its scaled cost
+    /// doesn't exceed `max_cost`
+    pub fn unscaled_max_cost(&self, op: u8, max_cost: Cost) -> Cost {
+        match self.percent(op) {
+            100 => max_cost,
+            0 => Cost::MAX,
+            // the scaled cost is rounded down, so any cost up to (but not
+            // including) the next multiple is within the limit
+            percent => min(
+                ((max_cost as u128 + 1) * 100).div_ceil(percent as u128) - 1,
+                Cost::MAX as u128,
+            ) as Cost,
+        }
+    }
+}
+
 pub fn check_cost(a: &Allocator, cost: Cost, max_cost: Cost) -> Result<(), EvalErr> {
     if cost > max_cost {
-        Err(EvalErr(a.nil(), "cost exceeded".into()))
+        Err(EvalErr::new(a.nil(), "cost exceeded".into()))
     } else {
         Ok(())
     }
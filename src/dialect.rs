@@ -2,11 +2,16 @@ use crate::allocator::{Allocator, NodePtr};
 use crate::cost::Cost;
 use crate::reduction::Response;
 
+// new operator sets are added by softforks, so matches on this outside the
+// crate need a wildcard arm
 #[repr(u32)]
 #[derive(Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum OperatorSet {
     Default,
     BLS,
+    // the operators of the ExtensionTable with this softfork extension id
+    Extension(u32),
 }
 
 pub trait Dialect {
@@ -22,5 +27,16 @@ pub trait Dialect {
         max_cost: Cost,
         extensions: OperatorSet,
     ) -> Response;
+    /// when true, unknown operators are no-ops that return nil, with a cost
+    /// derived from the opcode and the size of the arguments (see
+    /// `op_unknown`), and malformed softfork guards also evaluate to nil.
+    /// When false, they are errors. This allows programs to use operators
+    /// that will be added by future soft-forks
     fn allow_unknown_ops(&self) -> bool;
+    /// A lower bound of the cost of `op` with any arguments. The interpreter
+    /// fails with "cost exceeded", without calling op(), if this exceeds the
+    /// remaining budget. It must never be more than what op() would charge
+    fn op_min_cost(&self, _allocator: &Allocator, _op: NodePtr, _extensions: OperatorSet) -> Cost {
+        0
+    }
 }
@@ -0,0 +1,271 @@
+use crate::allocator::{Allocator, NodePtr, MAX_NUM_ATOMS, MAX_NUM_PAIRS};
+use crate::chik_dialect::{unknown_operator, ChikDialect, ExtensionTable, CHIK_EXTENSIONS};
+use crate::collections::HashSet;
+use crate::cost::Cost;
+use crate::dialect::{Dialect, OperatorSet};
+use crate::reduction::{Reduction, Response};
+use crate::run_program::run_program;
+
+/// A Chik dialect with all of its parameters bundled in one place. The
+/// operators and their semantics are the ones of `ChikDialect`, gated by
+/// `flags`. In addition, individual operators can be disabled, the softfork
+/// extensions can be replaced, the allocator limits can be lowered and
+/// allocating atoms can be made more expensive.
+pub struct DialectConfig {
+    // flags passed on to ChikDialect, e.g. NO_UNKNOWN_OPS or
+    // ENABLE_BLS_OPS_OUTSIDE_GUARD
+    flags: u32,
+
+    // opcodes (as big-endian integers) that are treated as unknown operators,
+    // even though ChikDialect implements them
+    pub disabled_ops: HashSet<u32>,
+
+    // the softfork extensions and their operators, e.g. CHIK_EXTENSIONS.
+    // Extensions not in this table enable nothing
+    pub softfork_extensions: &'static [ExtensionTable],
+
+    // the max number of atom bytes, atoms and pairs allocators created by
+    // new_allocator() may hold
+    pub heap_limit: usize,
+    pub atom_limit: usize,
+    pub pair_limit: usize,
+
+    // the cost of the atom bytes an operator allocates, given the number of
+    // bytes it added to the allocator's heap. This is charged on top of the
+    // operator's own cost, which already includes MALLOC_COST_PER_BYTE for
+    // every byte of the atom it returns. It's measured once the operator
+    // returns, so it can't stop an allocation, heap_limit does that. Pairs
+    // and atoms small enough to be stored in their NodePtr don't use the heap
+    // and aren't charged, they're bounded by pair_limit and atom_limit
+    pub malloc_cost: fn(usize) -> Cost,
+
+    // the operators are dispatched by ChikDialect, its dispatch tables are
+    // built once, from the flags
+    chik: ChikDialect,
+}
+
+impl DialectConfig {
+    pub fn new(
+        flags: u32,
+        disabled_ops: HashSet<u32>,
+        softfork_extensions: &'static [ExtensionTable],
+        heap_limit: usize,
+        atom_limit: usize,
+        pair_limit: usize,
+        malloc_cost: fn(usize) -> Cost,
+    ) -> Self {
+        Self {
+            flags,
+            disabled_ops,
+            softfork_extensions,
+            heap_limit,
+            atom_limit,
+            pair_limit,
+            malloc_cost,
+            chik: ChikDialect::with_extensions(flags, softfork_extensions),
+        }
+    }
+
+    /// the same rules as `ChikDialect::new(0)`
+    pub fn chik_mainnet() -> Self {
+        Self::new(
+            0,
+            HashSet::new(),
+            &CHIK_EXTENSIONS,
+            u32::MAX as usize,
+            MAX_NUM_ATOMS,
+            MAX_NUM_PAIRS,
+            no_malloc_cost,
+        )
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// create an allocator enforcing the heap, atom and pair limits of this
+    /// dialect
+    pub fn new_allocator(&self) -> Allocator {
+        Allocator::new_with_limits(self.heap_limit, self.atom_limit, self.pair_limit)
+    }
+
+    fn is_disabled(&self, allocator: &Allocator, o: NodePtr) -> bool {
+        if self.disabled_ops.is_empty() || allocator.atom_len(o) > 4 {
+            return false;
+        }
+        let opcode = allocator
+            .atom(o)
+            .as_ref()
+            .iter()
+            .fold(0_u32, |acc, b| (acc << 8) | *b as u32);
+        self.disabled_ops.contains(&opcode)
+    }
+}
+
+impl Dialect for DialectConfig {
+    fn op(
+        &self,
+        allocator: &mut Allocator,
+        o: NodePtr,
+        argument_list: NodePtr,
+        max_cost: Cost,
+        extension: OperatorSet,
+    ) -> Response {
+        if self.is_disabled(allocator, o) {
+            return unknown_operator(allocator, o, argument_list, self.flags, max_cost);
+        }
+        let heap_size = allocator.heap_size();
+        let Reduction(cost, node) =
+            self.chik
+                .op(allocator, o, argument_list, max_cost, extension)?;
+        let malloc_cost = (self.malloc_cost)(allocator.heap_size().saturating_sub(heap_size));
+        Ok(Reduction(cost.saturating_add(malloc_cost), node))
+    }
+
+    fn quote_kw(&self) -> u32 {
+        self.chik.quote_kw()
+    }
+    fn apply_kw(&self) -> u32 {
+        self.chik.apply_kw()
+    }
+    fn softfork_kw(&self) -> u32 {
+        self.chik.softfork_kw()
+    }
+
+    fn softfork_extension(&self, ext: u32) -> OperatorSet {
+        self.chik.softfork_extension(ext)
+    }
+
+    fn allow_unknown_ops(&self) -> bool {
+        self.chik.allow_unknown_ops()
+    }
+
+    fn op_min_cost(&self, allocator: &Allocator, o: NodePtr, extension: OperatorSet) -> Cost {
+        // disabled operators are unknown operators, which may cost less
+        if self.is_disabled(allocator, o) {
+            return 0;
+        }
+        self.chik.op_min_cost(allocator, o, extension)
+    }
+}
+
+/// the malloc cost of `DialectConfig::chik_mainnet()`. Allocations cost
+/// nothing beyond what the operators charge themselves
+pub fn no_malloc_cost(_bytes: usize) -> Cost {
+    0
+}
+
+/// run `program` under the rules of `dialect`. To have the dialect's limits
+/// enforced, `allocator` should be created by `DialectConfig::new_allocator()`
+pub fn run_program_dialect(
+    allocator: &mut Allocator,
+    dialect: &DialectConfig,
+    program: NodePtr,
+    args: NodePtr,
+    max_cost: Cost,
+) -> Response {
+    run_program(allocator, dialect, program, args, max_cost)
+}
+
+#[cfg(test)]
+use crate::chik_dialect::{ENABLE_BLS_OPS_OUTSIDE_GUARD, NO_UNKNOWN_OPS};
+#[cfg(test)]
+use crate::more_ops::{COINID_COST, SHA256_BASE_COST};
+#[cfg(test)]
+use crate::test_ops::{node_eq, parse_exp};
+
+// g1_negate of the G1 generator
+#[cfg(test)]
+const G1_NEGATE: &str = "(g1_negate (q . 0x97f1d3a73197d7942695638c4fa9ac0fc3688c4f9774b905a14e3a3f171bac586c55e83ff97a1aeffb3af00adb22c6bb))";
+
+#[test]
+fn test_mainnet() {
+    let dialect = DialectConfig::chik_mainnet();
+    assert!(dialect.softfork_extension(0) == OperatorSet::BLS);
+    assert!(dialect.softfork_extension(1) == OperatorSet::Default);
+    assert!(dialect.allow_unknown_ops());
+
+    // BLS is only available under the softfork guard, outside of it, it's an
+    // unknown operator
+    let mut a = dialect.new_allocator();
+    let nil = a.nil();
+    let (program, _) = parse_exp(&mut a, G1_NEGATE);
+    let result = run_program_dialect(&mut a, &dialect, program, nil, 10000000).unwrap();
+    assert_eq!(result.1, nil);
+
+    let mainnet = DialectConfig::chik_mainnet();
+    let dialect = DialectConfig::new(
+        ENABLE_BLS_OPS_OUTSIDE_GUARD,
+        mainnet.disabled_ops,
+        mainnet.softfork_extensions,
+        mainnet.heap_limit,
+        mainnet.atom_limit,
+        mainnet.pair_limit,
+        mainnet.malloc_cost,
+    );
+    let result = run_program_dialect(&mut a, &dialect, program, nil, 10000000).unwrap();
+    assert_eq!(a.atom_len(result.1), 48);
+}
+
+#[test]
+fn test_restricted() {
+    let dialect = DialectConfig::new(
+        NO_UNKNOWN_OPS | ENABLE_BLS_OPS_OUTSIDE_GUARD,
+        // BLS operators and coinid are disabled
+        (48..=61).collect(),
+        // and can't be enabled by the softfork guard either
+        &[],
+        1000,
+        100,
+        100,
+        // allocating atoms costs an extra 1000 per byte
+        |bytes| bytes as Cost * 1000,
+    );
+    let mut a = dialect.new_allocator();
+    let nil = a.nil();
+
+    // regular operators still work
+    let (program, _) = parse_exp(&mut a, "(c (+ (q . 1) (q . 2)) (q . (\"foo\")))");
+    let result = run_program_dialect(&mut a, &dialect, program, nil, 10000).unwrap();
+    let (expected, _) = parse_exp(&mut a, "(3 \"foo\")");
+    assert!(node_eq(&a, result.1, expected));
+
+    let (program, _) = parse_exp(&mut a, G1_NEGATE);
+    let e = run_program_dialect(&mut a, &dialect, program, nil, 10000000).unwrap_err();
+    assert_eq!(e.1, "unimplemented operator");
+
+    let (program, _) = parse_exp(&mut a, "(softfork (q . 919) (q . 0) (q x) (q . ()))");
+    let e = run_program_dialect(&mut a, &dialect, program, nil, 10000).unwrap_err();
+    assert_eq!(e.1, "unknown softfork extension");
+
+    // so does the malloc cost. "foobar" is 6 bytes on the heap, quoting the
+    // arguments doesn't allocate anything
+    let mainnet = DialectConfig::chik_mainnet();
+    let (program, _) = parse_exp(&mut a, "(concat (q . \"foo\") (q . \"bar\"))");
+    let base = run_program_dialect(&mut a, &mainnet, program, nil, 10000).unwrap();
+    let result = run_program_dialect(&mut a, &dialect, program, nil, 100000).unwrap();
+    assert_eq!(result.0, base.0 + 6000);
+    assert_eq!(a.atom(result.1).as_ref(), b"foobar");
+    let e = run_program_dialect(&mut a, &dialect, program, nil, base.0 + 5999).unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+
+    // the base costs of the operators are known ahead of calling them, except
+    // for the disabled ones
+    let sha256 = a.new_atom(&[11]).unwrap();
+    let coinid = a.new_atom(&[48]).unwrap();
+    assert_eq!(
+        dialect.op_min_cost(&a, sha256, OperatorSet::Default),
+        SHA256_BASE_COST
+    );
+    assert_eq!(dialect.op_min_cost(&a, coinid, OperatorSet::Default), 0);
+    assert_eq!(
+        mainnet.op_min_cost(&a, coinid, OperatorSet::BLS),
+        COINID_COST
+    );
+
+    // the heap limit applies
+    let buf = "41".repeat(300);
+    let (program, _) = parse_exp(&mut a, &format!("(concat (q . 0x{buf}) (q . 0x{buf}))"));
+    let e = run_program_dialect(&mut a, &dialect, program, nil, 10000000).unwrap_err();
+    assert_eq!(e.1, "out of memory");
+}
@@ -0,0 +1,487 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::allocator::{Allocator, Atom, NodePtr, SExp};
+use crate::dialect::Dialect;
+use crate::number::number_from_u8;
+use core::fmt;
+use core::fmt::Write;
+
+// the quote and apply keywords of the dialect being printed. Dialects may
+// remap them, all other operators are named as in the Chik dialect
+#[derive(Clone, Copy)]
+struct Keywords {
+    quote: u32,
+    apply: u32,
+}
+
+const CHIK_KEYWORDS: Keywords = Keywords { quote: 1, apply: 2 };
+
+// appended to the output when the budget is exhausted
+const TRUNCATED: &str = "...";
+
+/// Limits the work done rendering a tree. See `disassemble_with_budget()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisassembleBudget {
+    /// the max number of nodes (atoms and pairs) to visit
+    pub max_nodes: usize,
+    /// the max length of the output, not counting the truncation marker
+    pub max_output_len: usize,
+}
+
+impl Default for DisassembleBudget {
+    fn default() -> Self {
+        Self {
+            max_nodes: 100000,
+            max_output_len: 1000000,
+        }
+    }
+}
+
+const UNLIMITED: DisassembleBudget = DisassembleBudget {
+    max_nodes: usize::MAX,
+    max_output_len: usize::MAX,
+};
+
+// the names of the operators in the Chik dialect. These are used to print
+// atoms in the operator position of a list
+fn keyword(keywords: Keywords, op: u8) -> Option<&'static str> {
+    if op as u32 == keywords.quote {
+        return Some("q");
+    }
+    if op as u32 == keywords.apply {
+        return Some("a");
+    }
+    let name = match op {
+        3 => "i",
+        4 => "c",
+        5 => "f",
+        6 => "r",
+        7 => "l",
+        8 => "x",
+        9 => "=",
+        10 => ">s",
+        11 => "sha256",
+        12 => "substr",
+        13 => "strlen",
+        14 => "concat",
+        16 => "+",
+        17 => "-",
+        18 => "*",
+        19 => "/",
+        20 => "divmod",
+        21 => ">",
+        22 => "ash",
+        23 => "lsh",
+        24 => "logand",
+        25 => "logior",
+        26 => "logxor",
+        27 => "lognot",
+        29 => "point_add",
+        30 => "pubkey_for_exp",
+        32 => "not",
+        33 => "any",
+        34 => "all",
+        36 => "softfork",
+        48 => "coinid",
+        49 => "g1_subtract",
+        50 => "g1_multiply",
+        51 => "g1_negate",
+        52 => "g2_add",
+        53 => "g2_subtract",
+        54 => "g2_multiply",
+        55 => "g2_negate",
+        56 => "g1_map",
+        57 => "g2_map",
+        58 => "bls_pairing_identity",
+        59 => "bls_verify",
+        60 => "modpow",
+        61 => "%",
+        62 => "bls_verify_basic",
+        63 => "sha256_tagged",
+        _ => {
+            return None;
+        }
+    };
+    Some(name)
+}
+
+// returns true if the buffer is the canonical representation of an integer.
+// i.e. it doesn't have redundant leading zeros or 0xff bytes
+fn is_canonical_int(buf: &[u8]) -> bool {
+    match buf {
+        [] => true,
+        [0] => false,
+        [0, b, ..] => (b & 0x80) != 0,
+        [0xff, b, ..] => (b & 0x80) == 0,
+        _ => true,
+    }
+}
+
+// atoms are printed as quoted strings if they are valid UTF-8 (see
+// Atom::as_str()) without quotes or control characters, like NUL
+fn printable_str<'a>(atom: &'a Atom) -> Option<&'a str> {
+    atom.as_str()
+        .filter(|s| s.chars().all(|c| c != '"' && !c.is_control()))
+}
+
+// hex is only written up to `limit`, since it can be the longest rendering of
+// an atom
+fn write_atom(out: &mut String, keywords: Keywords, atom: Atom, operator: bool, limit: usize) {
+    let buf = atom.as_ref();
+    if buf.is_empty() {
+        out.push_str("()");
+    } else if let (true, Some(name)) = (operator && buf.len() == 1, keyword(keywords, buf[0])) {
+        out.push_str(name);
+    } else if let (true, Some(s)) = (buf.len() > 2, printable_str(&atom)) {
+        out.push('"');
+        out.push_str(s);
+        out.push('"');
+    } else if buf.len() <= 4 && is_canonical_int(buf) {
+        write!(out, "{}", number_from_u8(buf)).expect("write to String");
+    } else {
+        out.push_str("0x");
+        for b in buf {
+            if out.len() > limit {
+                break;
+            }
+            write!(out, "{b:02x}").expect("write to String");
+        }
+    }
+}
+
+enum Op {
+    // print a node. The bool indicates whether it's in the operator position
+    // of a list
+    Node(NodePtr, bool),
+    // print the remainder of a list, whose first element has already been
+    // printed
+    Rest(NodePtr),
+}
+
+fn disassemble_ops(
+    a: &Allocator,
+    keywords: Keywords,
+    mut ops: Vec<Op>,
+    budget: DisassembleBudget,
+    out: &mut String,
+) {
+    let limit = budget.max_output_len;
+    // every op visits one node
+    let mut nodes = 0;
+    let mut truncated = false;
+    while let Some(op) = ops.pop() {
+        nodes += 1;
+        if nodes > budget.max_nodes || out.len() > limit {
+            truncated = true;
+            break;
+        }
+        match op {
+            Op::Node(node, operator) => match a.sexp(node) {
+                SExp::Atom => write_atom(out, keywords, a.atom(node), operator, limit),
+                SExp::Pair(first, rest) => {
+                    out.push('(');
+                    ops.push(Op::Rest(rest));
+                    ops.push(Op::Node(first, true));
+                }
+            },
+            Op::Rest(node) => match a.sexp(node) {
+                SExp::Pair(first, rest) => {
+                    out.push(' ');
+                    ops.push(Op::Rest(rest));
+                    ops.push(Op::Node(first, false));
+                }
+                SExp::Atom => {
+                    if a.atom_len(node) != 0 {
+                        out.push_str(" . ");
+                        write_atom(out, keywords, a.atom(node), false, limit);
+                    }
+                    out.push(')');
+                }
+            },
+        }
+    }
+    if truncated || out.len() > limit {
+        // strings may contain multi-byte characters, don't split them
+        let mut end = limit;
+        while !out.is_char_boundary(end) {
+            end -= 1;
+        }
+        out.truncate(end);
+        out.push_str(TRUNCATED);
+    }
+}
+
+/// render a KLVM structure in the textual (assembler) form. Atoms in the
+/// operator position of a list are printed as the corresponding Chik operator
+/// name, printable strings are quoted, small integers are printed in decimal
+/// and everything else as hex.
+/// There's no limit on the size of the output, see
+/// `disassemble_with_budget()` for rendering untrusted trees.
+pub fn disassemble(a: &Allocator, node: NodePtr) -> String {
+    disassemble_with_budget(a, node, UNLIMITED)
+}
+
+/// like `disassemble()`, but once more than `budget.max_nodes` nodes have been
+/// visited, or the output exceeds `budget.max_output_len`, rendering stops
+/// and the output ends with `...`
+pub fn disassemble_with_budget(a: &Allocator, node: NodePtr, budget: DisassembleBudget) -> String {
+    let mut out = String::new();
+    disassemble_ops(
+        a,
+        CHIK_KEYWORDS,
+        vec![Op::Node(node, false)],
+        budget,
+        &mut out,
+    );
+    out
+}
+
+/// like `disassemble()`, but the quote and apply keywords are taken from the
+/// dialect. They are printed as `q` and `a` respectively.
+pub fn disassemble_with_dialect<D: Dialect>(a: &Allocator, dialect: &D, node: NodePtr) -> String {
+    let keywords = Keywords {
+        quote: dialect.quote_kw(),
+        apply: dialect.apply_kw(),
+    };
+    let mut out = String::new();
+    disassemble_ops(
+        a,
+        keywords,
+        vec![Op::Node(node, false)],
+        UNLIMITED,
+        &mut out,
+    );
+    out
+}
+
+/// render the list whose first element is `first` and remaining elements are
+/// `rest`, as if they had been combined into a pair
+pub fn disassemble_list(a: &Allocator, first: NodePtr, rest: NodePtr) -> String {
+    let mut out = String::from("(");
+    disassemble_ops(
+        a,
+        CHIK_KEYWORDS,
+        vec![Op::Rest(rest), Op::Node(first, true)],
+        UNLIMITED,
+        &mut out,
+    );
+    out
+}
+
+/// Renders a tree like `disassemble_with_budget()`, for use with `format!()`
+/// and friends. The default budget applies, unless another one is specified
+/// with `with_budget()`.
+pub struct Disassembly<'a> {
+    a: &'a Allocator,
+    node: NodePtr,
+    budget: DisassembleBudget,
+}
+
+impl<'a> Disassembly<'a> {
+    pub fn new(a: &'a Allocator, node: NodePtr) -> Self {
+        Self {
+            a,
+            node,
+            budget: DisassembleBudget::default(),
+        }
+    }
+
+    pub fn with_budget(self, budget: DisassembleBudget) -> Self {
+        Self { budget, ..self }
+    }
+}
+
+impl fmt::Display for Disassembly<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&disassemble_with_budget(self.a, self.node, self.budget))
+    }
+}
+
+// the max length of the output of DisplayNode, not counting the truncation
+// marker
+const DISPLAY_NODE_LEN: usize = 200;
+
+/// Renders a tree for log messages, e.g. `format!("{}", DisplayNode(&a, n))`.
+/// Just like `Disassembly`, but the output is cut off after 200 characters
+pub struct DisplayNode<'a>(pub &'a Allocator, pub NodePtr);
+
+impl fmt::Display for DisplayNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let budget = DisassembleBudget {
+            max_output_len: DISPLAY_NODE_LEN,
+            ..DisassembleBudget::default()
+        };
+        f.write_str(&disassemble_with_budget(self.0, self.1, budget))
+    }
+}
+
+#[cfg(test)]
+use rstest::rstest;
+
+#[cfg(test)]
+#[rstest]
+#[case("()", "()")]
+#[case("1", "1")]
+#[case("-1", "-1")]
+#[case("0x00", "0x00")]
+#[case("0x0080", "128")]
+#[case("0x000080", "0x000080")]
+#[case("0xdeadbeef", "-559038737")]
+#[case("0xdeadbeef00", "0xdeadbeef00")]
+#[case("\"foobar\"", "\"foobar\"")]
+#[case("(q . 1)", "(q . 1)")]
+#[case("(1 2 3)", "(q 2 3)")]
+#[case("((1 2) (3 4))", "((q 2) (i 4))")]
+#[case("(c (q . \"foo\") (f 1))", "(c (q . \"foo\") (f 1))")]
+#[case("(0x1337 . 0xff)", "(4919 . -1)")]
+#[case("(0x1337 . 2)", "(4919 . 2)")]
+#[case("0x636166c3a9", "\"caf\u{e9}\"")]
+#[case("0xe29883e29883", "\"\u{2603}\u{2603}\"")]
+#[case("(q . 0xf09fa680)", "(q . \"\u{1f980}\")")]
+#[case("0x610062", "6357090")]
+#[case("0x666f6f0a0a", "0x666f6f0a0a")]
+#[case("0x666f6f0000", "0x666f6f0000")]
+#[case("0x666f6fc328", "0x666f6fc328")]
+#[case("0x666f6f6fed", "0x666f6f6fed")]
+fn test_disassemble(#[case] input: &str, #[case] expected: &str) {
+    use crate::test_ops::parse_exp;
+
+    let mut a = Allocator::new();
+    let (node, rest) = parse_exp(&mut a, input);
+    assert_eq!(rest, "");
+    assert_eq!(disassemble(&a, node), expected);
+
+    if let SExp::Pair(first, rest) = a.sexp(node) {
+        assert_eq!(disassemble_list(&a, first, rest), expected);
+    }
+}
+
+#[cfg(test)]
+#[rstest]
+#[case("(q . 1)", "(1 . 1)")]
+#[case("(0x71 . 1)", "(q . 1)")]
+#[case("(0x61 (0x71 . 1) 1)", "(a (q . 1) 1)")]
+#[case("(1 2 3)", "(1 2 3)")]
+#[case("(c (0x71 . \"foo\") (f 1))", "(c (q . \"foo\") (f 1))")]
+fn test_disassemble_with_dialect(#[case] input: &str, #[case] expected: &str) {
+    use crate::collections::HashMap;
+    use crate::runtime_dialect::RuntimeDialect;
+    use crate::test_ops::parse_exp;
+
+    let mut a = Allocator::new();
+    let dialect = RuntimeDialect::new(HashMap::new(), vec![b'q'], vec![b'a'], 0);
+    let (node, rest) = parse_exp(&mut a, input);
+    assert_eq!(rest, "");
+    assert_eq!(disassemble_with_dialect(&a, &dialect, node), expected);
+}
+
+#[test]
+fn test_disassemble_budget() {
+    use crate::test_ops::parse_exp;
+
+    let mut a = Allocator::new();
+    // a tree with 2^1000 leaves, sharing its sub trees
+    let (mut node, _) = parse_exp(&mut a, "\"foobar\"");
+    for _ in 0..1000 {
+        node = a.new_pair(node, node).unwrap();
+    }
+    let budget = DisassembleBudget {
+        max_nodes: 1000,
+        max_output_len: 100,
+    };
+    let out = disassemble_with_budget(&a, node, budget);
+    assert_eq!(out.len(), 100 + TRUNCATED.len());
+    assert!(out.starts_with("((((((((((("));
+    assert!(out.ends_with(TRUNCATED));
+
+    // the node limit can kick in before the output limit
+    let budget = DisassembleBudget {
+        max_nodes: 10,
+        max_output_len: 1000,
+    };
+    let out = disassemble_with_budget(&a, node, budget);
+    assert!(out.len() < 1000);
+    assert!(out.ends_with(TRUNCATED));
+
+    // the default budget applies to Display
+    let out = Disassembly::new(&a, node).to_string();
+    let default = DisassembleBudget::default();
+    assert!(out.len() <= default.max_output_len + TRUNCATED.len());
+    assert!(out.ends_with(TRUNCATED));
+    let out = format!("{}", Disassembly::new(&a, node).with_budget(budget));
+    assert_eq!(out, disassemble_with_budget(&a, node, budget));
+
+    // a large atom
+    let atom = a.new_atom(&[0xcc; 100000]).unwrap();
+    let budget = DisassembleBudget {
+        max_nodes: 1000,
+        max_output_len: 10,
+    };
+    assert_eq!(disassemble_with_budget(&a, atom, budget), "0xcccccccc...");
+
+    // the output isn't truncated in the middle of a multi-byte character
+    let atom = a.new_str(&"\u{2603}".repeat(100)).unwrap();
+    let budget = DisassembleBudget {
+        max_nodes: 1000,
+        max_output_len: 11,
+    };
+    assert_eq!(
+        disassemble_with_budget(&a, atom, budget),
+        "\"\u{2603}\u{2603}\u{2603}..."
+    );
+}
+
+#[test]
+fn test_disassemble_within_budget() {
+    use crate::test_ops::parse_exp;
+
+    let mut a = Allocator::new();
+    let (node, _) = parse_exp(&mut a, "(c (q . \"foo\") (f 1))");
+    let expected = "(c (q . \"foo\") (f 1))";
+    // 6 pairs and 7 atoms, including the nil terminators of the 3 lists
+    for (max_nodes, max_output_len) in [(100, 100), (13, 100), (100, expected.len())] {
+        let budget = DisassembleBudget {
+            max_nodes,
+            max_output_len,
+        };
+        assert_eq!(disassemble_with_budget(&a, node, budget), expected);
+    }
+    let budget = DisassembleBudget {
+        max_nodes: 12,
+        max_output_len: 100,
+    };
+    assert!(disassemble_with_budget(&a, node, budget).ends_with(TRUNCATED));
+    let budget = DisassembleBudget {
+        max_nodes: 100,
+        max_output_len: expected.len() - 1,
+    };
+    assert_eq!(
+        disassemble_with_budget(&a, node, budget),
+        format!("{}...", &expected[..expected.len() - 1])
+    );
+    assert_eq!(Disassembly::new(&a, node).to_string(), expected);
+}
+
+#[test]
+fn test_display_node() {
+    use crate::test_ops::parse_exp;
+
+    let mut a = Allocator::new();
+    let (node, _) = parse_exp(&mut a, "(c (q . \"foo\") (f 1))");
+    assert_eq!(
+        format!("{}", DisplayNode(&a, node)),
+        "(c (q . \"foo\") (f 1))"
+    );
+
+    // a tree with 2^1000 leaves is cut off after 200 characters
+    let (mut node, _) = parse_exp(&mut a, "\"foobar\"");
+    for _ in 0..1000 {
+        node = a.new_pair(node, node).unwrap();
+    }
+    let out = DisplayNode(&a, node).to_string();
+    assert_eq!(out.len(), DISPLAY_NODE_LEN + TRUNCATED.len());
+    assert!(out.starts_with("(((((((((("));
+    assert!(out.ends_with(TRUNCATED));
+}
@@ -0,0 +1,335 @@
+use alloc::vec::Vec;
+
+use crate::allocator::{Allocator, NodePtr};
+use crate::chik_dialect::{ExtensionTable, OpDescriptor};
+use crate::cost::Cost;
+use crate::dialect::{Dialect, OperatorSet};
+use crate::reduction::Response;
+
+/// Softfork extensions added to a dialect at runtime. They are described by
+/// `ExtensionTable`s, the same way `ChikDialect` describes its own extensions
+/// (see `CHIK_EXTENSIONS`). The operators of an extension are only available
+/// inside a `softfork` guard passing its id, unless it has been activated
+/// outside of the guard. This allows new batches of operators to be shipped
+/// ahead of time, and activated by id.
+#[derive(Default, Clone)]
+pub struct ExtensionRegistry {
+    extensions: Vec<ExtensionTable>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add `extension`. Registering an extension id a second time replaces
+    /// the extension
+    pub fn register(&mut self, extension: ExtensionTable) {
+        self.extensions.retain(|ext| ext.id != extension.id);
+        self.extensions.push(extension);
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn get(&self, id: u32) -> Option<&ExtensionTable> {
+        self.extensions.iter().find(|ext| ext.id == id)
+    }
+}
+
+/// A dialect with the extensions of an `ExtensionRegistry` added to it. The
+/// extension ids in the registry take precedence over the softfork extensions
+/// of the underlying dialect. Inside a softfork guard enabling a registered
+/// extension, its operators are available in addition to the default operators
+/// of the underlying dialect. `flags` decides which of the operators are
+/// enabled, and which extensions are activated outside of the guard, like the
+/// flags of `ChikDialect`. Any other opcode is handled by the underlying
+/// dialect, including its rules for unknown operators.
+pub struct ExtendedDialect<D: Dialect> {
+    dialect: D,
+    registry: ExtensionRegistry,
+    flags: u32,
+}
+
+impl<D: Dialect> ExtendedDialect<D> {
+    pub fn new(dialect: D, registry: ExtensionRegistry, flags: u32) -> Self {
+        Self {
+            dialect,
+            registry,
+            flags,
+        }
+    }
+
+    pub fn registry(&self) -> &ExtensionRegistry {
+        &self.registry
+    }
+
+    // the registered operator `o` refers to with `extension` enabled, if any.
+    // Extension operators are single-byte opcodes
+    fn lookup(
+        &self,
+        allocator: &Allocator,
+        o: NodePtr,
+        extension: OperatorSet,
+    ) -> Option<&OpDescriptor> {
+        if allocator.atom_len(o) != 1 {
+            return None;
+        }
+        let opcode = allocator.atom(o).as_ref()[0];
+        self.registry
+            .extensions
+            .iter()
+            .filter(|ext| {
+                ext.operator_set == extension || (self.flags & ext.outside_guard_flag) != 0
+            })
+            .find_map(|ext| ext.lookup(opcode))
+            .filter(|op| (self.flags & op.flags) == op.flags)
+    }
+
+    // the operator set to pass on to the underlying dialect. It doesn't know
+    // about the registered extensions, inside their guards only its default
+    // operators are available
+    fn underlying(&self, extension: OperatorSet) -> OperatorSet {
+        if self
+            .registry
+            .extensions
+            .iter()
+            .any(|ext| ext.operator_set == extension)
+        {
+            OperatorSet::Default
+        } else {
+            extension
+        }
+    }
+}
+
+impl<D: Dialect> Dialect for ExtendedDialect<D> {
+    fn op(
+        &self,
+        allocator: &mut Allocator,
+        o: NodePtr,
+        argument_list: NodePtr,
+        max_cost: Cost,
+        extension: OperatorSet,
+    ) -> Response {
+        if let Some(op) = self.lookup(allocator, o, extension) {
+            return (op.f)(allocator, argument_list, max_cost);
+        }
+        self.dialect.op(
+            allocator,
+            o,
+            argument_list,
+            max_cost,
+            self.underlying(extension),
+        )
+    }
+
+    fn quote_kw(&self) -> u32 {
+        self.dialect.quote_kw()
+    }
+    fn apply_kw(&self) -> u32 {
+        self.dialect.apply_kw()
+    }
+    fn softfork_kw(&self) -> u32 {
+        self.dialect.softfork_kw()
+    }
+
+    fn softfork_extension(&self, ext: u32) -> OperatorSet {
+        match self.registry.get(ext) {
+            Some(ext) => ext.operator_set,
+            None => self.dialect.softfork_extension(ext),
+        }
+    }
+
+    fn allow_unknown_ops(&self) -> bool {
+        self.dialect.allow_unknown_ops()
+    }
+
+    fn op_min_cost(&self, allocator: &Allocator, o: NodePtr, extension: OperatorSet) -> Cost {
+        match self.lookup(allocator, o, extension) {
+            Some(op) => op.base_cost,
+            None => self
+                .dialect
+                .op_min_cost(allocator, o, self.underlying(extension)),
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::chik_dialect::{ChikDialect, NO_UNKNOWN_OPS};
+#[cfg(test)]
+use crate::err_utils::err;
+#[cfg(test)]
+use crate::op_utils::get_args;
+#[cfg(test)]
+use crate::reduction::Reduction;
+#[cfg(test)]
+use crate::run_program::run_program;
+#[cfg(test)]
+use crate::test_ops::parse_exp;
+
+// fails unless its only argument is 42
+#[cfg(test)]
+fn op_expect_42(a: &mut Allocator, args: NodePtr, _max_cost: Cost) -> Response {
+    let [n] = get_args::<1>(a, args, "expect_42")?;
+    if a.small_number(n) != Some(42) {
+        return err(n, "expected 42");
+    }
+    Ok(Reduction(10, a.nil()))
+}
+
+#[cfg(test)]
+fn op_noop(a: &mut Allocator, _args: NodePtr, _max_cost: Cost) -> Response {
+    Ok(Reduction(1, a.nil()))
+}
+
+// activates extension 7 outside of the softfork guard
+#[cfg(test)]
+const ENABLE_EXT7_OUTSIDE_GUARD: u32 = 0x8000_0000;
+
+#[cfg(test)]
+const EXT7: ExtensionTable = ExtensionTable {
+    id: 7,
+    operator_set: OperatorSet::Extension(7),
+    outside_guard_flag: ENABLE_EXT7_OUTSIDE_GUARD,
+    ops: &[OpDescriptor {
+        opcode: 200,
+        name: "expect_42",
+        f: op_expect_42,
+        base_cost: 10,
+        flags: 0,
+    }],
+};
+
+#[cfg(test)]
+const EXT8: ExtensionTable = ExtensionTable {
+    id: 8,
+    operator_set: OperatorSet::Extension(8),
+    outside_guard_flag: 0,
+    ops: &[OpDescriptor {
+        opcode: 201,
+        name: "noop",
+        f: op_noop,
+        base_cost: 0,
+        flags: 0,
+    }],
+};
+
+#[cfg(test)]
+fn run(dialect: &impl Dialect, program: &str) -> Result<(), String> {
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, program);
+    let nil = a.nil();
+    run_program(&mut a, dialect, program, nil, 100000)
+        .map(|_| ())
+        .map_err(|e| e.1)
+}
+
+#[test]
+fn test_extension_registry() {
+    let mut registry = ExtensionRegistry::new();
+    registry.register(EXT7);
+    registry.register(EXT8);
+    assert!(registry.contains(7));
+    assert!(!registry.contains(9));
+    assert_eq!(
+        registry.get(7).unwrap().lookup(200).unwrap().name,
+        "expect_42"
+    );
+    assert!(registry.get(7).unwrap().lookup(201).is_none());
+    assert!(registry.get(8).unwrap().lookup(200).is_none());
+
+    let dialect = ExtendedDialect::new(
+        ChikDialect::new(NO_UNKNOWN_OPS),
+        registry.clone(),
+        NO_UNKNOWN_OPS,
+    );
+
+    // the expected cost is 140 (the guard) + 20 (quote) + 1 (the operator
+    // call) + 10 (expect_42)
+    assert_eq!(
+        run(
+            &dialect,
+            "(softfork (q . 171) (q . 7) (q 0xc8 (q . 42)) (q . ()))"
+        ),
+        Ok(())
+    );
+    // the operator is called
+    assert_eq!(
+        run(
+            &dialect,
+            "(softfork (q . 171) (q . 7) (q 0xc8 (q . 41)) (q . ()))"
+        ),
+        Err("expected 42".to_string())
+    );
+    // the default operators are still available. + costs 755, and its
+    // operator call and two quotes cost another 41
+    assert_eq!(
+        run(
+            &dialect,
+            "(softfork (q . 947) (q . 7) (q 0xc8 (+ (q . 40) (q . 2))) (q . ()))"
+        ),
+        Ok(())
+    );
+
+    // the operator is unknown in other extensions, and outside of the guard
+    assert_eq!(
+        run(
+            &dialect,
+            "(softfork (q . 171) (q . 8) (q 0xc8 (q . 42)) (q . ()))"
+        ),
+        Err("unimplemented operator".to_string())
+    );
+    assert_eq!(
+        run(&dialect, "(0xc8 (q . 42))"),
+        Err("unimplemented operator".to_string())
+    );
+    // and so is the non-canonical encoding of the opcode
+    assert_eq!(
+        run(
+            &dialect,
+            "(softfork (q . 171) (q . 7) (q 0x00c8 (q . 42)) (q . ()))"
+        ),
+        Err("unimplemented operator".to_string())
+    );
+
+    // unregistered extensions are handled by the underlying dialect
+    assert_eq!(
+        run(
+            &dialect,
+            "(softfork (q . 171) (q . 9) (q 0xc8 (q . 42)) (q . ()))"
+        ),
+        Err("unknown softfork extension".to_string())
+    );
+    // the BLS extension
+    assert_eq!(
+        run(
+            &dialect,
+            "(softfork (q . 160) (q . 0) (q . (q . 42)) (q . ()))"
+        ),
+        Ok(())
+    );
+
+    // the base cost of the operator is known ahead of calling it
+    let mut a = Allocator::new();
+    let o = a.new_atom(&[0xc8]).unwrap();
+    assert_eq!(dialect.op_min_cost(&a, o, OperatorSet::Extension(7)), 10);
+    assert_eq!(dialect.op_min_cost(&a, o, OperatorSet::Default), 0);
+
+    // once activated outside the guard, the operators are available everywhere
+    let dialect = ExtendedDialect::new(
+        ChikDialect::new(NO_UNKNOWN_OPS),
+        registry,
+        NO_UNKNOWN_OPS | ENABLE_EXT7_OUTSIDE_GUARD,
+    );
+    assert_eq!(run(&dialect, "(0xc8 (q . 42))"), Ok(()));
+    assert_eq!(
+        run(&dialect, "(0xc8 (q . 41))"),
+        Err("expected 42".to_string())
+    );
+    assert_eq!(
+        run(&dialect, "(0xc9 (q . 42))"),
+        Err("unimplemented operator".to_string())
+    );
+}
@@ -1,27 +1,47 @@
-use std::collections::HashMap;
+// the dispatch tables of the Chik dialect need the BLS operators, which need
+// std. Without it, only the table types and base costs are available
+#![cfg_attr(not(feature = "std"), allow(unused_imports))]
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::collections::HashMap;
 
 use crate::allocator::{Allocator, NodePtr};
+#[cfg(feature = "std")]
 use crate::bls_ops::{
     op_bls_g1_multiply, op_bls_g1_negate, op_bls_g1_subtract, op_bls_g2_add, op_bls_g2_multiply,
     op_bls_g2_negate, op_bls_g2_subtract, op_bls_map_to_g1, op_bls_map_to_g2,
     op_bls_pairing_identity, op_bls_verify,
 };
+#[cfg(feature = "std")]
+use crate::chik_dialect::CHIK_EXTENSIONS;
+use crate::chik_dialect::{ExtensionTable, ENABLE_FIXED_DIV};
 use crate::core_ops::{op_cons, op_eq, op_first, op_if, op_listp, op_raise, op_rest};
 use crate::cost::Cost;
 use crate::more_ops::{
-    op_add, op_all, op_any, op_ash, op_concat, op_div, op_divmod, op_gr, op_gr_bytes, op_logand,
-    op_logior, op_lognot, op_logxor, op_lsh, op_mod, op_modpow, op_multiply, op_not, op_point_add,
-    op_pubkey_for_exp, op_sha256, op_strlen, op_substr, op_subtract,
+    op_add, op_all, op_any, op_ash, op_concat, op_div, op_div_fixed, op_divmod, op_gr, op_gr_bytes,
+    op_logand, op_logior, op_lognot, op_logxor, op_lsh, op_mod, op_modpow, op_multiply, op_not,
+    op_sha256, op_sha256_tagged, op_strlen, op_substr, op_subtract, DIVMOD_BASE_COST,
+    DIV_BASE_COST, POINT_ADD_BASE_COST, PUBKEY_BASE_COST, SHA256_BASE_COST,
 };
+#[cfg(feature = "std")]
+use crate::more_ops::{op_point_add, op_pubkey_for_exp};
 use crate::reduction::Response;
 use crate::secp_ops::{op_secp256k1_verify, op_secp256r1_verify};
 
-type OpFn = fn(&mut Allocator, NodePtr, Cost) -> Response;
+pub type OpFn = fn(&mut Allocator, NodePtr, Cost) -> Response;
 
 pub type FLookup = [Option<OpFn>; 256];
 
+/// The base cost of each single-byte operator, indexed by opcode. See
+/// `Dialect::op_min_cost()`. Operators without a significant base cost, and
+/// unknown operators, are 0
+pub type CostLookup = [Cost; 256];
+
+#[cfg(feature = "std")]
 pub fn opcode_by_name(name: &str) -> Option<OpFn> {
-    let opcode_lookup: [(OpFn, &str); 44] = [
+    let opcode_lookup: [(OpFn, &str); 45] = [
         (op_if, "op_if"),
         (op_cons, "op_cons"),
         (op_first, "op_first"),
@@ -30,6 +50,7 @@ pub fn opcode_by_name(name: &str) -> Option<OpFn> {
         (op_raise, "op_raise"),
         (op_eq, "op_eq"),
         (op_sha256, "op_sha256"),
+        (op_sha256_tagged, "op_sha256_tagged"),
         (op_add, "op_add"),
         (op_subtract, "op_subtract"),
         (op_multiply, "op_multiply"),
@@ -77,6 +98,7 @@ pub fn opcode_by_name(name: &str) -> Option<OpFn> {
     None
 }
 
+#[cfg(feature = "std")]
 pub fn f_lookup_for_hashmap(opcode_lookup_by_name: HashMap<String, Vec<u8>>) -> FLookup {
     let mut f_lookup = [None; 256];
     for (name, idx) in opcode_lookup_by_name.iter() {
@@ -89,3 +111,116 @@ pub fn f_lookup_for_hashmap(opcode_lookup_by_name: HashMap<String, Vec<u8>>) ->
     }
     f_lookup
 }
+
+// the core single-byte operators of the Chik dialect, given the flags.
+// Operators that are disabled by the flags (and the special forms quote, apply
+// and softfork) are None. The operators of softfork extensions are in their
+// ExtensionTable
+#[cfg(feature = "std")]
+fn chik_op(op: u8, flags: u32) -> Option<OpFn> {
+    let f: OpFn = match op {
+        // 1 = quote
+        // 2 = apply
+        3 => op_if,
+        4 => op_cons,
+        5 => op_first,
+        6 => op_rest,
+        7 => op_listp,
+        8 => op_raise,
+        9 => op_eq,
+        10 => op_gr_bytes,
+        11 => op_sha256,
+        12 => op_substr,
+        13 => op_strlen,
+        14 => op_concat,
+        // 15 ---
+        16 => op_add,
+        17 => op_subtract,
+        18 => op_multiply,
+        19 => {
+            if (flags & ENABLE_FIXED_DIV) != 0 {
+                op_div_fixed
+            } else {
+                op_div
+            }
+        }
+        20 => op_divmod,
+        21 => op_gr,
+        22 => op_ash,
+        23 => op_lsh,
+        24 => op_logand,
+        25 => op_logior,
+        26 => op_logxor,
+        27 => op_lognot,
+        // 28 ---
+        29 => op_point_add,
+        30 => op_pubkey_for_exp,
+        // 31 ---
+        32 => op_not,
+        33 => op_any,
+        34 => op_all,
+        // 35 ---
+        // 36 = softfork
+        _ => {
+            return None;
+        }
+    };
+    Some(f)
+}
+
+// the cost every invocation of a core single-byte operator is charged,
+// regardless of its arguments. This is only filled in for operators where it's
+// significant, the rest are 0. The base costs of the operators of softfork
+// extensions are in their ExtensionTable
+pub fn chik_op_base_cost(op: u8) -> Cost {
+    match op {
+        11 => SHA256_BASE_COST,
+        19 => DIV_BASE_COST,
+        20 => DIVMOD_BASE_COST,
+        29 => POINT_ADD_BASE_COST,
+        30 => PUBKEY_BASE_COST,
+        _ => 0,
+    }
+}
+
+// build the dispatch table for single-byte operators of the Chik dialect,
+// outside of any softfork guard. This is done once, when the dialect is
+// constructed, to avoid branching on the opcode for every operator invocation
+#[cfg(feature = "std")]
+pub fn f_lookup_for_flags(flags: u32) -> FLookup {
+    f_lookup_with_extensions(flags, &CHIK_EXTENSIONS)
+}
+
+// like f_lookup_for_flags(), with the operators of a different set of
+// extensions available outside of the guard, if their flag is set
+#[cfg(feature = "std")]
+pub fn f_lookup_with_extensions(flags: u32, extensions: &[ExtensionTable]) -> FLookup {
+    let mut f_lookup = [None; 256];
+    for (op, entry) in f_lookup.iter_mut().enumerate() {
+        *entry = chik_op(op as u8, flags);
+    }
+    for ext in extensions {
+        if (flags & ext.outside_guard_flag) != 0 {
+            ext.add_to(&mut f_lookup, flags);
+        }
+    }
+    f_lookup
+}
+
+// the base costs of the operators in the dispatch table built by
+// f_lookup_with_extensions()
+#[cfg(feature = "std")]
+pub fn base_cost_with_extensions(flags: u32, extensions: &[ExtensionTable]) -> CostLookup {
+    let mut base_cost = [0; 256];
+    for (op, entry) in base_cost.iter_mut().enumerate() {
+        if chik_op(op as u8, flags).is_some() {
+            *entry = chik_op_base_cost(op as u8);
+        }
+    }
+    for ext in extensions {
+        if (flags & ext.outside_guard_flag) != 0 {
+            ext.add_costs_to(&mut base_cost, flags);
+        }
+    }
+    base_cost
+}
@@ -0,0 +1,159 @@
+use crate::allocator::{Allocator, NodePtr};
+use crate::io;
+use crate::serde::node_from_bytes;
+use core::fmt;
+
+// Compressed block generators refer to puzzles in previous generators (the
+// block references) by index and byte range. The compression ROM extracts the
+// range with substr and deserializes it. These helpers do the same in rust.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefError {
+    // the block reference index is not in the list of block references
+    NoSuchRef(usize),
+    // these mirror the substr operator's bounds checks
+    StartOutOfBounds,
+    EndOutOfBounds,
+    InvalidBounds,
+}
+
+impl fmt::Display for RefError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSuchRef(idx) => write!(f, "no block reference at index {idx}"),
+            Self::StartOutOfBounds => write!(f, "block reference start out of bounds"),
+            Self::EndOutOfBounds => write!(f, "block reference end out of bounds"),
+            Self::InvalidBounds => write!(f, "block reference invalid bounds"),
+        }
+    }
+}
+
+impl core::error::Error for RefError {}
+
+/// Returns the bytes `[start, end)` of `block_ref`, with the same bounds checks
+/// as `(substr block_ref start end)`.
+pub fn extract_ref_slice(block_ref: &[u8], start: u32, end: u32) -> Result<&[u8], RefError> {
+    let len = block_ref.len();
+    if start as usize > len {
+        return Err(RefError::StartOutOfBounds);
+    }
+    if end as usize > len {
+        return Err(RefError::EndOutOfBounds);
+    }
+    if end < start {
+        return Err(RefError::InvalidBounds);
+    }
+    Ok(&block_ref[start as usize..end as usize])
+}
+
+/// Like `extract_ref_slice()`, but picks the block reference from the list of
+/// block references passed to the generator.
+pub fn extract_from_refs<R: AsRef<[u8]>>(
+    block_refs: &[R],
+    index: usize,
+    start: u32,
+    end: u32,
+) -> Result<&[u8], RefError> {
+    let block_ref = block_refs.get(index).ok_or(RefError::NoSuchRef(index))?;
+    extract_ref_slice(block_ref.as_ref(), start, end)
+}
+
+/// Deserialize a puzzle extracted by `extract_ref_slice()`. The slice is
+/// expected to hold a puzzle in the regular (non back-reference) serialization.
+/// Any bytes following the puzzle are ignored, the same as `node_from_bytes()`.
+pub fn decompress_puzzle(a: &mut Allocator, ref_slice: &[u8]) -> io::Result<NodePtr> {
+    node_from_bytes(a, ref_slice)
+}
+
+#[cfg(test)]
+use crate::serde::node_to_bytes;
+#[cfg(test)]
+use crate::test_ops::{node_eq, parse_exp};
+
+// a stand-in for a previous block's generator: a list of spends, each with a
+// puzzle. The offsets of the puzzles are known
+#[cfg(test)]
+fn fixture(a: &mut Allocator) -> (Vec<u8>, NodePtr, u32, u32) {
+    let (puzzle, _) = parse_exp(
+        a,
+        "(a (q 2 (i 11 (q 8) (q 4 (c 5 ()))) 1) (c (q . 0x1337) 1))",
+    );
+    let puzzle_bytes = node_to_bytes(a, puzzle).unwrap();
+    // (q . ((coin_id puzzle amount solution)))
+    let mut block_ref = vec![0xff, 0x01, 0xff, 0xff, 0xa0];
+    block_ref.extend_from_slice(&[0x42; 32]);
+    block_ref.push(0xff);
+    let start = block_ref.len() as u32;
+    block_ref.extend_from_slice(&puzzle_bytes);
+    let end = block_ref.len() as u32;
+    block_ref.extend_from_slice(&[0xff, 0x64, 0xff, 0x80, 0x80, 0x80]);
+    (block_ref, puzzle, start, end)
+}
+
+#[test]
+fn test_extract_ref() {
+    let mut a = Allocator::new();
+    let (block_ref, puzzle, start, end) = fixture(&mut a);
+    assert_eq!(start, 38);
+
+    let slice = extract_ref_slice(&block_ref, start, end).unwrap();
+    let decompressed = decompress_puzzle(&mut a, slice).unwrap();
+    assert!(node_eq(&a, decompressed, puzzle));
+
+    let refs = [vec![0x80], block_ref.clone()];
+    let slice = extract_from_refs(&refs, 1, start, end).unwrap();
+    let decompressed = decompress_puzzle(&mut a, slice).unwrap();
+    assert!(node_eq(&a, decompressed, puzzle));
+
+    // the whole block reference, and the empty slice at its end
+    let len = block_ref.len() as u32;
+    assert_eq!(extract_ref_slice(&block_ref, 0, len).unwrap(), block_ref);
+    assert!(extract_ref_slice(&block_ref, len, len).unwrap().is_empty());
+
+    // a slice that's one byte short is truncated
+    let slice = extract_ref_slice(&block_ref, start, end - 1).unwrap();
+    assert!(decompress_puzzle(&mut a, slice).is_err());
+
+    // a slice that starts one byte late is a different tree (or invalid)
+    let slice = extract_ref_slice(&block_ref, start + 1, end).unwrap();
+    if let Ok(n) = decompress_puzzle(&mut a, slice) {
+        assert!(!node_eq(&a, n, puzzle));
+    }
+}
+
+#[test]
+fn test_extract_ref_out_of_range() {
+    let mut a = Allocator::new();
+    let (block_ref, _, start, end) = fixture(&mut a);
+    let len = block_ref.len() as u32;
+
+    assert_eq!(
+        extract_ref_slice(&block_ref, len + 1, len + 1),
+        Err(RefError::StartOutOfBounds)
+    );
+    assert_eq!(
+        extract_ref_slice(&block_ref, start, len + 1),
+        Err(RefError::EndOutOfBounds)
+    );
+    assert_eq!(
+        extract_ref_slice(&block_ref, 0, u32::MAX),
+        Err(RefError::EndOutOfBounds)
+    );
+    assert_eq!(
+        extract_ref_slice(&block_ref, end, start),
+        Err(RefError::InvalidBounds)
+    );
+    assert_eq!(
+        extract_from_refs(&[block_ref], 1, start, end),
+        Err(RefError::NoSuchRef(1))
+    );
+    let no_refs: [&[u8]; 0] = [];
+    assert_eq!(
+        extract_from_refs(&no_refs, 0, 0, 0),
+        Err(RefError::NoSuchRef(0))
+    );
+    assert_eq!(
+        RefError::EndOutOfBounds.to_string(),
+        "block reference end out of bounds"
+    );
+}
@@ -1,26 +1,67 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 pub mod allocator;
+#[cfg(feature = "std")]
+pub mod allocator_pool;
+#[cfg(feature = "std")]
+pub mod block_generator;
 pub mod bls_ops;
+pub mod bytes;
 pub mod chik_dialect;
+pub mod conditions;
 pub mod core_ops;
 pub mod cost;
 pub mod dialect;
+#[cfg(feature = "std")]
+pub mod dialect_config;
+pub mod disassemble;
 pub mod err_utils;
+pub mod extension_registry;
 pub mod f_table;
+pub mod generator_refs;
+pub mod io;
+pub mod match_pattern;
+pub mod merkle;
 pub mod more_ops;
 pub mod number;
 pub mod op_utils;
+pub mod partial_eval;
+#[cfg(feature = "std")]
+pub mod program;
 pub mod reduction;
 pub mod run_program;
+#[cfg(feature = "std")]
 pub mod runtime_dialect;
 pub mod secp_ops;
 pub mod serde;
 pub mod sha2;
+#[cfg(feature = "std")]
+pub mod test_vector;
+#[cfg(feature = "trace")]
+pub mod trace;
 pub mod traverse_path;
 
+// std's hash maps aren't available without std
+pub(crate) mod collections {
+    #[cfg(not(feature = "std"))]
+    pub use hashbrown::{HashMap, HashSet};
+    #[cfg(feature = "std")]
+    pub use std::collections::{HashMap, HashSet};
+}
+
 pub use allocator::{Allocator, Atom, NodePtr, SExp};
-pub use chik_dialect::ChikDialect;
 pub use run_program::run_program;
 
+// the Chik dialects need the BLS operators, which need std. Without it, they
+// are left out rather than built with operators that fail, which would reject
+// programs the consensus rules accept
+#[cfg(feature = "std")]
+pub use chik_dialect::ChikDialect;
+#[cfg(feature = "std")]
+pub use dialect_config::{run_program_dialect, DialectConfig};
+
 pub use chik_dialect::{
     ENABLE_BLS_OPS_OUTSIDE_GUARD, ENABLE_FIXED_DIV, LIMIT_HEAP, MEMPOOL_MODE, NO_UNKNOWN_OPS,
 };
@@ -34,6 +75,15 @@ pub use run_program::run_program_with_pre_eval;
 #[cfg(feature = "counters")]
 pub use run_program::Counters;
 
+#[cfg(feature = "traceback")]
+pub use run_program::{format_traceback, run_program_with_traceback, TracebackErr};
+
+#[cfg(feature = "memoize")]
+pub use run_program::run_program_with_memoization;
+
+#[cfg(feature = "metrics")]
+pub use run_program::{run_program_with_metrics, EvalMetrics};
+
 #[cfg(test)]
 mod tests;
 
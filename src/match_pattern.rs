@@ -0,0 +1,350 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::allocator::{Allocator, NodePtr, SExp};
+use crate::collections::HashMap;
+
+// atoms in a pattern starting with this byte (and followed by at least one
+// more byte) are capture slots. The remaining bytes are the name of the
+// capture
+const CAPTURE_PREFIX: u8 = b'$';
+
+fn capture_name(a: &Allocator, node: NodePtr) -> Option<Vec<u8>> {
+    let atom = a.atom(node);
+    match atom.as_ref() {
+        [CAPTURE_PREFIX, name @ ..] if !name.is_empty() => Some(name.to_vec()),
+        _ => None,
+    }
+}
+
+/// Match `target` against `pattern`. Pairs in the pattern must match pairs in
+/// the target, and atoms must match exactly, except for capture slots. Atoms
+/// of the form `$name` in the pattern match any sub tree in the target, which
+/// is returned in the map, keyed by `name`. If the same name is used more than
+/// once, all the captured sub trees must be equal. Returns None if the target
+/// doesn't match.
+pub fn match_pattern(
+    a: &Allocator,
+    pattern: NodePtr,
+    target: NodePtr,
+) -> Option<HashMap<Vec<u8>, NodePtr>> {
+    let mut captures = HashMap::<Vec<u8>, NodePtr>::new();
+    let mut stack = vec![(pattern, target)];
+    while let Some((p, t)) = stack.pop() {
+        match a.sexp(p) {
+            SExp::Pair(p1, p2) => match a.sexp(t) {
+                SExp::Pair(t1, t2) => {
+                    stack.push((p2, t2));
+                    stack.push((p1, t1));
+                }
+                SExp::Atom => {
+                    return None;
+                }
+            },
+            SExp::Atom => {
+                if let Some(name) = capture_name(a, p) {
+                    if let Some(prev) = captures.get(&name) {
+                        if !a.structural_eq(*prev, t) {
+                            return None;
+                        }
+                    } else {
+                        captures.insert(name, t);
+                    }
+                } else if !t.is_atom() || !a.atom_eq(p, t) {
+                    return None;
+                }
+            }
+        }
+    }
+    Some(captures)
+}
+
+/// Identifies a capture in a `Pattern`
+pub type CaptureId = u32;
+
+/// A pattern built in Rust, as opposed to the KLVM patterns passed to
+/// `match_pattern()`. This can express things those can't, like matching any
+/// atom but not a pair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern {
+    /// matches any sub tree
+    Any,
+    /// matches an atom with exactly these bytes
+    Atom(Vec<u8>),
+    /// matches any atom
+    AnyAtom,
+    /// matches a pair whose first and rest match the two patterns
+    Pair(Box<Pattern>, Box<Pattern>),
+    /// matches what the inner pattern matches, and captures the sub tree
+    Capture(CaptureId, Box<Pattern>),
+}
+
+impl Pattern {
+    pub fn pair(first: Pattern, rest: Pattern) -> Self {
+        Self::Pair(Box::new(first), Box::new(rest))
+    }
+
+    pub fn capture(id: CaptureId, inner: Pattern) -> Self {
+        Self::Capture(id, Box::new(inner))
+    }
+}
+
+/// Match `node` against `pattern`. Returns the sub trees captured by
+/// `Pattern::Capture`, keyed by their ID, or None if the node doesn't match.
+/// If the same ID is used more than once, all the captured sub trees must be
+/// equal.
+pub fn match_node(
+    a: &Allocator,
+    node: NodePtr,
+    pattern: &Pattern,
+) -> Option<HashMap<CaptureId, NodePtr>> {
+    let mut captures = HashMap::<CaptureId, NodePtr>::new();
+    let mut stack = vec![(pattern, node)];
+    while let Some((p, n)) = stack.pop() {
+        match p {
+            Pattern::Any => {}
+            Pattern::Atom(bytes) => {
+                if !n.is_atom() || a.atom(n).as_ref() != bytes.as_slice() {
+                    return None;
+                }
+            }
+            Pattern::AnyAtom => {
+                if !n.is_atom() {
+                    return None;
+                }
+            }
+            Pattern::Pair(p1, p2) => {
+                let SExp::Pair(n1, n2) = a.sexp(n) else {
+                    return None;
+                };
+                stack.push((p2, n2));
+                stack.push((p1, n1));
+            }
+            Pattern::Capture(id, inner) => {
+                if let Some(prev) = captures.get(id) {
+                    if !a.structural_eq(*prev, n) {
+                        return None;
+                    }
+                } else {
+                    captures.insert(*id, n);
+                }
+                stack.push((inner, n));
+            }
+        }
+    }
+    Some(captures)
+}
+
+#[cfg(test)]
+use crate::test_ops::{node_eq, parse_exp};
+
+// the standard transaction puzzle (p2_delegated_puzzle_or_hidden_puzzle),
+// serialized
+#[cfg(test)]
+const STANDARD_PUZZLE_HEX: &str =
+    include_str!("../tests/puzzles/p2_delegated_puzzle_or_hidden_puzzle.hex");
+
+// a curried puzzle has the form: (a (q . MOD) (c (q . ARG1) ... 1))
+#[cfg(test)]
+fn curry_one(a: &mut Allocator, module: NodePtr, arg: NodePtr) -> NodePtr {
+    let q = a.one();
+    let nil = a.nil();
+    let apply = a.new_atom(&[2]).unwrap();
+    let cons = a.new_atom(&[4]).unwrap();
+    let quoted_mod = a.new_pair(q, module).unwrap();
+    let quoted_arg = a.new_pair(q, arg).unwrap();
+    let env = a.new_pair(q, nil).unwrap();
+    let env = a.new_pair(quoted_arg, env).unwrap();
+    let env = a.new_pair(cons, env).unwrap();
+    let rest = a.new_pair(env, nil).unwrap();
+    let rest = a.new_pair(quoted_mod, rest).unwrap();
+    a.new_pair(apply, rest).unwrap()
+}
+
+#[test]
+fn test_match_curried_puzzle() {
+    use crate::serde::{node_from_bytes, tree_hash};
+
+    let mut a = Allocator::new();
+    let bytes = hex::decode(STANDARD_PUZZLE_HEX.trim()).expect("hex");
+    let module = node_from_bytes(&mut a, &bytes).expect("node_from_bytes");
+    assert_eq!(
+        hex::encode(tree_hash(&a, module).unwrap()),
+        "e9aaa49f45bad5c889b86ee3341550c155cfdd10c3a6757de618d20612fffd52"
+    );
+    let pk = a.new_atom(&[0xa6; 48]).unwrap();
+    let puzzle = curry_one(&mut a, module, pk);
+
+    // capture both the module and the curried key
+    let (pattern, _) = parse_exp(&mut a, "(a (q . \"$mod\") (c (q . \"$pk\") 1))");
+    let captures = match_pattern(&a, pattern, puzzle).expect("match");
+    assert_eq!(captures.len(), 2);
+    assert!(node_eq(&a, captures[b"mod".as_slice()], module));
+    assert_eq!(a.atom(captures[b"pk".as_slice()]).as_ref(), [0xa6; 48]);
+
+    // pin the module as a literal, capturing only the key
+    let pk_slot = a.new_atom(b"$pk").unwrap();
+    let pattern = curry_one(&mut a, module, pk_slot);
+    let captures = match_pattern(&a, pattern, puzzle).expect("match");
+    assert_eq!(captures.len(), 1);
+    assert_eq!(a.atom(captures[b"pk".as_slice()]).as_ref(), [0xa6; 48]);
+
+    // a different module doesn't match
+    let (other_mod, _) = parse_exp(&mut a, "(2 (i 11 (q 8) (q 8)) 1)");
+    let other = curry_one(&mut a, other_mod, pk);
+    assert_eq!(match_pattern(&a, pattern, other), None);
+}
+
+#[test]
+fn test_match_near_misses() {
+    let mut a = Allocator::new();
+    let (pattern, _) = parse_exp(&mut a, "(a (q . \"$mod\") (c (q . \"$pk\") 1))");
+
+    for target in [
+        // not curried
+        "(a (q . 1) 1)",
+        // curried with two arguments
+        "(a (q . 1) (c (q . 0x1337) (c (q . 0x4242) 1)))",
+        // the environment isn't passed through
+        "(a (q . 1) (c (q . 0x1337) 2))",
+        // a different operator
+        "(i (q . 1) (c (q . 0x1337) 1))",
+        // an atom instead of a pair
+        "0x1337",
+        "()",
+        // a list that's too long
+        "(a (q . 1) (c (q . 0x1337) 1) 1)",
+    ] {
+        let (t, _) = parse_exp(&mut a, target);
+        assert_eq!(match_pattern(&a, pattern, t), None, "{target}");
+    }
+
+    let (t, _) = parse_exp(&mut a, "(a (q . 1) (c (q . 0x1337) 1))");
+    let captures = match_pattern(&a, pattern, t).unwrap();
+    assert_eq!(a.atom(captures[b"mod".as_slice()]).as_ref(), [1]);
+    assert_eq!(a.atom(captures[b"pk".as_slice()]).as_ref(), [0x13, 0x37]);
+}
+
+#[test]
+fn test_match_repeated_capture() {
+    let mut a = Allocator::new();
+    let (pattern, _) = parse_exp(&mut a, "(\"$x\" \"$x\" \"$\")");
+
+    let (t, _) = parse_exp(&mut a, "((1 2) (1 2) \"$\")");
+    let captures = match_pattern(&a, pattern, t).unwrap();
+    let (expected, _) = parse_exp(&mut a, "(1 2)");
+    assert!(node_eq(&a, captures[b"x".as_slice()], expected));
+
+    // the same capture must match the same sub tree
+    let (t, _) = parse_exp(&mut a, "((1 2) (1 3) \"$\")");
+    assert_eq!(match_pattern(&a, pattern, t), None);
+
+    // "$" on its own is a literal
+    let (t, _) = parse_exp(&mut a, "((1 2) (1 2) 1)");
+    assert_eq!(match_pattern(&a, pattern, t), None);
+}
+
+#[test]
+fn test_match_deep() {
+    // build a pattern and a target nested deeper than we could recurse
+    let mut a = Allocator::new();
+    let (capture, _) = parse_exp(&mut a, "\"$leaf\"");
+    let leaf = a.new_atom(b"leaf").unwrap();
+    let nil = a.nil();
+    let mut pattern = capture;
+    let mut target = leaf;
+    for _ in 0..1_000_000 {
+        pattern = a.new_pair(pattern, nil).unwrap();
+        target = a.new_pair(target, nil).unwrap();
+    }
+    let captures = match_pattern(&a, pattern, target).unwrap();
+    assert_eq!(captures[b"leaf".as_slice()], leaf);
+
+    let one = a.one();
+    let target = a.new_pair(target, one).unwrap();
+    let pattern = a.new_pair(pattern, nil).unwrap();
+    assert_eq!(match_pattern(&a, pattern, target), None);
+}
+
+#[test]
+fn test_match_node_pair() {
+    let mut a = Allocator::new();
+    let pattern = Pattern::pair(
+        Pattern::capture(0, Pattern::AnyAtom),
+        Pattern::capture(1, Pattern::Any),
+    );
+
+    // (a . b)
+    let (t, _) = parse_exp(&mut a, "(0x1337 . (1 2))");
+    let captures = match_node(&a, t, &pattern).unwrap();
+    assert_eq!(captures.len(), 2);
+    assert_eq!(a.atom(captures[&0]).as_ref(), [0x13, 0x37]);
+    let (expected, _) = parse_exp(&mut a, "(1 2)");
+    assert!(node_eq(&a, captures[&1], expected));
+
+    // the first element must be an atom
+    let (t, _) = parse_exp(&mut a, "((1 . 2) . 3)");
+    assert_eq!(match_node(&a, t, &pattern), None);
+    // and the node must be a pair
+    let (t, _) = parse_exp(&mut a, "0x1337");
+    assert_eq!(match_node(&a, t, &pattern), None);
+}
+
+#[test]
+fn test_match_node_curried() {
+    let mut a = Allocator::new();
+    // (a (q . MOD) (c (q . ARG) 1))
+    let list = |items: Vec<Pattern>| {
+        items
+            .into_iter()
+            .rev()
+            .fold(Pattern::Atom(vec![]), |rest, item| {
+                Pattern::pair(item, rest)
+            })
+    };
+    let quoted = |inner: Pattern| Pattern::pair(Pattern::Atom(vec![1]), inner);
+    let pattern = list(vec![
+        Pattern::Atom(vec![2]),
+        quoted(Pattern::capture(0, Pattern::Any)),
+        list(vec![
+            Pattern::Atom(vec![4]),
+            quoted(Pattern::capture(1, Pattern::AnyAtom)),
+            Pattern::Atom(vec![1]),
+        ]),
+    ]);
+
+    let (t, _) = parse_exp(&mut a, "(a (q 2 (i 11 (q 8) (q 8)) 1) (c (q . 0x1337) 1))");
+    let captures = match_node(&a, t, &pattern).unwrap();
+    let (module, _) = parse_exp(&mut a, "(2 (i 11 (q 8) (q 8)) 1)");
+    assert!(node_eq(&a, captures[&0], module));
+    assert_eq!(a.atom(captures[&1]).as_ref(), [0x13, 0x37]);
+
+    for target in [
+        // the argument is a pair
+        "(a (q . 1) (c (q 1 2) 1))",
+        // the environment isn't passed through
+        "(a (q . 1) (c (q . 0x1337) 2))",
+        // a list that's too long
+        "(a (q . 1) (c (q . 0x1337) 1) 1)",
+        "()",
+    ] {
+        let (t, _) = parse_exp(&mut a, target);
+        assert_eq!(match_node(&a, t, &pattern), None, "{target}");
+    }
+}
+
+#[test]
+fn test_match_node_repeated_capture() {
+    let mut a = Allocator::new();
+    let pattern = Pattern::pair(
+        Pattern::capture(7, Pattern::Any),
+        Pattern::capture(7, Pattern::Any),
+    );
+    let (t, _) = parse_exp(&mut a, "((1 2) . (1 2))");
+    let captures = match_node(&a, t, &pattern).unwrap();
+    assert_eq!(captures.len(), 1);
+
+    let (t, _) = parse_exp(&mut a, "((1 2) . (1 3))");
+    assert_eq!(match_node(&a, t, &pattern), None);
+}
@@ -0,0 +1,217 @@
+use alloc::vec::Vec;
+
+use crate::bytes::Bytes32;
+use crate::serde::{hash_atom, hash_pair};
+
+// a leaf is hashed like an atom and an inner node like a pair, so the root of
+// a tree is the tree hash of the KLVM structure with the same shape, with the
+// leaves as atoms. That lets a puzzle verify a proof with the sha256 operator
+fn leaf_hash(leaf: &Bytes32) -> Bytes32 {
+    hash_atom(leaf.as_ref()).into()
+}
+
+fn node_hash(left: &Bytes32, right: &Bytes32) -> Bytes32 {
+    hash_pair(left, right).into()
+}
+
+// the number of leaves in the left subtree of a tree with `len` leaves. The
+// left subtree gets the extra leaf when `len` is odd
+fn left_len(len: usize) -> usize {
+    len.div_ceil(2)
+}
+
+fn subtree_hash(leaves: &[Bytes32]) -> Bytes32 {
+    if leaves.len() == 1 {
+        return leaf_hash(&leaves[0]);
+    }
+    let (left, right) = leaves.split_at(left_len(leaves.len()));
+    node_hash(&subtree_hash(left), &subtree_hash(right))
+}
+
+/// A Merkle tree over a list of 32 byte values (e.g. tree hashes of KLVM
+/// values). The list is split in half recursively, the left half getting the
+/// extra leaf when the length is odd.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    leaves: Vec<Bytes32>,
+    root: Bytes32,
+}
+
+impl MerkleTree {
+    /// panics if `hashes` is empty
+    pub fn from_leaves(hashes: &[Bytes32]) -> Self {
+        assert!(!hashes.is_empty(), "a Merkle tree needs at least one leaf");
+        Self {
+            leaves: hashes.to_vec(),
+            root: subtree_hash(hashes),
+        }
+    }
+
+    pub fn root(&self) -> Bytes32 {
+        self.root
+    }
+
+    pub fn leaves(&self) -> &[Bytes32] {
+        &self.leaves
+    }
+
+    /// The proof for the leaf at `index`, from the leaf up to the root. Each
+    /// entry is the hash of the sibling, and whether the sibling is on the
+    /// right. Returns None if the index is out of bounds
+    pub fn proof_for(&self, index: usize) -> Option<Vec<(Bytes32, bool)>> {
+        if index >= self.leaves.len() {
+            return None;
+        }
+        let mut proof = Vec::new();
+        let (mut start, mut end) = (0, self.leaves.len());
+        while end - start > 1 {
+            let mid = start + left_len(end - start);
+            if index < mid {
+                proof.push((subtree_hash(&self.leaves[mid..end]), true));
+                end = mid;
+            } else {
+                proof.push((subtree_hash(&self.leaves[start..mid]), false));
+                start = mid;
+            }
+        }
+        proof.reverse();
+        Some(proof)
+    }
+}
+
+/// Check that `leaf` is in the tree with the specified root
+pub fn verify_proof(root: &Bytes32, leaf: &Bytes32, proof: &[(Bytes32, bool)]) -> bool {
+    let hash = proof
+        .iter()
+        .fold(leaf_hash(leaf), |hash, (sibling, is_right)| {
+            if *is_right {
+                node_hash(&hash, sibling)
+            } else {
+                node_hash(sibling, &hash)
+            }
+        });
+    hash == *root
+}
+
+#[cfg(test)]
+use crate::allocator::{Allocator, NodePtr};
+#[cfg(test)]
+use crate::serde::tree_hash;
+#[cfg(test)]
+use crate::sha2::Sha256;
+
+#[cfg(test)]
+fn test_leaves(count: usize) -> Vec<Bytes32> {
+    (0..count)
+        .map(|i| {
+            let mut ctx = Sha256::new();
+            ctx.update((i as u32).to_be_bytes());
+            ctx.finalize().into()
+        })
+        .collect()
+}
+
+// the KLVM structure with the same shape as the tree, with the leaves as
+// atoms
+#[cfg(test)]
+fn to_node(a: &mut Allocator, leaves: &[Bytes32]) -> NodePtr {
+    if leaves.len() == 1 {
+        return a.new_atom_b32(&leaves[0]).unwrap();
+    }
+    let (left, right) = leaves.split_at(left_len(leaves.len()));
+    let left = to_node(a, left);
+    let right = to_node(a, right);
+    a.new_pair(left, right).unwrap()
+}
+
+#[test]
+fn test_merkle_tree() {
+    for count in 1..20 {
+        let leaves = test_leaves(count);
+        let tree = MerkleTree::from_leaves(&leaves);
+
+        // the root is the tree hash of the same structure
+        let mut a = Allocator::new();
+        let node = to_node(&mut a, &leaves);
+        assert_eq!(tree.root(), tree_hash(&a, node).unwrap());
+
+        for (i, leaf) in leaves.iter().enumerate() {
+            let proof = tree.proof_for(i).unwrap();
+            assert!(verify_proof(&tree.root(), leaf, &proof));
+            // the proof is for this leaf only
+            let other = &leaves[(i + 1) % count];
+            assert_eq!(verify_proof(&tree.root(), other, &proof), count == 1);
+            // and it's a balanced tree
+            assert!(proof.len() <= count.next_power_of_two().trailing_zeros() as usize);
+        }
+        assert_eq!(tree.proof_for(count), None);
+    }
+}
+
+#[test]
+fn test_merkle_proof_tampered() {
+    let leaves = test_leaves(5);
+    let tree = MerkleTree::from_leaves(&leaves);
+    let proof = tree.proof_for(3).unwrap();
+
+    let mut bad = proof.clone();
+    bad[0].1 = !bad[0].1;
+    assert!(!verify_proof(&tree.root(), &leaves[3], &bad));
+
+    let mut bad = proof.clone();
+    bad[1].0 = leaves[0];
+    assert!(!verify_proof(&tree.root(), &leaves[3], &bad));
+
+    assert!(!verify_proof(&tree.root(), &leaves[3], &proof[1..]));
+}
+
+// verifies a proof with the sha256 operator. The arguments are
+// (root leaf proof), where proof is a list of (sibling . is_right)
+#[cfg(test)]
+const VERIFY_PUZZLE: &str = "(= 2 (a (q . LOOP) (c (q . LOOP) (c (sha256 (q . 1) 5) (c 11 ())))))";
+
+// the environment is (LOOP hash proof)
+#[cfg(test)]
+const VERIFY_LOOP: &str = "(a (i 11 (q . (a 2 (c 2 (c NEXT (c (r 11) ()))))) (q . 5)) 1)";
+
+#[cfg(test)]
+const VERIFY_NEXT: &str =
+    "(a (i (r (f 11)) (q . (sha256 (q . 2) 5 (f (f 11)))) (q . (sha256 (q . 2) (f (f 11)) 5))) 1)";
+
+#[test]
+fn test_merkle_proof_klvm() {
+    use crate::chik_dialect::ChikDialect;
+    use crate::reduction::Reduction;
+    use crate::run_program::run_program;
+    use crate::test_ops::parse_exp;
+
+    let puzzle = VERIFY_PUZZLE.replace("LOOP", &VERIFY_LOOP.replace("NEXT", VERIFY_NEXT));
+    let leaves = test_leaves(7);
+    let tree = MerkleTree::from_leaves(&leaves);
+
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, &puzzle);
+
+    let mut run = |root: &Bytes32, leaf: &Bytes32, proof: &[(Bytes32, bool)]| -> bool {
+        let mut proof_list = a.nil();
+        for (sibling, is_right) in proof.iter().rev() {
+            let sibling = a.new_atom_b32(sibling).unwrap();
+            let is_right = if *is_right { a.one() } else { a.nil() };
+            let item = a.new_pair(sibling, is_right).unwrap();
+            proof_list = a.new_pair(item, proof_list).unwrap();
+        }
+        let root = a.new_atom_b32(root).unwrap();
+        let leaf = a.new_atom_b32(leaf).unwrap();
+        let nil = a.nil();
+        let args = a.new_pair_many(&[root, leaf, proof_list], nil).unwrap();
+        let Reduction(_, result) =
+            run_program(&mut a, &ChikDialect::new(0), program, args, 11000000000).unwrap();
+        a.atom_len(result) != 0
+    };
+
+    for (i, leaf) in leaves.iter().enumerate() {
+        let proof = tree.proof_for(i).unwrap();
+        assert!(run(&tree.root(), leaf, &proof));
+        assert!(!run(&tree.root(), &leaves[(i + 1) % leaves.len()], &proof));
+    }
+}
@@ -1,20 +1,26 @@
+use alloc::format;
+use alloc::vec::Vec;
+
+use core::ops::BitAndAssign;
+use core::ops::BitOrAssign;
+use core::ops::BitXorAssign;
 use hex_literal::hex;
 use num_bigint::{BigUint, Sign};
 use num_integer::Integer;
-use std::ops::BitAndAssign;
-use std::ops::BitOrAssign;
-use std::ops::BitXorAssign;
 
-use crate::allocator::{len_for_value, Allocator, NodePtr, NodeVisitor, SExp};
+use crate::allocator::{len_for_value, Allocator, NodePtr, NodeVisitor};
 use crate::cost::{check_cost, Cost};
 use crate::err_utils::err;
-use crate::number::Number;
+use crate::number::{node_from_i128, Number};
+#[cfg(feature = "std")]
+use crate::op_utils::mod_group_order;
 use crate::op_utils::{
-    atom, atom_len, get_args, get_varargs, i32_atom, int_atom, match_args, mod_group_order,
+    atom, atom_len, get_args, get_varargs, i32_atom, i64_from_u8, int_atom, match_args,
     new_atom_and_cost, nilp, u32_from_u8, MALLOC_COST_PER_BYTE,
 };
-use crate::reduction::{Reduction, Response};
+use crate::reduction::{EvalErr, Reduction, Response};
 use crate::sha2::Sha256;
+#[cfg(feature = "std")]
 use chik_bls::G1Element;
 
 const ARITH_BASE_COST: Cost = 99;
@@ -46,13 +52,13 @@ const CONCAT_BASE_COST: Cost = 142;
 const CONCAT_COST_PER_ARG: Cost = 135;
 const CONCAT_COST_PER_BYTE: Cost = 3;
 
-const DIVMOD_BASE_COST: Cost = 1116;
+pub(crate) const DIVMOD_BASE_COST: Cost = 1116;
 const DIVMOD_COST_PER_BYTE: Cost = 6;
 
-const DIV_BASE_COST: Cost = 988;
+pub(crate) const DIV_BASE_COST: Cost = 988;
 const DIV_COST_PER_BYTE: Cost = 4;
 
-const SHA256_BASE_COST: Cost = 87;
+pub(crate) const SHA256_BASE_COST: Cost = 87;
 const SHA256_COST_PER_ARG: Cost = 134;
 const SHA256_COST_PER_BYTE: Cost = 2;
 
@@ -69,25 +75,27 @@ const BOOL_COST_PER_ARG: Cost = 300;
 // in the point_add benchmark
 
 // increased from 31592 to better model Raspberry PI
-const POINT_ADD_BASE_COST: Cost = 101094;
+pub(crate) const POINT_ADD_BASE_COST: Cost = 101094;
 // increased from 419994 to better model Raspberry PI
+#[cfg(feature = "std")]
 const POINT_ADD_COST_PER_ARG: Cost = 1343980;
 
 // Raspberry PI 4 is about 2.833543 / 0.447859 = 6.32686 times slower
 // in the pubkey benchmark
 
 // increased from 419535 to better model Raspberry PI
-const PUBKEY_BASE_COST: Cost = 1325730;
+pub(crate) const PUBKEY_BASE_COST: Cost = 1325730;
 // increased from 12 to closer model Raspberry PI
+#[cfg(feature = "std")]
 const PUBKEY_COST_PER_BYTE: Cost = 38;
 
 // the new coinid operator
 // we subtract 153 cost as a discount, to incentivize using this operator rather
 // than "naked" sha256
-const COINID_COST: Cost =
+pub(crate) const COINID_COST: Cost =
     SHA256_BASE_COST + SHA256_COST_PER_ARG * 3 + SHA256_COST_PER_BYTE * (32 + 32 + 8) - 153;
 
-const MODPOW_BASE_COST: Cost = 17000;
+pub(crate) const MODPOW_BASE_COST: Cost = 17000;
 const MODPOW_COST_PER_BYTE_BASE_VALUE: Cost = 38;
 // the cost for exponent and modular scale by the square of the size of the
 // respective operands
@@ -203,7 +211,7 @@ pub fn op_unknown(
         }
     };
 
-    let mut cost = match cost_function {
+    let cost = match cost_function {
         0 => 1,
         1 => {
             let mut cost = ARITH_BASE_COST;
@@ -264,7 +272,10 @@ pub fn op_unknown(
     assert!(cost > 0);
 
     check_cost(allocator, cost, max_cost)?;
-    cost *= cost_multiplier + 1;
+    // this may overflow for very large arguments. Release builds have always
+    // wrapped here, and a wrapped cost at or below u32::MAX is accepted. This
+    // is consensus behavior, so it must not change without a soft-fork
+    let cost = cost.wrapping_mul(cost_multiplier + 1);
     if cost > u32::MAX as u64 {
         err(o, "invalid operator")
     } else {
@@ -316,6 +327,30 @@ fn test_unknown_op_reserved() {
     );
 }
 
+#[test]
+fn test_unknown_op_cost_wraps() {
+    let mut a = Allocator::new();
+
+    // cost function 2 (mul) over two 1 MiB atoms costs 8602518481. With a
+    // multiplier of 0x7fd01105 the product wraps to 2375088102, which is
+    // accepted
+    let op = a.new_atom(&[0x7f, 0xd0, 0x11, 0x05, 0x80]).unwrap();
+    let arg = a.new_atom(&vec![0x7f; 1 << 20]).unwrap();
+    let args = a.new_pair(arg, NodePtr::NIL).unwrap();
+    let args = a.new_pair(arg, args).unwrap();
+    assert_eq!(
+        op_unknown(&mut a, op, args, Cost::MAX),
+        Ok(Reduction(2375088102, NodePtr::NIL))
+    );
+
+    // one less on the multiplier doesn't wrap, and is above u32::MAX
+    let op = a.new_atom(&[0x7f, 0xd0, 0x11, 0x04, 0x80]).unwrap();
+    assert_eq!(
+        op_unknown(&mut a, op, args, Cost::MAX).unwrap_err().1,
+        "invalid operator"
+    );
+}
+
 #[test]
 fn test_lenient_mode_last_bits() {
     let mut a = crate::allocator::Allocator::new();
@@ -380,7 +415,7 @@ pub const PRECOMPUTED_HASHES: [[u8; 32]; 37] = [
     hex!("3f2d2a889d22530bd1abdc40ff1cbb23ca53ae3f1983e58c70d46a15c120e780"),
 ];
 
-pub fn op_sha256(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Response {
+pub fn op_sha256(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
     let mut cost = SHA256_BASE_COST;
 
     if let Some([v0, v1]) = match_args::<2>(a, input) {
@@ -397,28 +432,144 @@ pub fn op_sha256(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Respo
         }
     }
 
-    let mut byte_count: usize = 0;
+    hash_args(a, input, cost, max_cost, "sha256")
+}
+
+/// The sha256 of the concatenation of the atoms `args`, without building the
+/// concatenation. Fails if any of them is a pair. No cost is charged
+pub fn sha256_atoms(
+    a: &Allocator,
+    args: impl Iterator<Item = NodePtr>,
+) -> Result<[u8; 32], EvalErr> {
     let mut hasher = Sha256::new();
-    while let Some((arg, rest)) = a.next(input) {
-        input = rest;
+    for arg in args {
+        hasher.update(atom(a, arg, "sha256")?);
+    }
+    Ok(hasher.finalize())
+}
+
+// hashes the concatenation of all atoms in the argument list. The cost is
+// charged (and the arguments validated) before any hashing is done
+fn hash_args(
+    a: &mut Allocator,
+    input: NodePtr,
+    mut cost: Cost,
+    max_cost: Cost,
+    name: &str,
+) -> Response {
+    let mut byte_count: usize = 0;
+    let mut args = input;
+    while let Some((arg, rest)) = a.next(args) {
+        args = rest;
         cost += SHA256_COST_PER_ARG;
         check_cost(
             a,
             cost + byte_count as Cost * SHA256_COST_PER_BYTE,
             max_cost,
         )?;
-        let blob = atom(a, arg, "sha256")?;
-        byte_count += blob.as_ref().len();
-        hasher.update(blob);
+        let Some(len) = a.try_atom_len(arg) else {
+            return err(arg, &format!("{name} on list"));
+        };
+        byte_count += len;
     }
     cost += byte_count as Cost * SHA256_COST_PER_BYTE;
-    new_atom_and_cost(a, cost, &hasher.finalize())
+
+    let mut args = input;
+    let digest = sha256_atoms(
+        a,
+        core::iter::from_fn(|| {
+            let (arg, rest) = a.next(args)?;
+            args = rest;
+            Some(arg)
+        }),
+    )?;
+    new_atom_and_cost(a, cost, &digest)
+}
+
+// (sha256_tagged tag data...) hashes the tag followed by the data atoms. The
+// result and cost are the same as for (sha256 tag data...), but the tag can't
+// be left out
+pub fn op_sha256_tagged(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
+    match a.next(input) {
+        Some((_, data)) if a.next(data).is_some() => {}
+        _ => {
+            return err(
+                input,
+                "sha256_tagged takes a tag and at least 1 data argument",
+            );
+        }
+    }
+    hash_args(a, input, SHA256_BASE_COST, max_cost, "sha256_tagged")
+}
+
+// the running total of the arithmetic operators. Operands are usually small,
+// so the total is kept in an i128 as long as every operand fits in 8 bytes and
+// the total doesn't overflow. Otherwise it falls back to Number. The result
+// (and the cost) is the same either way
+enum Total {
+    Small(i128),
+    Big(Number),
+}
+
+impl Total {
+    fn new(fast_path: bool, v: i128) -> Self {
+        if fast_path {
+            Self::Small(v)
+        } else {
+            Self::Big(v.into())
+        }
+    }
+
+    // combine the total with the atom `arg`, returning the length of the atom
+    fn apply(
+        &mut self,
+        a: &Allocator,
+        arg: NodePtr,
+        small_op: fn(i128, i128) -> Option<i128>,
+        big_op: fn(&mut Number, Number),
+    ) -> usize {
+        let (operand, len) = match a.node(arg) {
+            NodeVisitor::Buffer(buf) => (i64_from_u8(buf), buf.len()),
+            NodeVisitor::U32(val) => (Some(val as i64), len_for_value(val)),
+            NodeVisitor::Pair(_, _) => panic!("expected atom"),
+        };
+        if let Self::Small(total) = *self {
+            if let Some(v) = operand.and_then(|v| small_op(total, v.into())) {
+                *self = Self::Small(v);
+                return len;
+            }
+            *self = Self::Big(total.into());
+        }
+        if let Self::Big(total) = self {
+            big_op(total, a.number(arg));
+        }
+        len
+    }
+
+    // the same as limbs_for_int()
+    fn limbs(&self) -> usize {
+        match self {
+            Self::Small(v) => (128 - v.unsigned_abs().leading_zeros() as usize).div_ceil(8),
+            Self::Big(v) => limbs_for_int(v),
+        }
+    }
+
+    fn into_node(self, a: &mut Allocator) -> Result<NodePtr, EvalErr> {
+        match self {
+            Self::Small(v) => node_from_i128(a, v),
+            Self::Big(v) => a.new_number(v),
+        }
+    }
+}
+
+pub fn op_add(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
+    op_add_impl(a, input, max_cost, true)
 }
 
-pub fn op_add(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Response {
+fn op_add_impl(a: &mut Allocator, mut input: NodePtr, max_cost: Cost, fast_path: bool) -> Response {
     let mut cost = ARITH_BASE_COST;
     let mut byte_count: usize = 0;
-    let mut total: Number = 0.into();
+    let mut total = Total::new(fast_path, 0);
     while let Some((arg, rest)) = a.next(input) {
         input = rest;
         cost += ARITH_COST_PER_ARG;
@@ -427,98 +578,82 @@ pub fn op_add(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Response
             cost + (byte_count as Cost * ARITH_COST_PER_BYTE),
             max_cost,
         )?;
-
-        match a.node(arg) {
-            NodeVisitor::Buffer(buf) => {
-                use crate::number::number_from_u8;
-                total += number_from_u8(buf);
-                byte_count += buf.len();
-            }
-            NodeVisitor::U32(val) => {
-                total += val;
-                byte_count += len_for_value(val);
-            }
-            NodeVisitor::Pair(_, _) => {
-                return err(arg, "+ requires int args");
-            }
+        if arg.is_pair() {
+            return err(arg, "+ requires int args");
         }
+        byte_count += total.apply(a, arg, i128::checked_add, |t, v| *t += v);
     }
-    let total = a.new_number(total)?;
+    let total = total.into_node(a)?;
     cost += byte_count as Cost * ARITH_COST_PER_BYTE;
     Ok(malloc_cost(a, cost, total))
 }
 
-pub fn op_subtract(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Response {
+pub fn op_subtract(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
+    op_subtract_impl(a, input, max_cost, true)
+}
+
+fn op_subtract_impl(
+    a: &mut Allocator,
+    mut input: NodePtr,
+    max_cost: Cost,
+    fast_path: bool,
+) -> Response {
     let mut cost = ARITH_BASE_COST;
     let mut byte_count: usize = 0;
-    let mut total: Number = 0.into();
+    let mut total = Total::new(fast_path, 0);
     let mut is_first = true;
     while let Some((arg, rest)) = a.next(input) {
         input = rest;
         cost += ARITH_COST_PER_ARG;
         check_cost(a, cost + byte_count as Cost * ARITH_COST_PER_BYTE, max_cost)?;
-        if is_first {
-            let (v, len) = int_atom(a, arg, "-")?;
-            byte_count = len;
-            total = v;
+        if arg.is_pair() {
+            return err(arg, "- requires int args");
+        }
+        byte_count += if is_first {
+            total.apply(a, arg, i128::checked_add, |t, v| *t += v)
         } else {
-            match a.node(arg) {
-                NodeVisitor::Buffer(buf) => {
-                    use crate::number::number_from_u8;
-                    total -= number_from_u8(buf);
-                    byte_count += buf.len();
-                }
-                NodeVisitor::U32(val) => {
-                    total -= val;
-                    byte_count += len_for_value(val);
-                }
-                NodeVisitor::Pair(_, _) => {
-                    return err(arg, "- requires int args");
-                }
-            }
+            total.apply(a, arg, i128::checked_sub, |t, v| *t -= v)
         };
         is_first = false;
     }
-    let total = a.new_number(total)?;
+    let total = total.into_node(a)?;
     cost += byte_count as Cost * ARITH_COST_PER_BYTE;
     Ok(malloc_cost(a, cost, total))
 }
 
-pub fn op_multiply(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Response {
+pub fn op_multiply(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response {
+    op_multiply_impl(a, input, max_cost, true)
+}
+
+fn op_multiply_impl(
+    a: &mut Allocator,
+    mut input: NodePtr,
+    max_cost: Cost,
+    fast_path: bool,
+) -> Response {
     let mut cost: Cost = MUL_BASE_COST;
     let mut first_iter: bool = true;
-    let mut total: Number = 1.into();
+    let mut total = Total::new(fast_path, 1);
     let mut l0: usize = 0;
     while let Some((arg, rest)) = a.next(input) {
         input = rest;
         check_cost(a, cost, max_cost)?;
+        if arg.is_pair() {
+            return err(arg, "* requires int args");
+        }
+        let l1 = total.apply(a, arg, i128::checked_mul, |t, v| *t *= v);
         if first_iter {
-            (total, l0) = int_atom(a, arg, "*")?;
+            l0 = l1;
             first_iter = false;
             continue;
         }
 
-        let l1 = match a.node(arg) {
-            NodeVisitor::Buffer(buf) => {
-                use crate::number::number_from_u8;
-                total *= number_from_u8(buf);
-                buf.len()
-            }
-            NodeVisitor::U32(val) => {
-                total *= val;
-                len_for_value(val)
-            }
-            NodeVisitor::Pair(_, _) => {
-                return err(arg, "* requires int args");
-            }
-        };
-
         cost += MUL_COST_PER_OP;
         cost += (l0 + l1) as Cost * MUL_LINEAR_COST_PER_BYTE;
         cost += (l0 * l1) as Cost / MUL_SQUARE_COST_PER_BYTE_DIVIDER;
-        l0 = limbs_for_int(&total);
+        l0 = total.limbs();
     }
-    let total = a.new_number(total)?;
+    let total = total.into_node(a)?;
     Ok(malloc_cost(a, cost, total))
 }
 
@@ -566,7 +701,7 @@ pub fn op_divmod(a: &mut Allocator, input: NodePtr, _max_cost: Cost) -> Response
         let r1 = a.new_number(r)?;
 
         let c = (a.atom_len(q1) + a.atom_len(r1)) as Cost * MALLOC_COST_PER_BYTE;
-        let r: NodePtr = a.new_pair(q1, r1)?;
+        let r: NodePtr = a.new_small_pair(q1, r1)?;
         Ok(Reduction(cost + c, r))
     }
 }
@@ -628,18 +763,88 @@ pub fn op_substr(a: &mut Allocator, input: NodePtr, _max_cost: Cost) -> Response
     }
     let size = atom_len(a, a0, "substr")?;
     let start = i32_atom(a, start, "substr")?;
-
     let end = if argc == 3 {
         i32_atom(a, end, "substr")?
     } else {
+        // the two argument form extends to the end of the atom. For atoms
+        // larger than i32::MAX bytes, this wraps to a negative end, which
+        // fails below. That's part of consensus
         size as i32
     };
-    if end < 0 || start < 0 || end as usize > size || end < start {
-        err(input, "invalid indices for substr")
-    } else {
-        let r = a.new_substr(a0, start as u32, end as u32)?;
-        let cost: Cost = 1;
-        Ok(Reduction(cost, r))
+
+    // negative indices fail the conversion
+    let (Ok(start), Ok(end)) = (usize::try_from(start), usize::try_from(end)) else {
+        return err(input, "invalid indices for substr");
+    };
+    if end > size || end < start {
+        return err(input, "invalid indices for substr");
+    }
+    let r = a.new_substr(a0, start as u32, end as u32)?;
+    let cost: Cost = 1;
+    Ok(Reduction(cost, r))
+}
+
+#[cfg(test)]
+use crate::allocator::SExp;
+#[cfg(test)]
+use crate::chik_dialect::{
+    ChikDialect, ENABLE_BLS_OPS_OUTSIDE_GUARD, ENABLE_SHA256_TAGGED, NO_UNKNOWN_OPS,
+};
+#[cfg(test)]
+use crate::run_program::run_program;
+#[cfg(test)]
+use crate::test_ops::{node_eq, parse_exp};
+#[cfg(test)]
+use rstest::rstest;
+
+#[cfg(test)]
+#[rstest]
+// in range
+#[case(&[0, 5], Some("hello"))]
+#[case(&[1, 4], Some("ell"))]
+#[case(&[1], Some("ello"))]
+#[case(&[0], Some("hello"))]
+// start == end
+#[case(&[0, 0], Some(""))]
+#[case(&[3, 3], Some(""))]
+#[case(&[5, 5], Some(""))]
+#[case(&[5], Some(""))]
+// start > end
+#[case(&[3, 2], None)]
+#[case(&[6], None)]
+#[case(&[6, 5], None)]
+// end > len
+#[case(&[0, 6], None)]
+#[case(&[6, 6], None)]
+#[case(&[0, 0x7fffffff], None)]
+// negative indices
+#[case(&[-1], None)]
+#[case(&[-1, 5], None)]
+#[case(&[0, -1], None)]
+#[case(&[-2, -1], None)]
+#[case(&[-0x80000000, 5], None)]
+fn test_substr_bounds(#[case] indices: &[i64], #[case] expected: Option<&str>) {
+    let mut a = Allocator::new();
+    let mut args = a.nil();
+    for idx in indices.iter().rev() {
+        let v = a.new_number((*idx).into()).unwrap();
+        args = a.new_pair(v, args).unwrap();
+    }
+    let s = a.new_atom(b"hello").unwrap();
+    args = a.new_pair(s, args).unwrap();
+
+    match (op_substr(&mut a, args, 100000), expected) {
+        (Ok(Reduction(cost, r)), Some(expected)) => {
+            assert_eq!(a.atom(r).as_ref(), expected.as_bytes());
+            assert_eq!(cost, 1);
+        }
+        (Err(e), None) => {
+            assert_eq!(e.0, args);
+            assert_eq!(e.1, "invalid indices for substr");
+        }
+        (r, _) => {
+            panic!("unexpected substr result: {r:?}");
+        }
     }
 }
 
@@ -655,10 +860,10 @@ pub fn op_concat(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Respo
             cost + total_size as Cost * CONCAT_COST_PER_BYTE,
             max_cost,
         )?;
-        match a.sexp(arg) {
-            SExp::Pair(_, _) => return err(arg, "concat on list"),
-            SExp::Atom => total_size += a.atom_len(arg),
+        let Some(len) = a.try_atom_len(arg) else {
+            return err(arg, "concat on list");
         };
+        total_size += len;
         terms.push(arg);
     }
 
@@ -669,6 +874,215 @@ pub fn op_concat(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Respo
     Ok(Reduction(cost, new_atom))
 }
 
+#[cfg(test)]
+#[rstest]
+#[case("(\"tag\" \"foo\")")]
+#[case("(\"tag\" \"foo\" \"bar\")")]
+#[case("(() 0x1337)")]
+#[case("(\"tag\" ())")]
+#[case("(0xcafef00d 1 2 3 4 5 6 7 8 9 10)")]
+fn test_sha256_tagged(#[case] env: &str) {
+    // sha256_tagged is equivalent to hashing the concatenation of its
+    // arguments in the program
+    let mut a = Allocator::new();
+    let dialect = ChikDialect::new(ENABLE_BLS_OPS_OUTSIDE_GUARD | ENABLE_SHA256_TAGGED);
+    let (env, _) = parse_exp(&mut a, env);
+
+    // (sha256_tagged (q . tag) (q . data)...) and
+    // (sha256 (concat (q . tag) (q . data)...))
+    let quote = a.one();
+    let nil = a.nil();
+    let args: Vec<NodePtr> = a.list_iter(env).unwrap().collect();
+    let mut quoted = Vec::new();
+    for arg in args {
+        quoted.push(a.new_pair(quote, arg).unwrap());
+    }
+    let quoted = a.new_pair_many(&quoted, nil).unwrap();
+    let op = a.new_small_number(63).unwrap();
+    let tagged = a.new_pair(op, quoted).unwrap();
+    let op = a.new_small_number(14).unwrap();
+    let concat = a.new_pair(op, quoted).unwrap();
+    let concat = a.new_pair(concat, nil).unwrap();
+    let op = a.new_small_number(11).unwrap();
+    let hashed = a.new_pair(op, concat).unwrap();
+
+    let Reduction(_, expected) = run_program(&mut a, &dialect, hashed, nil, 11000000000).unwrap();
+    let Reduction(_, result) = run_program(&mut a, &dialect, tagged, nil, 11000000000).unwrap();
+    assert!(node_eq(&a, result, expected));
+
+    // and costs the same as sha256 with the same arguments
+    let Reduction(cost, result) = op_sha256_tagged(&mut a, env, 11000000000).unwrap();
+    assert!(node_eq(&a, result, expected));
+    let Reduction(sha256_cost, _) = op_sha256(&mut a, env, 11000000000).unwrap();
+    assert_eq!(cost, sha256_cost);
+}
+
+#[cfg(test)]
+#[rstest]
+#[case(&[])]
+#[case(&[0])]
+#[case(&[0, 0, 0])]
+#[case(&[1])]
+#[case(&[32, 0, 1])]
+#[case(&[0, 1000, 0, 64])]
+#[case(&[55, 1, 8])]
+#[case(&[1_000_000, 0, 3_000_000])]
+fn test_sha256_atoms(#[case] sizes: &[usize]) {
+    let mut a = Allocator::new();
+    let mut concat = Vec::new();
+    let mut args = Vec::new();
+    for (i, size) in sizes.iter().enumerate() {
+        let buf: Vec<u8> = (0..*size).map(|j| (i * 7 + j) as u8).collect();
+        concat.extend_from_slice(&buf);
+        args.push(a.new_atom(&buf).unwrap());
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(&concat);
+    let expected = hasher.finalize();
+
+    assert_eq!(sha256_atoms(&a, args.iter().copied()).unwrap(), expected);
+
+    // the operator returns the same hash, at the same cost as before
+    let nil = a.nil();
+    let list = a.new_pair_many(&args, nil).unwrap();
+    let Reduction(cost, result) = op_sha256(&mut a, list, 11000000000).unwrap();
+    assert_eq!(a.atom(result).as_ref(), expected);
+    assert_eq!(
+        cost,
+        SHA256_BASE_COST
+            + sizes.len() as Cost * SHA256_COST_PER_ARG
+            + concat.len() as Cost * SHA256_COST_PER_BYTE
+            + 32 * MALLOC_COST_PER_BYTE
+    );
+}
+
+#[test]
+fn test_sha256_atoms_pair() {
+    let mut a = Allocator::new();
+    let (pair, _) = parse_exp(&mut a, "(1 . 2)");
+    let one = a.one();
+    let e = sha256_atoms(&a, [one, pair].into_iter()).unwrap_err();
+    assert_eq!(e.0, pair);
+    assert_eq!(e.1, "sha256 on list");
+}
+
+#[test]
+fn test_sha256_tagged_flags() {
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, "(sha256_tagged (q . \"tag\") (q . \"foo\"))");
+    let nil = a.nil();
+    for flags in [
+        NO_UNKNOWN_OPS,
+        NO_UNKNOWN_OPS | ENABLE_BLS_OPS_OUTSIDE_GUARD,
+        NO_UNKNOWN_OPS | ENABLE_SHA256_TAGGED,
+    ] {
+        let e = run_program(&mut a, &ChikDialect::new(flags), program, nil, 11000000000);
+        assert_eq!(e.unwrap_err().1, "unimplemented operator");
+    }
+    let dialect =
+        ChikDialect::new(NO_UNKNOWN_OPS | ENABLE_BLS_OPS_OUTSIDE_GUARD | ENABLE_SHA256_TAGGED);
+    let Reduction(_, result) = run_program(&mut a, &dialect, program, nil, 11000000000).unwrap();
+    let mut hasher = Sha256::new();
+    hasher.update(b"tagfoo");
+    assert_eq!(a.atom(result).as_ref(), hasher.finalize());
+}
+
+#[test]
+fn test_divmod_reuses_pairs() {
+    let mut a = Allocator::new();
+    let seven = a.new_small_number(7).unwrap();
+    let nil = a.nil();
+    let mut args = Vec::new();
+    let mut expected = Vec::new();
+    for i in 0..100 {
+        let n = a.new_small_number(i).unwrap();
+        let list = a.new_pair(seven, nil).unwrap();
+        args.push(a.new_pair(n, list).unwrap());
+
+        // the result and cost in an allocator of its own
+        let mut a2 = Allocator::new();
+        let n = a2.new_small_number(i).unwrap();
+        let d = a2.new_small_number(7).unwrap();
+        let nil = a2.nil();
+        let list = a2.new_pair(d, nil).unwrap();
+        let list = a2.new_pair(n, list).unwrap();
+        let Reduction(cost, r) = op_divmod(&mut a2, list, 100000).unwrap();
+        let SExp::Pair(q, r) = a2.sexp(r) else {
+            panic!("expected pair");
+        };
+        expected.push((cost, a2.small_number(q), a2.small_number(r)));
+    }
+
+    // 10000 divmods only allocate a pair per distinct result
+    let pairs = a.pair_count();
+    for i in 0..10000 {
+        let Reduction(cost, r) = op_divmod(&mut a, args[i % 100], 100000).unwrap();
+        let SExp::Pair(q, r) = a.sexp(r) else {
+            panic!("expected pair");
+        };
+        assert_eq!(
+            (cost, a.small_number(q), a.small_number(r)),
+            expected[i % 100]
+        );
+    }
+    assert_eq!(a.pair_count(), pairs + 100);
+
+    // negative results aren't small atoms, so their pairs are not reused
+    let (args, _) = parse_exp(&mut a, "(-10 3)");
+    let pairs = a.pair_count();
+    let r1 = op_divmod(&mut a, args, 100000).unwrap().1;
+    let r2 = op_divmod(&mut a, args, 100000).unwrap().1;
+    assert!(node_eq(&a, r1, r2));
+    assert_eq!(a.pair_count(), pairs + 2);
+}
+
+#[test]
+fn test_concat_many() {
+    // a mix of small atoms, heap atoms and empty atoms
+    let mut a = Allocator::new();
+    let mut expected = Vec::<u8>::new();
+    let mut args = a.nil();
+    let mut terms = Vec::new();
+    for i in 0..1000_u32 {
+        let term = match i % 4 {
+            0 => vec![],
+            1 => (i & 0x7f).to_be_bytes()[3..].to_vec(),
+            2 => i.to_be_bytes().to_vec(),
+            _ => vec![0xcc; (i % 50) as usize],
+        };
+        terms.push(term);
+    }
+    for term in terms.iter().rev() {
+        let node = a.new_atom(term).unwrap();
+        args = a.new_pair(node, args).unwrap();
+    }
+    for term in &terms {
+        expected.extend_from_slice(term);
+    }
+
+    let Reduction(cost, result) = op_concat(&mut a, args, 11000000000).unwrap();
+    assert_eq!(a.atom(result).as_ref(), expected);
+    assert_eq!(
+        cost,
+        CONCAT_BASE_COST
+            + 1000 * CONCAT_COST_PER_ARG
+            + expected.len() as Cost * (CONCAT_COST_PER_BYTE + MALLOC_COST_PER_BYTE)
+    );
+
+    // concatenating the result with itself
+    let nil = a.nil();
+    let args = a.new_pair(result, nil).unwrap();
+    let args = a.new_pair(result, args).unwrap();
+    let Reduction(_, result2) = op_concat(&mut a, args, 11000000000).unwrap();
+    assert_eq!(
+        a.atom(result2).as_ref(),
+        [&expected[..], &expected[..]].concat()
+    );
+
+    // running out of cost
+    assert_eq!(op_concat(&mut a, args, 100).unwrap_err().1, "cost exceeded");
+}
+
 pub fn op_ash(a: &mut Allocator, input: NodePtr, _max_cost: Cost) -> Response {
     let [n0, n1] = get_args::<2>(a, input, "ash")?;
     let (i0, l0) = int_atom(a, n0, "ash")?;
@@ -896,6 +1310,7 @@ pub fn op_all(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Response
     Ok(Reduction(cost, if is_all { a.one() } else { a.nil() }))
 }
 
+#[cfg(feature = "std")]
 pub fn op_pubkey_for_exp(a: &mut Allocator, input: NodePtr, _max_cost: Cost) -> Response {
     let [n] = get_args::<1>(a, input, "pubkey_for_exp")?;
     let (v0, v0_len) = int_atom(a, n, "pubkey_for_exp")?;
@@ -910,6 +1325,7 @@ pub fn op_pubkey_for_exp(a: &mut Allocator, input: NodePtr, _max_cost: Cost) ->
     ))
 }
 
+#[cfg(feature = "std")]
 pub fn op_point_add(a: &mut Allocator, mut input: NodePtr, max_cost: Cost) -> Response {
     let mut cost = POINT_ADD_BASE_COST;
     let mut total = G1Element::default();
@@ -941,7 +1357,7 @@ pub fn op_coinid(a: &mut Allocator, input: NodePtr, _max_cost: Cost) -> Response
     let amount = amount_atom.as_ref();
     if !amount.is_empty() {
         if (amount[0] & 0x80) != 0 {
-            return err(input, "coinid: invalid amount (may not be negative");
+            return err(input, "coinid: invalid amount (may not be negative)");
         }
         if amount == [0_u8] || (amount.len() > 1 && amount[0] == 0 && (amount[1] & 0x80) == 0) {
             return err(
@@ -979,10 +1395,19 @@ pub fn op_modpow(a: &mut Allocator, input: NodePtr, max_cost: Cost) -> Response
     let (base, bsize) = int_atom(a, base, "modpow")?;
     cost += bsize as Cost * MODPOW_COST_PER_BYTE_BASE_VALUE;
     let (exponent, esize) = int_atom(a, exponent, "modpow")?;
-    cost += (esize * esize) as Cost * MODPOW_COST_PER_BYTE_EXPONENT;
+    // the sizes are squared, which may overflow for (very) large atoms
+    cost = cost.saturating_add(
+        (esize as Cost)
+            .saturating_mul(esize as Cost)
+            .saturating_mul(MODPOW_COST_PER_BYTE_EXPONENT),
+    );
     check_cost(a, cost, max_cost)?;
     let (modulus, msize) = int_atom(a, modulus, "modpow")?;
-    cost += (msize * msize) as Cost * MODPOW_COST_PER_BYTE_MOD;
+    cost = cost.saturating_add(
+        (msize as Cost)
+            .saturating_mul(msize as Cost)
+            .saturating_mul(MODPOW_COST_PER_BYTE_MOD),
+    );
     check_cost(a, cost, max_cost)?;
 
     if exponent.sign() == Sign::Minus {
@@ -1039,3 +1464,162 @@ fn sha256_small_values() {
         test_sha256_atom(&[0xff, val]);
     }
 }
+
+#[test]
+fn test_arithmetic_fast_path() {
+    // the i128 fast path of the arithmetic operators must produce the same
+    // result and cost as the Number path. Operands are random, mostly up to 8
+    // bytes, with redundant leading 0x00 and 0xff bytes. Some are 9 bytes, to
+    // exercise falling back to Number
+    let mut a = Allocator::new();
+    let checkpoint = a.checkpoint();
+
+    // splitmix64
+    let mut state: u64 = 0x1337;
+    let mut rand = || {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+
+    type OpImpl = fn(&mut Allocator, NodePtr, Cost, bool) -> Response;
+    let ops: [(&str, OpImpl); 3] = [
+        ("+", op_add_impl),
+        ("-", op_subtract_impl),
+        ("*", op_multiply_impl),
+    ];
+
+    for _ in 0..1_000_000 {
+        a.restore_checkpoint(&checkpoint);
+        let num_args = 2 + (rand() % 8 == 0) as u64 * (rand() % 3);
+        let mut args = a.nil();
+        for _ in 0..num_args {
+            let r = rand();
+            let len = (r % 10) as usize;
+            let mut buf = rand().to_be_bytes().to_vec();
+            buf.push((r >> 8) as u8);
+            let buf = &mut buf[..len];
+            match (r >> 16) % 4 {
+                0 if len > 0 => buf[0] = 0,
+                1 if len > 0 => buf[0] = 0xff,
+                _ => {}
+            }
+            let arg = a.new_atom(buf).unwrap();
+            args = a.new_pair(arg, args).unwrap();
+        }
+
+        for (name, op) in &ops {
+            let Reduction(fast_cost, fast) = op(&mut a, args, 11000000000, true).unwrap();
+            let Reduction(big_cost, big) = op(&mut a, args, 11000000000, false).unwrap();
+            assert_eq!(a.atom(fast), a.atom(big), "{name}");
+            assert_eq!(fast_cost, big_cost);
+        }
+    }
+}
+
+#[cfg(test)]
+#[rstest]
+#[case("(0x7fffffffffffffff 0x7fffffffffffffff)")]
+#[case("(0x8000000000000000 0x8000000000000000 0x8000000000000000)")]
+#[case("(0x7fffffffffffffff 0x7fffffffffffffff 0x7fffffffffffffff 0x7fffffffffffffff)")]
+#[case("(0x008000000000000000 1)")]
+#[case("(0x0000000000000001 0xffffffffffffffff)")]
+#[case("(1 2 3 0x01000000000000000000000000000000 -1)")]
+#[case("(-1 0x000000000000000000000000000001)")]
+fn test_arithmetic_fast_path_overflow(#[case] args: &str) {
+    // operands at the edge of the fast path, and totals that overflow i128
+    let mut a = Allocator::new();
+    let (args, _) = parse_exp(&mut a, args);
+    for op in [op_add_impl, op_subtract_impl, op_multiply_impl] {
+        let Reduction(fast_cost, fast) = op(&mut a, args, 11000000000, true).unwrap();
+        let Reduction(big_cost, big) = op(&mut a, args, 11000000000, false).unwrap();
+        assert_eq!(a.atom(fast), a.atom(big));
+        assert_eq!(fast_cost, big_cost);
+    }
+}
+
+// the result of lognot is always the minimal encoding of the number. The cost
+// depends on the length of the input, not the output
+#[cfg(test)]
+#[rstest]
+#[case(&[], &[0xff])]
+#[case(&[0], &[0xff])]
+#[case(&[0xff], &[])]
+#[case(&[0, 0, 0], &[0xff])]
+#[case(&[0xff, 0xff], &[])]
+#[case(&[0x0c], &[0xf3])]
+#[case(&[0x00, 0xff], &[0xff, 0x00])]
+#[case(&[0xff, 0x00], &[0x00, 0xff])]
+#[case(&[0x80, 0x00], &[0x7f, 0xff])]
+#[case(&[0xff, 0x7f, 0xff], &[0x00, 0x80, 0x00])]
+#[case(&[0x00, 0x80, 0x00], &[0xff, 0x7f, 0xff])]
+#[case(&[0x02, 0x9c, 0x2e, 0x24, 0x3b, 0xce, 0x47], &[0xfd, 0x63, 0xd1, 0xdb, 0xc4, 0x31, 0xb8])]
+fn test_lognot(#[case] input: &[u8], #[case] expected: &[u8]) {
+    let mut a = Allocator::new();
+    let n = a.new_atom(input).unwrap();
+    let nil = a.nil();
+    let args = a.new_pair(n, nil).unwrap();
+    let Reduction(cost, result) = op_lognot(&mut a, args, 11000000000).unwrap();
+    assert_eq!(a.atom(result).as_ref(), expected);
+    assert_eq!(
+        cost,
+        LOGNOT_BASE_COST
+            + input.len() as Cost * LOGNOT_COST_PER_BYTE
+            + expected.len() as Cost * MALLOC_COST_PER_BYTE
+    );
+
+    // lognot is its own inverse, up to the canonical encoding
+    let args = a.new_pair(result, nil).unwrap();
+    let Reduction(_, back) = op_lognot(&mut a, args, 11000000000).unwrap();
+    assert_eq!(a.number(back), a.number(n));
+}
+
+#[cfg(test)]
+const PARENT: [u8; 32] = hex!("1234500000000000000000000000000000000000000000000000000000000000");
+#[cfg(test)]
+const PUZZLE_HASH: [u8; 32] =
+    hex!("6789abcdef000000000000000000000000000000000000000000000000000000");
+
+#[cfg(test)]
+#[rstest]
+#[case(&PARENT, &PUZZLE_HASH, &hex!("075bcd15"), Ok(hex!("69bfe81b052bfc6bd7f3fb9167fec61793175b897c16a35827f947d5cc98e4bc")))]
+#[case(&PARENT, &PUZZLE_HASH, &hex!("00ffffffffffffffff"), Ok(hex!("609d2d5e3081fbc1106950950f3ea3dbb4eaec96a57a544ba83b8a762b457168")))]
+#[case(&[0; 32], &[0; 32], &[], Ok(hex!("f5a5fd42d16a20302798ef6ed309979b43003d2320d9f0e8ea9831a92759fb4b")))]
+#[case(&PARENT[..31], &PUZZLE_HASH, &hex!("075bcd15"), Err("coinid: invalid parent coin id (must be 32 bytes)"))]
+#[case(&[0; 33], &PUZZLE_HASH, &hex!("075bcd15"), Err("coinid: invalid parent coin id (must be 32 bytes)"))]
+#[case(&PARENT, &PUZZLE_HASH[..31], &hex!("075bcd15"), Err("coinid: invalid puzzle hash (must be 32 bytes)"))]
+#[case(&PARENT, &PUZZLE_HASH, &hex!("ff"), Err("coinid: invalid amount (may not be negative)"))]
+#[case(&PARENT, &PUZZLE_HASH, &hex!("80"), Err("coinid: invalid amount (may not be negative)"))]
+#[case(&PARENT, &PUZZLE_HASH, &hex!("00"), Err("coinid: invalid amount (may not have redundant leading zero)"))]
+#[case(&PARENT, &PUZZLE_HASH, &hex!("0001"), Err("coinid: invalid amount (may not have redundant leading zero)"))]
+#[case(&PARENT, &PUZZLE_HASH, &hex!("00075bcd15"), Err("coinid: invalid amount (may not have redundant leading zero)"))]
+#[case(&PARENT, &PUZZLE_HASH, &hex!("010000000000000000"), Err("coinid: invalid amount (may not exceed max coin amount)"))]
+#[case(&PARENT, &PUZZLE_HASH, &hex!("00010000000000000000"), Err("coinid: invalid amount (may not have redundant leading zero)"))]
+fn test_coinid(
+    #[case] parent: &[u8],
+    #[case] puzzle_hash: &[u8],
+    #[case] amount: &[u8],
+    #[case] expected: Result<[u8; 32], &str>,
+) {
+    let mut a = Allocator::new();
+    let parent = a.new_atom(parent).unwrap();
+    let puzzle_hash = a.new_atom(puzzle_hash).unwrap();
+    let amount = a.new_atom(amount).unwrap();
+    let nil = a.nil();
+    let args = a
+        .new_pair_many(&[parent, puzzle_hash, amount], nil)
+        .unwrap();
+    match (op_coinid(&mut a, args, 11000000000), expected) {
+        (Ok(Reduction(cost, result)), Ok(expected)) => {
+            assert_eq!(a.atom(result).as_ref(), expected);
+            assert_eq!(cost, COINID_COST + 32 * MALLOC_COST_PER_BYTE);
+        }
+        (Err(e), Err(expected)) => {
+            assert_eq!(e.0, args);
+            assert_eq!(e.1, expected);
+        }
+        (r, expected) => panic!("unexpected result {r:?}, expected {expected:?}"),
+    }
+}
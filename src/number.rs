@@ -1,9 +1,31 @@
+use alloc::vec::Vec;
+
 use crate::allocator::{Allocator, NodePtr};
 use crate::reduction::EvalErr;
 
+use core::fmt;
 use num_bigint::BigInt;
 pub type Number = BigInt;
 
+/// The reasons decoding an atom into a fixed width integer can fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntDecodeErr {
+    NotAnAtom,
+    /// the value doesn't fit in the target type
+    Overflow,
+}
+
+impl fmt::Display for IntDecodeErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotAnAtom => "not an atom",
+            Self::Overflow => "integer overflow",
+        })
+    }
+}
+
+impl core::error::Error for IntDecodeErr {}
+
 // This low-level conversion function is meant to be used by the Allocator, for
 // logic interacting with the KLVM heap/allocator, use new_number() and number()
 // instead.
@@ -21,6 +43,27 @@ pub fn node_from_number(allocator: &mut Allocator, item: &Number) -> Result<Node
     allocator.new_atom(slice)
 }
 
+// Like node_from_number(), but for the fast path of the arithmetic operators.
+// The atom is the same as node_from_number() creates for the same value
+pub fn node_from_i128(allocator: &mut Allocator, item: i128) -> Result<NodePtr, EvalErr> {
+    let bytes = item.to_be_bytes();
+    let mut slice = &bytes[..];
+
+    // make number minimal by removing leading 0x00 and 0xff bytes, as long as
+    // that doesn't change the sign
+    while let [first, second, ..] = slice {
+        if (*first == 0 && (second & 0x80) == 0) || (*first == 0xff && (second & 0x80) != 0) {
+            slice = &slice[1..];
+        } else {
+            break;
+        }
+    }
+    if slice == [0] {
+        slice = &[];
+    }
+    allocator.new_atom(slice)
+}
+
 // This low-level conversion function is meant to be used by the Allocator, for
 // logic interacting with the KLVM heap/allocator, use new_number() and number()
 // instead.
@@ -174,6 +217,36 @@ fn roundtrip_bytes(b: &[u8]) {
     }
 }
 
+#[test]
+fn test_node_from_i128() {
+    let mut a = Allocator::new();
+    for v in [
+        0,
+        1,
+        -1,
+        127,
+        128,
+        -128,
+        -129,
+        255,
+        256,
+        0x3ffffff,
+        0x4000000,
+        i64::MAX as i128,
+        i64::MIN as i128,
+        u64::MAX as i128,
+        i128::MAX,
+        i128::MIN,
+        i128::MAX - 1,
+        i128::MIN + 1,
+    ] {
+        let expected = node_from_number(&mut a, &v.into()).unwrap();
+        let ptr = node_from_i128(&mut a, v).unwrap();
+        assert_eq!(a.atom(ptr), a.atom(expected));
+        assert_eq!(a.number(ptr), v.into());
+    }
+}
+
 #[test]
 fn test_number_round_trip_bytes() {
     roundtrip_bytes(&[]);
@@ -254,7 +327,7 @@ fn test_round_trip_u64() {
 
 #[cfg(test)]
 fn roundtrip_i64(v: i64) {
-    use std::cmp::Ordering;
+    use core::cmp::Ordering;
 
     let num: Number = v.into();
 
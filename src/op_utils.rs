@@ -1,11 +1,18 @@
+use alloc::format;
+
 use crate::allocator::{Allocator, Atom, NodePtr, NodeVisitor, SExp};
 use crate::cost::Cost;
 use crate::err_utils::err;
 use crate::number::Number;
 use crate::reduction::EvalErr;
 use crate::reduction::{Reduction, Response};
+#[cfg(feature = "std")]
 use lazy_static::lazy_static;
-use num_bigint::{BigUint, Sign};
+#[cfg(feature = "std")]
+use num_bigint::BigUint;
+#[cfg(feature = "std")]
+use num_bigint::Sign;
+#[cfg(feature = "std")]
 use num_integer::Integer;
 
 // We ascribe some additional cost per byte for operations that allocate new atoms
@@ -17,7 +24,7 @@ pub fn get_args<const N: usize>(
     name: &str,
 ) -> Result<[NodePtr; N], EvalErr> {
     match_args::<N>(a, args).ok_or_else(|| {
-        EvalErr(
+        EvalErr::new(
             args,
             format!(
                 "{name} takes exactly {N} argument{}",
@@ -219,6 +226,59 @@ fn test_rest() {
     assert_eq!(r.1, "rest of non-cons");
 }
 
+// the quote keyword (opcode) in the chik dialect
+const QUOTE: u32 = 1;
+
+/// Returns X if `node` is a quoted value, `(q . X)`. The quote keyword is
+/// recognized the same way run_program() does, i.e. it must be the atom 1 in
+/// its canonical form
+pub fn unwrap_quote(a: &Allocator, node: NodePtr) -> Option<NodePtr> {
+    match a.sexp(node) {
+        SExp::Pair(op, value) if a.small_number(op) == Some(QUOTE) => Some(value),
+        _ => None,
+    }
+}
+
+/// Returns `(q . node)`. This is the inverse of `unwrap_quote()`
+pub fn make_quote(a: &mut Allocator, node: NodePtr) -> Result<NodePtr, EvalErr> {
+    let quote = a.one();
+    a.new_pair(quote, node)
+}
+
+#[test]
+fn test_quote() {
+    use crate::test_ops::{node_eq, parse_exp};
+    let mut a = Allocator::new();
+    let atom = a.new_atom(b"foobar").unwrap();
+    let (list, _) = parse_exp(&mut a, "(a (q 1 2 (3 . 4)) (q . ()) . 5)");
+    let nil = a.nil();
+    let one = a.one();
+    for node in [atom, list, nil, one] {
+        let quoted = make_quote(&mut a, node).unwrap();
+        assert_eq!(unwrap_quote(&a, quoted), Some(node));
+        // quoting twice, unwraps one level at a time
+        let twice = make_quote(&mut a, quoted).unwrap();
+        assert_eq!(unwrap_quote(&a, twice), Some(quoted));
+    }
+
+    // nodes parsed as (q . X)
+    let (quoted, _) = parse_exp(&mut a, "(q 1 2 (3 . 4))");
+    let (expected, _) = parse_exp(&mut a, "(1 2 (3 . 4))");
+    let value = unwrap_quote(&a, quoted).unwrap();
+    assert!(node_eq(&a, value, expected));
+    let requoted = make_quote(&mut a, value).unwrap();
+    assert!(node_eq(&a, requoted, quoted));
+
+    // not quotes
+    let non_canonical = a.new_atom(&[0, 1]).unwrap();
+    let non_canonical = a.new_pair(non_canonical, atom).unwrap();
+    for s in ["(a 1 2)", "(2 . 1)", "((q . 1) . 1)", "1", "()"] {
+        let (node, _) = parse_exp(&mut a, s);
+        assert_eq!(unwrap_quote(&a, node), None);
+    }
+    assert_eq!(unwrap_quote(&a, non_canonical), None);
+}
+
 pub fn int_atom(a: &Allocator, args: NodePtr, op_name: &str) -> Result<(Number, usize), EvalErr> {
     match a.sexp(args) {
         SExp::Atom => Ok((a.number(args), a.atom_len(args))),
@@ -252,9 +312,9 @@ fn test_int_atom_failure() {
 }
 
 pub fn atom_len(a: &Allocator, args: NodePtr, op_name: &str) -> Result<usize, EvalErr> {
-    match a.sexp(args) {
-        SExp::Atom => Ok(a.atom_len(args)),
-        _ => err(args, &format!("{op_name} requires an atom")),
+    match a.try_atom_len(args) {
+        Some(len) => Ok(len),
+        None => err(args, &format!("{op_name} requires an atom")),
     }
 }
 
@@ -492,6 +552,40 @@ fn test_i32_from_u8() {
     assert_eq!(i32_from_u8(&[0x7d, 0xcc, 0x55, 0x88, 0xf3]), None);
 }
 
+// like i32_from_u8(), but for atoms of up to 8 bytes. This is used by the fast
+// path of the arithmetic operators
+pub fn i64_from_u8(buf: &[u8]) -> Option<i64> {
+    if buf.len() > 8 {
+        return None;
+    }
+    let mut bytes = if buf.first().is_some_and(|b| (b & 0x80) != 0) {
+        [0xff; 8]
+    } else {
+        [0; 8]
+    };
+    bytes[8 - buf.len()..].copy_from_slice(buf);
+    Some(i64::from_be_bytes(bytes))
+}
+
+#[test]
+fn test_i64_from_u8() {
+    assert_eq!(i64_from_u8(&[]), Some(0));
+    assert_eq!(i64_from_u8(&[0xcc]), Some(-52));
+    assert_eq!(i64_from_u8(&[0x00, 0xcc]), Some(0xcc));
+    assert_eq!(i64_from_u8(&[0xcc, 0x55, 0x88, 0xf3]), Some(-866809613));
+    assert_eq!(i64_from_u8(&[0xff; 8]), Some(-1));
+    assert_eq!(i64_from_u8(&[0x00; 8]), Some(0));
+    assert_eq!(
+        i64_from_u8(&[0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+        Some(i64::MAX)
+    );
+    assert_eq!(i64_from_u8(&[0x80, 0, 0, 0, 0, 0, 0, 0]), Some(i64::MIN));
+
+    // any atom larger than 8 bytes is rejected, even if the value fits
+    assert_eq!(i64_from_u8(&[0x00; 9]), None);
+    assert_eq!(i64_from_u8(&[0x00, 0x80, 0, 0, 0, 0, 0, 0, 0]), None);
+}
+
 pub fn u64_from_bytes(buf: &[u8]) -> u64 {
     if buf.is_empty() {
         return 0;
@@ -530,6 +624,18 @@ fn test_u64_from_bytes() {
     );
 }
 
+/// Interpret an atom as a signed, two's complement, 32 bit integer. This is
+/// how `substr` takes its indices and `ash` and `lsh` their shift counts.
+///
+/// The rules are the same as `args_as_int32()` in the Python implementation,
+/// and are part of consensus:
+/// * atoms of 0 to 4 bytes are accepted, and sign-extended. The empty atom is
+///   0.
+/// * redundant leading 0x00 or 0xff bytes are accepted, as long as the atom
+///   is at most 4 bytes. e.g. 0x00000001 is 1 and 0xffff is -1.
+/// * atoms longer than 4 bytes are rejected, even if their value would fit.
+///   e.g. 0x0000000001.
+/// * 2^31 and larger can't be encoded, 0x80000000 is -2^31.
 pub fn i32_atom(a: &Allocator, args: NodePtr, op_name: &str) -> Result<i32, EvalErr> {
     match a.node(args) {
         NodeVisitor::Buffer(buf) => match i32_from_u8(buf) {
@@ -571,11 +677,51 @@ fn test_i32_atom() {
     assert_eq!(r.1, "test requires int32 args (with no leading zeros)");
 }
 
+#[cfg(test)]
+#[rstest]
+#[case(&[], Some(0))]
+#[case(&[0x00], Some(0))]
+#[case(&[0x00, 0x00, 0x00, 0x00], Some(0))]
+#[case(&[0x00, 0x00, 0x00, 0x00, 0x00], None)]
+#[case(&[0x01], Some(1))]
+#[case(&[0x00, 0x01], Some(1))]
+#[case(&[0x00, 0x00, 0x00, 0x01], Some(1))]
+#[case(&[0x00, 0x00, 0x00, 0x00, 0x01], None)]
+#[case(&[0xff], Some(-1))]
+#[case(&[0xff, 0xff], Some(-1))]
+#[case(&[0xff, 0xff, 0xff, 0xff], Some(-1))]
+#[case(&[0xff, 0xff, 0xff, 0xff, 0xff], None)]
+// 2^31 - 1
+#[case(&[0x7f, 0xff, 0xff, 0xff], Some(i32::MAX))]
+#[case(&[0x00, 0x7f, 0xff, 0xff, 0xff], None)]
+// -2^31
+#[case(&[0x80, 0x00, 0x00, 0x00], Some(i32::MIN))]
+#[case(&[0xff, 0x80, 0x00, 0x00, 0x00], None)]
+// 2^31 doesn't fit in 4 bytes
+#[case(&[0x00, 0x80, 0x00, 0x00, 0x00], None)]
+#[case(&[0x80, 0x00, 0x00], Some(-0x800000))]
+#[case(&[0x00, 0x80, 0x00, 0x00], Some(0x800000))]
+#[case(&[0x01, 0x00, 0x00, 0x00, 0x00], None)]
+fn test_i32_atom_encodings(#[case] buf: &[u8], #[case] expected: Option<i32>) {
+    let mut a = Allocator::new();
+    let n = a.new_atom(buf).unwrap();
+    let r = i32_atom(&a, n, "test");
+    match expected {
+        Some(v) => assert_eq!(r.unwrap(), v),
+        None => {
+            let e = r.unwrap_err();
+            assert_eq!(e.0, n);
+            assert_eq!(e.1, "test requires int32 args (with no leading zeros)");
+        }
+    }
+}
+
 pub fn new_atom_and_cost(a: &mut Allocator, cost: Cost, buf: &[u8]) -> Response {
     let c = buf.len() as Cost * MALLOC_COST_PER_BYTE;
     Ok(Reduction(cost + c, a.new_atom(buf)?))
 }
 
+#[cfg(feature = "std")]
 pub fn mod_group_order(n: Number) -> Number {
     let order = GROUP_ORDER.clone();
     let mut remainder = n.mod_floor(&order);
@@ -585,6 +731,7 @@ pub fn mod_group_order(n: Number) -> Number {
     remainder
 }
 
+#[cfg(feature = "std")]
 lazy_static! {
     static ref GROUP_ORDER: Number = {
         let order_as_bytes = &[
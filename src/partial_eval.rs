@@ -0,0 +1,216 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::allocator::{Allocator, NodePtr, SExp};
+use crate::cost::Cost;
+use crate::dialect::{Dialect, OperatorSet};
+use crate::reduction::{EvalErr, Reduction};
+
+// the max cost of folding a single operator. Operators that exceed this are
+// left for run-time
+const MAX_FOLD_COST: Cost = 11000000000;
+
+enum Op {
+    // partially evaluate the node and push the result onto the value stack
+    Eval(NodePtr),
+    // the arguments to this operator invocation have been evaluated and are
+    // on the value stack. Pop them, and fold the operator if all of them are
+    // quoted
+    Fold(NodePtr, usize),
+}
+
+// returns the quoted value, if this node has the form (q . X)
+fn quoted_value<D: Dialect>(a: &Allocator, dialect: &D, node: NodePtr) -> Option<NodePtr> {
+    match a.sexp(node) {
+        SExp::Pair(op, value) if a.small_number(op) == Some(dialect.quote_kw()) => Some(value),
+        _ => None,
+    }
+}
+
+fn try_fold<D: Dialect>(
+    a: &mut Allocator,
+    dialect: &D,
+    op: NodePtr,
+    args: &[NodePtr],
+) -> Result<Option<NodePtr>, EvalErr> {
+    let mut values = Vec::with_capacity(args.len());
+    for arg in args {
+        let Some(v) = quoted_value(a, dialect, *arg) else {
+            return Ok(None);
+        };
+        values.push(v);
+    }
+    let mut arg_list = a.nil();
+    for v in values.into_iter().rev() {
+        arg_list = a.new_pair(v, arg_list)?;
+    }
+    // operators that fail are left in place, to fail at run-time
+    let checkpoint = a.checkpoint();
+    match dialect.op(a, op, arg_list, MAX_FOLD_COST, OperatorSet::Default) {
+        Ok(Reduction(_, result)) => {
+            let quote = a.new_small_number(dialect.quote_kw())?;
+            Ok(Some(a.new_pair(quote, result)?))
+        }
+        Err(_) => {
+            a.restore_checkpoint(&checkpoint);
+            Ok(None)
+        }
+    }
+}
+
+/// Fold sub expressions of `program` that only apply operators to quoted
+/// values into their (quoted) result. e.g. `(+ (q . 2) (q . 3))` becomes
+/// `(q . 5)`. Anything that depends on the environment, apply, softfork and
+/// the `((X) ...)` form are left untouched, as are operators that fail.
+/// Quoted values are never rewritten. The program is assumed to run outside
+/// of a softfork guard. Running the returned program produces the same result
+/// (or error) as the original for any environment, but at a lower cost.
+pub fn partial_eval<D: Dialect>(
+    a: &mut Allocator,
+    dialect: &D,
+    program: NodePtr,
+) -> Result<NodePtr, EvalErr> {
+    let mut ops = vec![Op::Eval(program)];
+    let mut values = Vec::<NodePtr>::new();
+
+    while let Some(op) = ops.pop() {
+        match op {
+            Op::Eval(node) => {
+                let SExp::Pair(operator, args) = a.sexp(node) else {
+                    // an environment lookup
+                    values.push(node);
+                    continue;
+                };
+                // small_number() returns None for pairs
+                let keyword = a.small_number(operator);
+                let special = operator.is_pair()
+                    || keyword == Some(dialect.quote_kw())
+                    || keyword == Some(dialect.softfork_kw());
+                let arg_count = a.list_len(args);
+                match arg_count {
+                    Some(count) if !special => {
+                        ops.push(Op::Fold(node, count));
+                        let args: Vec<NodePtr> = a.list_iter(args).expect("proper list").collect();
+                        for arg in args.into_iter().rev() {
+                            ops.push(Op::Eval(arg));
+                        }
+                    }
+                    _ => {
+                        values.push(node);
+                    }
+                }
+            }
+            Op::Fold(node, count) => {
+                let new_args = values.split_off(values.len() - count);
+                let SExp::Pair(operator, args) = a.sexp(node) else {
+                    unreachable!();
+                };
+                if a.small_number(operator) != Some(dialect.apply_kw()) {
+                    if let Some(folded) = try_fold(a, dialect, operator, &new_args)? {
+                        values.push(folded);
+                        continue;
+                    }
+                }
+                let old_args: Vec<NodePtr> = a.list_iter(args).expect("proper list").collect();
+                if old_args == new_args {
+                    values.push(node);
+                    continue;
+                }
+                let mut arg_list = a.nil();
+                for arg in new_args.into_iter().rev() {
+                    arg_list = a.new_pair(arg, arg_list)?;
+                }
+                values.push(a.new_pair(operator, arg_list)?);
+            }
+        }
+    }
+    Ok(values.pop().expect("partial_eval produced no value"))
+}
+
+#[cfg(test)]
+use crate::chik_dialect::{ChikDialect, NO_UNKNOWN_OPS};
+#[cfg(test)]
+use crate::run_program::run_program;
+#[cfg(test)]
+use crate::test_ops::{node_eq, parse_exp};
+#[cfg(test)]
+use rstest::rstest;
+
+#[cfg(test)]
+#[rstest]
+#[case("(+ (q . 2) (q . 3))", "(q . 5)")]
+#[case(
+    "(sha256 (q . \"foobar\"))",
+    "(q . 0xc3ab8ff13720e8ad9047dd39466b3c8974e592c2fa383d4a3960714caef0c4f2)"
+)]
+#[case("(+ (q . 1) (* (q . 2) (q . 3)))", "(q . 7)")]
+#[case("(c (q . 1) (q . (2 3)))", "(q . (1 2 3))")]
+#[case("(f (q . (1 2 3)))", "(q . 1)")]
+#[case("(+)", "(q . 0)")]
+// environment lookups are left alone
+#[case("(+ 1 (q . 2))", "(+ 1 (q . 2))")]
+#[case("(+ 5 (* (q . 2) (q . 3)))", "(+ 5 (q . 6))")]
+#[case(
+    "(c (sha256 2) (concat (q . \"foo\") (q . \"bar\")))",
+    "(c (sha256 2) (q . \"foobar\"))"
+)]
+// quoted values are not touched
+#[case("(q . (+ (q . 2) (q . 3)))", "(q . (+ (q . 2) (q . 3)))")]
+#[case("(q 16 (q . 2) (q . 3))", "(q 16 (q . 2) (q . 3))")]
+// apply is not folded, but its arguments are
+#[case("(a (q . 1) (+ (q . 2) (q . 3)))", "(a (q . 1) (q . 5))")]
+// failing operators are left alone
+#[case("(x (q . \"fail\"))", "(x (q . \"fail\"))")]
+#[case("(f (q . 1))", "(f (q . 1))")]
+#[case("(c (+ (q . 1) (q . 2)) (f (q . 1)))", "(c (q . 3) (f (q . 1)))")]
+// softfork and the ((X) ...) syntax are left alone
+#[case("(softfork (+ (q . 1) (q . 2)))", "(softfork (+ (q . 1) (q . 2)))")]
+#[case("((c) (+ (q . 1) (q . 2)))", "((c) (+ (q . 1) (q . 2)))")]
+// improper argument lists are left alone
+#[case("(+ (q . 1) . (q . 2))", "(+ (q . 1) . (q . 2))")]
+fn test_partial_eval(#[case] program: &str, #[case] expected: &str) {
+    let mut a = Allocator::new();
+    let dialect = ChikDialect::new(NO_UNKNOWN_OPS);
+    let (program, _) = parse_exp(&mut a, program);
+    let (expected, _) = parse_exp(&mut a, expected);
+    let folded = partial_eval(&mut a, &dialect, program).unwrap();
+    assert!(node_eq(&a, folded, expected));
+
+    // the program's behavior is preserved, for a few different environments
+    for env in ["()", "(1 2 3)", "(0x1337 . \"foo\")"] {
+        let (env, _) = parse_exp(&mut a, env);
+        let original = run_program(&mut a, &dialect, program, env, 11000000000);
+        let result = run_program(&mut a, &dialect, folded, env, 11000000000);
+        match (original, result) {
+            (Ok(Reduction(original_cost, original)), Ok(Reduction(cost, result))) => {
+                assert!(node_eq(&a, original, result));
+                assert!(cost <= original_cost);
+            }
+            (Err(original), Err(result)) => {
+                assert_eq!(original.1, result.1);
+            }
+            _ => {
+                panic!("mismatching results");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_partial_eval_deep() {
+    // make sure we don't recurse
+    let mut a = Allocator::new();
+    let dialect = ChikDialect::new(0);
+    let (plus, _) = parse_exp(&mut a, "+");
+    let (one, _) = parse_exp(&mut a, "(q . 1)");
+    let nil = a.nil();
+    let mut program = one;
+    for _ in 0..100000 {
+        let args = a.new_pair(one, nil).unwrap();
+        let args = a.new_pair(program, args).unwrap();
+        program = a.new_pair(plus, args).unwrap();
+    }
+    let folded = partial_eval(&mut a, &dialect, program).unwrap();
+    let (expected, _) = parse_exp(&mut a, "(q . 100001)");
+    assert!(node_eq(&a, folded, expected));
+}
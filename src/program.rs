@@ -0,0 +1,420 @@
+use crate::allocator::{Allocator, NodePtr, SExp};
+use crate::bytes::Bytes32;
+use crate::chik_dialect::ChikDialect;
+use crate::cost::Cost;
+use crate::dialect::Dialect;
+use crate::reduction::{EvalErr, Response};
+use crate::run_program::run_program;
+use crate::serde::{
+    node_from_bytes_backrefs, node_to_bytes, serialized_length_from_bytes, tree_hash,
+    tree_hash_from_stream,
+};
+use std::io;
+use std::io::Cursor;
+use std::sync::{Arc, OnceLock};
+
+// the curry convention also relies on the cons operator. The quote and apply
+// keywords are taken from the dialect
+const CONS: u32 = 4;
+
+#[cfg(test)]
+thread_local! {
+    // the number of times a tree hash has been computed, on this thread
+    static TREE_HASH_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// A serialized KLVM program, known to be valid. The serialization may use
+/// back references. The buffer is shared between clones and the tree hash is
+/// computed at most once.
+#[derive(Debug, Clone)]
+pub struct Program {
+    bytes: Arc<[u8]>,
+    tree_hash: OnceLock<Bytes32>,
+}
+
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for Program {}
+
+impl AsRef<[u8]> for Program {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl TryFrom<&[u8]> for Program {
+    type Error = io::Error;
+
+    /// Same as `Program::from_bytes_validated()`
+    fn try_from(buf: &[u8]) -> io::Result<Self> {
+        Self::from_bytes_validated(buf)
+    }
+}
+
+impl Program {
+    /// The buffer must contain exactly one serialized KLVM structure
+    pub fn from_bytes_validated(buf: &[u8]) -> io::Result<Self> {
+        if serialized_length_from_bytes(buf)? != buf.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing bytes after program",
+            ));
+        }
+        Ok(Self {
+            bytes: buf.into(),
+            tree_hash: OnceLock::new(),
+        })
+    }
+
+    pub fn from_node(a: &Allocator, node: NodePtr) -> io::Result<Self> {
+        let bytes = node_to_bytes(a, node)?;
+        Ok(Self {
+            bytes: bytes.into(),
+            tree_hash: OnceLock::new(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn to_node(&self, a: &mut Allocator) -> io::Result<NodePtr> {
+        node_from_bytes_backrefs(a, &self.bytes)
+    }
+
+    /// The tree hash is computed straight from the serialized form, unless it
+    /// uses back references. Those are expanded in a temporary allocator
+    pub fn tree_hash(&self) -> Bytes32 {
+        *self.tree_hash.get_or_init(|| {
+            #[cfg(test)]
+            TREE_HASH_COUNT.with(|c| c.set(c.get() + 1));
+            // the streaming hasher fails on back references, any other
+            // serialization was validated on construction
+            if let Ok(hash) = tree_hash_from_stream(&mut Cursor::new(&self.bytes[..])) {
+                return hash;
+            }
+            let mut a = Allocator::new();
+            let node = self
+                .to_node(&mut a)
+                .expect("program was validated on construction");
+            tree_hash(&a, node)
+                .expect("deserialized programs can't have cycles")
+                .into()
+        })
+    }
+
+    /// Run the program in `a`, using the Chik dialect with no flags set
+    pub fn run(&self, a: &mut Allocator, args: NodePtr, max_cost: Cost) -> Response {
+        self.run_with_flags(a, args, max_cost, 0)
+    }
+
+    /// Run the program in `a`, using the Chik dialect with the specified flags
+    pub fn run_with_flags(
+        &self,
+        a: &mut Allocator,
+        env: NodePtr,
+        max_cost: Cost,
+        flags: u32,
+    ) -> Response {
+        let program = self
+            .to_node(a)
+            .map_err(|e| EvalErr::new(a.nil(), e.to_string()))?;
+        run_program(a, &ChikDialect::new(flags), program, env, max_cost)
+    }
+
+    /// Bind the first arguments of this program to `args`. The returned
+    /// program has the form: `(a (q . PROGRAM) (c (q . ARG1) (c (q . ARG2) 1)))`
+    /// where `q` and `a` are the dialect's quote and apply keywords
+    pub fn curry<D: Dialect>(
+        &self,
+        a: &mut Allocator,
+        dialect: &D,
+        args: &[NodePtr],
+    ) -> io::Result<Self> {
+        let program = self.to_node(a)?;
+        let quote = a.new_small_number(dialect.quote_kw())?;
+        let cons = a.new_small_number(CONS)?;
+        let apply = a.new_small_number(dialect.apply_kw())?;
+        let nil = a.nil();
+
+        let mut env = a.one();
+        for arg in args.iter().rev() {
+            let quoted = a.new_pair(quote, *arg)?;
+            let tail = a.new_pair(env, nil)?;
+            let tail = a.new_pair(quoted, tail)?;
+            env = a.new_pair(cons, tail)?;
+        }
+        let quoted = a.new_pair(quote, program)?;
+        let tail = a.new_pair(env, nil)?;
+        let tail = a.new_pair(quoted, tail)?;
+        let curried = a.new_pair(apply, tail)?;
+        Self::from_node(a, curried)
+    }
+
+    /// The inverse of `curry()`. Returns the inner program and the curried
+    /// arguments, or None if this program isn't on the curried form, using the
+    /// dialect's keywords.
+    pub fn uncurry<D: Dialect>(
+        &self,
+        a: &mut Allocator,
+        dialect: &D,
+    ) -> io::Result<Option<(Self, Vec<NodePtr>)>> {
+        let node = self.to_node(a)?;
+        let Some([op, quoted, mut env]) = list3(a, node) else {
+            return Ok(None);
+        };
+        if !is_op(a, op, dialect.apply_kw()) {
+            return Ok(None);
+        }
+        let quote = dialect.quote_kw();
+        let Some(program) = unquote(a, quote, quoted) else {
+            return Ok(None);
+        };
+        let mut args = Vec::new();
+        // the environment is passed through (as 1) at the end
+        while !is_op(a, env, 1) {
+            let Some([op, quoted, rest]) = list3(a, env) else {
+                return Ok(None);
+            };
+            if !is_op(a, op, CONS) {
+                return Ok(None);
+            }
+            let Some(arg) = unquote(a, quote, quoted) else {
+                return Ok(None);
+            };
+            args.push(arg);
+            env = rest;
+        }
+        Ok(Some((Self::from_node(a, program)?, args)))
+    }
+}
+
+// the same test run_program() uses to identify keywords
+fn is_op(a: &Allocator, node: NodePtr, op: u32) -> bool {
+    a.small_number(node) == Some(op)
+}
+
+// returns X, if the node has the form (q . X)
+fn unquote(a: &Allocator, quote: u32, node: NodePtr) -> Option<NodePtr> {
+    match a.sexp(node) {
+        SExp::Pair(op, value) if is_op(a, op, quote) => Some(value),
+        _ => None,
+    }
+}
+
+// returns the elements of the node, if it's a proper list of 3 elements
+fn list3(a: &Allocator, node: NodePtr) -> Option<[NodePtr; 3]> {
+    let (first, rest) = a.next(node)?;
+    let (second, rest) = a.next(rest)?;
+    let (third, rest) = a.next(rest)?;
+    if !rest.is_atom() || a.atom_len(rest) != 0 {
+        return None;
+    }
+    Some([first, second, third])
+}
+
+#[cfg(test)]
+use crate::dialect::OperatorSet;
+#[cfg(test)]
+use crate::reduction::Reduction;
+#[cfg(test)]
+use crate::test_ops::{node_eq, parse_exp};
+
+#[cfg(test)]
+fn program(s: &str) -> Program {
+    let mut a = Allocator::new();
+    let (node, _) = parse_exp(&mut a, s);
+    Program::from_node(&a, node).unwrap()
+}
+
+#[test]
+fn test_program_validation() {
+    // (+ 2 5)
+    let buf = [0xff, 0x10, 0xff, 0x02, 0xff, 0x05, 0x80];
+    let p = Program::from_bytes_validated(&buf).unwrap();
+    assert_eq!(p.as_bytes(), buf);
+    assert_eq!(p, program("(+ 2 5)"));
+
+    // back references are allowed
+    let buf = [0xff, 0x83, b'f', b'o', b'o', 0xfe, 0x02];
+    let p = Program::from_bytes_validated(&buf).unwrap();
+    let mut a = Allocator::new();
+    let node = p.to_node(&mut a).unwrap();
+    let (expected, _) = parse_exp(&mut a, "(\"foo\" . \"foo\")");
+    assert!(node_eq(&a, node, expected));
+
+    let invalid: [&[u8]; 5] = [
+        &[],
+        // truncated
+        &[0xff, 0x10, 0xff, 0x02, 0xff, 0x05],
+        &[0x83, b'f', b'o'],
+        // trailing bytes
+        &[0xff, 0x10, 0xff, 0x02, 0xff, 0x05, 0x80, 0x80],
+        // invalid back reference
+        &[0xff, 0x83, b'f', b'o', b'o', 0xfe, 0x10],
+    ];
+    for buf in invalid {
+        assert!(Program::from_bytes_validated(buf).is_err());
+    }
+}
+
+#[test]
+fn test_program_tree_hash() {
+    let p = program("(+ 2 5)");
+    let count = TREE_HASH_COUNT.with(|c| c.get());
+
+    let mut a = Allocator::new();
+    let node = p.to_node(&mut a).unwrap();
+    let expected: Bytes32 = tree_hash(&a, node).unwrap().into();
+
+    assert_eq!(p.tree_hash(), expected);
+    assert_eq!(TREE_HASH_COUNT.with(|c| c.get()), count + 1);
+    assert_eq!(p.tree_hash(), expected);
+    // clones share the cached value
+    let p2 = p.clone();
+    assert_eq!(p2.tree_hash(), expected);
+    assert_eq!(TREE_HASH_COUNT.with(|c| c.get()), count + 1);
+}
+
+#[test]
+fn test_program_try_from() {
+    // (+ 2 5)
+    let buf: &[u8] = &[0xff, 0x10, 0xff, 0x02, 0xff, 0x05, 0x80];
+    let p: Program = buf.try_into().unwrap();
+    assert_eq!(p, program("(+ 2 5)"));
+    let mut a = Allocator::new();
+    let (args, _) = parse_exp(&mut a, "(3 4)");
+    let Reduction(_, result) = p.run(&mut a, args, 11000000000).unwrap();
+    assert_eq!(a.number(result), 7.into());
+
+    let invalid: [&[u8]; 4] = [
+        &[],
+        &[0xff, 0x10],
+        &[0x80, 0x80],
+        &[0xff, 0x83, b'f', b'o', b'o', 0xfe, 0x10],
+    ];
+    for buf in invalid {
+        assert!(Program::try_from(buf).is_err());
+    }
+}
+
+#[test]
+fn test_program_tree_hash_backrefs() {
+    // ("foo" . "foo"), with and without a back reference
+    let expected = program("(\"foo\" . \"foo\")").tree_hash();
+    let buf: &[u8] = &[0xff, 0x83, b'f', b'o', b'o', 0xfe, 0x02];
+    let p = Program::try_from(buf).unwrap();
+    assert_eq!(p.tree_hash(), expected);
+}
+
+#[test]
+fn test_program_run_curry() {
+    let mut a = Allocator::new();
+    let p = program("(+ 2 5)");
+    let (env, _) = parse_exp(&mut a, "(3 4)");
+    let Reduction(cost, result) = p.run(&mut a, env, 11000000000).unwrap();
+    assert_eq!(a.number(result), 7.into());
+
+    // bind the first argument
+    let three = a.new_small_number(3).unwrap();
+    let dialect = ChikDialect::new(0);
+    let curried = p.curry(&mut a, &dialect, &[three]).unwrap();
+    assert_eq!(curried, program("(a (q + 2 5) (c (q . 3) 1))"));
+    let (env, _) = parse_exp(&mut a, "(4)");
+    let Reduction(curried_cost, result) = curried.run(&mut a, env, 11000000000).unwrap();
+    assert_eq!(a.number(result), 7.into());
+    assert!(curried_cost > cost);
+
+    let (inner, args) = curried.uncurry(&mut a, &dialect).unwrap().unwrap();
+    assert_eq!(inner, p);
+    assert_eq!(args.len(), 1);
+    assert_eq!(a.number(args[0]), 3.into());
+
+    // no arguments
+    let curried = p.curry(&mut a, &dialect, &[]).unwrap();
+    assert_eq!(curried, program("(a (q + 2 5) 1)"));
+    let (inner, args) = curried.uncurry(&mut a, &dialect).unwrap().unwrap();
+    assert_eq!(inner, p);
+    assert!(args.is_empty());
+
+    // not curried
+    for s in [
+        "(+ 2 5)",
+        "(a (q + 2 5) 2)",
+        "(a (q + 2 5) (c (q . 3) 2))",
+        "(a (q + 2 5) (c 3 1))",
+        "(a 1 1)",
+        "(a (q + 2 5) 1 1)",
+    ] {
+        assert!(program(s).uncurry(&mut a, &dialect).unwrap().is_none());
+    }
+
+    // running out of cost
+    assert_eq!(curried.run(&mut a, env, 1).unwrap_err().1, "cost exceeded");
+}
+
+// the Chik dialect, but with quote and apply remapped to "q" and "a"
+#[cfg(test)]
+struct RemappedDialect(ChikDialect);
+
+#[cfg(test)]
+impl Dialect for RemappedDialect {
+    fn quote_kw(&self) -> u32 {
+        b'q' as u32
+    }
+    fn apply_kw(&self) -> u32 {
+        b'a' as u32
+    }
+    fn softfork_kw(&self) -> u32 {
+        self.0.softfork_kw()
+    }
+    fn softfork_extension(&self, ext: u32) -> OperatorSet {
+        self.0.softfork_extension(ext)
+    }
+    fn op(
+        &self,
+        allocator: &mut Allocator,
+        op: NodePtr,
+        args: NodePtr,
+        max_cost: Cost,
+        extensions: OperatorSet,
+    ) -> Response {
+        self.0.op(allocator, op, args, max_cost, extensions)
+    }
+    fn allow_unknown_ops(&self) -> bool {
+        self.0.allow_unknown_ops()
+    }
+}
+
+#[test]
+fn test_program_curry_dialect() {
+    let mut a = Allocator::new();
+    let dialect = RemappedDialect(ChikDialect::new(0));
+    let p = program("(+ 2 5)");
+
+    let three = a.new_small_number(3).unwrap();
+    let curried = p.curry(&mut a, &dialect, &[three]).unwrap();
+    assert_eq!(curried, program("(0x61 (0x71 + 2 5) (c (0x71 . 3) 1))"));
+
+    let node = curried.to_node(&mut a).unwrap();
+    let (env, _) = parse_exp(&mut a, "(4)");
+    let Reduction(_, result) = run_program(&mut a, &dialect, node, env, 11000000000).unwrap();
+    assert_eq!(a.number(result), 7.into());
+
+    let (inner, args) = curried.uncurry(&mut a, &dialect).unwrap().unwrap();
+    assert_eq!(inner, p);
+    assert_eq!(args.len(), 1);
+    assert_eq!(a.number(args[0]), 3.into());
+
+    // the keywords of the Chik dialect don't match
+    assert!(curried
+        .uncurry(&mut a, &ChikDialect::new(0))
+        .unwrap()
+        .is_none());
+    let chik_curried = p.curry(&mut a, &ChikDialect::new(0), &[three]).unwrap();
+    assert!(chik_curried.uncurry(&mut a, &dialect).unwrap().is_none());
+}
@@ -1,26 +1,236 @@
-use std::{fmt, io};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
-use crate::allocator::NodePtr;
+use core::fmt;
+
+use crate::allocator::{Allocator, NodePtr, SExp};
 use crate::cost::Cost;
+use crate::disassemble::{disassemble_with_budget, DisassembleBudget};
+use crate::io;
 
+/// An error from running a program: the node it refers to and a message. For
+/// an error raised by the program itself, with `x`, the message is
+/// `RAISE_MSG` and the node is the value that was raised
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EvalErr(pub NodePtr, pub String);
 
+/// The message of errors raised by the program with `x`. No other error uses
+/// it
+pub const RAISE_MSG: &str = "klvm raise";
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Reduction(pub Cost, pub NodePtr);
 
 pub type Response = Result<Reduction, EvalErr>;
 
+/// A coarse classification of an `EvalErr`, for tools that need to tell
+/// failures apart without matching on messages. The names returned by
+/// `as_str()` are stable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// the program called `x` (raise)
+    Raise,
+    CostExceeded,
+    /// the allocator's heap limit was reached
+    OutOfMemory,
+    TooManyAtoms,
+    TooManyPairs,
+    /// the value or environment stack limit was reached
+    StackLimit,
+    /// the operator is not defined by the dialect
+    UnknownOperator,
+    /// the operator is reserved, or not an atom
+    InvalidOperator,
+    PathIntoAtom,
+    Interrupted,
+    /// any other error, typically invalid arguments to an operator
+    Other,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Raise => "raise",
+            Self::CostExceeded => "cost_exceeded",
+            Self::OutOfMemory => "out_of_memory",
+            Self::TooManyAtoms => "too_many_atoms",
+            Self::TooManyPairs => "too_many_pairs",
+            Self::StackLimit => "stack_limit",
+            Self::UnknownOperator => "unknown_operator",
+            Self::InvalidOperator => "invalid_operator",
+            Self::PathIntoAtom => "path_into_atom",
+            Self::Interrupted => "interrupted",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl EvalErr {
+    pub fn new(node: NodePtr, msg: String) -> Self {
+        Self(node, msg)
+    }
+
+    /// The error returned by `x` (raise), when it's passed `value`. That's
+    /// either the single atom argument or the full argument list, see
+    /// `op_raise()`. The value is only rendered by `display()`
+    pub fn raise(value: NodePtr) -> Self {
+        Self(value, RAISE_MSG.to_string())
+    }
+
+    /// The value passed to `x`, if this error was raised by the program
+    pub fn raised(&self) -> Option<NodePtr> {
+        (self.1 == RAISE_MSG).then_some(self.0)
+    }
+
+    /// The kind of error. Error messages are part of consensus (they're
+    /// compared against other implementations), so classifying by message is
+    /// stable
+    pub fn kind(&self) -> ErrorKind {
+        match self.1.as_str() {
+            RAISE_MSG => ErrorKind::Raise,
+            "cost exceeded" => ErrorKind::CostExceeded,
+            "out of memory" => ErrorKind::OutOfMemory,
+            "too many atoms" => ErrorKind::TooManyAtoms,
+            "too many pairs" => ErrorKind::TooManyPairs,
+            "value stack limit reached" | "environment stack limit reached" => {
+                ErrorKind::StackLimit
+            }
+            "unimplemented operator" => ErrorKind::UnknownOperator,
+            "invalid operator" | "reserved operator" => ErrorKind::InvalidOperator,
+            "path into atom" => ErrorKind::PathIntoAtom,
+            "interrupted" => ErrorKind::Interrupted,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// The bytes of the operator, if this is an unknown operator error (i.e.
+    /// the dialect has no operator with this opcode, and unknown operators
+    /// are not allowed). The node of such an error is the operator atom
+    pub fn unknown_op(&self, a: &Allocator) -> Option<Vec<u8>> {
+        if self.kind() != ErrorKind::UnknownOperator || !self.0.is_atom() {
+            return None;
+        }
+        Some(a.atom(self.0).as_ref().to_vec())
+    }
+
+    /// Like the Display implementation, but with access to the allocator. For
+    /// `x` (raise), the first argument is disassembled in full (up to the
+    /// default `DisassembleBudget`), e.g.
+    /// `klvm raise: "ASSERT_MY_AMOUNT failed"` or `klvm raise: (q 2)`. For
+    /// unknown operators, the opcode is included, e.g.
+    /// `unimplemented operator: 0xc8`
+    pub fn display(&self, a: &Allocator) -> String {
+        if let Some(op) = self.unknown_op(a) {
+            return format!("{}: 0x{}", self.1, hex_string(&op));
+        }
+        if let Some(arg) = self.raised().and_then(|value| raised_first_arg(a, value)) {
+            let arg = disassemble_with_budget(a, arg, DisassembleBudget::default());
+            return format!("{}: {arg}", self.1);
+        }
+        self.to_string()
+    }
+}
+
+// op_raise() unwraps a single atom argument, otherwise the value is the full
+// argument list. Raising without arguments raises nil
+fn raised_first_arg(a: &Allocator, value: NodePtr) -> Option<NodePtr> {
+    match a.sexp(value) {
+        SExp::Atom if a.atom_len(value) == 0 => None,
+        SExp::Atom => Some(value),
+        SExp::Pair(first, _) => Some(first),
+    }
+}
+
+fn hex_string(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{b:02x}")).collect()
+}
+
 impl fmt::Display for EvalErr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Error at {:?}: {}", self.0, self.1)
     }
 }
 
-impl std::error::Error for EvalErr {}
+impl core::error::Error for EvalErr {}
 
 impl From<EvalErr> for io::Error {
     fn from(v: EvalErr) -> Self {
         Self::new(io::ErrorKind::Other, v.1)
     }
 }
+
+#[cfg(test)]
+use crate::chik_dialect::{ChikDialect, NO_UNKNOWN_OPS};
+#[cfg(test)]
+use crate::run_program::run_program;
+#[cfg(test)]
+use crate::test_ops::parse_exp;
+
+#[cfg(test)]
+use rstest::rstest;
+
+#[cfg(test)]
+#[rstest]
+#[case("(x (q . 1))", 0, 1000, ErrorKind::Raise)]
+#[case("(sha256 (q . \"foobar\"))", 0, 100, ErrorKind::CostExceeded)]
+#[case("(0x0fffff (q . 1))", NO_UNKNOWN_OPS, 1000, ErrorKind::UnknownOperator)]
+#[case("(0x0fffffffff)", 0, 1000, ErrorKind::InvalidOperator)]
+#[case("(f (q . 1))", 0, 1000, ErrorKind::Other)]
+fn test_error_kind(
+    #[case] prg: &str,
+    #[case] flags: u32,
+    #[case] max_cost: Cost,
+    #[case] expected: ErrorKind,
+) {
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, prg);
+    let env = a.nil();
+    let e = run_program(&mut a, &ChikDialect::new(flags), program, env, max_cost).unwrap_err();
+    assert_eq!(e.kind(), expected);
+}
+
+#[test]
+fn test_error_kind_names() {
+    assert_eq!(ErrorKind::CostExceeded.as_str(), "cost_exceeded");
+    assert_eq!(ErrorKind::Raise.to_string(), "raise");
+    let e = EvalErr::new(NodePtr::NIL, "path into atom".to_string());
+    assert_eq!(e.kind(), ErrorKind::PathIntoAtom);
+}
+
+#[test]
+fn test_unknown_op() {
+    let mut a = Allocator::new();
+    let nil = a.nil();
+    let dialect = ChikDialect::new(NO_UNKNOWN_OPS);
+    for (prg, opcode) in [
+        ("(0xc8 (q . 1))", vec![200]),
+        // 200 is serialized with a leading zero, to not be negative
+        ("(200 (q . 1))", vec![0, 200]),
+        ("(0x0fffff (q . 1))", vec![0x0f, 0xff, 0xff]),
+    ] {
+        let (program, _) = parse_exp(&mut a, prg);
+        let e = run_program(&mut a, &dialect, program, nil, 100000).unwrap_err();
+        assert_eq!(e.1, "unimplemented operator");
+        assert_eq!(e.unknown_op(&a), Some(opcode.clone()));
+        assert_eq!(
+            e.display(&a),
+            format!("unimplemented operator: 0x{}", hex_string(&opcode))
+        );
+    }
+
+    // with unknown operators allowed, 200 is not an error
+    let (program, _) = parse_exp(&mut a, "(0xc8 (q . 1))");
+    run_program(&mut a, &ChikDialect::new(0), program, nil, 100000).unwrap();
+
+    // other errors don't have an operator
+    let (program, _) = parse_exp(&mut a, "(x (q . 200))");
+    let e = run_program(&mut a, &dialect, program, nil, 100000).unwrap_err();
+    assert_eq!(e.unknown_op(&a), None);
+}
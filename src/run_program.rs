@@ -1,10 +1,32 @@
-use super::traverse_path::{traverse_path, traverse_path_fast};
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::traverse_path::{
+    lazy_env_lookup, traverse_path, traverse_path_fast, traverse_path_lazy,
+};
 use crate::allocator::{Allocator, Checkpoint, NodePtr, NodeVisitor, SExp};
-use crate::cost::Cost;
+use crate::bytes::Bytes32;
+use crate::cost::{Cost, UNLIMITED_COST};
 use crate::dialect::{Dialect, OperatorSet};
 use crate::err_utils::err;
+use crate::io;
 use crate::op_utils::{first, get_args, uint_atom};
 use crate::reduction::{EvalErr, Reduction, Response};
+use crate::serde::{node_to_bytes, tree_hash};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "pre-eval")]
+use alloc::boxed::Box;
+#[cfg(feature = "traceback")]
+use alloc::format;
+#[cfg(feature = "traceback")]
+use alloc::string::String;
+
+#[cfg(feature = "memoize")]
+use crate::collections::HashMap;
+#[cfg(feature = "memoize")]
+use crate::serde::tree_hash_with_cache;
 
 // lowered from 46
 const QUOTE_COST: Cost = 20;
@@ -19,6 +41,78 @@ const OP_COST: Cost = 1;
 // exceeded
 const STACK_SIZE_LIMIT: usize = 20000000;
 
+// the cancellation flag is checked once every this many operations. An
+// operation is cheap (often just a few pointer manipulations), so checking an
+// atomic on every one of them would be noticeable. This keeps the overhead
+// negligible while still responding within a fraction of a millisecond
+const CANCEL_CHECK_INTERVAL: u32 = 1024;
+
+/// The high-water mark of allocations made while running a program. The
+/// allocator only releases memory when a softfork guard exits, so this may be
+/// greater than the allocator's usage once the program completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PeakUsage {
+    pub peak_pairs: usize,
+    pub peak_atom_bytes: usize,
+}
+
+/// Deterministic resource usage of a program, as returned by
+/// `run_program_with_metrics()`. Everything is counted in logical units,
+/// independent of the platform and the allocator's actual memory use.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EvalMetrics {
+    /// the peak number of bytes allocated by the program, counting atom
+    /// contents plus `PAIR_BYTES` per pair. Allocations inside softfork
+    /// guards are included, even though they're released when the guard
+    /// exits
+    pub peak_heap: usize,
+    /// the peak combined size of the value, environment and operation stacks
+    pub peak_stack_depth: usize,
+    /// the number of operations taken off the operation stack
+    pub reductions: u64,
+}
+
+/// The number of bytes a pair counts as in `EvalMetrics::peak_heap`, i.e. two
+/// 32 bit node pointers
+#[cfg(feature = "metrics")]
+pub const PAIR_BYTES: usize = 8;
+
+/// The result of a successful `run_program_output()`. The serialization and
+/// tree hash of the result are computed on demand, from the allocator the
+/// program ran in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunOutput {
+    pub result: NodePtr,
+    pub cost: Cost,
+}
+
+impl RunOutput {
+    pub fn serialized(&self, a: &Allocator) -> io::Result<Vec<u8>> {
+        node_to_bytes(a, self.result)
+    }
+
+    pub fn tree_hash(&self, a: &Allocator) -> Bytes32 {
+        tree_hash(a, self.result)
+            .expect("the allocator doesn't hold cycles")
+            .into()
+    }
+}
+
+impl From<Reduction> for RunOutput {
+    fn from(r: Reduction) -> Self {
+        Self {
+            result: r.1,
+            cost: r.0,
+        }
+    }
+}
+
+/// Produces the sub trees of lazy environments, for
+/// `run_program_with_env_provider()`. It's passed the path into the lazy
+/// environment, `1` being the lazy environment itself
+pub type EnvProvider<'a> = dyn FnMut(&mut Allocator, &[u8]) -> Result<NodePtr, EvalErr> + 'a;
+
 #[cfg(feature = "pre-eval")]
 pub type PreEval =
     Box<dyn Fn(&mut Allocator, NodePtr, NodePtr) -> Result<Option<Box<PostEval>>, EvalErr>>;
@@ -35,6 +129,12 @@ enum Operation {
 
     #[cfg(feature = "pre-eval")]
     PostEval,
+
+    #[cfg(feature = "traceback")]
+    PopFrame,
+
+    #[cfg(feature = "memoize")]
+    MemoStore,
 }
 
 #[cfg(feature = "counters")]
@@ -64,6 +164,110 @@ impl Counters {
     }
 }
 
+// (program hash, env hash)
+#[cfg(feature = "memoize")]
+type MemoKey = ([u8; 32], [u8; 32]);
+
+// the results of apply, keyed by the tree hashes of the program and the
+// environment. Only applications outside of softfork guards are cached, since
+// the allocator is rewound when exiting a guard
+#[cfg(feature = "memoize")]
+struct Memo {
+    // tree hashes of nodes, computed as needed
+    hashes: HashMap<NodePtr, [u8; 32]>,
+    // (program hash, env hash) -> (result, cost)
+    results: HashMap<MemoKey, (NodePtr, Cost)>,
+    // the applications currently being evaluated, and the cost before they
+    // started. Popped by the MemoStore operation
+    pending: Vec<(MemoKey, Cost)>,
+}
+
+#[cfg(feature = "memoize")]
+impl Memo {
+    fn new() -> Self {
+        Self {
+            hashes: HashMap::new(),
+            results: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    fn tree_hash(&mut self, a: &Allocator, node: NodePtr) -> Result<[u8; 32], EvalErr> {
+        tree_hash_with_cache(a, node, &mut self.hashes)
+            .map_err(|e| EvalErr::new(node, e.to_string()))
+    }
+}
+
+// the stack of operator calls currently being evaluated, as (operator,
+// operands). Only the innermost `limit` frames are kept. The number of frames
+// that have been dropped from the bottom of the stack is tracked in `dropped`,
+// to keep pushes and pops balanced.
+#[cfg(feature = "traceback")]
+struct FrameStack {
+    frames: alloc::collections::VecDeque<(NodePtr, NodePtr)>,
+    limit: usize,
+    dropped: usize,
+}
+
+#[cfg(feature = "traceback")]
+impl FrameStack {
+    fn new(limit: usize) -> Self {
+        FrameStack {
+            frames: alloc::collections::VecDeque::new(),
+            limit,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, operator: NodePtr, operands: NodePtr) {
+        if self.frames.len() == self.limit {
+            self.frames.pop_front();
+            self.dropped += 1;
+        }
+        if self.limit > 0 {
+            self.frames.push_back((operator, operands));
+        }
+    }
+
+    fn pop(&mut self) {
+        if self.frames.pop_back().is_none() {
+            self.dropped -= 1;
+        }
+    }
+}
+
+// the error returned by run_program_with_traceback(). In addition to the
+// EvalErr, it holds the chain of operator calls that were being evaluated when
+// the failure occurred, as (operator, operands), innermost call last.
+#[cfg(feature = "traceback")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracebackErr {
+    pub err: EvalErr,
+    pub frames: Vec<(NodePtr, NodePtr)>,
+}
+
+// frames wider than this are truncated by format_traceback()
+#[cfg(feature = "traceback")]
+const MAX_FRAME_WIDTH: usize = 120;
+
+#[cfg(feature = "traceback")]
+pub fn format_traceback(a: &Allocator, e: &TracebackErr) -> String {
+    use crate::disassemble::{disassemble, disassemble_list};
+
+    let mut ret = format!("{}: {}\n", e.err.1, disassemble(a, e.err.0));
+    ret.push_str("traceback (innermost last):\n");
+    for (idx, (operator, operands)) in e.frames.iter().enumerate() {
+        let mut frame = disassemble_list(a, *operator, *operands);
+        if frame.len() > MAX_FRAME_WIDTH {
+            // the disassembly is plain ASCII, so this is a char boundary
+            frame.truncate(MAX_FRAME_WIDTH - 3);
+            frame.push_str("...");
+        }
+        ret.push_str(&format!("  {idx}: {frame}\n"));
+    }
+    ret
+}
+
 // this represents the state we were in before entering a soft-fork guard. We
 // may need this to long-jump out of the guard, and also to validate the cost
 // when exiting the guard
@@ -104,6 +308,26 @@ struct RunProgramContext<'a, D> {
     pre_eval: Option<PreEval>,
     #[cfg(feature = "pre-eval")]
     posteval_stack: Vec<Box<PostEval>>,
+
+    #[cfg(feature = "traceback")]
+    traceback: Option<FrameStack>,
+
+    #[cfg(feature = "memoize")]
+    memo: Option<Memo>,
+
+    // the heap usage (as counted by EvalMetrics) when the program started
+    #[cfg(feature = "metrics")]
+    metrics_heap_base: usize,
+    #[cfg(feature = "metrics")]
+    metrics: EvalMetrics,
+
+    cancel: Option<&'a AtomicBool>,
+
+    peak_usage: Option<PeakUsage>,
+
+    // looks up paths into lazy environments. Without one, lazy environments
+    // are just hash stubs
+    env_provider: Option<&'a mut EnvProvider<'a>>,
 }
 
 fn augment_cost_errors(r: Result<Cost, EvalErr>, max_cost: NodePtr) -> Result<Cost, EvalErr> {
@@ -111,7 +335,7 @@ fn augment_cost_errors(r: Result<Cost, EvalErr>, max_cost: NodePtr) -> Result<Co
         if &e.1 != "cost exceeded" {
             e
         } else {
-            EvalErr(max_cost, e.1)
+            EvalErr::new(max_cost, e.1)
         }
     })
 }
@@ -121,21 +345,21 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
     #[inline(always)]
     fn account_val_push(&mut self) {
         self.counters.val_stack_usage =
-            std::cmp::max(self.counters.val_stack_usage, self.val_stack.len());
+            core::cmp::max(self.counters.val_stack_usage, self.val_stack.len());
     }
 
     #[cfg(feature = "counters")]
     #[inline(always)]
     fn account_env_push(&mut self) {
         self.counters.env_stack_usage =
-            std::cmp::max(self.counters.env_stack_usage, self.env_stack.len());
+            core::cmp::max(self.counters.env_stack_usage, self.env_stack.len());
     }
 
     #[cfg(feature = "counters")]
     #[inline(always)]
     fn account_op_push(&mut self) {
         self.counters.op_stack_usage =
-            std::cmp::max(self.counters.op_stack_usage, self.op_stack.len());
+            core::cmp::max(self.counters.op_stack_usage, self.op_stack.len());
     }
 
     #[cfg(not(feature = "counters"))]
@@ -150,6 +374,37 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
     #[inline(always)]
     fn account_op_push(&mut self) {}
 
+    #[cfg(feature = "metrics")]
+    fn metrics_heap(&self) -> usize {
+        self.allocator.heap_size() + self.allocator.pair_count() * PAIR_BYTES
+    }
+
+    // sample the heap and stack usage. This is called before every step of the
+    // interpreter, which is frequent enough to observe every peak, since the
+    // heap only shrinks when a softfork guard exits (in a step of its own)
+    #[cfg(feature = "metrics")]
+    #[inline(always)]
+    fn account_step(&mut self) {
+        let heap = self.metrics_heap().saturating_sub(self.metrics_heap_base);
+        let stack = self.val_stack.len() + self.env_stack.len() + self.op_stack.len();
+        self.metrics.peak_heap = core::cmp::max(self.metrics.peak_heap, heap);
+        self.metrics.peak_stack_depth = core::cmp::max(self.metrics.peak_stack_depth, stack);
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    #[inline(always)]
+    fn account_step(&mut self) {}
+
+    #[cfg(feature = "metrics")]
+    #[inline(always)]
+    fn account_reduction(&mut self) {
+        self.metrics.reductions += 1;
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    #[inline(always)]
+    fn account_reduction(&mut self) {}
+
     pub fn pop(&mut self) -> Result<NodePtr, EvalErr> {
         let v: Option<NodePtr> = self.val_stack.pop();
         match v {
@@ -195,6 +450,17 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
             counters: Counters::new(),
             pre_eval,
             posteval_stack: Vec::new(),
+            #[cfg(feature = "traceback")]
+            traceback: None,
+            #[cfg(feature = "memoize")]
+            memo: None,
+            #[cfg(feature = "metrics")]
+            metrics_heap_base: 0,
+            #[cfg(feature = "metrics")]
+            metrics: EvalMetrics::default(),
+            cancel: None,
+            peak_usage: None,
+            env_provider: None,
         }
     }
 
@@ -212,9 +478,86 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
             pre_eval: None,
             #[cfg(feature = "pre-eval")]
             posteval_stack: Vec::new(),
+            #[cfg(feature = "traceback")]
+            traceback: None,
+            #[cfg(feature = "memoize")]
+            memo: None,
+            #[cfg(feature = "metrics")]
+            metrics_heap_base: 0,
+            #[cfg(feature = "metrics")]
+            metrics: EvalMetrics::default(),
+            cancel: None,
+            peak_usage: None,
+            env_provider: None,
         }
     }
 
+    // record that we're entering an operator call. The frame is popped again
+    // by the PopFrame operation once the call (and, in the case of apply, the
+    // program it invokes) completes. This must be called right before pushing
+    // the Apply operation
+    #[cfg(feature = "traceback")]
+    fn push_frame(&mut self, operator: NodePtr, operands: NodePtr) {
+        if let Some(tb) = &mut self.traceback {
+            tb.push(operator, operands);
+            self.op_stack.push(Operation::PopFrame);
+        }
+    }
+
+    #[cfg(not(feature = "traceback"))]
+    #[inline(always)]
+    fn push_frame(&mut self, _operator: NodePtr, _operands: NodePtr) {}
+
+    // if the result of applying program to env is cached, push it and return
+    // its cost. Otherwise, arrange for the result to be cached once the
+    // evaluation completes
+    #[cfg(feature = "memoize")]
+    fn memo_lookup(
+        &mut self,
+        program: NodePtr,
+        env: NodePtr,
+        current_cost: Cost,
+    ) -> Result<Option<Cost>, EvalErr> {
+        if !self.softfork_stack.is_empty() {
+            return Ok(None);
+        }
+        let Some(memo) = self.memo.as_mut() else {
+            return Ok(None);
+        };
+        let key = (
+            memo.tree_hash(self.allocator, program)?,
+            memo.tree_hash(self.allocator, env)?,
+        );
+        if let Some((result, cost)) = memo.results.get(&key).copied() {
+            self.push(result)?;
+            return Ok(Some(cost));
+        }
+        memo.pending.push((key, current_cost));
+        self.op_stack.push(Operation::MemoStore);
+        self.account_op_push();
+        Ok(None)
+    }
+
+    #[cfg(not(feature = "memoize"))]
+    #[inline(always)]
+    fn memo_lookup(
+        &mut self,
+        _program: NodePtr,
+        _env: NodePtr,
+        _current_cost: Cost,
+    ) -> Result<Option<Cost>, EvalErr> {
+        Ok(None)
+    }
+
+    #[cfg(feature = "memoize")]
+    fn memo_store(&mut self, current_cost: Cost) {
+        let memo = self.memo.as_mut().expect("MemoStore without memo");
+        let (key, start_cost) = memo.pending.pop().expect("MemoStore without pending entry");
+        let result = *self.val_stack.last().expect("MemoStore without result");
+        memo.results
+            .insert(key, (result, current_cost - start_cost));
+    }
+
     fn cons_op(&mut self) -> Result<Cost, EvalErr> {
         /* Join the top two operands. */
         let v1 = self.pop()?;
@@ -236,6 +579,7 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
             Ok(QUOTE_COST)
         } else {
             self.push_env(env)?;
+            self.push_frame(operator_node, operand_list);
             self.op_stack.push(Operation::Apply);
             self.account_op_push();
             self.push(operator_node)?;
@@ -258,7 +602,9 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
                 operands = rest;
             }
             // ensure a correct nil terminator
-            if self.allocator.atom_len(operands) != 0 {
+            if operands.is_hash_stub() {
+                err(operands, "descend into hash stub")
+            } else if self.allocator.atom_len(operands) != 0 {
                 err(operand_list, "bad operand list")
             } else {
                 self.push(self.allocator.nil())?;
@@ -278,7 +624,20 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
 
         // put a bunch of ops on op_stack
         let SExp::Pair(op_node, op_list) = self.allocator.sexp(program) else {
+            if program.is_hash_stub() {
+                // a lazy environment evaluated as a program, e.g. by apply
+                if let Some(program) = self.materialize(program)? {
+                    return self.eval_pair(program, env);
+                }
+                return err(program, "descend into hash stub");
+            }
             // the program is just a bitfield path through the env tree
+            if let Some(provider) = self.env_provider.as_deref_mut() {
+                let path = self.allocator.atom(program).as_ref().to_vec();
+                let r = traverse_path_lazy(self.allocator, &path, env, provider)?;
+                self.push(r.1)?;
+                return Ok(r.0);
+            }
             let r = match self.allocator.node(program) {
                 NodeVisitor::Buffer(buf) => traverse_path(self.allocator, buf, env)?,
                 NodeVisitor::U32(val) => traverse_path_fast(self.allocator, val, env)?,
@@ -303,6 +662,7 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
                 self.push_env(env)?;
                 self.push(new_operator)?;
                 self.push(op_list)?;
+                self.push_frame(new_operator, op_list);
                 self.op_stack.push(Operation::Apply);
                 self.account_op_push();
                 Ok(APPLY_COST)
@@ -317,7 +677,7 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
         let env: NodePtr = *self
             .env_stack
             .last()
-            .ok_or_else(|| EvalErr(program, "runtime error: env stack empty".into()))?;
+            .ok_or_else(|| EvalErr::new(program, "runtime error: env stack empty".into()))?;
         self.push(v2)?;
 
         // on the way back, build a list from the values
@@ -343,16 +703,85 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
         }
     }
 
+    // the sub tree of a lazy environment that's used as a value, rather than
+    // being looked into by a path. None if `node` isn't a lazy environment, or
+    // there's no provider
+    fn materialize(&mut self, node: NodePtr) -> Result<Option<NodePtr>, EvalErr> {
+        let Some(provider) = self.env_provider.as_deref_mut() else {
+            return Ok(None);
+        };
+        if !self.allocator.is_lazy_env(node) {
+            return Ok(None);
+        }
+        lazy_env_lookup(self.allocator, node, &[1], provider).map(Some)
+    }
+
+    // replace the lazy environments passed to an operator with their sub
+    // trees. The operand list is only rebuilt if there are any
+    fn materialize_args(&mut self, operand_list: NodePtr) -> Result<NodePtr, EvalErr> {
+        let mut args = Vec::new();
+        let mut changed = false;
+        let mut node = operand_list;
+        while let Some((arg, rest)) = self.allocator.next(node) {
+            match self.materialize(arg)? {
+                Some(value) => {
+                    args.push(value);
+                    changed = true;
+                }
+                None => args.push(arg),
+            }
+            node = rest;
+        }
+        if !changed {
+            return Ok(operand_list);
+        }
+        for arg in args.into_iter().rev() {
+            node = self.allocator.new_pair(arg, node)?;
+        }
+        Ok(node)
+    }
+
+    // operators may look inside any of their arguments, so none of them may
+    // be a hash stub. The exception is apply, whose environment is only
+    // descended into by paths, which check for stubs themselves
+    fn check_hash_stubs(&self, operator: NodePtr, operand_list: NodePtr) -> Result<(), EvalErr> {
+        if operator.is_hash_stub() {
+            return err(operator, "descend into hash stub");
+        }
+        let mut args = operand_list;
+        while let Some((arg, rest)) = self.allocator.next(args) {
+            if arg.is_hash_stub() {
+                return err(arg, "descend into hash stub");
+            }
+            args = rest;
+        }
+        if args.is_hash_stub() {
+            return err(args, "descend into hash stub");
+        }
+        Ok(())
+    }
+
     fn apply_op(&mut self, current_cost: Cost, max_cost: Cost) -> Result<Cost, EvalErr> {
-        let operand_list = self.pop()?;
+        let mut operand_list = self.pop()?;
         let operator = self.pop()?;
         if self.env_stack.pop().is_none() {
             return err(operator, "runtime error: env stack empty");
         }
         let op_atom = self.allocator.small_number(operator);
 
+        if self.allocator.has_hash_stubs() && op_atom != Some(self.dialect.apply_kw()) {
+            if self.env_provider.is_some() {
+                operand_list = self.materialize_args(operand_list)?;
+            }
+            self.check_hash_stubs(operator, operand_list)?;
+        }
+
         if op_atom == Some(self.dialect.apply_kw()) {
             let [new_operator, env] = get_args::<2>(self.allocator, operand_list, "apply")?;
+            // the cached cost includes APPLY_COST
+            if let Some(cost) = self.memo_lookup(new_operator, env, current_cost)? {
+                return Ok(cost);
+            }
             self.eval_pair(new_operator, env).map(|c| c + APPLY_COST)
         } else if op_atom == Some(self.dialect.softfork_kw()) {
             let expected_cost = uint_atom::<8>(
@@ -404,6 +833,25 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
                 OperatorSet::Default
             };
 
+            #[cfg(feature = "tracing")]
+            let span = tracing::trace_span!(
+                "op",
+                opcode = ?self.allocator.atom(operator).as_ref(),
+                args = self.allocator.list_len(operand_list).unwrap_or(0),
+                cost = tracing::field::Empty
+            )
+            .entered();
+
+            // fail before doing any work if the operator can't possibly fit
+            // in the remaining budget
+            if self
+                .dialect
+                .op_min_cost(self.allocator, operator, current_extensions)
+                > max_cost
+            {
+                return err(operator, "cost exceeded");
+            }
+
             let r = self.dialect.op(
                 self.allocator,
                 operator,
@@ -411,11 +859,24 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
                 max_cost,
                 current_extensions,
             )?;
+
+            #[cfg(feature = "tracing")]
+            span.record("cost", r.0);
+
             self.push(r.1)?;
             Ok(r.0)
         }
     }
 
+    // record the current allocator usage, if we're tracking peak usage. This
+    // must be called before restoring a checkpoint
+    fn observe_usage(&mut self) {
+        if let Some(peak) = &mut self.peak_usage {
+            peak.peak_pairs = core::cmp::max(peak.peak_pairs, self.allocator.pair_count());
+            peak.peak_atom_bytes = core::cmp::max(peak.peak_atom_bytes, self.allocator.heap_size());
+        }
+    }
+
     fn exit_guard(&mut self, current_cost: Cost) -> Result<Cost, EvalErr> {
         // this is called when we are done executing a softfork program.
         // This is when we have to validate the cost
@@ -439,6 +900,7 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
         // softfork program. Since the softfork always return nil, no value can
         // escape the softfork program, and it's therefore safe to restore the
         // heap
+        self.observe_usage();
         self.allocator.restore_checkpoint(&guard.allocator_state);
 
         // the softfork always returns nil, pop the value pushed by the
@@ -452,19 +914,57 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
     }
 
     pub fn run_program(&mut self, program: NodePtr, env: NodePtr, max_cost: Cost) -> Response {
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!("run_program", cost = tracing::field::Empty).entered();
+
+        let mut cost: Cost = 0;
+        let ret = self.run_program_impl(program, env, max_cost, &mut cost);
+        self.observe_usage();
+
+        #[cfg(feature = "tracing")]
+        span.record("cost", cost);
+
+        ret
+    }
+
+    // the cost accumulated so far is tracked in `cost`, which remains valid
+    // if the program fails. It then holds the cost of all operations that
+    // completed before the failure
+    fn run_program_impl(
+        &mut self,
+        program: NodePtr,
+        env: NodePtr,
+        max_cost: Cost,
+        cost: &mut Cost,
+    ) -> Response {
         self.val_stack = vec![];
         self.op_stack = vec![];
 
-        // max_cost is always in effect, and necessary to prevent wrap-around of
-        // the cost integer.
-        let max_cost = if max_cost == 0 { Cost::MAX } else { max_cost };
+        // a max_cost of 0 means there is no limit. Costs are still added with
+        // overflow checks, so they can't wrap around
+        let max_cost = if max_cost == 0 {
+            UNLIMITED_COST
+        } else {
+            max_cost
+        };
         let max_cost_ptr = self.allocator.new_number(max_cost.into())?;
 
-        let mut cost: Cost = 0;
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics_heap_base = self.metrics_heap();
+        }
 
-        cost += self.eval_pair(program, env)?;
+        *cost += self.eval_pair(program, env)?;
 
+        let mut steps: u32 = 0;
         loop {
+            if let Some(cancel) = self.cancel {
+                if steps.is_multiple_of(CANCEL_CHECK_INTERVAL) && cancel.load(Ordering::Relaxed) {
+                    return err(self.allocator.nil(), "interrupted");
+                }
+                steps = steps.wrapping_add(1);
+            }
+
             // if we are in a softfork guard, temporarily use the guard's
             // expected cost as the upper limit. This lets us fail early in case
             // it's wrong. It's guaranteed to be <= max_cost, because we check
@@ -475,20 +975,22 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
                 max_cost
             };
 
-            if cost > effective_max_cost {
+            if *cost > effective_max_cost {
                 return err(max_cost_ptr, "cost exceeded");
             }
+            self.account_step();
             let top = self.op_stack.pop();
             let op = match top {
                 Some(f) => f,
                 None => break,
             };
-            cost += match op {
+            self.account_reduction();
+            let op_cost = match op {
                 Operation::Apply => augment_cost_errors(
-                    self.apply_op(cost, effective_max_cost - cost),
+                    self.apply_op(*cost, effective_max_cost - *cost),
                     max_cost_ptr,
                 )?,
-                Operation::ExitGuard => self.exit_guard(cost)?,
+                Operation::ExitGuard => self.exit_guard(*cost)?,
                 Operation::Cons => self.cons_op()?,
                 Operation::SwapEval => augment_cost_errors(self.swap_eval_op(), max_cost_ptr)?,
                 #[cfg(feature = "pre-eval")]
@@ -498,12 +1000,30 @@ impl<'a, D: Dialect> RunProgramContext<'a, D> {
                     f(self.allocator, peek);
                     0
                 }
+                #[cfg(feature = "traceback")]
+                Operation::PopFrame => {
+                    if let Some(tb) = &mut self.traceback {
+                        tb.pop();
+                    }
+                    0
+                }
+                #[cfg(feature = "memoize")]
+                Operation::MemoStore => {
+                    self.memo_store(*cost);
+                    0
+                }
             };
+            *cost = cost
+                .checked_add(op_cost)
+                .ok_or_else(|| EvalErr::new(max_cost_ptr, "cost exceeded".to_string()))?;
         }
-        Ok(Reduction(cost, self.pop()?))
+        Ok(Reduction(*cost, self.pop()?))
     }
 }
 
+/// Run `program` with `env` as its environment. Fails with "cost exceeded" if
+/// the cost exceeds `max_cost`. A `max_cost` of 0 (or `UNLIMITED_COST`) means
+/// there is no limit.
 pub fn run_program<'a, D: Dialect>(
     allocator: &'a mut Allocator,
     dialect: &'a D,
@@ -515,6 +1035,73 @@ pub fn run_program<'a, D: Dialect>(
     rpc.run_program(program, env, max_cost)
 }
 
+/// Like `run_program()`, but returns a `RunOutput`, to serialize or hash the
+/// result
+pub fn run_program_output<D: Dialect>(
+    allocator: &mut Allocator,
+    dialect: &D,
+    program: NodePtr,
+    env: NodePtr,
+    max_cost: Cost,
+) -> Result<RunOutput, EvalErr> {
+    run_program(allocator, dialect, program, env, max_cost).map(RunOutput::from)
+}
+
+// like run_program(), but if the program fails, the error is returned along
+// with the cost that was consumed up to the point of failure. This is
+// deterministic; it's the sum of the costs of all operations that completed
+// before the failing one.
+pub fn run_program_with_partial_cost<'a, D: Dialect>(
+    allocator: &'a mut Allocator,
+    dialect: &'a D,
+    program: NodePtr,
+    env: NodePtr,
+    max_cost: Cost,
+) -> Result<Reduction, (EvalErr, Cost)> {
+    let mut rpc = RunProgramContext::new(allocator, dialect);
+    let mut cost: Cost = 0;
+    rpc.run_program_impl(program, env, max_cost, &mut cost)
+        .map_err(|e| (e, cost))
+}
+
+// like run_program(), but the evaluation can be aborted from another thread
+// by setting `cancel`. The flag is polled periodically, so the program may
+// perform up to a thousand or so more operations before it fails with
+// "interrupted". Setting the flag before the call fails it immediately.
+pub fn run_program_with_cancel<'a, D: Dialect>(
+    allocator: &'a mut Allocator,
+    dialect: &'a D,
+    program: NodePtr,
+    env: NodePtr,
+    max_cost: Cost,
+    cancel: &'a AtomicBool,
+) -> Response {
+    let mut rpc = RunProgramContext::new(allocator, dialect);
+    rpc.cancel = Some(cancel);
+    rpc.run_program(program, env, max_cost)
+}
+
+// like run_program(), but lazy environments (see Allocator::new_lazy_env())
+// are produced on demand by `provider`. Paths reaching a lazy environment
+// pass the rest of the path to the provider, and a lazy environment used as
+// a value (e.g. passed to an operator) asks the provider for path 1, i.e. the
+// whole sub tree. The provider may be called more than once for the same
+// path. Results and costs are the same as if the sub trees had been part of
+// the environment all along, except that the result may still contain lazy
+// environments the program never looked into
+pub fn run_program_with_env_provider<'a, D: Dialect>(
+    allocator: &'a mut Allocator,
+    dialect: &'a D,
+    program: NodePtr,
+    env: NodePtr,
+    max_cost: Cost,
+    provider: &'a mut EnvProvider<'a>,
+) -> Response {
+    let mut rpc = RunProgramContext::new(allocator, dialect);
+    rpc.env_provider = Some(provider);
+    rpc.run_program(program, env, max_cost)
+}
+
 #[cfg(feature = "pre-eval")]
 pub fn run_program_with_pre_eval<'a, D: Dialect>(
     allocator: &'a mut Allocator,
@@ -528,6 +1115,84 @@ pub fn run_program_with_pre_eval<'a, D: Dialect>(
     rpc.run_program(program, env, max_cost)
 }
 
+// like run_program(), but on failure, the error includes the innermost
+// `max_frames` operator calls that were being evaluated. Use
+// format_traceback() to render it.
+#[cfg(feature = "traceback")]
+pub fn run_program_with_traceback<'a, D: Dialect>(
+    allocator: &'a mut Allocator,
+    dialect: &'a D,
+    program: NodePtr,
+    env: NodePtr,
+    max_cost: Cost,
+    max_frames: usize,
+) -> Result<Reduction, TracebackErr> {
+    let mut rpc = RunProgramContext::new(allocator, dialect);
+    rpc.traceback = Some(FrameStack::new(max_frames));
+    rpc.run_program(program, env, max_cost)
+        .map_err(|err| TracebackErr {
+            err,
+            frames: rpc
+                .traceback
+                .take()
+                .map(|tb| tb.frames.into_iter().collect())
+                .unwrap_or_default(),
+        })
+}
+
+// like run_program(), but the results of apply are cached within the run,
+// keyed by the tree hashes of the program and environment. Repeated
+// applications return the cached result and are charged the same cost as
+// the first one, so results and costs are unchanged, but the work is only done
+// once. Hashing every application has a cost of its own, so this is meant for
+// analysis tools, never for consensus.
+#[cfg(feature = "memoize")]
+pub fn run_program_with_memoization<'a, D: Dialect>(
+    allocator: &'a mut Allocator,
+    dialect: &'a D,
+    program: NodePtr,
+    env: NodePtr,
+    max_cost: Cost,
+) -> Response {
+    let mut rpc = RunProgramContext::new(allocator, dialect);
+    rpc.memo = Some(Memo::new());
+    rpc.run_program(program, env, max_cost)
+}
+
+// like run_program(), but also returns the peak number of pairs and bytes of
+// atoms allocated while running the program, including allocations inside
+// softfork guards that were released when the guard exited. The usage
+// includes whatever was allocated before the call.
+pub fn run_program_with_peak_usage<'a, D: Dialect>(
+    allocator: &'a mut Allocator,
+    dialect: &'a D,
+    program: NodePtr,
+    env: NodePtr,
+    max_cost: Cost,
+) -> (PeakUsage, Response) {
+    let mut rpc = RunProgramContext::new(allocator, dialect);
+    rpc.peak_usage = Some(PeakUsage::default());
+    let ret = rpc.run_program(program, env, max_cost);
+    (rpc.peak_usage.unwrap_or_default(), ret)
+}
+
+// like run_program(), but also returns the peak heap usage, stack depth and
+// number of reductions of the program. These are deterministic, and returned
+// whether the program succeeds or fails. Heap usage is counted from the start
+// of the call, so it doesn't include the program and environment themselves
+#[cfg(feature = "metrics")]
+pub fn run_program_with_metrics<'a, D: Dialect>(
+    allocator: &'a mut Allocator,
+    dialect: &'a D,
+    program: NodePtr,
+    env: NodePtr,
+    max_cost: Cost,
+) -> (EvalMetrics, Response) {
+    let mut rpc = RunProgramContext::new(allocator, dialect);
+    let ret = rpc.run_program(program, env, max_cost);
+    (rpc.metrics, ret)
+}
+
 #[cfg(feature = "counters")]
 pub fn run_program_with_counters<'a, D: Dialect>(
     allocator: &'a mut Allocator,
@@ -1190,6 +1855,26 @@ const TEST_CASES: &[RunProgramTest] = &[
         err: "unknown softfork extension",
     },
 
+    // unknown operators inside the softfork guard follow the same rules as
+    // outside of it. Unless NO_UNKNOWN_OPS is set, they are no-ops with a
+    // well defined cost, returning nil
+    RunProgramTest {
+        prg: "(softfork (q . 162) (q . 0) (q 0x0001 (q . 42)) (q . ()))",
+        args: "()",
+        flags: 0,
+        result: Some("()"),
+        cost: 243,
+        err: "",
+    },
+    RunProgramTest {
+        prg: "(softfork (q . 162) (q . 0) (q 0x0001 (q . 42)) (q . ()))",
+        args: "()",
+        flags: NO_UNKNOWN_OPS,
+        result: None,
+        cost: 10000,
+        err: "unimplemented operator",
+    },
+
     // coinid extension
     // make sure we can execute the coinid operator under softfork 0
     // this program raises an exception if the computed coin ID matches the
@@ -1242,6 +1927,52 @@ const TEST_CASES: &[RunProgramTest] = &[
         err: "unimplemented operator",
     },
 
+    // quote and apply are only the canonical, single byte, opcodes 1 and 2.
+    // With a leading zero, they are unknown operators (with cost 1)
+    RunProgramTest {
+        prg: "(0x0001 (q . 42))",
+        args: "()",
+        flags: 0,
+        result: Some("()"),
+        cost: 22,
+        err: "",
+    },
+    RunProgramTest {
+        prg: "(0x0002 (q . 42))",
+        args: "()",
+        flags: 0,
+        result: Some("()"),
+        cost: 22,
+        err: "",
+    },
+    // 0x00 is not reserved, it's an unknown operator, just like in the
+    // reference implementation
+    RunProgramTest {
+        prg: "(0x00 (q . 42))",
+        args: "()",
+        flags: 0,
+        result: Some("()"),
+        cost: 22,
+        err: "",
+    },
+    RunProgramTest {
+        prg: "(0x0001 (q . 42))",
+        args: "()",
+        flags: NO_UNKNOWN_OPS,
+        result: None,
+        cost: 22,
+        err: "unimplemented operator",
+    },
+    // the empty atom is reserved
+    RunProgramTest {
+        prg: "(() (q . 42))",
+        args: "()",
+        flags: 0,
+        result: None,
+        cost: 22,
+        err: "reserved operator",
+    },
+
     // secp261k1
 
     RunProgramTest {
@@ -1327,6 +2058,43 @@ fn test_run_program() {
     }
 }
 
+#[test]
+fn test_partial_cost() {
+    use crate::chik_dialect::ChikDialect;
+
+    let mut a = Allocator::new();
+    let dialect = ChikDialect::new(0);
+
+    // the arguments are evaluated last-to-first, so the sha256 call completes
+    // before we raise
+    let prefix = check(parse_exp(&mut a, "(sha256 (q . \"foo\"))"));
+    let program = check(parse_exp(&mut a, "(c (x) (sha256 (q . \"foo\")))"));
+    let args = a.nil();
+
+    let Reduction(prefix_cost, _) =
+        run_program_with_partial_cost(&mut a, &dialect, prefix, args, 0).unwrap();
+
+    let (err, partial_cost) =
+        run_program_with_partial_cost(&mut a, &dialect, program, args, 0).unwrap_err();
+    assert_eq!(err.1, "klvm raise");
+    assert!(partial_cost > 0);
+    // in addition to the sha256 call, the partial cost includes the base cost
+    // of the "c" and "x" operators, but not the failing "x" call itself
+    assert_eq!(partial_cost, prefix_cost + 2 * OP_COST);
+
+    // the partial cost is deterministic
+    let (_, partial_cost2) =
+        run_program_with_partial_cost(&mut a, &dialect, program, args, 0).unwrap_err();
+    assert_eq!(partial_cost, partial_cost2);
+
+    // when we run out of cost, the partial cost is what pushed us over the
+    // limit
+    let (err, partial_cost) =
+        run_program_with_partial_cost(&mut a, &dialect, prefix, args, prefix_cost - 1).unwrap_err();
+    assert_eq!(err.1, "cost exceeded");
+    assert_eq!(partial_cost, prefix_cost);
+}
+
 #[cfg(test)]
 use rstest::rstest;
 
@@ -1527,3 +2295,723 @@ fn test_counters() {
 
     assert_eq!(result.unwrap().0, cost);
 }
+
+#[cfg(feature = "traceback")]
+#[test]
+fn test_traceback() {
+    use crate::chik_dialect::ChikDialect;
+
+    let mut a = Allocator::new();
+
+    // the raise is nested three operator calls deep
+    let program = check(parse_exp(&mut a, "(c (q . 1) (f (x (q . \"boom\"))))"));
+    let args = a.nil();
+
+    let err =
+        run_program_with_traceback(&mut a, &ChikDialect::new(0), program, args, 0, 10).unwrap_err();
+    assert_eq!(err.err.1, "klvm raise");
+    assert_eq!(err.frames.len(), 3);
+
+    assert_eq!(
+        format_traceback(&a, &err),
+        "klvm raise: \"boom\"\n\
+         traceback (innermost last):\n  \
+         0: (c (q . 1) (f (x (q . \"boom\"))))\n  \
+         1: (f (x (q . \"boom\")))\n  \
+         2: (x (q . \"boom\"))\n"
+    );
+
+    // only the innermost frames are kept
+    let err =
+        run_program_with_traceback(&mut a, &ChikDialect::new(0), program, args, 0, 2).unwrap_err();
+    assert_eq!(err.frames.len(), 2);
+    assert!(format_traceback(&a, &err).contains("  1: (x (q . \"boom\"))"));
+
+    // frames are popped as calls complete, including calls made via apply
+    let program = check(parse_exp(
+        &mut a,
+        "(c (a (q . (a (q x) ())) ()) (a (q f (q 1 2)) ()))",
+    ));
+    let err =
+        run_program_with_traceback(&mut a, &ChikDialect::new(0), program, args, 0, 10).unwrap_err();
+    assert_eq!(err.frames.len(), 4);
+    // atoms that aren't in operator position are printed as integers
+    assert_eq!(
+        format_traceback(&a, &err),
+        "klvm raise: ()\n\
+         traceback (innermost last):\n  \
+         0: (c (a (q 2 (q 8) ()) ()) (a (q 5 (q 1 2)) ()))\n  \
+         1: (a (q 2 (q 8) ()) ())\n  \
+         2: (a (q 8) ())\n  \
+         3: (x)\n"
+    );
+
+    // very wide frames are truncated
+    let long = "0x".to_string() + &"ab".repeat(200);
+    let program = check(parse_exp(&mut a, &format!("(x (q . {long}))")));
+    let err =
+        run_program_with_traceback(&mut a, &ChikDialect::new(0), program, args, 0, 10).unwrap_err();
+    let tb = format_traceback(&a, &err);
+    let lines: Vec<&str> = tb.lines().collect();
+    assert_eq!(lines[2].len(), MAX_FRAME_WIDTH + 5);
+    assert!(lines[2].ends_with("..."));
+}
+
+#[cfg(test)]
+// counts the operators dispatched to the dialect. Once `trigger` operators
+// have been executed, the cancel flag is set
+struct CancellingDialect<'a> {
+    inner: crate::chik_dialect::ChikDialect,
+    ops: core::cell::Cell<usize>,
+    trigger: usize,
+    cancel: &'a AtomicBool,
+}
+
+#[cfg(test)]
+impl Dialect for CancellingDialect<'_> {
+    fn quote_kw(&self) -> u32 {
+        self.inner.quote_kw()
+    }
+    fn apply_kw(&self) -> u32 {
+        self.inner.apply_kw()
+    }
+    fn softfork_kw(&self) -> u32 {
+        self.inner.softfork_kw()
+    }
+    fn softfork_extension(&self, ext: u32) -> OperatorSet {
+        self.inner.softfork_extension(ext)
+    }
+    fn op(
+        &self,
+        allocator: &mut Allocator,
+        op: NodePtr,
+        args: NodePtr,
+        max_cost: Cost,
+        extensions: OperatorSet,
+    ) -> Response {
+        self.ops.set(self.ops.get() + 1);
+        if self.ops.get() == self.trigger {
+            self.cancel.store(true, Ordering::Relaxed);
+        }
+        self.inner.op(allocator, op, args, max_cost, extensions)
+    }
+    fn allow_unknown_ops(&self) -> bool {
+        self.inner.allow_unknown_ops()
+    }
+    fn op_min_cost(&self, allocator: &Allocator, op: NodePtr, extensions: OperatorSet) -> Cost {
+        self.inner.op_min_cost(allocator, op, extensions)
+    }
+}
+
+// a program that never terminates (until it runs out of cost or heap). It
+// applies itself, cons'ing a new environment every iteration
+#[cfg(test)]
+const INFINITE_LOOP: (&str, &str) = ("(a 2 (c 2 ()))", "((a 2 (c 2 ())))");
+
+#[test]
+fn test_cancel() {
+    use crate::chik_dialect::ChikDialect;
+
+    let mut a = Allocator::new();
+    let program = check(parse_exp(&mut a, INFINITE_LOOP.0));
+    let args = check(parse_exp(&mut a, INFINITE_LOOP.1));
+
+    // the flag is set after 1000 operators. The program must stop within
+    // CANCEL_CHECK_INTERVAL iterations of the interpreter loop, each of which
+    // executes at most one operator
+    let cancel = AtomicBool::new(false);
+    let dialect = CancellingDialect {
+        inner: ChikDialect::new(0),
+        ops: core::cell::Cell::new(0),
+        trigger: 1000,
+        cancel: &cancel,
+    };
+    let e = run_program_with_cancel(&mut a, &dialect, program, args, 0, &cancel).unwrap_err();
+    assert_eq!(e.1, "interrupted");
+    assert!(dialect.ops.get() >= 1000);
+    assert!(dialect.ops.get() <= 1000 + CANCEL_CHECK_INTERVAL as usize);
+
+    // a flag that's already set stops the program before it starts
+    let dialect = ChikDialect::new(0);
+    let e = run_program_with_cancel(&mut a, &dialect, program, args, 0, &cancel).unwrap_err();
+    assert_eq!(e.1, "interrupted");
+
+    // without setting the flag, programs run to completion
+    let cancel = AtomicBool::new(false);
+    let program = check(parse_exp(&mut a, "(+ 2 5)"));
+    let args = check(parse_exp(&mut a, "(3 4)"));
+    let Reduction(cost, result) =
+        run_program_with_cancel(&mut a, &dialect, program, args, 0, &cancel).unwrap();
+    assert_eq!(a.number(result), 7.into());
+    let Reduction(expected_cost, _) = run_program(&mut a, &dialect, program, args, 0).unwrap();
+    assert_eq!(cost, expected_cost);
+}
+
+#[test]
+fn test_cancel_from_thread() {
+    use crate::chik_dialect::ChikDialect;
+    use alloc::sync::Arc;
+    use core::time::Duration;
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let worker = {
+        let cancel = cancel.clone();
+        std::thread::spawn(move || {
+            let mut a = Allocator::new();
+            let program = check(parse_exp(&mut a, INFINITE_LOOP.0));
+            let args = check(parse_exp(&mut a, INFINITE_LOOP.1));
+            let dialect = ChikDialect::new(0);
+            run_program_with_cancel(&mut a, &dialect, program, args, 0, &cancel)
+        })
+    };
+    std::thread::sleep(Duration::from_millis(20));
+    cancel.store(true, Ordering::Relaxed);
+    let e = worker.join().unwrap().unwrap_err();
+    assert_eq!(e.1, "interrupted");
+}
+
+#[test]
+fn test_hash_stub() {
+    use crate::chik_dialect::ChikDialect;
+
+    let dialect = ChikDialect::new(0);
+    let mut a = Allocator::new();
+    let stub = a.new_hash_stub([0x42; 32].into()).unwrap();
+    let five = a.new_small_number(5).unwrap();
+    let nil = a.nil();
+    // the environment is (stub 5)
+    let env = a.new_pair(five, nil).unwrap();
+    let env = a.new_pair(stub, env).unwrap();
+
+    let run = |a: &mut Allocator, program: &str| {
+        let program = check(parse_exp(a, program));
+        run_program(a, &dialect, program, env, 0)
+    };
+
+    // the stub can be referenced and passed to apply, as long as nothing
+    // looks inside it
+    assert_eq!(run(&mut a, "2").unwrap().1, stub);
+    assert_eq!(run(&mut a, "(a (q . 5) 1)").unwrap().1, five);
+    assert_eq!(run(&mut a, "(a (q . 1) 2)").unwrap().1, stub);
+
+    for program in [
+        // paths through the stub
+        "4",
+        "(a (q . 2) 2)",
+        // running the stub
+        "(a 2 (q . ()))",
+        // operators may look inside any argument, even cons
+        "(f 2)",
+        "(sha256 2)",
+        "(c 2 (q . ()))",
+    ] {
+        let e = run(&mut a, program).unwrap_err();
+        assert_eq!(e.1, "descend into hash stub", "{program}");
+        assert_eq!(e.0, stub, "{program}");
+    }
+}
+
+// the environment (1000 1000000000000 0x4242...), fully present and with its
+// rest as a lazy environment. Returns (full env, lazy env, the rest)
+#[cfg(test)]
+fn lazy_test_env(a: &mut Allocator) -> (NodePtr, NodePtr, NodePtr) {
+    let head = a.new_small_number(1000).unwrap();
+    let amount = a.new_number(1000000000000_u64.into()).unwrap();
+    let puzzle_hash = a.new_atom(&[0x42; 32]).unwrap();
+    let nil = a.nil();
+    let rest = a.new_pair(puzzle_hash, nil).unwrap();
+    let rest = a.new_pair(amount, rest).unwrap();
+    let full = a.new_pair(head, rest).unwrap();
+    let lazy_env = a.new_lazy_env().unwrap();
+    let lazy = a.new_pair(head, lazy_env).unwrap();
+    (full, lazy, rest)
+}
+
+#[cfg(test)]
+#[rstest]
+// two paths into the lazy environment. Arguments are evaluated last to first
+#[case("(+ 2 5 (strlen 11))", &[&[5][..], &[2][..]])]
+// the lazy environment itself, as a path or passed to an operator
+#[case("(f 3)", &[&[1][..]])]
+#[case("(sha256 (f (r 1)))", &[&[1][..]])]
+// as the environment of apply
+#[case("(a (q + 2 (strlen 5)) 3)", &[&[1][..]])]
+fn test_env_provider(#[case] program: &str, #[case] expected_paths: &[&[u8]]) {
+    use crate::chik_dialect::ChikDialect;
+    use crate::test_ops::node_eq;
+
+    let dialect = ChikDialect::new(0);
+    let mut a = Allocator::new();
+    let (full, lazy, rest) = lazy_test_env(&mut a);
+    let program = check(parse_exp(&mut a, program));
+    let expected = run_program(&mut a, &dialect, program, full, 11000000000).unwrap();
+
+    let mut paths = Vec::<Vec<u8>>::new();
+    let mut provider = |a: &mut Allocator, path: &[u8]| {
+        paths.push(path.to_vec());
+        traverse_path(a, path, rest).map(|r| r.1)
+    };
+    let Reduction(cost, result) =
+        run_program_with_env_provider(&mut a, &dialect, program, lazy, 11000000000, &mut provider)
+            .unwrap();
+    assert_eq!(cost, expected.0);
+    assert!(node_eq(&a, result, expected.1));
+    assert_eq!(paths, expected_paths);
+}
+
+#[test]
+fn test_env_provider_errors() {
+    use crate::chik_dialect::ChikDialect;
+
+    let dialect = ChikDialect::new(0);
+    let mut a = Allocator::new();
+    let (_, lazy, _) = lazy_test_env(&mut a);
+    let lazy_env = a.next(lazy).unwrap().1;
+    let program = check(parse_exp(&mut a, "(+ 2 5)"));
+
+    // errors from the provider are passed through
+    let nil = a.nil();
+    let mut provider =
+        |_: &mut Allocator, _: &[u8]| -> Result<NodePtr, EvalErr> { err(nil, "coin not found") };
+    let e =
+        run_program_with_env_provider(&mut a, &dialect, program, lazy, 11000000000, &mut provider)
+            .unwrap_err();
+    assert_eq!(e, EvalErr::new(nil, "coin not found".to_string()));
+
+    // without a provider, the lazy environment is just a hash stub
+    let e = run_program(&mut a, &dialect, program, lazy, 11000000000).unwrap_err();
+    assert_eq!(
+        e,
+        EvalErr::new(lazy_env, "descend into hash stub".to_string())
+    );
+
+    // lazy environments the program doesn't look into are returned as-is
+    let program = check(parse_exp(&mut a, "(r 1)"));
+    let mut provider =
+        |_: &mut Allocator, _: &[u8]| -> Result<NodePtr, EvalErr> { panic!("unexpected lookup") };
+    let Reduction(_, result) =
+        run_program_with_env_provider(&mut a, &dialect, program, lazy, 11000000000, &mut provider)
+            .unwrap();
+    assert_eq!(result, lazy_env);
+}
+
+#[test]
+fn test_peak_usage() {
+    use crate::chik_dialect::ChikDialect;
+
+    let dialect = ChikDialect::new(0);
+    let mut a = Allocator::new();
+    let big = a.new_atom(&[0x42; 1000]).unwrap();
+    let nil = a.nil();
+    let env = a.new_pair(big, nil).unwrap();
+
+    // the softfork guard allocates a 4000 byte atom, which is released when
+    // the guard exits. The expected cost of the guard includes the cost of
+    // entering it
+    let inner = check(parse_exp(&mut a, "(concat 2 2 2 2)"));
+    let Reduction(inner_cost, _) = run_program(&mut a, &dialect, inner, env, 0).unwrap();
+    let program = format!(
+        "(c (softfork (q . {}) (q . 0) (q concat 2 2 2 2) 1) (q . ()))",
+        inner_cost + GUARD_COST
+    );
+    let program = check(parse_exp(&mut a, &program));
+
+    let checkpoint = a.checkpoint();
+    let (peak, result) = run_program_with_peak_usage(&mut a, &dialect, program, env, 0);
+    let Reduction(_, result) = result.unwrap();
+    assert!(a.next(result).is_some());
+    assert!(peak.peak_atom_bytes >= a.heap_size() + 4000);
+    assert!(peak.peak_pairs > a.pair_count());
+
+    // without the softfork guard, nothing is released
+    a.restore_checkpoint(&checkpoint);
+    let program = check(parse_exp(&mut a, "(c (concat 2 2 2 2) (q . ()))"));
+    let (peak, result) = run_program_with_peak_usage(&mut a, &dialect, program, env, 0);
+    result.unwrap();
+    assert_eq!(peak.peak_atom_bytes, a.heap_size());
+    assert_eq!(peak.peak_pairs, a.pair_count());
+}
+
+// the stack depths and reductions are worked out by hand, by following the
+// interpreter. Any change to the accounting must update them
+#[cfg(feature = "metrics")]
+#[cfg(test)]
+#[rstest]
+// quote doesn't take any steps
+#[case("(q . 1)", 0, 1, 0)]
+// two pairs for the argument list
+#[case("(+ (q . 2) (q . 3))", 2 * PAIR_BYTES, 8, 5)]
+#[case("(+ (q . 1) (+ (q . 2) (q . 3)))", 4 * PAIR_BYTES, 15, 10)]
+// plus the 6 bytes of the concatenated atom
+#[case("(concat (q . \"foo\") (q . \"bar\"))", 2 * PAIR_BYTES + 6, 8, 5)]
+fn test_metrics(
+    #[case] program: &str,
+    #[case] peak_heap: usize,
+    #[case] peak_stack_depth: usize,
+    #[case] reductions: u64,
+) {
+    use crate::chik_dialect::ChikDialect;
+
+    let mut a = Allocator::new();
+    let program = check(parse_exp(&mut a, program));
+    let env = a.nil();
+    let (metrics, result) =
+        run_program_with_metrics(&mut a, &ChikDialect::new(0), program, env, 10000);
+    result.unwrap();
+    assert_eq!(
+        metrics,
+        EvalMetrics {
+            peak_heap,
+            peak_stack_depth,
+            reductions,
+        }
+    );
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_metrics_failure() {
+    use crate::chik_dialect::ChikDialect;
+
+    // the metrics are returned even if the program fails
+    let mut a = Allocator::new();
+    let program = check(parse_exp(&mut a, "(x)"));
+    let env = a.nil();
+    let (metrics, result) =
+        run_program_with_metrics(&mut a, &ChikDialect::new(0), program, env, 10000);
+    assert_eq!(result.unwrap_err().1, "klvm raise");
+    assert_eq!(
+        metrics,
+        EvalMetrics {
+            peak_heap: 0,
+            peak_stack_depth: 4,
+            reductions: 1,
+        }
+    );
+}
+
+#[cfg(feature = "memoize")]
+#[test]
+fn test_memoization() {
+    use crate::chik_dialect::ChikDialect;
+    use crate::test_ops::node_eq;
+    use core::cell::Cell;
+
+    // counts the operators dispatched to the dialect
+    struct CountingDialect {
+        inner: ChikDialect,
+        ops: Cell<usize>,
+    }
+
+    impl Dialect for CountingDialect {
+        fn quote_kw(&self) -> u32 {
+            self.inner.quote_kw()
+        }
+        fn apply_kw(&self) -> u32 {
+            self.inner.apply_kw()
+        }
+        fn softfork_kw(&self) -> u32 {
+            self.inner.softfork_kw()
+        }
+        fn softfork_extension(&self, ext: u32) -> OperatorSet {
+            self.inner.softfork_extension(ext)
+        }
+        fn op(
+            &self,
+            allocator: &mut Allocator,
+            op: NodePtr,
+            args: NodePtr,
+            max_cost: Cost,
+            extensions: OperatorSet,
+        ) -> Response {
+            self.ops.set(self.ops.get() + 1);
+            self.inner.op(allocator, op, args, max_cost, extensions)
+        }
+        fn allow_unknown_ops(&self) -> bool {
+            self.inner.allow_unknown_ops()
+        }
+    }
+
+    let dialect = CountingDialect {
+        inner: ChikDialect::new(0),
+        ops: Cell::new(0),
+    };
+    let mut a = Allocator::new();
+
+    // the same program is applied to the same environment three times. The
+    // last time the environment is a different, but equal, tree
+    let program = check(parse_exp(
+        &mut a,
+        "(c (a 2 3) (c (a 2 3) (c (a 2 (q . (\"foo\" . \"bar\"))) ())))",
+    ));
+    let args = check(parse_exp(
+        &mut a,
+        "((sha256 (concat 2 3)) . (\"foo\" . \"bar\"))",
+    ));
+
+    let Reduction(cost, result) = run_program(&mut a, &dialect, program, args, 0).unwrap();
+    // sha256 and concat three times, and the three c
+    assert_eq!(dialect.ops.get(), 9);
+
+    dialect.ops.set(0);
+    let Reduction(memo_cost, memo_result) =
+        run_program_with_memoization(&mut a, &dialect, program, args, 0).unwrap();
+    // sha256 and concat only once
+    assert_eq!(dialect.ops.get(), 5);
+    assert_eq!(cost, memo_cost);
+    assert!(node_eq(&a, result, memo_result));
+
+    // running out of cost fails the same way
+    for max_cost in [cost - 1, cost / 2] {
+        let e1 = run_program(&mut a, &dialect, program, args, max_cost).unwrap_err();
+        let e2 =
+            run_program_with_memoization(&mut a, &dialect, program, args, max_cost).unwrap_err();
+        assert_eq!(e1.1, e2.1);
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing_spans() {
+    use crate::chik_dialect::ChikDialect;
+    use core::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    type SpanFields = Vec<(String, String)>;
+
+    // records the name and fields of every span
+    #[derive(Default)]
+    struct Spans(Mutex<Vec<(String, SpanFields)>>);
+
+    struct Fields<'a>(&'a mut SpanFields);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .push((field.name().to_string(), format!("{value:?}")));
+        }
+    }
+
+    struct Capture(Arc<Spans>);
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut spans = self.0 .0.lock().unwrap();
+            let mut fields = Vec::new();
+            span.record(&mut Fields(&mut fields));
+            spans.push((span.metadata().name().to_string(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.0 .0.lock().unwrap();
+            let idx = span.into_u64() as usize - 1;
+            values.record(&mut Fields(&mut spans[idx].1));
+        }
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+        fn event(&self, _event: &Event<'_>) {}
+        fn enter(&self, _span: &Id) {}
+        fn exit(&self, _span: &Id) {}
+    }
+
+    let spans = Arc::new(Spans::default());
+    let mut a = Allocator::new();
+    let program = check(parse_exp(&mut a, "(+ (q . 1) (* (q . 2) (q . 3)))"));
+    let args = a.nil();
+
+    let result = tracing::subscriber::with_default(Capture(spans.clone()), || {
+        run_program(&mut a, &ChikDialect::new(0), program, args, 0).unwrap()
+    });
+
+    let spans = spans.0.lock().unwrap();
+    let names: Vec<&str> = spans.iter().map(|(name, _)| name.as_str()).collect();
+    // the arguments are evaluated before the operator is called
+    assert_eq!(names, ["run_program", "op", "op"]);
+
+    let field = |idx: usize, name: &str| -> String {
+        spans[idx]
+            .1
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.clone())
+            .unwrap()
+    };
+    assert_eq!(field(0, "cost"), result.0.to_string());
+    // multiply
+    assert_eq!(field(1, "opcode"), "[18]");
+    assert_eq!(field(1, "args"), "2");
+    // add
+    assert_eq!(field(2, "opcode"), "[16]");
+    assert_eq!(field(2, "args"), "2");
+    let op_cost: u64 =
+        field(1, "cost").parse::<u64>().unwrap() + field(2, "cost").parse::<u64>().unwrap();
+    assert!(op_cost < result.0);
+}
+
+#[test]
+fn test_unlimited_cost() {
+    use crate::chik_dialect::ChikDialect;
+
+    // in consensus mode, a softfork guard with invalid arguments is charged
+    // its cost without running anything. This makes for an expensive program
+    // that's cheap to run
+    let mut a = Allocator::new();
+    let program = check(parse_exp(&mut a, "(softfork (q . 100000000000))"));
+    let args = a.nil();
+    let dialect = ChikDialect::new(0);
+
+    let e = run_program(&mut a, &dialect, program, args, 11000000000).unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+
+    for max_cost in [0, UNLIMITED_COST] {
+        let Reduction(cost, _) = run_program(&mut a, &dialect, program, args, max_cost).unwrap();
+        assert_eq!(cost, 100000000021);
+    }
+}
+
+#[cfg(test)]
+// operator 200 costs close to Cost::MAX, regardless of the max_cost it's
+// passed. Every other operator is handled by the Chik dialect
+struct ExpensiveDialect {
+    inner: crate::chik_dialect::ChikDialect,
+}
+
+#[cfg(test)]
+const EXPENSIVE_OP_COST: Cost = Cost::MAX - 1000;
+
+#[cfg(test)]
+impl Dialect for ExpensiveDialect {
+    fn quote_kw(&self) -> u32 {
+        self.inner.quote_kw()
+    }
+    fn apply_kw(&self) -> u32 {
+        self.inner.apply_kw()
+    }
+    fn softfork_kw(&self) -> u32 {
+        self.inner.softfork_kw()
+    }
+    fn softfork_extension(&self, ext: u32) -> OperatorSet {
+        self.inner.softfork_extension(ext)
+    }
+    fn op(
+        &self,
+        allocator: &mut Allocator,
+        op: NodePtr,
+        args: NodePtr,
+        max_cost: Cost,
+        extensions: OperatorSet,
+    ) -> Response {
+        // 0xc8 has its top bit set, so it's not a small (positive) number
+        if allocator.atom(op).as_ref() == [200] {
+            return Ok(Reduction(EXPENSIVE_OP_COST, allocator.nil()));
+        }
+        self.inner.op(allocator, op, args, max_cost, extensions)
+    }
+    fn allow_unknown_ops(&self) -> bool {
+        self.inner.allow_unknown_ops()
+    }
+}
+
+#[test]
+fn test_cost_overflow() {
+    let dialect = ExpensiveDialect {
+        inner: crate::chik_dialect::ChikDialect::new(0),
+    };
+    let mut a = Allocator::new();
+    let args = a.nil();
+
+    // a single call fits
+    let program = check(parse_exp(&mut a, "(0xc8)"));
+    let Reduction(cost, _) = run_program(&mut a, &dialect, program, args, 0).unwrap();
+    assert_eq!(cost, EXPENSIVE_OP_COST + OP_COST);
+
+    // two calls would wrap around
+    let program = check(parse_exp(&mut a, "(c (0xc8) (0xc8))"));
+    let e = run_program(&mut a, &dialect, program, args, 0).unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+}
+
+#[test]
+fn test_op_min_cost() {
+    use crate::chik_dialect::ChikDialect;
+    use crate::more_ops::{op_sha256, SHA256_BASE_COST};
+
+    let cancel = AtomicBool::new(false);
+    let dialect = CancellingDialect {
+        inner: ChikDialect::new(0),
+        ops: core::cell::Cell::new(0),
+        trigger: usize::MAX,
+        cancel: &cancel,
+    };
+    let mut a = Allocator::new();
+    let args = a.nil();
+    let program = check(parse_exp(&mut a, "(sha256 (q . 0xcafef00d))"));
+    let Reduction(total_cost, _) = run_program(&mut a, &dialect, program, args, 0).unwrap();
+    assert_eq!(dialect.ops.get(), 1);
+
+    // the cost of everything leading up to the sha256 operator
+    let sha_args = check(parse_exp(&mut a, "(0xcafef00d)"));
+    let op_cost = op_sha256(&mut a, sha_args, Cost::MAX).unwrap().0;
+    let pre_cost = total_cost - op_cost;
+
+    // the remaining budget is less than the base cost of sha256. The operator
+    // isn't called
+    dialect.ops.set(0);
+    let e = run_program(
+        &mut a,
+        &dialect,
+        program,
+        args,
+        pre_cost + SHA256_BASE_COST - 1,
+    )
+    .unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+    assert_eq!(dialect.ops.get(), 0);
+
+    // with just enough for the base cost, the operator is called, and fails
+    dialect.ops.set(0);
+    let e = run_program(&mut a, &dialect, program, args, pre_cost + SHA256_BASE_COST).unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+    assert_eq!(dialect.ops.get(), 1);
+
+    // programs within the budget are unaffected
+    let Reduction(cost, _) = run_program(&mut a, &dialect, program, args, total_cost).unwrap();
+    assert_eq!(cost, total_cost);
+    let e = run_program(&mut a, &dialect, program, args, total_cost - 1).unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+}
+
+#[test]
+fn test_run_output() {
+    use crate::chik_dialect::ChikDialect;
+    use crate::io::Cursor;
+    use crate::serde::tree_hash_from_stream;
+    use crate::test_ops::node_eq;
+
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, "(c 2 (c 5 (q 0x1337 ())))");
+    let (env, _) = parse_exp(&mut a, "(1 (2 3))");
+    let out = run_program_output(&mut a, &ChikDialect::new(0), program, env, 11000000000).unwrap();
+    let Reduction(cost, result) =
+        run_program(&mut a, &ChikDialect::new(0), program, env, 11000000000).unwrap();
+    assert_eq!(out.cost, cost);
+
+    // the result is (1 (2 3) 0x1337 ())
+    let (expected, _) = parse_exp(&mut a, "(1 (2 3) 0x1337 ())");
+    assert!(node_eq(&a, out.result, expected));
+    let serialized = out.serialized(&a).unwrap();
+    assert_eq!(serialized, node_to_bytes(&a, result).unwrap());
+    assert_eq!(hex::encode(&serialized), "ff01ffff02ff0380ff821337ff8080");
+    assert_eq!(
+        out.tree_hash(&a),
+        tree_hash_from_stream(&mut Cursor::new(&serialized[..])).unwrap()
+    );
+
+    let e = run_program_output(&mut a, &ChikDialect::new(0), program, env, 10).unwrap_err();
+    assert_eq!(e.1, "cost exceeded");
+}
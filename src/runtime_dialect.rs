@@ -1,12 +1,16 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
 use crate::allocator::{Allocator, NodePtr};
 use crate::chik_dialect::NO_UNKNOWN_OPS;
+use crate::collections::HashMap;
 use crate::cost::Cost;
 use crate::dialect::{Dialect, OperatorSet};
 use crate::err_utils::err;
 use crate::f_table::{f_lookup_for_hashmap, FLookup};
 use crate::more_ops::op_unknown;
 use crate::reduction::Response;
-use std::collections::HashMap;
 
 pub struct RuntimeDialect {
     f_lookup: FLookup,
@@ -0,0 +1,163 @@
+use alloc::vec::Vec;
+
+use crate::io;
+use crate::io::{Cursor, Read};
+
+use super::errors::bad_encoding;
+use super::parse_atom::decode_size;
+use super::write_atom::write_atom;
+
+const MAX_SINGLE_BYTE: u8 = 0x7f;
+const CONS_BOX_MARKER: u8 = 0xff;
+
+/// Re-encode a serialized KLVM object (without back references) with the
+/// minimal length prefix for every atom. The input may use longer prefixes
+/// than necessary, e.g. `0x81 0x05` for the atom `0x05`, which some older
+/// serializers produced. This is done in a single pass over the input,
+/// without building the object in an Allocator.
+/// Returns the canonical serialization and whether the input already was
+/// canonical (in which case the output is identical to the input). Fails if
+/// the input is malformed, including if there are bytes after the object.
+pub fn canonicalize_serialization(input: &[u8]) -> io::Result<(Vec<u8>, bool)> {
+    let mut f = Cursor::new(input);
+    let mut ret = Vec::with_capacity(input.len());
+    // the number of objects left to parse
+    let mut pending: u64 = 1;
+    let mut b = [0; 1];
+    while pending > 0 {
+        pending -= 1;
+        f.read_exact(&mut b)?;
+        if b[0] == CONS_BOX_MARKER {
+            ret.push(CONS_BOX_MARKER);
+            pending += 2;
+        } else if b[0] <= MAX_SINGLE_BYTE {
+            ret.push(b[0]);
+        } else {
+            let blob_size = decode_size(&mut f, b[0])?;
+            let pos = f.position() as usize;
+            if ((input.len() - pos) as u64) < blob_size {
+                return Err(bad_encoding());
+            }
+            let end = pos + blob_size as usize;
+            write_atom(&mut ret, &input[pos..end])?;
+            f.set_position(end as u64);
+        }
+    }
+    if f.position() as usize != input.len() {
+        return Err(bad_encoding());
+    }
+    // a non-minimal prefix is always longer than the minimal one, so the
+    // input is canonical if, and only if, nothing changed
+    let canonical = ret == input;
+    Ok((ret, canonical))
+}
+
+#[cfg(test)]
+use super::tools::tree_hash_from_stream;
+#[cfg(test)]
+use super::write_atom::write_atom_with_prefix;
+#[cfg(test)]
+use super::{node_from_bytes, node_to_bytes};
+#[cfg(test)]
+use crate::allocator::Allocator;
+#[cfg(test)]
+use rstest::rstest;
+
+#[cfg(test)]
+fn check_canonical(input: &[u8], expected: &[u8]) {
+    let (out, canonical) = canonicalize_serialization(input).unwrap();
+    assert_eq!(out, expected);
+    assert_eq!(canonical, input == expected);
+
+    // the output is what we get when round-tripping through an Allocator, and
+    // the tree hash doesn't change
+    let mut a = Allocator::new();
+    let node = node_from_bytes(&mut a, input).unwrap();
+    assert_eq!(node_to_bytes(&a, node).unwrap(), out);
+    assert_eq!(
+        tree_hash_from_stream(&mut Cursor::new(input)).unwrap(),
+        tree_hash_from_stream(&mut Cursor::new(&out[..])).unwrap()
+    );
+
+    // canonicalizing is idempotent
+    assert_eq!(
+        canonicalize_serialization(&out).unwrap(),
+        (out.clone(), true)
+    );
+}
+
+// atoms of various sizes, with every length prefix large enough to hold the
+// size
+#[cfg(test)]
+#[rstest]
+#[case(0)]
+#[case(1)]
+#[case(2)]
+#[case(0x3f)]
+#[case(0x40)]
+#[case(0x1fff)]
+#[case(0x2000)]
+#[case(0xfffff)]
+#[case(0x100000)]
+fn test_canonicalize_atom(#[case] size: usize) {
+    for first in [0x05_u8, 0x80] {
+        let mut atom = vec![0xaa_u8; size];
+        if let Some(b) = atom.first_mut() {
+            *b = first;
+        }
+        let mut expected = Vec::new();
+        write_atom(&mut expected, &atom).unwrap();
+
+        for prefix_bytes in 0..=5 {
+            let mut input = Vec::new();
+            if write_atom_with_prefix(&mut input, &atom, prefix_bytes).is_err() {
+                continue;
+            }
+            check_canonical(&input, &expected);
+        }
+    }
+}
+
+#[test]
+fn test_canonicalize_tree() {
+    // (0x05 () 0x1337) where every atom uses a 2 byte prefix
+    let mut input = vec![0xff];
+    write_atom_with_prefix(&mut input, &[0x05], 2).unwrap();
+    input.push(0xff);
+    write_atom_with_prefix(&mut input, &[], 2).unwrap();
+    input.push(0xff);
+    write_atom_with_prefix(&mut input, &[0x13, 0x37], 2).unwrap();
+    write_atom_with_prefix(&mut input, &[], 3).unwrap();
+    check_canonical(
+        &input,
+        &[0xff, 0x05, 0xff, 0x80, 0xff, 0x82, 0x13, 0x37, 0x80],
+    );
+
+    // already canonical
+    check_canonical(
+        &[0xff, 0x05, 0xff, 0x80, 0xff, 0x82, 0x13, 0x37, 0x80],
+        &[0xff, 0x05, 0xff, 0x80, 0xff, 0x82, 0x13, 0x37, 0x80],
+    );
+    check_canonical(&[0x80], &[0x80]);
+}
+
+#[cfg(test)]
+#[rstest]
+// empty input
+#[case(&[])]
+// truncated pair
+#[case(&[0xff, 0x01])]
+// truncated atom
+#[case(&[0x83, 0x01, 0x02])]
+#[case(&[0xc0])]
+// trailing bytes
+#[case(&[0x01, 0x01])]
+#[case(&[0xff, 0x01, 0x80, 0x80])]
+// back references aren't supported
+#[case(&[0xff, 0x01, 0xfe, 0x02])]
+// length prefix too large
+#[case(&[0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])]
+#[case(&[0xfc, 0x04, 0x00, 0x00, 0x00, 0x00])]
+fn test_canonicalize_invalid(#[case] input: &[u8]) {
+    assert!(canonicalize_serialization(input).is_err());
+}
@@ -0,0 +1,196 @@
+use std::fmt;
+use std::io;
+use std::io::Read;
+
+use crate::allocator::{Allocator, NodePtr};
+
+use super::de_br::node_from_bytes_backrefs;
+
+// the header of a compressed program. No serialized program can start with
+// 0xfd, it's the length prefix of an atom of at least 2^40 bytes, which is
+// rejected by the deserializers. This tells compressed programs apart from
+// uncompressed ones
+const MAGIC: [u8; 4] = [0xfd, b'z', b's', b't'];
+
+// the compression level passed to zstd. 0 means zstd's default
+const COMPRESSION_LEVEL: i32 = 0;
+
+// the largest window (as log2 of its size) we accept when decompressing. The
+// decoder allocates the window up-front, so this bounds its memory use. The
+// default compression level uses a 2 MiB window
+const MAX_WINDOW_LOG: u32 = 22;
+
+#[derive(Debug)]
+pub enum CompressError {
+    /// the input doesn't start with the header of a compressed program
+    NotCompressed,
+    /// the decompressed program is larger than the limit
+    TooLarge,
+    /// the compressed data is invalid
+    Invalid(io::Error),
+}
+
+impl fmt::Display for CompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotCompressed => write!(f, "not a compressed program"),
+            Self::TooLarge => write!(f, "decompressed program too large"),
+            Self::Invalid(e) => write!(f, "invalid compressed program: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for CompressError {}
+
+impl From<CompressError> for io::Error {
+    fn from(e: CompressError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// Compress a serialized program (with or without back references) with
+/// zstd. The result starts with a header that tells it apart from an
+/// uncompressed program, see `node_from_bytes_auto()`
+pub fn compress_program(bytes: &[u8]) -> Vec<u8> {
+    let mut ret = MAGIC.to_vec();
+    let compressed =
+        zstd::stream::encode_all(bytes, COMPRESSION_LEVEL).expect("zstd compression failed");
+    ret.extend_from_slice(&compressed);
+    ret
+}
+
+/// Decompress a program compressed by `compress_program()`. The input is
+/// untrusted, decompression stops as soon as the output exceeds
+/// `max_decompressed` bytes, regardless of the sizes recorded in the
+/// compressed data
+pub fn decompress_program(bytes: &[u8], max_decompressed: usize) -> Result<Vec<u8>, CompressError> {
+    let Some(compressed) = bytes.strip_prefix(&MAGIC[..]) else {
+        return Err(CompressError::NotCompressed);
+    };
+    let mut decoder =
+        zstd::stream::read::Decoder::with_buffer(compressed).map_err(CompressError::Invalid)?;
+    decoder
+        .window_log_max(MAX_WINDOW_LOG)
+        .map_err(CompressError::Invalid)?;
+
+    let mut ret = Vec::new();
+    decoder
+        .take(max_decompressed as u64 + 1)
+        .read_to_end(&mut ret)
+        .map_err(CompressError::Invalid)?;
+    if ret.len() > max_decompressed {
+        return Err(CompressError::TooLarge);
+    }
+    Ok(ret)
+}
+
+/// Deserialize a program that may be compressed by `compress_program()`, and
+/// may use back references. Uncompressed programs are accepted as-is.
+/// Compressed programs larger than `max_decompressed` bytes (once
+/// decompressed) fail
+pub fn node_from_bytes_auto(
+    allocator: &mut Allocator,
+    bytes: &[u8],
+    max_decompressed: usize,
+) -> io::Result<NodePtr> {
+    if bytes.starts_with(&MAGIC) {
+        let buf = decompress_program(bytes, max_decompressed)?;
+        node_from_bytes_backrefs(allocator, &buf)
+    } else {
+        node_from_bytes_backrefs(allocator, bytes)
+    }
+}
+
+#[cfg(test)]
+use super::{node_to_bytes, node_to_bytes_backrefs};
+#[cfg(test)]
+use crate::test_ops::{node_eq, parse_exp};
+
+#[cfg(test)]
+const PROGRAM: &str = "(a (q 2 (i 5 (q 2 2 (c 2 (c 11 ()))) (q . 0x1337)) 1) (c (q . 0xcafef00d) (q 0xcafef00d 0xcafef00d)))";
+
+#[test]
+fn test_compress_roundtrip() {
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, PROGRAM);
+    for raw in [
+        node_to_bytes(&a, program).unwrap(),
+        node_to_bytes_backrefs(&a, program).unwrap(),
+        vec![0x80],
+    ] {
+        let compressed = compress_program(&raw);
+        assert!(compressed.starts_with(&MAGIC));
+        assert_eq!(decompress_program(&compressed, raw.len()).unwrap(), raw);
+
+        let node = node_from_bytes_auto(&mut a, &compressed, raw.len()).unwrap();
+        let expected = node_from_bytes_backrefs(&mut a, &raw).unwrap();
+        assert!(node_eq(&a, node, expected));
+    }
+}
+
+#[test]
+fn test_decompress_limit() {
+    // 20 MB of zeros compresses to a few kB
+    let raw = vec![0_u8; 20_000_000];
+    let compressed = compress_program(&raw);
+    assert!(compressed.len() < 10_000);
+
+    assert!(matches!(
+        decompress_program(&compressed, 1_000_000),
+        Err(CompressError::TooLarge)
+    ));
+    assert!(matches!(
+        decompress_program(&compressed, raw.len() - 1),
+        Err(CompressError::TooLarge)
+    ));
+    assert_eq!(
+        decompress_program(&compressed, raw.len()).unwrap().len(),
+        raw.len()
+    );
+
+    let mut a = Allocator::new();
+    let e = node_from_bytes_auto(&mut a, &compressed, 1_000_000).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(e.to_string(), "decompressed program too large");
+}
+
+#[test]
+fn test_decompress_invalid() {
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, PROGRAM);
+    let raw = node_to_bytes(&a, program).unwrap();
+
+    assert!(matches!(
+        decompress_program(&raw, 1000),
+        Err(CompressError::NotCompressed)
+    ));
+
+    let compressed = compress_program(&raw);
+    let truncated = &compressed[..compressed.len() - 4];
+    assert!(matches!(
+        decompress_program(truncated, 1000),
+        Err(CompressError::Invalid(_))
+    ));
+    let mut garbage = MAGIC.to_vec();
+    garbage.extend_from_slice(b"not zstd");
+    assert!(matches!(
+        decompress_program(&garbage, 1000),
+        Err(CompressError::Invalid(_))
+    ));
+}
+
+#[test]
+fn test_auto_raw() {
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, PROGRAM);
+    // legacy blobs, with and without back references, are parsed as-is
+    for raw in [
+        node_to_bytes(&a, program).unwrap(),
+        node_to_bytes_backrefs(&a, program).unwrap(),
+    ] {
+        let node = node_from_bytes_auto(&mut a, &raw, 0).unwrap();
+        assert!(node_eq(&a, node, program));
+    }
+    // 0xfd is never the start of a valid program
+    assert!(node_from_bytes_backrefs(&mut a, &[0xfd, 0, 0, 0, 0, 0, 0]).is_err());
+}
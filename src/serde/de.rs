@@ -1,5 +1,8 @@
-use std::io;
-use std::io::{Cursor, Read};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::io;
+use crate::io::{Cursor, Read};
 
 use crate::allocator::{Allocator, NodePtr};
 
@@ -13,7 +16,7 @@ enum ParseOp {
     Cons,
 }
 
-/// deserialize a klvm node from a `std::io::Cursor`
+/// deserialize a klvm node from a `crate::io::Cursor`
 pub fn node_from_stream(allocator: &mut Allocator, f: &mut Cursor<&[u8]>) -> io::Result<NodePtr> {
     let mut values: Vec<NodePtr> = Vec::new();
     let mut ops = vec![ParseOp::SExp];
@@ -43,6 +46,8 @@ pub fn node_from_stream(allocator: &mut Allocator, f: &mut Cursor<&[u8]>) -> io:
 }
 
 pub fn node_from_bytes(allocator: &mut Allocator, b: &[u8]) -> io::Result<NodePtr> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("node_from_bytes", len = b.len()).entered();
     let mut buffer = Cursor::new(b);
     node_from_stream(allocator, &mut buffer)
 }
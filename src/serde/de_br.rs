@@ -1,6 +1,8 @@
-use std::collections::HashSet;
-use std::io;
-use std::io::{Cursor, Read};
+use alloc::vec;
+
+use crate::collections::HashSet;
+use crate::io;
+use crate::io::{Cursor, Read};
 
 use crate::allocator::{Allocator, NodePtr, SExp};
 use crate::traverse_path::traverse_path;
@@ -16,7 +18,7 @@ enum ParseOp {
     Cons,
 }
 
-/// deserialize a klvm node from a `std::io::Cursor`
+/// deserialize a klvm node from a `crate::io::Cursor`
 pub fn node_from_stream_backrefs(
     allocator: &mut Allocator,
     f: &mut Cursor<&[u8]>,
@@ -62,6 +64,9 @@ pub fn node_from_stream_backrefs(
     }
 }
 
+/// deserialize a klvm node, which may use back references. A back reference
+/// resolves to the node it refers to, it's not copied. Subtrees repeated by
+/// back references are allocated once, and shared in the resulting tree.
 pub fn node_from_bytes_backrefs(allocator: &mut Allocator, b: &[u8]) -> io::Result<NodePtr> {
     let mut buffer = Cursor::new(b);
     node_from_stream_backrefs(allocator, &mut buffer, |_node| {})
@@ -182,3 +187,58 @@ fn test_deserialize_with_backrefs_record() {
         &["9b615f766572795f6c6f6e675f72657065617465645f737472696e67"],
     );
 }
+
+#[test]
+fn test_deserialize_with_backrefs_shared() {
+    use crate::serde::{node_from_bytes, node_to_bytes, node_to_bytes_backrefs};
+
+    // a list of 100 distinct, 32 byte, atoms, repeated three times
+    let mut a = Allocator::new();
+    let mut subtree = a.nil();
+    for i in 0..100_u8 {
+        let atom = a.new_atom(&[i; 32]).unwrap();
+        subtree = a.new_pair(atom, subtree).unwrap();
+    }
+    let nil = a.nil();
+    let tree = a.new_pair_many(&[subtree, subtree, subtree], nil).unwrap();
+    let expanded = node_to_bytes(&a, tree).unwrap();
+    let compressed = node_to_bytes_backrefs(&a, tree).unwrap();
+    assert!(compressed.len() < expanded.len() / 2);
+
+    let mut shared = Allocator::new();
+    let node = node_from_bytes_backrefs(&mut shared, &compressed).unwrap();
+    let items: Vec<NodePtr> = shared.list_iter(node).unwrap().collect();
+    assert_eq!(items.len(), 3);
+    assert_eq!(items[0], items[1]);
+    assert_eq!(items[0], items[2]);
+    // the atoms are only allocated once
+    assert_eq!(shared.heap_size(), 100 * 32);
+    #[cfg(feature = "counters")]
+    assert_eq!(shared.atom_count(), 100);
+
+    let mut naive = Allocator::new();
+    let naive_node = node_from_bytes(&mut naive, &expanded).unwrap();
+    assert_eq!(naive.heap_size(), 3 * 100 * 32);
+    #[cfg(feature = "counters")]
+    assert_eq!(naive.atom_count(), 3 * 100);
+    // and so are the pairs of the repeated sub tree. The allocator's pair
+    // count also includes the deserializer's stack, so count the distinct
+    // pairs reachable from the root instead
+    assert_eq!(distinct_pairs(&shared, node), 100 + 3);
+    assert_eq!(distinct_pairs(&naive, naive_node), 3 * 100 + 3);
+}
+
+#[cfg(test)]
+fn distinct_pairs(a: &Allocator, root: NodePtr) -> usize {
+    let mut visited = HashSet::<NodePtr>::new();
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        if let SExp::Pair(first, rest) = a.sexp(node) {
+            if visited.insert(node) {
+                stack.push(first);
+                stack.push(rest);
+            }
+        }
+    }
+    visited.len()
+}
@@ -1,9 +1,17 @@
-use std::io::{Error, ErrorKind};
+use crate::io::{Error, ErrorKind};
 
 pub fn bad_encoding() -> Error {
     Error::new(ErrorKind::InvalidInput, "bad encoding")
 }
 
+pub fn hash_stub_error() -> Error {
+    Error::new(ErrorKind::InvalidInput, "can't serialize hash stub")
+}
+
+pub fn cycle_error() -> Error {
+    Error::new(ErrorKind::InvalidData, "cycle detected")
+}
+
 pub fn internal_error() -> Error {
     Error::new(ErrorKind::InvalidInput, "internal error")
 }
@@ -1,25 +1,48 @@
 mod bytes32;
+mod canonical;
+#[cfg(feature = "zstd")]
+mod compress;
 mod de;
 mod de_br;
+#[cfg(feature = "std")]
 mod de_tree;
 mod errors;
 mod object_cache;
 mod parse_atom;
 mod read_cache_lookup;
+#[cfg(feature = "std")]
+mod scan;
 mod ser;
 mod ser_br;
+mod sexp_tree;
 mod tools;
+#[cfg(feature = "std")]
 mod utils;
 pub mod write_atom;
 
 #[cfg(test)]
 mod test;
+#[cfg(test)]
+mod test_adversarial;
 
+pub use canonical::canonicalize_serialization;
+#[cfg(feature = "zstd")]
+pub use compress::{compress_program, decompress_program, node_from_bytes_auto, CompressError};
 pub use de::node_from_bytes;
 pub use de_br::{node_from_bytes_backrefs, node_from_bytes_backrefs_record};
+#[cfg(feature = "std")]
 pub use de_tree::{parse_triples, ParsedTriple};
-pub use ser::{node_to_bytes, node_to_bytes_limit};
+#[cfg(feature = "memoize")]
+pub(crate) use object_cache::tree_hash_with_cache;
+pub(crate) use object_cache::{serialized_length, structural_hash, ObjectCache};
+pub use object_cache::{sort_by_tree_hash, tree_hash, CycleDetected};
+#[cfg(feature = "std")]
+pub use scan::{scan_programs, ScanError, ScanPrograms};
+pub use ser::{node_to_bytes, node_to_bytes_limit, serialize_into, SerializeErr};
 pub use ser_br::{node_to_bytes_backrefs, node_to_bytes_backrefs_limit};
+pub use sexp_tree::SExpTree;
+pub(crate) use tools::{hash_atom, hash_pair};
 pub use tools::{
     serialized_length_from_bytes, serialized_length_from_bytes_trusted, tree_hash_from_stream,
+    tree_hash_stream,
 };
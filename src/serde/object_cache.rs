@@ -7,9 +7,26 @@
 /// have a non-recursive implementation (as it keeps a stack of uncached
 /// objects locally).
 use crate::allocator::{Allocator, NodePtr, SExp};
-use std::collections::HashMap;
+use crate::collections::HashMap;
 type CachedFunction<T> = fn(&mut ObjectCache<T>, &Allocator, NodePtr) -> Option<T>;
 use super::bytes32::{hash_blobs, Bytes32};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// The error returned when a traversal finds a pair that contains itself.
+/// The allocator doesn't allow building such a structure, this guards against
+/// the traversal looping forever if it somehow holds one anyway
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleDetected;
+
+impl fmt::Display for CycleDetected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("cycle detected")
+    }
+}
+
+impl core::error::Error for CycleDetected {}
 
 pub struct ObjectCache<'a, T> {
     cache: HashMap<NodePtr, T>,
@@ -35,10 +52,12 @@ impl<'a, T: Clone> ObjectCache<'a, T> {
     }
 
     /// return the function value for this node, either from cache
-    /// or by calculating it
-    pub fn get_or_calculate(&mut self, node: &NodePtr) -> Option<&T> {
-        self.calculate(node);
-        self.get_from_cache(node)
+    /// or by calculating it. Fails if the tree contains a cycle
+    pub fn get_or_calculate(&mut self, node: &NodePtr) -> Result<&T, CycleDetected> {
+        self.calculate(node)?;
+        Ok(self
+            .get_from_cache(node)
+            .expect("calculate() caches the node"))
     }
 
     /// return the cached value for this node, or `None`
@@ -53,7 +72,7 @@ impl<'a, T: Clone> ObjectCache<'a, T> {
 
     /// calculate the function's value for the given node, traversing uncached children
     /// as necessary
-    fn calculate(&mut self, root_node: &NodePtr) {
+    fn calculate(&mut self, root_node: &NodePtr) -> Result<(), CycleDetected> {
         let mut obj_list = vec![*root_node];
         while let Some(node) = obj_list.pop() {
             let v = self.get_from_cache(&node);
@@ -62,6 +81,9 @@ impl<'a, T: Clone> ObjectCache<'a, T> {
                 None => match (self.f)(self, self.allocator, node) {
                     None => match self.allocator.sexp(node) {
                         SExp::Pair(left, right) => {
+                            if left.may_cycle_to(node) || right.may_cycle_to(node) {
+                                return Err(CycleDetected);
+                            }
                             obj_list.push(node);
                             obj_list.push(left);
                             obj_list.push(right);
@@ -74,10 +96,12 @@ impl<'a, T: Clone> ObjectCache<'a, T> {
                 },
             }
         }
+        Ok(())
     }
 }
 
-/// calculate the standard `sha256tree` has for a node
+/// calculate the standard `sha256tree` has for a node. A hash stub hashes as
+/// the sub tree it stands in for
 
 pub fn treehash(
     cache: &mut ObjectCache<Bytes32>,
@@ -91,31 +115,120 @@ pub fn treehash(
                 .get_from_cache(&right)
                 .map(|right_value| hash_blobs(&[&[2], left_value, right_value])),
         },
-        SExp::Atom => Some(hash_blobs(&[&[1], allocator.atom(node).as_ref()])),
+        SExp::Atom => match allocator.hash_stub(node) {
+            Some(hash) => Some(*hash),
+            None => Some(hash_blobs(&[&[1], allocator.atom(node).as_ref()])),
+        },
     }
 }
 
-/// calculate the serialized length (without backrefs) of a node. This is used
-/// to check if using backrefs is actually smaller.
+/// calculate the standard `sha256tree` hash of a node. Shared sub trees are
+/// only hashed once. Fails if the tree contains a cycle
+pub fn tree_hash(allocator: &Allocator, node: NodePtr) -> Result<Bytes32, CycleDetected> {
+    tree_hash_with_cache(allocator, node, &mut HashMap::new())
+}
 
-pub fn serialized_length(
+/// like `tree_hash()`, but the hashes of the sub trees are kept in `hashes`,
+/// to be reused by later calls. The nodes in `hashes` must not have been
+/// freed (e.g. by restoring a checkpoint) since they were hashed
+pub(crate) fn tree_hash_with_cache(
+    allocator: &Allocator,
+    node: NodePtr,
+    hashes: &mut HashMap<NodePtr, Bytes32>,
+) -> Result<Bytes32, CycleDetected> {
+    let mut cache = ObjectCache {
+        cache: core::mem::take(hashes),
+        allocator,
+        f: treehash,
+    };
+    let ret = cache.get_or_calculate(&node).copied();
+    *hashes = cache.cache;
+    ret
+}
+
+// the multiplier of FxHash
+const FX_SEED: u64 = 0x517c_c1b7_2722_0a95;
+
+fn fx_add(hash: u64, word: u64) -> u64 {
+    (hash.rotate_left(5) ^ word).wrapping_mul(FX_SEED)
+}
+
+/// calculate a fast, non-cryptographic hash of the structure and atoms of a
+/// node. See `Allocator::structural_hash()`
+pub fn structural_hash(
     cache: &mut ObjectCache<u64>,
     allocator: &Allocator,
     node: NodePtr,
 ) -> Option<u64> {
     match allocator.sexp(node) {
-        SExp::Pair(left, right) => match cache.get_from_cache(&left) {
-            None => None,
-            Some(left_value) => cache.get_from_cache(&right).map(|right_value| {
-                1_u64
-                    .saturating_add(*left_value)
-                    .saturating_add(*right_value)
-            }),
-        },
+        SExp::Pair(left, right) => {
+            let left = *cache.get_from_cache(&left)?;
+            let right = *cache.get_from_cache(&right)?;
+            Some(fx_add(fx_add(fx_add(0, 2), left), right))
+        }
+        SExp::Atom => {
+            let atom = allocator.atom(node);
+            let buf = atom.as_ref();
+            let mut hash = fx_add(fx_add(0, 1), buf.len() as u64);
+            let mut chunks = buf.chunks_exact(8);
+            for chunk in &mut chunks {
+                hash = fx_add(hash, u64::from_le_bytes(chunk.try_into().unwrap()));
+            }
+            let rest = chunks.remainder();
+            if !rest.is_empty() {
+                let mut word = [0_u8; 8];
+                word[..rest.len()].copy_from_slice(rest);
+                hash = fx_add(hash, u64::from_le_bytes(word));
+            }
+            Some(hash)
+        }
+    }
+}
+
+/// Sort `nodes` in place by their tree hashes, compared lexicographically.
+/// This is a canonical order that doesn't depend on how the trees were
+/// allocated. The tree hash of every sub tree is computed once, even if it's
+/// shared between nodes. Nodes with identical trees keep their relative order.
+pub fn sort_by_tree_hash(allocator: &Allocator, nodes: &mut [NodePtr]) {
+    let mut cache = ObjectCache::new(allocator, treehash);
+    let mut keyed: Vec<(Bytes32, NodePtr)> = nodes
+        .iter()
+        .map(|node| {
+            let hash = cache
+                .get_or_calculate(node)
+                .expect("treehash is defined for all nodes");
+            (*hash, *node)
+        })
+        .collect();
+    // sort_by_key() is stable
+    keyed.sort_by_key(|item| item.0);
+    for (dst, (_, node)) in nodes.iter_mut().zip(keyed) {
+        *dst = node;
+    }
+}
+
+/// calculate the serialized length (without backrefs) of a node. This is used
+/// to check if using backrefs is actually smaller. A hash stub can't be
+/// serialized, so the length of a tree containing one is `None`
+pub fn serialized_length(
+    cache: &mut ObjectCache<Option<u64>>,
+    allocator: &Allocator,
+    node: NodePtr,
+) -> Option<Option<u64>> {
+    match allocator.sexp(node) {
+        SExp::Pair(left, right) => {
+            let left = *cache.get_from_cache(&left)?;
+            let right = *cache.get_from_cache(&right)?;
+            Some(
+                left.zip(right)
+                    .map(|(left, right)| 1_u64.saturating_add(left).saturating_add(right)),
+            )
+        }
+        SExp::Atom if node.is_hash_stub() => Some(None),
         SExp::Atom => {
             let buf = allocator.atom(node);
             let lb: u64 = buf.as_ref().len().try_into().unwrap_or(u64::MAX);
-            Some(if lb == 0 || (lb == 1 && buf.as_ref()[0] < 128) {
+            Some(Some(if lb == 0 || (lb == 1 && buf.as_ref()[0] < 128) {
                 1
             } else if lb < 0x40 {
                 1 + lb
@@ -127,19 +240,19 @@ pub fn serialized_length(
                 4 + lb
             } else {
                 5 + lb
-            })
+            }))
         }
     }
 }
 
 #[cfg(test)]
-use std::cmp::max;
+use core::cmp::max;
 
 #[cfg(test)]
-use std::fmt::Debug;
+use core::fmt::Debug;
 
 #[cfg(test)]
-use std::io::Cursor;
+use crate::io::Cursor;
 
 #[cfg(test)]
 use hex::FromHex;
@@ -179,7 +292,7 @@ where
 
     assert_eq!(oc.get_from_cache(&obj), None);
 
-    oc.calculate(&obj);
+    oc.calculate(&obj).unwrap();
 
     assert_eq!(oc.get_from_cache(&obj), Some(&expected_value));
 
@@ -226,10 +339,10 @@ fn test_treehash() {
 #[test]
 fn test_serialized_length() {
     let check = |a, b| check_cached_function(a, b, serialized_length);
-    check("ff83666f6f83626172", 9); // (foo . bar)
-    check("ff83666f6fff8362617280", 11); // (foo bar)
-    check("ffff0102ff0304", 7); // ((1 . 2) . (3 . 4))
-    check("ff01ff02ff03ff04ff05ff0680", 13); // (1 2 3 4 5 6)
+    check("ff83666f6f83626172", Some(9)); // (foo . bar)
+    check("ff83666f6fff8362617280", Some(11)); // (foo bar)
+    check("ffff0102ff0304", Some(7)); // ((1 . 2) . (3 . 4))
+    check("ff01ff02ff03ff04ff05ff0680", Some(13)); // (1 2 3 4 5 6)
 }
 
 // this test takes a very long time (>60s) in debug mode, so it only runs in release mode
@@ -249,7 +362,7 @@ fn test_very_long_list() {
         top = allocator.new_pair(atom, top).unwrap();
     }
 
-    let expected_value = LIST_SIZE * 2 + 1;
+    let expected_value = Some(LIST_SIZE * 2 + 1);
     let mut oc = ObjectCache::new(&allocator, serialized_length);
     assert_eq!(oc.get_or_calculate(&top).unwrap().clone(), expected_value);
 
@@ -259,3 +372,85 @@ fn test_very_long_list() {
     let mut oc = ObjectCache::new(&allocator, treehash);
     assert_eq!(oc.get_or_calculate(&top).unwrap().clone(), expected_value);
 }
+
+#[test]
+fn test_serialized_length_hash_stub() {
+    let mut allocator = Allocator::new();
+    let stub = allocator.new_hash_stub([0x42; 32].into()).unwrap();
+    let one = allocator.one();
+    let tree = allocator.new_pair(one, stub).unwrap();
+    let tree = allocator.new_pair(tree, one).unwrap();
+    let mut oc = ObjectCache::new(&allocator, serialized_length);
+    assert_eq!(oc.get_or_calculate(&stub), Ok(&None));
+    assert_eq!(oc.get_or_calculate(&tree), Ok(&None));
+    // the parts of the tree without the stub still have a length
+    assert_eq!(oc.get_or_calculate(&one), Ok(&Some(1)));
+}
+
+#[test]
+fn test_cycle() {
+    let mut allocator = Allocator::new();
+    let one = allocator.one();
+    let nil = allocator.nil();
+    // (1 . <self>)
+    let direct = allocator.new_pair(nil, nil).unwrap();
+    allocator.set_pair(direct, one, direct);
+    // p1 -> p2 -> p1
+    let p1 = allocator.new_pair(nil, nil).unwrap();
+    let p2 = allocator.new_pair(p1, one).unwrap();
+    allocator.set_pair(p1, one, p2);
+    let tree = allocator.new_pair(one, p2).unwrap();
+
+    for node in [direct, p1, p2, tree] {
+        assert_eq!(tree_hash(&allocator, node), Err(CycleDetected));
+        let mut oc = ObjectCache::new(&allocator, serialized_length);
+        assert_eq!(oc.get_or_calculate(&node), Err(CycleDetected));
+        let mut oc = ObjectCache::new(&allocator, structural_hash);
+        assert_eq!(oc.get_or_calculate(&node), Err(CycleDetected));
+    }
+
+    // the parts of the allocator without a cycle can still be hashed. (1)
+    let ok = allocator.new_pair(one, nil).unwrap();
+    assert_eq!(
+        tree_hash(&allocator, ok).unwrap(),
+        <[u8; 32]>::from_hex("ba4484b961b7a2369d948d06c55b64bdbfaffb326bc13b490ab1215dd33d8d46")
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_sort_by_tree_hash() {
+    let mut allocator = Allocator::new();
+    let mut nodes = Vec::new();
+    // (foo . bar), (foo bar), 1
+    for hex in ["ff83666f6f83626172", "ff83666f6fff8362617280", "01"] {
+        let blob: Vec<u8> = Vec::from_hex(hex).unwrap();
+        let mut cursor: Cursor<&[u8]> = Cursor::new(&blob);
+        nodes.push(node_from_stream(&mut allocator, &mut cursor).unwrap());
+    }
+
+    let mut expected: Vec<(Bytes32, NodePtr)> = nodes
+        .iter()
+        .map(|node| {
+            let mut oc = ObjectCache::new(&allocator, treehash);
+            (*oc.get_or_calculate(node).unwrap(), *node)
+        })
+        .collect();
+    expected.sort();
+    let expected: Vec<NodePtr> = expected.into_iter().map(|(_, node)| node).collect();
+
+    sort_by_tree_hash(&allocator, &mut nodes);
+    assert_eq!(nodes, expected);
+    // 1 < (foo . bar) < (foo bar)
+    assert_eq!(nodes[0], allocator.one());
+
+    // the order doesn't depend on the input order
+    let mut reversed: Vec<NodePtr> = nodes.iter().rev().copied().collect();
+    sort_by_tree_hash(&allocator, &mut reversed);
+    assert_eq!(reversed, expected);
+
+    // duplicates are kept
+    let mut dups = vec![nodes[1], nodes[0], nodes[1]];
+    sort_by_tree_hash(&allocator, &mut dups);
+    assert_eq!(dups, [nodes[0], nodes[1], nodes[1]]);
+}
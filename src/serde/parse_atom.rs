@@ -1,4 +1,4 @@
-use std::io::{Cursor, Read, Result, Seek, SeekFrom};
+use crate::io::{Cursor, Read, Result, Seek, SeekFrom};
 
 use crate::allocator::{Allocator, NodePtr};
 
@@ -60,7 +60,9 @@ fn parse_atom_ptr<'a>(f: &'a mut Cursor<&[u8]>, first_byte: u8) -> Result<&'a [u
     } else {
         let blob_size = decode_size(f, first_byte)?;
         let pos = f.position() as usize;
-        if f.get_ref().len() < pos + blob_size as usize {
+        // compare against the remaining input before converting the size to
+        // usize, which may truncate it on 32 bit platforms
+        if (f.get_ref().len().saturating_sub(pos) as u64) < blob_size {
             return Err(bad_encoding());
         }
         f.seek(SeekFrom::Current(blob_size as i64))?;
@@ -98,7 +100,7 @@ pub fn parse_path<'a>(f: &'a mut Cursor<&[u8]>) -> Result<&'a [u8]> {
 }
 
 #[cfg(test)]
-use std::io::ErrorKind;
+use crate::io::ErrorKind;
 
 #[cfg(test)]
 use super::write_atom::write_atom;
@@ -226,3 +228,42 @@ fn test_truncated_parse_atom() {
     let err = ret.unwrap_err();
     assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
 }
+
+#[cfg(test)]
+use super::{
+    node_from_bytes, node_from_bytes_backrefs, parse_triples, serialized_length_from_bytes,
+    serialized_length_from_bytes_trusted, tree_hash_from_stream,
+};
+
+#[cfg(test)]
+use rstest::rstest;
+
+// atoms whose length prefix claims more bytes than there are left in the
+// input must fail before anything is allocated for them
+#[cfg(test)]
+#[rstest]
+// 2^31 bytes
+#[case(&[0xf8, 0x80, 0, 0, 0, 1, 2, 3, 4, 5])]
+// 2^31 + 3 bytes, which truncates to 3 if converted to a 32 bit usize
+#[case(&[0xf8, 0x80, 0, 0, 3, 1, 2, 3, 4, 5])]
+// 2^32 + 3 bytes
+#[case(&[0xf9, 0, 0, 0, 3, 1, 2, 3, 4, 5])]
+// the largest length we support (2^34 - 1)
+#[case(&[0xfb, 0xff, 0xff, 0xff, 0xff, 1, 2, 3, 4, 5])]
+// 2^34 bytes, which is larger than the encoding supports
+#[case(&[0xfc, 0x04, 0, 0, 0, 0, 1, 2, 3, 4])]
+// an atom in a pair
+#[case(&[0xff, 0xf8, 0x80, 0, 0, 0, 1, 2, 3, 4])]
+fn test_atom_exceeds_input(#[case] buf: &[u8]) {
+    let mut a = Allocator::new();
+    assert!(node_from_bytes(&mut a, buf).is_err());
+    assert!(node_from_bytes_backrefs(&mut a, buf).is_err());
+
+    assert!(serialized_length_from_bytes(buf).is_err());
+    assert!(serialized_length_from_bytes_trusted(buf).is_err());
+    assert!(tree_hash_from_stream(&mut Cursor::new(buf)).is_err());
+
+    // the streaming parser
+    assert!(parse_triples(&mut Cursor::new(buf), false).is_err());
+    assert!(parse_triples(&mut Cursor::new(buf), true).is_err());
+}
@@ -15,7 +15,9 @@
 /// these objects that no longer exist, so we reference-count them.
 ///
 /// All hashes correspond to sha256 tree hashes.
-use std::collections::{HashMap, HashSet};
+use crate::collections::{HashMap, HashSet};
+use alloc::vec;
+use alloc::vec::Vec;
 
 use super::bytes32::{hash_blob, hash_blobs, Bytes32};
 
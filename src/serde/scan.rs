@@ -0,0 +1,251 @@
+use std::fmt;
+use std::io;
+use std::io::Read;
+
+use crate::allocator::{Allocator, NodePtr};
+
+use super::de_br::node_from_bytes_backrefs;
+use super::parse_atom::decode_size;
+use super::tree_hash;
+
+const MAX_SINGLE_BYTE: u8 = 0x7f;
+const BACK_REFERENCE: u8 = 0xfe;
+const CONS_BOX_MARKER: u8 = 0xff;
+
+/// An error reading a program from the stream passed to `scan_programs()`
+#[derive(Debug)]
+pub struct ScanError {
+    /// the offset of the start of the program in the stream
+    pub offset: u64,
+    pub error: io::Error,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "program at offset {}: {}", self.offset, self.error)
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+// a reader that records everything read from `inner` in `buf`
+struct Tee<'a, R> {
+    inner: &'a mut R,
+    buf: &'a mut Vec<u8>,
+}
+
+impl<R: Read> Read for Tee<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(out)?;
+        self.buf.extend_from_slice(&out[..n]);
+        Ok(n)
+    }
+}
+
+impl<R: Read> Tee<'_, R> {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut b = [0; 1];
+        self.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    // read `len` bytes. They are streamed into `buf`, so a bogus length prefix
+    // can't make us allocate more than the stream actually holds
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        if io::copy(&mut self.by_ref().take(len), &mut io::sink())? < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+// read the next serialized program from `r` into `buf`, without deserializing
+// it. This follows the structure the same way serialized_length_from_bytes()
+// does. Returns false if the stream ends before the start of a program
+fn read_program<R: Read>(r: &mut R, buf: &mut Vec<u8>) -> io::Result<bool> {
+    buf.clear();
+    let mut f = Tee { inner: r, buf };
+    let mut b = [0; 1];
+    if f.read(&mut b)? == 0 {
+        return Ok(false);
+    }
+    let mut ops_counter = 1;
+    loop {
+        ops_counter -= 1;
+        if b[0] == CONS_BOX_MARKER {
+            ops_counter += 2;
+        } else if b[0] == BACK_REFERENCE {
+            let first_byte = f.read_byte()?;
+            if first_byte > MAX_SINGLE_BYTE {
+                let path_size = decode_size(&mut f, first_byte)?;
+                f.skip(path_size)?;
+            }
+        } else if b[0] > MAX_SINGLE_BYTE {
+            let blob_size = decode_size(&mut f, b[0])?;
+            f.skip(blob_size)?;
+        }
+        if ops_counter == 0 {
+            return Ok(true);
+        }
+        b[0] = f.read_byte()?;
+    }
+}
+
+/// The iterator returned by `scan_programs()`
+pub struct ScanPrograms<'a, R> {
+    allocator: &'a mut Allocator,
+    reader: R,
+    // the offset of the next program in the stream
+    offset: u64,
+    // the serialized form of the current program
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R> ScanPrograms<'_, R> {
+    /// The allocator the programs are deserialized into
+    pub fn allocator(&self) -> &Allocator {
+        self.allocator
+    }
+}
+
+impl<R: Read> Iterator for ScanPrograms<'_, R> {
+    type Item = Result<(NodePtr, [u8; 32]), ScanError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let offset = self.offset;
+        match read_program(&mut self.reader, &mut self.buf) {
+            Ok(true) => {}
+            Ok(false) => {
+                self.done = true;
+                return None;
+            }
+            Err(error) => {
+                // we don't know where this program ends, so we can't find the
+                // start of the next one
+                self.done = true;
+                return Some(Err(ScanError { offset, error }));
+            }
+        }
+        self.offset += self.buf.len() as u64;
+
+        // a program that fails to deserialize doesn't leave anything behind in
+        // the allocator
+        let checkpoint = self.allocator.checkpoint();
+        match node_from_bytes_backrefs(self.allocator, &self.buf) {
+            Ok(node) => match tree_hash(self.allocator, node) {
+                Ok(hash) => Some(Ok((node, hash))),
+                Err(e) => {
+                    self.allocator.restore_checkpoint(&checkpoint);
+                    let error = io::Error::new(io::ErrorKind::InvalidData, e);
+                    Some(Err(ScanError { offset, error }))
+                }
+            },
+            Err(error) => {
+                self.allocator.restore_checkpoint(&checkpoint);
+                Some(Err(ScanError { offset, error }))
+            }
+        }
+    }
+}
+
+/// Deserialize the programs in `r` into `allocator`, one at a time, yielding
+/// each along with its tree hash. The stream holds any number of serialized
+/// programs back to back, with or without back references.
+///
+/// A program that fails to deserialize (e.g. because of an invalid back
+/// reference, or exceeding the allocator's limits) is reported as an error and
+/// the scan continues with the next program. If the end of a program can't be
+/// found (the stream is truncated or an atom has an invalid length prefix), the
+/// error is reported and the scan ends.
+pub fn scan_programs<R: Read>(allocator: &mut Allocator, r: R) -> ScanPrograms<'_, R> {
+    ScanPrograms {
+        allocator,
+        reader: r,
+        offset: 0,
+        buf: Vec::new(),
+        done: false,
+    }
+}
+
+#[cfg(test)]
+use super::{node_to_bytes, node_to_bytes_backrefs, tree_hash_from_stream};
+#[cfg(test)]
+use crate::test_ops::parse_exp;
+#[cfg(test)]
+use std::io::Cursor;
+
+#[cfg(test)]
+fn hash_of(a: &Allocator, node: NodePtr) -> [u8; 32] {
+    let buf = node_to_bytes(a, node).unwrap();
+    tree_hash_from_stream(&mut Cursor::new(buf.as_slice()))
+        .unwrap()
+        .into()
+}
+
+#[test]
+fn test_scan_programs() {
+    let mut a = Allocator::new();
+    let (first, _) = parse_exp(
+        &mut a,
+        "(a (q 2 (i 11 (q 8) (q 4 (c 5 ()))) 1) (c (q . 0x1337) 1))",
+    );
+    let (third, _) = parse_exp(
+        &mut a,
+        "((\"foobar\" . \"foobar\") \"foobar\" . \"foobar\")",
+    );
+    let mut stream = node_to_bytes(&a, first).unwrap();
+    let second_offset = stream.len() as u64;
+    // a back reference into the empty stack of parsed nodes
+    stream.extend_from_slice(&[0xff, 0xfe, 0x02, 0x80]);
+    stream.extend_from_slice(&node_to_bytes_backrefs(&a, third).unwrap());
+
+    let mut scan_alloc = Allocator::new();
+    let mut scan = scan_programs(&mut scan_alloc, Cursor::new(&stream));
+
+    let (node, hash) = scan.next().unwrap().unwrap();
+    assert_eq!(hash, hash_of(&a, first));
+    assert_eq!(hash, hash_of(scan.allocator(), node));
+
+    let e = scan.next().unwrap().unwrap_err();
+    assert_eq!(e.offset, second_offset);
+    assert_eq!(e.error.to_string(), "path into atom");
+
+    let (node, hash) = scan.next().unwrap().unwrap();
+    assert_eq!(hash, hash_of(&a, third));
+    assert_eq!(hash, hash_of(scan.allocator(), node));
+    assert!(scan.next().is_none());
+    assert!(scan.next().is_none());
+}
+
+#[test]
+fn test_scan_programs_framing_error() {
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, "(q . \"foobar\")");
+    let program = node_to_bytes(&a, program).unwrap();
+
+    // a truncated program, or an invalid length prefix, ends the scan, since
+    // the start of the next program can't be found
+    let mut truncated = program.clone();
+    truncated.extend_from_slice(&program[..program.len() - 1]);
+    let mut bad_prefix = program.clone();
+    bad_prefix.extend_from_slice(&[0xff, 0xfc, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    bad_prefix.extend_from_slice(&program);
+    for stream in [truncated, bad_prefix] {
+        let mut scan_alloc = Allocator::new();
+        let results: Vec<_> = scan_programs(&mut scan_alloc, Cursor::new(&stream)).collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert_eq!(
+            results[1].as_ref().unwrap_err().offset,
+            program.len() as u64
+        );
+    }
+
+    // an empty stream has no programs
+    let mut scan_alloc = Allocator::new();
+    assert!(scan_programs(&mut scan_alloc, io::empty()).next().is_none());
+}
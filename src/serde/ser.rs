@@ -1,8 +1,13 @@
-use std::io;
-use std::io::Cursor;
-use std::io::ErrorKind;
-use std::io::Write;
+use alloc::vec;
+use alloc::vec::Vec;
 
+use crate::io;
+use crate::io::Cursor;
+use crate::io::ErrorKind;
+use crate::io::Write;
+use core::fmt;
+
+use super::errors::{cycle_error, hash_stub_error};
 use super::write_atom::write_atom;
 use crate::allocator::{len_for_value, Allocator, NodePtr, NodeVisitor};
 
@@ -37,10 +42,13 @@ impl<W: io::Write> Write for LimitedWriter<W> {
     }
 }
 
-/// serialize a node
+/// serialize a node. Fails if the tree contains a hash stub or a cycle
 pub fn node_to_stream<W: io::Write>(a: &Allocator, node: NodePtr, f: &mut W) -> io::Result<()> {
     let mut values: Vec<NodePtr> = vec![node];
     while let Some(v) = values.pop() {
+        if v.is_hash_stub() {
+            return Err(hash_stub_error());
+        }
         match a.node(v) {
             NodeVisitor::Buffer(buf) => write_atom(f, buf)?,
             NodeVisitor::U32(val) => {
@@ -49,6 +57,9 @@ pub fn node_to_stream<W: io::Write>(a: &Allocator, node: NodePtr, f: &mut W) ->
                 write_atom(f, &buf[4 - len..])?
             }
             NodeVisitor::Pair(left, right) => {
+                if left.may_cycle_to(v) || right.may_cycle_to(v) {
+                    return Err(cycle_error());
+                }
                 f.write_all(&[CONS_BOX_MARKER])?;
                 values.push(right);
                 values.push(left);
@@ -59,6 +70,8 @@ pub fn node_to_stream<W: io::Write>(a: &Allocator, node: NodePtr, f: &mut W) ->
 }
 
 pub fn node_to_bytes_limit(a: &Allocator, node: NodePtr, limit: usize) -> io::Result<Vec<u8>> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("node_to_bytes").entered();
     let buffer = Cursor::new(Vec::new());
     let mut writer = LimitedWriter::new(buffer, limit);
     node_to_stream(a, node, &mut writer)?;
@@ -70,6 +83,42 @@ pub fn node_to_bytes(a: &Allocator, node: NodePtr) -> io::Result<Vec<u8>> {
     node_to_bytes_limit(a, node, 2000000)
 }
 
+/// The error returned by `serialize_into()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerializeErr {
+    BufferTooSmall,
+    HashStub,
+    Cycle,
+}
+
+impl fmt::Display for SerializeErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BufferTooSmall => write!(f, "buffer too small"),
+            Self::HashStub => write!(f, "can't serialize hash stub"),
+            Self::Cycle => write!(f, "cycle detected"),
+        }
+    }
+}
+
+impl core::error::Error for SerializeErr {}
+
+/// serialize a node into a fixed size buffer, returning the number of bytes
+/// written. Fails if the serialization doesn't fit in `buf`. In that case,
+/// the contents of `buf` are unspecified; part of it may have been written.
+pub fn serialize_into(a: &Allocator, node: NodePtr, buf: &mut [u8]) -> Result<usize, SerializeErr> {
+    let mut cursor = Cursor::new(buf);
+    // writing to a slice only fails when it's full. Atoms in the allocator
+    // are never too large to serialize, the only other failures are a hash
+    // stub and a cycle
+    node_to_stream(a, node, &mut cursor).map_err(|e| match e.kind() {
+        ErrorKind::InvalidInput => SerializeErr::HashStub,
+        ErrorKind::InvalidData => SerializeErr::Cycle,
+        _ => SerializeErr::BufferTooSmall,
+    })?;
+    Ok(cursor.position() as usize)
+}
+
 #[test]
 fn test_serialize_limit() {
     let mut a = Allocator::new();
@@ -103,3 +152,89 @@ fn test_serialize_limit() {
         );
     }
 }
+
+#[test]
+fn test_serialize_hash_stub() {
+    use crate::serde::node_to_bytes_backrefs;
+
+    let mut a = Allocator::new();
+    let stub = a.new_hash_stub([0x42; 32].into()).unwrap();
+    let one = a.one();
+    let tree = a.new_pair(one, stub).unwrap();
+    for node in [stub, tree] {
+        let e = node_to_bytes(&a, node).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert_eq!(e.to_string(), "can't serialize hash stub");
+        let e = node_to_bytes_backrefs(&a, node).unwrap_err();
+        assert_eq!(e.to_string(), "can't serialize hash stub");
+        let mut buf = [0_u8; 100];
+        assert_eq!(
+            serialize_into(&a, node, &mut buf),
+            Err(SerializeErr::HashStub)
+        );
+    }
+}
+
+#[test]
+fn test_serialize_cycle() {
+    use crate::serde::node_to_bytes_backrefs;
+
+    let mut a = Allocator::new();
+    let one = a.one();
+    let nil = a.nil();
+    // (1 . <self>)
+    let direct = a.new_pair(nil, nil).unwrap();
+    a.set_pair(direct, one, direct);
+    // p1 -> p2 -> p1
+    let p1 = a.new_pair(nil, nil).unwrap();
+    let p2 = a.new_pair(p1, one).unwrap();
+    a.set_pair(p1, one, p2);
+    let tree = a.new_pair(one, p2).unwrap();
+    for node in [direct, p1, p2, tree] {
+        let e = node_to_bytes(&a, node).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "cycle detected");
+        let e = node_to_bytes_backrefs(&a, node).unwrap_err();
+        assert_eq!(e.to_string(), "cycle detected");
+        let mut buf = [0_u8; 100];
+        assert_eq!(serialize_into(&a, node, &mut buf), Err(SerializeErr::Cycle));
+    }
+}
+
+#[test]
+fn test_serialize_into() {
+    let mut a = Allocator::new();
+
+    let leaf = a.new_atom(&[1, 2, 3, 4, 5]).unwrap();
+    let l1 = a.new_pair(leaf, leaf).unwrap();
+    let l2 = a.new_pair(l1, l1).unwrap();
+    let expected = node_to_bytes(&a, l2).unwrap();
+    assert_eq!(expected.len(), 27);
+
+    // exactly the right size
+    let mut buf = [0_u8; 27];
+    assert_eq!(serialize_into(&a, l2, &mut buf), Ok(27));
+    assert_eq!(buf[..], expected[..]);
+
+    // larger buffers are only partially used
+    let mut buf = [0xcc_u8; 100];
+    assert_eq!(serialize_into(&a, l2, &mut buf), Ok(27));
+    assert_eq!(buf[..27], expected[..]);
+    assert!(buf[27..].iter().all(|b| *b == 0xcc));
+
+    // one byte too small
+    let mut buf = [0_u8; 26];
+    assert_eq!(
+        serialize_into(&a, l2, &mut buf),
+        Err(SerializeErr::BufferTooSmall)
+    );
+
+    let nil = a.nil();
+    assert_eq!(
+        serialize_into(&a, nil, &mut []),
+        Err(SerializeErr::BufferTooSmall)
+    );
+    let mut buf = [0_u8; 1];
+    assert_eq!(serialize_into(&a, nil, &mut buf), Ok(1));
+    assert_eq!(buf, [0x80]);
+}
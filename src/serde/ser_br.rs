@@ -1,8 +1,12 @@
 // Serialization with "back-references"
 
-use std::io;
-use std::io::Cursor;
+use alloc::vec;
+use alloc::vec::Vec;
 
+use crate::io;
+use crate::io::Cursor;
+
+use super::errors::{cycle_error, hash_stub_error};
 use super::object_cache::{serialized_length, treehash, ObjectCache};
 use super::read_cache_lookup::ReadCacheLookup;
 use super::write_atom::write_atom;
@@ -42,12 +46,19 @@ pub fn node_to_stream_backrefs<W: io::Write>(
         let op = read_op_stack.pop();
         assert!(op == Some(ReadOp::Parse));
 
-        let node_serialized_length = *slc
+        // a stub could be written as a back reference to an identical sub
+        // tree, but we can't rely on there being one
+        if node_to_write.is_hash_stub() {
+            return Err(hash_stub_error());
+        }
+
+        let node_serialized_length = slc
             .get_or_calculate(&node_to_write)
-            .expect("couldn't calculate serialized length");
+            .map_err(|_| cycle_error())?
+            .ok_or_else(hash_stub_error)?;
         let node_tree_hash = thc
             .get_or_calculate(&node_to_write)
-            .expect("can't get treehash");
+            .map_err(|_| cycle_error())?;
         match read_cache_lookup.find_path(node_tree_hash, node_serialized_length) {
             Some(path) => {
                 f.write_all(&[BACK_REFERENCE])?;
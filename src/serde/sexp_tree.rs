@@ -0,0 +1,259 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::allocator::{Allocator, NodePtr};
+use crate::reduction::EvalErr;
+
+/// An owned klvm tree, detached from any `Allocator`. It can be stored or
+/// sent to another thread, and later copied into an allocator with
+/// `to_allocator()`. See `Allocator::to_owned_tree()`.
+///
+/// Building, copying into an allocator and dropping a tree don't recurse, so
+/// they work for arbitrarily deep trees.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SExpTree {
+    Atom(Vec<u8>),
+    Pair(Box<SExpTree>, Box<SExpTree>),
+}
+
+enum Op<'a> {
+    Visit(&'a SExpTree),
+    Cons,
+}
+
+impl SExpTree {
+    /// Allocate a copy of this tree in `a`
+    pub fn to_allocator(&self, a: &mut Allocator) -> Result<NodePtr, EvalErr> {
+        let mut ops = vec![Op::Visit(self)];
+        let mut values = Vec::<NodePtr>::new();
+        while let Some(op) = ops.pop() {
+            match op {
+                Op::Visit(Self::Atom(buf)) => {
+                    values.push(a.new_atom(buf)?);
+                }
+                Op::Visit(Self::Pair(first, rest)) => {
+                    ops.push(Op::Cons);
+                    ops.push(Op::Visit(rest));
+                    ops.push(Op::Visit(first));
+                }
+                Op::Cons => {
+                    let rest = values.pop().expect("internal error, empty stack");
+                    let first = values.pop().expect("internal error, empty stack");
+                    values.push(a.new_pair(first, rest)?);
+                }
+            }
+        }
+        Ok(values.pop().expect("internal error, empty stack"))
+    }
+
+    // moves the children of a pair onto the stack, leaving empty atoms in
+    // their place
+    fn take_children(&mut self, stack: &mut Vec<SExpTree>) {
+        if let Self::Pair(first, rest) = self {
+            stack.push(core::mem::replace(&mut **first, Self::Atom(Vec::new())));
+            stack.push(core::mem::replace(&mut **rest, Self::Atom(Vec::new())));
+        }
+    }
+}
+
+impl Drop for SExpTree {
+    fn drop(&mut self) {
+        // dropping the boxed children would recurse, one level per pair
+        let mut stack = Vec::new();
+        self.take_children(&mut stack);
+        while let Some(mut tree) = stack.pop() {
+            tree.take_children(&mut stack);
+        }
+    }
+}
+
+impl From<u64> for SExpTree {
+    /// the atom of the klvm integer `v`, i.e. the shortest big endian, two's
+    /// complement, encoding. Zero is the empty atom
+    fn from(v: u64) -> Self {
+        let bytes = v.to_be_bytes();
+        let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+        let mut buf = Vec::with_capacity(9 - start);
+        if start < bytes.len() && bytes[start] & 0x80 != 0 {
+            // a leading zero keeps the number positive
+            buf.push(0);
+        }
+        buf.extend_from_slice(&bytes[start..]);
+        Self::Atom(buf)
+    }
+}
+
+impl From<&[u8]> for SExpTree {
+    fn from(v: &[u8]) -> Self {
+        Self::Atom(v.to_vec())
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for SExpTree {
+    fn from(v: &[u8; N]) -> Self {
+        Self::Atom(v.to_vec())
+    }
+}
+
+impl From<&str> for SExpTree {
+    fn from(v: &str) -> Self {
+        Self::Atom(v.as_bytes().to_vec())
+    }
+}
+
+impl<A: Into<SExpTree>, B: Into<SExpTree>> From<(A, B)> for SExpTree {
+    fn from((first, rest): (A, B)) -> Self {
+        Self::Pair(Box::new(first.into()), Box::new(rest.into()))
+    }
+}
+
+/// Builds an `SExpTree` from klvm-like syntax. Lists are written in
+/// parentheses, with ` . ` before the last element for an improper list.
+/// Atoms are integer (u64), string or byte string literals, `()` for nil, or
+/// any expression convertible into an `SExpTree`, in braces.
+///
+/// ```
+/// use klvmr::sexp;
+/// use klvmr::serde::SExpTree;
+///
+/// let x = 1337;
+/// let tree: SExpTree = sexp!((1 "foo" (3 . 4) () {x}));
+/// ```
+#[macro_export]
+macro_rules! sexp {
+    (()) => {
+        $crate::serde::SExpTree::Atom(Vec::new())
+    };
+    (($($items:tt)+)) => {
+        $crate::sexp!(@list $($items)+)
+    };
+    ({ $e:expr }) => {
+        $crate::serde::SExpTree::from($e)
+    };
+    ($atom:literal) => {
+        $crate::serde::SExpTree::from($atom)
+    };
+    (@list $first:tt . $rest:tt) => {
+        $crate::serde::SExpTree::from(($crate::sexp!($first), $crate::sexp!($rest)))
+    };
+    (@list $first:tt $($rest:tt)+) => {
+        $crate::serde::SExpTree::from(($crate::sexp!($first), $crate::sexp!(@list $($rest)+)))
+    };
+    (@list $first:tt) => {
+        $crate::serde::SExpTree::from(($crate::sexp!($first), $crate::sexp!(())))
+    };
+}
+
+#[cfg(test)]
+use super::node_to_bytes;
+#[cfg(test)]
+use crate::test_ops::parse_exp;
+#[cfg(test)]
+use rstest::rstest;
+
+#[test]
+fn test_owned_tree_round_trip() {
+    let mut a = Allocator::new();
+    let (node, _) = parse_exp(
+        &mut a,
+        "(a (q 2 (i 11 (q 8) (q 4 (c 5 ()))) 1) (c (q . 0x1337) \"foobar\" . -1))",
+    );
+    let tree = a.to_owned_tree(node);
+
+    // the tree can be moved to another thread
+    let tree = std::thread::spawn(move || tree).join().unwrap();
+
+    let mut fresh = Allocator::new();
+    let copy = tree.to_allocator(&mut fresh).unwrap();
+    assert_eq!(
+        node_to_bytes(&fresh, copy).unwrap(),
+        node_to_bytes(&a, node).unwrap()
+    );
+    assert_eq!(fresh.to_owned_tree(copy), tree);
+
+    let copy = tree.to_allocator(&mut a).unwrap();
+    assert!(a.structural_eq(node, copy));
+
+    let nil = a.nil();
+    assert_eq!(a.to_owned_tree(nil), SExpTree::Atom(vec![]));
+    let one = a.one();
+    assert_eq!(a.to_owned_tree(one), SExpTree::Atom(vec![1]));
+    let pair = a.new_pair(one, nil).unwrap();
+    assert_eq!(
+        a.to_owned_tree(pair),
+        SExpTree::Pair(
+            Box::new(SExpTree::Atom(vec![1])),
+            Box::new(SExpTree::Atom(vec![]))
+        )
+    );
+}
+
+#[test]
+fn test_owned_tree_deep() {
+    let mut a = Allocator::new();
+    let one = a.one();
+    let mut deep = one;
+    for _ in 0..1000000 {
+        deep = a.new_pair(deep, one).unwrap();
+        deep = a.new_pair(one, deep).unwrap();
+    }
+    let tree = a.to_owned_tree(deep);
+    let copy = tree.to_allocator(&mut a).unwrap();
+    assert!(a.structural_eq(deep, copy));
+    drop(tree);
+}
+
+#[test]
+fn test_owned_tree_allocator_limit() {
+    let mut a = Allocator::new();
+    let (node, _) = parse_exp(&mut a, "(1 2 3 4 5)");
+    let tree = a.to_owned_tree(node);
+    let mut limited = Allocator::new_with_limits(500000000, 1000, 3);
+    assert_eq!(
+        tree.to_allocator(&mut limited).unwrap_err().1,
+        "too many pairs"
+    );
+}
+
+#[test]
+fn test_from_u64() {
+    let mut a = Allocator::new();
+    for v in [
+        0,
+        1,
+        127,
+        128,
+        255,
+        256,
+        0x7fff,
+        0x8000,
+        u32::MAX as u64,
+        u64::MAX,
+    ] {
+        let expected = a.new_number(v.into()).unwrap();
+        let node = SExpTree::from(v).to_allocator(&mut a).unwrap();
+        assert!(a.structural_eq(node, expected), "{v}");
+    }
+}
+
+#[cfg(test)]
+#[rstest]
+#[case(sexp!(()), "()")]
+#[case(sexp!(1), "1")]
+#[case(sexp!(1337), "1337")]
+#[case(sexp!("foobar"), "\"foobar\"")]
+#[case(sexp!(b"\x13\x37"), "0x1337")]
+#[case(sexp!((1 2 (3 . 4))), "(1 2 (3 . 4))")]
+#[case(sexp!((1 . 2)), "(1 . 2)")]
+#[case(sexp!((1 2 . 3)), "(1 2 . 3)")]
+#[case(sexp!((() (()) ((1)))), "(() (()) ((1)))")]
+#[case(sexp!((2 (1 . 128) {(2, 3)} {&[0xff_u8][..]})), "(2 (1 . 128) (2 . 3) 0xff)")]
+#[case(SExpTree::from((1, "foo")), "(1 . \"foo\")")]
+fn test_sexp_macro(#[case] tree: SExpTree, #[case] expected: &str) {
+    let mut a = Allocator::new();
+    let node = tree.to_allocator(&mut a).unwrap();
+    let (expected, _) = parse_exp(&mut a, expected);
+    assert!(a.structural_eq(node, expected));
+    assert_eq!(a.to_owned_tree(expected), tree);
+}
@@ -1,6 +1,13 @@
 use hex::FromHex;
 
-use crate::allocator::Allocator;
+use proptest::prelude::*;
+use std::io::Cursor;
+
+use crate::allocator::{Allocator, NodePtr};
+use crate::test_ops::node_eq;
+
+use crate::serde::object_cache::{serialized_length, treehash, ObjectCache};
+use crate::serde::tools::{serialized_length_from_bytes, tree_hash_from_stream};
 
 use crate::serde::de::node_from_bytes;
 use crate::serde::de_br::node_from_bytes_backrefs;
@@ -62,3 +69,69 @@ fn test_round_trip() {
 
     check("ff83666f6ffffe01fffe01fffe01fffe01fffe01fffe0180");
 }
+
+// a tree generated by proptest, to be built in an Allocator
+#[derive(Clone, Debug)]
+enum Tree {
+    Atom(Vec<u8>),
+    Pair(Box<Tree>, Box<Tree>),
+}
+
+fn arb_tree() -> impl Strategy<Value = Tree> {
+    // atoms are biased towards short ones, to exercise the small atom and
+    // single-byte encodings, but some are long enough to need multi-byte
+    // length prefixes
+    let leaf = prop_oneof![
+        4 => proptest::collection::vec(any::<u8>(), 0..4),
+        1 => proptest::collection::vec(any::<u8>(), 0..200),
+        1 => proptest::collection::vec(any::<u8>(), 0x2000..0x2100),
+    ]
+    .prop_map(Tree::Atom);
+    leaf.prop_recursive(16, 256, 2, |inner| {
+        (inner.clone(), inner).prop_map(|(l, r)| Tree::Pair(Box::new(l), Box::new(r)))
+    })
+}
+
+fn build_tree(a: &mut Allocator, tree: &Tree) -> NodePtr {
+    match tree {
+        Tree::Atom(buf) => a.new_atom(buf).unwrap(),
+        Tree::Pair(l, r) => {
+            let l = build_tree(a, l);
+            let r = build_tree(a, r);
+            a.new_pair(l, r).unwrap()
+        }
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_serialize_round_trip(tree in arb_tree()) {
+        let mut a = Allocator::new();
+        let node = build_tree(&mut a, &tree);
+        let buf = node_to_bytes(&a, node).unwrap();
+        prop_assert_eq!(serialized_length_from_bytes(&buf).unwrap(), buf.len() as u64);
+
+        let mut cache = ObjectCache::new(&a, serialized_length);
+        prop_assert_eq!(*cache.get_or_calculate(&node).unwrap(), Some(buf.len() as u64));
+
+        let node2 = node_from_bytes(&mut a, &buf).unwrap();
+        prop_assert!(node_eq(&a, node, node2));
+
+        // the same holds with back references
+        let buf_br = node_to_bytes_backrefs(&a, node).unwrap();
+        let node3 = node_from_bytes_backrefs(&mut a, &buf_br).unwrap();
+        prop_assert!(node_eq(&a, node, node3));
+    }
+
+    #[test]
+    fn prop_tree_hash(tree in arb_tree()) {
+        let mut a = Allocator::new();
+        let node = build_tree(&mut a, &tree);
+        let buf = node_to_bytes(&a, node).unwrap();
+
+        let mut cache = ObjectCache::new(&a, treehash);
+        let expected = *cache.get_or_calculate(&node).unwrap();
+        let mut cursor = Cursor::new(buf.as_slice());
+        prop_assert_eq!(tree_hash_from_stream(&mut cursor).unwrap(), expected);
+    }
+}
@@ -0,0 +1,163 @@
+// deterministic coverage of known-dangerous serialized inputs. Every
+// deserializer must fail on these with a specific error, without panicking,
+// hanging or trying to allocate the memory the input claims to need
+
+use std::io::{Cursor, ErrorKind};
+
+use crate::allocator::Allocator;
+use crate::serde::canonical::canonicalize_serialization;
+use crate::serde::de::node_from_bytes;
+use crate::serde::de_br::node_from_bytes_backrefs;
+use crate::serde::tools::{serialized_length_from_bytes, tree_hash_from_stream, tree_hash_stream};
+
+#[derive(Debug, Clone, Copy)]
+enum Expect {
+    // the input ends before the object does
+    Eof,
+    // "bad encoding", e.g. an atom larger than the remaining input
+    BadEncoding,
+    // a back reference that doesn't resolve
+    PathIntoAtom,
+}
+
+fn check_err(name: &str, err: std::io::Error, expect: Expect) {
+    match expect {
+        Expect::Eof => {
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "{name}: {err}");
+        }
+        Expect::BadEncoding => {
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{name}: {err}");
+            assert_eq!(err.to_string(), "bad encoding", "{name}");
+        }
+        Expect::PathIntoAtom => {
+            assert_eq!(err.kind(), ErrorKind::Other, "{name}: {err}");
+            assert_eq!(err.to_string(), "path into atom", "{name}");
+        }
+    }
+}
+
+// `plain` is the expected error from the deserializers that don't support back
+// references, `backrefs` is the expected error from the ones that do
+fn check_adversarial(input: &[u8], plain: Expect, backrefs: Expect) {
+    let mut a = Allocator::new();
+    check_err(
+        "node_from_bytes",
+        node_from_bytes(&mut a, input).unwrap_err(),
+        plain,
+    );
+    check_err(
+        "tree_hash_from_stream",
+        tree_hash_from_stream(&mut Cursor::new(input)).unwrap_err(),
+        plain,
+    );
+    check_err(
+        "tree_hash_stream",
+        tree_hash_stream(input).unwrap_err(),
+        plain,
+    );
+    check_err(
+        "canonicalize_serialization",
+        canonicalize_serialization(input).unwrap_err(),
+        plain,
+    );
+
+    check_err(
+        "node_from_bytes_backrefs",
+        node_from_bytes_backrefs(&mut a, input).unwrap_err(),
+        backrefs,
+    );
+    check_err(
+        "serialized_length_from_bytes",
+        serialized_length_from_bytes(input).unwrap_err(),
+        backrefs,
+    );
+}
+
+#[test]
+fn test_adversarial_empty_input() {
+    check_adversarial(&[], Expect::Eof, Expect::Eof);
+}
+
+#[test]
+fn test_adversarial_truncated_length_prefix() {
+    // a 2 byte length prefix, missing its second byte
+    check_adversarial(&[0xc0], Expect::Eof, Expect::Eof);
+    // a 3 byte length prefix, missing its last byte
+    check_adversarial(&[0xe0, 0x00], Expect::Eof, Expect::Eof);
+    // a 6 byte length prefix, inside a pair
+    check_adversarial(&[0xff, 0x01, 0xfc, 0x00, 0x00], Expect::Eof, Expect::Eof);
+}
+
+#[test]
+fn test_adversarial_truncated_atom() {
+    check_adversarial(
+        &[0x83, 0x01, 0x02],
+        Expect::BadEncoding,
+        Expect::BadEncoding,
+    );
+    check_adversarial(
+        &[0xff, 0x82, 0x01, 0x02, 0x90, 0x01],
+        Expect::BadEncoding,
+        Expect::BadEncoding,
+    );
+}
+
+#[test]
+fn test_adversarial_gigabyte_length_prefix() {
+    // an atom claiming to be 1 GiB
+    check_adversarial(
+        &[0xf8, 0x40, 0x00, 0x00, 0x00],
+        Expect::BadEncoding,
+        Expect::BadEncoding,
+    );
+    // an atom claiming to be just below 16 GiB, the largest size we accept in
+    // a length prefix
+    check_adversarial(
+        &[0xfc, 0x03, 0xff, 0xff, 0xff, 0xff],
+        Expect::BadEncoding,
+        Expect::BadEncoding,
+    );
+    // 16 GiB and above is rejected when decoding the length prefix
+    check_adversarial(
+        &[0xfc, 0x04, 0x00, 0x00, 0x00, 0x00],
+        Expect::BadEncoding,
+        Expect::BadEncoding,
+    );
+    // the same, as the right hand side of a pair
+    check_adversarial(
+        &[0xff, 0x80, 0xf8, 0x40, 0x00, 0x00, 0x00, 0x00],
+        Expect::BadEncoding,
+        Expect::BadEncoding,
+    );
+}
+
+#[test]
+fn test_adversarial_deeply_nested_pairs() {
+    // one million pairs, and nothing else
+    let mut input = vec![0xff_u8; 1_000_000];
+    check_adversarial(&input, Expect::Eof, Expect::Eof);
+
+    // the same, followed by one atom too few
+    input.resize(2_000_000, 0x80);
+    check_adversarial(&input, Expect::Eof, Expect::Eof);
+
+    // with the last atom in place, it's a valid (left-deep) tree
+    input.push(0x80);
+    let mut a = Allocator::new();
+    let node = node_from_bytes(&mut a, &input).unwrap();
+    assert!(node.is_pair());
+    assert_eq!(
+        serialized_length_from_bytes(&input).unwrap(),
+        input.len() as u64
+    );
+}
+
+#[test]
+fn test_adversarial_stray_back_reference() {
+    // a back reference with no path. Without back reference support, 0xfe is
+    // the start of a 7 byte length prefix
+    check_adversarial(&[0xfe], Expect::Eof, Expect::Eof);
+    // a back reference into an empty stack
+    check_adversarial(&[0xfe, 0x02], Expect::Eof, Expect::PathIntoAtom);
+    check_adversarial(&[0xff, 0xfe, 0x02, 0x80], Expect::Eof, Expect::PathIntoAtom);
+}
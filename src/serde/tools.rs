@@ -1,5 +1,10 @@
-use std::io;
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::io;
+use crate::io::{Cursor, Read, Seek, SeekFrom};
+
+use crate::bytes::Bytes32;
 
 use super::errors::bad_encoding;
 use super::parse_atom::decode_size;
@@ -47,14 +52,14 @@ pub fn serialized_length_from_bytes_trusted(b: &[u8]) -> io::Result<u64> {
 
 use crate::sha2::Sha256;
 
-fn hash_atom(buf: &[u8]) -> [u8; 32] {
+pub(crate) fn hash_atom(buf: &[u8]) -> [u8; 32] {
     let mut ctx = Sha256::new();
     ctx.update([1_u8]);
     ctx.update(buf);
     ctx.finalize()
 }
 
-fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+pub(crate) fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
     let mut ctx = Sha256::new();
     ctx.update([2_u8]);
     ctx.update(left);
@@ -69,7 +74,7 @@ enum ParseOp {
 }
 
 // computes the tree-hash of a KLVM structure in serialized form
-pub fn tree_hash_from_stream(f: &mut Cursor<&[u8]>) -> io::Result<[u8; 32]> {
+pub fn tree_hash_from_stream(f: &mut Cursor<&[u8]>) -> io::Result<Bytes32> {
     let mut values: Vec<[u8; 32]> = Vec::new();
     let mut ops = vec![ParseOp::SExp];
 
@@ -104,7 +109,62 @@ pub fn tree_hash_from_stream(f: &mut Cursor<&[u8]>) -> io::Result<[u8; 32]> {
             }
         }
     }
-    Ok(values.pop().unwrap())
+    Ok(values.pop().unwrap().into())
+}
+
+// atoms are hashed in chunks of this size by tree_hash_stream()
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Compute the tree hash of a serialized KLVM object (without back
+/// references) read from `r`. Unlike `tree_hash_from_stream()`, the input
+/// doesn't need to be in memory. Atoms are hashed as they are read, so this
+/// only keeps the partial hashes along the current path in memory, i.e.
+/// O(tree depth). Any bytes after the object are not read.
+pub fn tree_hash_stream<R: Read>(mut r: R) -> io::Result<Bytes32> {
+    let mut values: Vec<[u8; 32]> = Vec::new();
+    let mut ops = vec![ParseOp::SExp];
+    let mut buf = [0_u8; READ_CHUNK_SIZE];
+
+    while let Some(op) = ops.pop() {
+        match op {
+            ParseOp::SExp => {
+                r.read_exact(&mut buf[..1])?;
+                let b = buf[0];
+                if b == CONS_BOX_MARKER {
+                    ops.push(ParseOp::Cons);
+                    ops.push(ParseOp::SExp);
+                    ops.push(ParseOp::SExp);
+                } else if b == 0x80 {
+                    values.push(hash_atom(&[]));
+                } else if b <= MAX_SINGLE_BYTE {
+                    values.push(hash_atom(&[b]));
+                } else {
+                    let mut remaining = decode_size(&mut r, b)?;
+                    let mut ctx = Sha256::new();
+                    ctx.update([1_u8]);
+                    while remaining > 0 {
+                        let len = remaining.min(READ_CHUNK_SIZE as u64) as usize;
+                        r.read_exact(&mut buf[..len]).map_err(|e| {
+                            if e.kind() == io::ErrorKind::UnexpectedEof {
+                                bad_encoding()
+                            } else {
+                                e
+                            }
+                        })?;
+                        ctx.update(&buf[..len]);
+                        remaining -= len as u64;
+                    }
+                    values.push(ctx.finalize());
+                }
+            }
+            ParseOp::Cons => {
+                let v2 = values.pop();
+                let v1 = values.pop();
+                values.push(hash_pair(&v1.unwrap(), &v2.unwrap()));
+            }
+        }
+    }
+    Ok(values.pop().unwrap().into())
 }
 
 /// validate that a buffer is a valid KLVM serialization, and return the length
@@ -177,10 +237,7 @@ fn test_tree_hash_max_single_byte() {
     ctx.update([1_u8]);
     ctx.update([0x7f_u8]);
     let mut cursor = Cursor::<&[u8]>::new(&[0x7f_u8]);
-    assert_eq!(
-        tree_hash_from_stream(&mut cursor).unwrap(),
-        ctx.finalize().as_slice()
-    );
+    assert_eq!(tree_hash_from_stream(&mut cursor).unwrap(), ctx.finalize());
 }
 
 #[test]
@@ -189,10 +246,7 @@ fn test_tree_hash_one() {
     ctx.update([1_u8]);
     ctx.update([1_u8]);
     let mut cursor = Cursor::<&[u8]>::new(&[1_u8]);
-    assert_eq!(
-        tree_hash_from_stream(&mut cursor).unwrap(),
-        ctx.finalize().as_slice()
-    );
+    assert_eq!(tree_hash_from_stream(&mut cursor).unwrap(), ctx.finalize());
 }
 
 #[test]
@@ -201,10 +255,7 @@ fn test_tree_hash_zero() {
     ctx.update([1_u8]);
     ctx.update([0_u8]);
     let mut cursor = Cursor::<&[u8]>::new(&[0_u8]);
-    assert_eq!(
-        tree_hash_from_stream(&mut cursor).unwrap(),
-        ctx.finalize().as_slice()
-    );
+    assert_eq!(tree_hash_from_stream(&mut cursor).unwrap(), ctx.finalize());
 }
 
 #[test]
@@ -212,10 +263,7 @@ fn test_tree_hash_nil() {
     let mut ctx = Sha256::new();
     ctx.update([1_u8]);
     let mut cursor = Cursor::<&[u8]>::new(&[0x80_u8]);
-    assert_eq!(
-        tree_hash_from_stream(&mut cursor).unwrap(),
-        ctx.finalize().as_slice()
-    );
+    assert_eq!(tree_hash_from_stream(&mut cursor).unwrap(), ctx.finalize());
 }
 
 #[test]
@@ -249,8 +297,8 @@ fn test_tree_hash_list() {
     let buf = Vec::from_hex("ff01ff02ff03ff04ff0580").unwrap();
     let mut cursor = Cursor::<&[u8]>::new(&buf);
     assert_eq!(
-        tree_hash_from_stream(&mut cursor).unwrap().to_vec(),
-        Vec::from_hex("123190dddde51acfc61f48429a879a7b905d1726a52991f7d63349863d06b1b6").unwrap()
+        tree_hash_from_stream(&mut cursor).unwrap().to_string(),
+        "123190dddde51acfc61f48429a879a7b905d1726a52991f7d63349863d06b1b6"
     );
 }
 
@@ -260,8 +308,8 @@ fn test_tree_hash_tree() {
     let buf = Vec::from_hex("ffff0102ff0304").unwrap();
     let mut cursor = Cursor::<&[u8]>::new(&buf);
     assert_eq!(
-        tree_hash_from_stream(&mut cursor).unwrap().to_vec(),
-        Vec::from_hex("2824018d148bc6aed0847e2c86aaa8a5407b916169f15b12cea31fa932fc4c8d").unwrap()
+        tree_hash_from_stream(&mut cursor).unwrap().to_string(),
+        "2824018d148bc6aed0847e2c86aaa8a5407b916169f15b12cea31fa932fc4c8d"
     );
 }
 
@@ -271,11 +319,93 @@ fn test_tree_hash_tree_large_atom() {
     let buf = Vec::from_hex("ffff0102ff0386666f6f626172").unwrap();
     let mut cursor = Cursor::<&[u8]>::new(&buf);
     assert_eq!(
-        tree_hash_from_stream(&mut cursor).unwrap().to_vec(),
-        Vec::from_hex("b28d5b401bd02b65b7ed93de8e916cfc488738323e568bcca7e032c3a97a12e4").unwrap()
+        tree_hash_from_stream(&mut cursor).unwrap().to_string(),
+        "b28d5b401bd02b65b7ed93de8e916cfc488738323e568bcca7e032c3a97a12e4"
     );
 }
 
+#[test]
+fn test_tree_hash_stream_small() {
+    for hex in [
+        "80",
+        "01",
+        "7f",
+        "ff01ff02ff03ff04ff0580",
+        "ffff0102ff0304",
+        "ffff0102ff0386666f6f626172",
+    ] {
+        let buf = Vec::from_hex(hex).unwrap();
+        assert_eq!(
+            tree_hash_stream(&buf[..]).unwrap(),
+            tree_hash_from_stream(&mut Cursor::new(&buf[..])).unwrap()
+        );
+    }
+
+    // truncated input
+    for hex in ["8f", "8fff", "cfff0000", "ff01", "ff01ff02"] {
+        let buf = Vec::from_hex(hex).unwrap();
+        assert!(tree_hash_stream(&buf[..]).is_err());
+    }
+    // back references aren't supported
+    let buf = Vec::from_hex("ff01fe02").unwrap();
+    assert!(tree_hash_stream(&buf[..]).is_err());
+}
+
+// returns at most one byte per read, to exercise short reads
+#[cfg(test)]
+struct SlowReader<'a>(&'a [u8]);
+
+#[cfg(test)]
+impl Read for SlowReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((first, rest)) = self.0.split_first() else {
+            return Ok(0);
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        buf[0] = *first;
+        self.0 = rest;
+        Ok(1)
+    }
+}
+
+#[test]
+fn test_tree_hash_stream_large() {
+    use crate::serde::node_to_bytes_limit;
+    use crate::Allocator;
+
+    // a long list of atoms of various sizes (some larger than the read
+    // chunk), with a deeply nested tree as the last element
+    let mut a = Allocator::new();
+    let mut deep = a.nil();
+    for i in 0..100_000_u32 {
+        let atom = a.new_atom(&i.to_be_bytes()).unwrap();
+        deep = a.new_pair(deep, atom).unwrap();
+    }
+    let mut list = a.nil();
+    list = a.new_pair(deep, list).unwrap();
+    for i in 0..2000_usize {
+        let size = (i * 37) % (READ_CHUNK_SIZE * 3);
+        let buf: Vec<u8> = (0..size).map(|j| (i + j) as u8).collect();
+        let atom = a.new_atom(&buf).unwrap();
+        list = a.new_pair(atom, list).unwrap();
+    }
+    let buf = node_to_bytes_limit(&a, list, 100_000_000).unwrap();
+    assert!(buf.len() > 10_000_000);
+
+    let expected = tree_hash_from_stream(&mut Cursor::new(&buf[..])).unwrap();
+    assert_eq!(tree_hash_stream(&buf[..]).unwrap(), expected);
+    assert_eq!(tree_hash_stream(SlowReader(&buf)).unwrap(), expected);
+
+    // trailing bytes are left in the reader
+    let mut trailing = buf.clone();
+    trailing.extend_from_slice(b"foobar");
+    let mut r = &trailing[..];
+    assert_eq!(tree_hash_stream(&mut r).unwrap(), expected);
+    assert_eq!(r, b"foobar");
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -333,7 +463,7 @@ mod test {
 
     #[test]
     fn test_serialized_length_from_bytes() {
-        use std::io::ErrorKind;
+        use crate::io::ErrorKind;
         assert_eq!(
             serialized_length_from_bytes(&[0x7f, 0x00, 0x00, 0x00]).unwrap(),
             1
@@ -1,5 +1,7 @@
-use std::io;
-use std::io::ErrorKind;
+use alloc::vec::Vec;
+
+use crate::io;
+use crate::io::ErrorKind;
 
 /// all atoms serialize their contents verbatim. All expect those one-byte atoms
 /// from 0x00-0x7f also have a prefix encoding their length. This function
@@ -52,6 +54,59 @@ pub fn write_atom<W: io::Write>(f: &mut W, atom: &[u8]) -> io::Result<()> {
     f.write_all(atom)
 }
 
+/// the length prefix `write_atom()` writes before `atom`, in a buffer along
+/// with its length. The prefix is empty for one byte atoms in the range
+/// 0x00-0x7f
+pub(crate) fn atom_prefix(atom: &[u8]) -> ([u8; 5], usize) {
+    let mut buf = [0_u8; 5];
+    let mut cursor = io::Cursor::new(&mut buf[..]);
+    let u8_0 = if !atom.is_empty() { atom[0] } else { 0 };
+    write_atom_encoding_prefix_with_size(&mut cursor, u8_0, atom.len() as u64)
+        .expect("atoms in the allocator have a valid length prefix");
+    let len = cursor.position() as usize;
+    (buf, len)
+}
+
+/// serialize an atom using a length prefix of exactly `prefix_bytes` bytes,
+/// even when a shorter prefix would do. This produces *non-canonical*
+/// serializations, and is only meant for building test vectors for other
+/// KLVM parsers. Use `write_atom()` for everything else.
+/// A `prefix_bytes` of 0 is only valid for single byte atoms in the range
+/// 0x00-0x7f. Fails if the prefix can't represent the length of the atom.
+pub fn write_atom_with_prefix(
+    buffer: &mut Vec<u8>,
+    bytes: &[u8],
+    prefix_bytes: usize,
+) -> io::Result<()> {
+    let size = bytes.len() as u64;
+    if prefix_bytes == 0 {
+        if size != 1 || bytes[0] > 0x7f {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "atom requires a length prefix",
+            ));
+        }
+        buffer.extend_from_slice(bytes);
+        return Ok(());
+    }
+    // a prefix of N bytes has N leading 1-bits and a 0-bit, leaving 7 * N - 1
+    // bits for the length
+    if prefix_bytes > 5 || size >= (1_u64 << (7 * prefix_bytes - 1)) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "length prefix too small for atom",
+        ));
+    }
+    let size_bytes = size.to_be_bytes();
+    let mut prefix = [0_u8; 5];
+    let prefix = &mut prefix[..prefix_bytes];
+    prefix.copy_from_slice(&size_bytes[8 - prefix_bytes..]);
+    prefix[0] |= 0xff << (8 - prefix_bytes);
+    buffer.extend_from_slice(prefix);
+    buffer.extend_from_slice(bytes);
+    Ok(())
+}
+
 #[test]
 fn test_write_atom_encoding_prefix_with_size() {
     let mut buf = Vec::<u8>::new();
@@ -173,3 +228,60 @@ fn test_write_atom() {
         assert_eq!(buf, expected_prefix);
     }
 }
+
+#[test]
+fn test_write_atom_with_prefix() {
+    use crate::allocator::Allocator;
+    use crate::serde::node_from_bytes;
+
+    for atom in [&[][..], &[0x01][..], &[0x80][..], &b"foobar"[..]] {
+        let mut canonical = Vec::<u8>::new();
+        write_atom(&mut canonical, atom).unwrap();
+
+        let mut buf1 = Vec::<u8>::new();
+        write_atom_with_prefix(&mut buf1, atom, 1).unwrap();
+        assert_eq!(buf1[0], 0x80 | atom.len() as u8);
+        assert_eq!(&buf1[1..], atom);
+
+        let mut buf5 = Vec::<u8>::new();
+        write_atom_with_prefix(&mut buf5, atom, 5).unwrap();
+        assert_eq!(buf5[..5], [0xf8, 0, 0, 0, atom.len() as u8]);
+        assert_eq!(&buf5[5..], atom);
+
+        // the parser is lenient and accepts all forms
+        for buf in [&canonical, &buf1, &buf5] {
+            let mut a = Allocator::new();
+            let node = node_from_bytes(&mut a, buf).unwrap();
+            assert_eq!(a.atom(node).as_ref(), atom);
+        }
+    }
+
+    // all prefix sizes
+    let atom = [0xaa; 3];
+    for (prefix_bytes, expected_prefix) in [
+        (1, vec![0x83]),
+        (2, vec![0xc0, 0x03]),
+        (3, vec![0xe0, 0x00, 0x03]),
+        (4, vec![0xf0, 0x00, 0x00, 0x03]),
+        (5, vec![0xf8, 0x00, 0x00, 0x00, 0x03]),
+    ] {
+        let mut buf = Vec::<u8>::new();
+        write_atom_with_prefix(&mut buf, &atom, prefix_bytes).unwrap();
+        assert_eq!(&buf[..prefix_bytes], &expected_prefix[..]);
+    }
+
+    // no prefix is only allowed for small single-byte atoms
+    let mut buf = Vec::<u8>::new();
+    write_atom_with_prefix(&mut buf, &[0x7f], 0).unwrap();
+    assert_eq!(buf, [0x7f]);
+    assert!(write_atom_with_prefix(&mut buf, &[0x80], 0).is_err());
+    assert!(write_atom_with_prefix(&mut buf, &[], 0).is_err());
+    assert!(write_atom_with_prefix(&mut buf, &[1, 2], 0).is_err());
+
+    // the prefix must be able to represent the length
+    assert!(write_atom_with_prefix(&mut buf, &[0; 0x3f], 1).is_ok());
+    assert!(write_atom_with_prefix(&mut buf, &[0; 0x40], 1).is_err());
+    assert!(write_atom_with_prefix(&mut buf, &[0; 0x1fff], 2).is_ok());
+    assert!(write_atom_with_prefix(&mut buf, &[0; 0x2000], 2).is_err());
+    assert!(write_atom_with_prefix(&mut buf, &[0; 3], 6).is_err());
+}
@@ -1,15 +1,11 @@
 use crate::allocator::{Allocator, NodePtr, SExp};
-use crate::bls_ops::{
-    op_bls_g1_multiply, op_bls_g1_negate, op_bls_g1_subtract, op_bls_g2_add, op_bls_g2_multiply,
-    op_bls_g2_negate, op_bls_g2_subtract, op_bls_map_to_g1, op_bls_map_to_g2,
-    op_bls_pairing_identity, op_bls_verify,
-};
+use crate::chik_dialect::CHIK_EXTENSIONS;
 use crate::core_ops::{op_cons, op_eq, op_first, op_if, op_listp, op_raise, op_rest};
 use crate::cost::Cost;
 use crate::more_ops::{
-    op_add, op_all, op_any, op_ash, op_coinid, op_concat, op_div, op_divmod, op_gr, op_gr_bytes,
-    op_logand, op_logior, op_lognot, op_logxor, op_lsh, op_mod, op_modpow, op_multiply, op_not,
-    op_point_add, op_pubkey_for_exp, op_sha256, op_strlen, op_substr, op_subtract,
+    op_add, op_all, op_any, op_ash, op_concat, op_div, op_divmod, op_gr, op_gr_bytes, op_logand,
+    op_logior, op_lognot, op_logxor, op_lsh, op_multiply, op_not, op_point_add, op_pubkey_for_exp,
+    op_sha256, op_strlen, op_substr, op_subtract,
 };
 use crate::number::Number;
 use crate::reduction::{EvalErr, Reduction, Response};
@@ -101,6 +97,8 @@ fn parse_atom(a: &mut Allocator, v: &str) -> NodePtr {
             "bls_verify" => a.new_atom(&[59]).unwrap(),
             "modpow" => a.new_atom(&[60]).unwrap(),
             "%" => a.new_atom(&[61]).unwrap(),
+            "bls_verify_basic" => a.new_atom(&[62]).unwrap(),
+            "sha256_tagged" => a.new_atom(&[63]).unwrap(),
             "secp256k1_verify" => a.new_atom(&[0x13, 0xd6, 0x1f, 0x00]).unwrap(),
             "secp256r1_verify" => a.new_atom(&[0x1c, 0x3a, 0x8f, 0x00]).unwrap(),
             _ => {
@@ -176,13 +174,23 @@ pub fn parse_exp<'a>(a: &mut Allocator, v: &'a str) -> (NodePtr, &'a str) {
 }
 
 pub fn node_eq(allocator: &Allocator, s1: NodePtr, s2: NodePtr) -> bool {
-    match (allocator.sexp(s1), allocator.sexp(s2)) {
-        (SExp::Pair(s1a, s1b), SExp::Pair(s2a, s2b)) => {
-            node_eq(allocator, s1a, s2a) && node_eq(allocator, s1b, s2b)
+    // use an explicit stack, to not overflow the call stack on deep trees
+    let mut stack = vec![(s1, s2)];
+    while let Some((s1, s2)) = stack.pop() {
+        match (allocator.sexp(s1), allocator.sexp(s2)) {
+            (SExp::Pair(s1a, s1b), SExp::Pair(s2a, s2b)) => {
+                stack.push((s1b, s2b));
+                stack.push((s1a, s2a));
+            }
+            (SExp::Atom, SExp::Atom) => {
+                if !allocator.atom_eq(s1, s2) {
+                    return false;
+                }
+            }
+            _ => return false,
         }
-        (SExp::Atom, SExp::Atom) => allocator.atom_eq(s1, s2),
-        _ => false,
     }
+    true
 }
 
 type Opf = fn(&mut Allocator, NodePtr, Cost) -> Response;
@@ -214,27 +222,8 @@ fn run_op_test(op: &Opf, args_str: &str, expected: &str, expected_cost: u64) {
 use rstest::rstest;
 
 #[cfg(test)]
-#[rstest]
-#[case("test-core-ops")]
-#[case("test-more-ops")]
-#[case("test-bls-ops")]
-#[case("test-blspy-g1")]
-#[case("test-blspy-g2")]
-#[case("test-blspy-hash")]
-#[case("test-blspy-pairing")]
-#[case("test-blspy-verify")]
-#[case("test-bls-zk")]
-#[case("test-secp-verify")]
-#[case("test-secp256k1")]
-#[case("test-secp256r1")]
-#[case("test-modpow")]
-#[case("test-sha256")]
-fn test_ops(#[case] filename: &str) {
-    use std::fs::read_to_string;
-
-    let filename = format!("op-tests/{filename}.txt");
-
-    let funs = HashMap::from([
+fn op_functions() -> HashMap<&'static str, Opf> {
+    let mut funs = HashMap::from([
         ("i", op_if as Opf),
         ("c", op_cons as Opf),
         ("f", op_first as Opf),
@@ -248,7 +237,6 @@ fn test_ops(#[case] filename: &str) {
         ("*", op_multiply as Opf),
         ("/", op_div as Opf),
         ("divmod", op_divmod as Opf),
-        ("%", op_mod as Opf),
         ("substr", op_substr as Opf),
         ("strlen", op_strlen as Opf),
         ("point_add", op_point_add as Opf),
@@ -265,24 +253,42 @@ fn test_ops(#[case] filename: &str) {
         ("not", op_not as Opf),
         ("any", op_any as Opf),
         ("all", op_all as Opf),
-        //the BLS extension
-        ("coinid", op_coinid as Opf),
         ("g1_add", op_point_add as Opf),
-        ("g1_subtract", op_bls_g1_subtract as Opf),
-        ("g1_multiply", op_bls_g1_multiply as Opf),
-        ("g1_negate", op_bls_g1_negate as Opf),
-        ("g2_add", op_bls_g2_add as Opf),
-        ("g2_subtract", op_bls_g2_subtract as Opf),
-        ("g2_multiply", op_bls_g2_multiply as Opf),
-        ("g2_negate", op_bls_g2_negate as Opf),
-        ("g1_map", op_bls_map_to_g1 as Opf),
-        ("g2_map", op_bls_map_to_g2 as Opf),
-        ("bls_pairing_identity", op_bls_pairing_identity as Opf),
-        ("bls_verify", op_bls_verify as Opf),
         ("secp256k1_verify", op_secp256k1_verify as Opf),
         ("secp256r1_verify", op_secp256r1_verify as Opf),
-        ("modpow", op_modpow as Opf),
     ]);
+    // the operators of the softfork extensions
+    for ext in CHIK_EXTENSIONS.iter() {
+        for op in ext.ops {
+            funs.insert(op.name, op.f);
+        }
+    }
+    funs
+}
+
+#[cfg(test)]
+#[rstest]
+#[case("test-core-ops")]
+#[case("test-more-ops")]
+#[case("test-bls-ops")]
+#[case("test-blspy-g1")]
+#[case("test-blspy-g2")]
+#[case("test-blspy-hash")]
+#[case("test-blspy-pairing")]
+#[case("test-blspy-verify")]
+#[case("test-bls-zk")]
+#[case("test-secp-verify")]
+#[case("test-secp256k1")]
+#[case("test-secp256r1")]
+#[case("test-modpow")]
+#[case("test-sha256")]
+#[case("test-sha256-tagged")]
+fn test_ops(#[case] filename: &str) {
+    use std::fs::read_to_string;
+
+    let filename = format!("op-tests/{filename}.txt");
+
+    let funs = op_functions();
 
     println!("Test cases from: {filename}");
     let test_cases = read_to_string(filename).expect("test file not found");
@@ -316,13 +322,132 @@ fn test_ops(#[case] filename: &str) {
     }
 }
 
+// run the test cases through ChikDialect, to ensure its dispatch picks the
+// same operator the test cases were written for, with identical results and
+// cost
+#[cfg(test)]
+#[rstest]
+#[case("test-core-ops")]
+#[case("test-more-ops")]
+#[case("test-bls-ops")]
+#[case("test-modpow")]
+#[case("test-secp-verify")]
+fn test_dialect_dispatch(#[case] filename: &str) {
+    use crate::chik_dialect::{ChikDialect, ENABLE_BLS_OPS_OUTSIDE_GUARD};
+    use crate::dialect::{Dialect, OperatorSet};
+    use crate::serde::node_to_bytes;
+    use std::fs::read_to_string;
+
+    let filename = format!("op-tests/{filename}.txt");
+    let funs = op_functions();
+
+    let outside_guard = ChikDialect::new(ENABLE_BLS_OPS_OUTSIDE_GUARD);
+    let inside_guard = ChikDialect::new(0);
+
+    let test_cases = read_to_string(filename).expect("test file not found");
+    for t in test_cases.split('\n') {
+        let t = t.trim();
+        if t.is_empty() || t.starts_with(';') {
+            continue;
+        }
+        let (op_name, t) = t.split_once(' ').unwrap();
+        let (args, _) = t.split_once("=>").unwrap();
+
+        let mut a = Allocator::new();
+        let op = parse_atom(&mut a, op_name);
+        let (args, rest) = parse_list(&mut a, args.trim());
+        assert_eq!(rest, "");
+        let cp = a.checkpoint();
+
+        // the results are compared in serialized form, since restoring the
+        // checkpoint invalidates the nodes
+        let expected = funs[op_name](&mut a, args, 10000000000)
+            .map(|Reduction(cost, node)| (cost, node_to_bytes(&a, node).unwrap()))
+            .map_err(|e| e.1);
+        for (dialect, extension) in [
+            (&outside_guard, OperatorSet::Default),
+            (&inside_guard, OperatorSet::BLS),
+        ] {
+            a.restore_checkpoint(&cp);
+            let result = dialect
+                .op(&mut a, op, args, 10000000000, extension)
+                .map(|Reduction(cost, node)| (cost, node_to_bytes(&a, node).unwrap()))
+                .map_err(|e| e.1);
+            assert_eq!(expected, result, "({op_name} {t})");
+        }
+    }
+}
+
+// the operator in these test cases is the opcode itself, rather than a name.
+// The expected results and costs are computed by
+// tools/generate-unknown-ops-tests.py, from the reference implementation's
+// rules for unknown operators
+#[test]
+fn test_unknown_ops() {
+    use crate::chik_dialect::{ChikDialect, NO_UNKNOWN_OPS};
+    use crate::dialect::{Dialect, OperatorSet};
+    use crate::more_ops::op_unknown;
+    use crate::serde::node_to_bytes;
+    use std::fs::read_to_string;
+
+    let strict = ChikDialect::new(NO_UNKNOWN_OPS);
+    let dialect = ChikDialect::new(0);
+
+    let test_cases = read_to_string("op-tests/test-unknown-ops.txt").expect("test file not found");
+    for t in test_cases.split('\n') {
+        let t = t.trim();
+        if t.is_empty() || t.starts_with(';') {
+            continue;
+        }
+        let (op_name, t) = t.split_once(' ').unwrap_or((t, ""));
+        let (args, out) = t.split_once("=>").unwrap();
+        let (expected, expected_cost) = out.split_once('|').unwrap_or((out, "0"));
+        let expected = expected.trim();
+        let expected_cost: Cost = expected_cost.trim().parse().unwrap();
+
+        let mut a = Allocator::new();
+        let op = parse_atom(&mut a, op_name);
+        let (args, rest) = parse_list(&mut a, args.trim());
+        assert_eq!(rest, "");
+        let cp = a.checkpoint();
+
+        println!("{op_name} {t}");
+        let result = op_unknown(&mut a, op, args, 10000000000)
+            .map(|Reduction(cost, node)| (cost, node_to_bytes(&a, node).unwrap()));
+        if expected == "FAIL" {
+            assert!(result.is_err());
+        } else {
+            let (expected, rest) = parse_exp(&mut a, expected);
+            assert_eq!(rest, "");
+            let expected = node_to_bytes(&a, expected).unwrap();
+            assert_eq!(result.as_ref().unwrap(), &(expected_cost, expected));
+        }
+
+        // opcodes the dialect doesn't implement must be dispatched to the
+        // unknown operator rules. This includes 0x00, and quote and apply with
+        // leading zeros
+        a.restore_checkpoint(&cp);
+        let Err(e) = strict.op(&mut a, op, args, 10000000000, OperatorSet::Default) else {
+            continue;
+        };
+        if e.1 != "unimplemented operator" {
+            continue;
+        }
+        a.restore_checkpoint(&cp);
+        let dispatched = dialect
+            .op(&mut a, op, args, 10000000000, OperatorSet::Default)
+            .map(|Reduction(cost, node)| (cost, node_to_bytes(&a, node).unwrap()));
+        assert_eq!(result.map_err(|e| e.1), dispatched.map_err(|e| e.1));
+    }
+}
+
 #[test]
 fn test_single_argument_raise_atom() {
     let mut allocator = Allocator::new();
     let a1 = allocator.new_atom(&[65]).unwrap();
     let args = allocator.new_pair(a1, allocator.nil()).unwrap();
     let result = op_raise(&mut allocator, args, 100000);
-    assert_eq!(result, Err(EvalErr(a1, "klvm raise".to_string())));
+    assert_eq!(result, Err(EvalErr::raise(a1)));
 }
 
 #[test]
@@ -337,7 +462,7 @@ fn test_single_argument_raise_pair() {
     // ((a1 a2))
     args = allocator.new_pair(args, allocator.nil()).unwrap();
     let result = op_raise(&mut allocator, args, 100000);
-    assert_eq!(result, Err(EvalErr(args, "klvm raise".to_string())));
+    assert_eq!(result, Err(EvalErr::raise(args)));
 }
 
 #[test]
@@ -350,7 +475,68 @@ fn test_multi_argument_raise() {
     // (a1 a2)
     args = allocator.new_pair(a1, args).unwrap();
     let result = op_raise(&mut allocator, args, 100000);
-    assert_eq!(result, Err(EvalErr(args, "klvm raise".to_string())));
+    assert_eq!(result, Err(EvalErr::raise(args)));
+}
+
+#[test]
+fn test_raise_display() {
+    use crate::chik_dialect::ChikDialect;
+    use crate::run_program::run_program;
+
+    let mut a = Allocator::new();
+    let dialect = ChikDialect::new(0);
+    let nil = a.nil();
+
+    // no arguments
+    let (program, _) = parse_exp(&mut a, "(x)");
+    let err = run_program(&mut a, &dialect, program, nil, 100000).unwrap_err();
+    assert!(node_eq(&a, err.0, a.nil()));
+    assert_eq!(err.raised(), Some(err.0));
+    assert_eq!(err.display(&a), err.to_string());
+    assert!(err.display(&a).ends_with("klvm raise"));
+
+    // a single string
+    let (program, _) = parse_exp(&mut a, "(x (q . \"ASSERT_MY_AMOUNT failed\"))");
+    let err = run_program(&mut a, &dialect, program, nil, 100000).unwrap_err();
+    assert_eq!(a.atom(err.0).as_ref(), b"ASSERT_MY_AMOUNT failed");
+    assert_eq!(err.raised(), Some(err.0));
+    assert_eq!(err.display(&a), "klvm raise: \"ASSERT_MY_AMOUNT failed\"");
+    assert!(err.to_string().ends_with(": klvm raise"));
+
+    // a list of arguments, the first one is a string
+    let (program, _) = parse_exp(&mut a, "(x (q . \"failed\") (q 1 2))");
+    let err = run_program(&mut a, &dialect, program, nil, 100000).unwrap_err();
+    let (expected, _) = parse_exp(&mut a, "(\"failed\" (1 2))");
+    assert!(node_eq(&a, err.0, expected));
+    assert_eq!(err.display(&a), "klvm raise: \"failed\"");
+
+    // a structured argument is disassembled
+    let (program, _) = parse_exp(&mut a, "(x (q \"code\" 42))");
+    let err = run_program(&mut a, &dialect, program, nil, 100000).unwrap_err();
+    let (expected, _) = parse_exp(&mut a, "((\"code\" 42))");
+    assert!(node_eq(&a, err.0, expected));
+    assert_eq!(err.display(&a), "klvm raise: (\"code\" 42)");
+
+    // and so is an atom that's not printable
+    let (program, _) = parse_exp(&mut a, "(x (q . 0x00ff00ff00))");
+    let err = run_program(&mut a, &dialect, program, nil, 100000).unwrap_err();
+    assert_eq!(err.display(&a), "klvm raise: 0x00ff00ff00");
+
+    // a large argument is only rendered by display()
+    let list = (1000..2000)
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let (program, _) = parse_exp(&mut a, &format!("(x (q {list}))"));
+    let err = run_program(&mut a, &dialect, program, nil, 100000).unwrap_err();
+    assert!(err.to_string().ends_with(": klvm raise"));
+    assert_eq!(err.display(&a), format!("klvm raise: ({list})"));
+
+    // other errors are not raises
+    let (program, _) = parse_exp(&mut a, "(f (q . 1))");
+    let err = run_program(&mut a, &dialect, program, nil, 100000).unwrap_err();
+    assert_eq!(err.raised(), None);
+    assert_eq!(err.display(&a), err.to_string());
 }
 
 #[cfg(feature = "pre-eval")]
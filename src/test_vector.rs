@@ -0,0 +1,329 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::allocator::{Allocator, NodePtr};
+use crate::chik_dialect::ChikDialect;
+use crate::cost::Cost;
+use crate::io;
+use crate::reduction::Reduction;
+use crate::run_program::run_program;
+use crate::serde::{node_from_bytes, node_to_bytes, tree_hash};
+use core::fmt;
+
+// the layout of a serialized test vector is (all integers are little-endian):
+// magic (8 bytes)
+// version (u32)
+// followed by one record per field, in any order:
+// tag (u8)
+// length (u32)
+// value (length bytes)
+// Every field must be present exactly once.
+const TEST_VECTOR_MAGIC: &[u8; 8] = b"KLVMTVEC";
+const TEST_VECTOR_VERSION: u32 = 1;
+
+// the value is the serialized program
+const TAG_PROGRAM: u8 = 1;
+// the value is the serialized arguments
+const TAG_ARGS: u8 = 2;
+// the ChikDialect flags (u32)
+const TAG_FLAGS: u8 = 3;
+// the max cost (u64)
+const TAG_MAX_COST: u8 = 4;
+// the tree hash of the expected result (32 bytes)
+const TAG_RESULT: u8 = 5;
+// the expected cost (u64)
+const TAG_COST: u8 = 6;
+
+fn vector_err<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// A program, its arguments and the result and cost of running it, bundled
+/// for distribution as a test vector. The result is recorded as its tree hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestVector {
+    pub program: Vec<u8>,
+    pub args: Vec<u8>,
+    pub flags: u32,
+    pub max_cost: Cost,
+    pub result: [u8; 32],
+    pub cost: Cost,
+}
+
+/// The ways running a `TestVector` can fail to reproduce the recorded outcome
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    // the program or arguments could not be deserialized
+    Invalid(String),
+    // the program failed
+    Failed(String),
+    Result {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    Cost {
+        expected: Cost,
+        actual: Cost,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(e) => write!(f, "invalid test vector: {e}"),
+            Self::Failed(e) => write!(f, "program failed: {e}"),
+            Self::Result { expected, actual } => write!(
+                f,
+                "result mismatch: expected {} got {}",
+                hex_str(expected),
+                hex_str(actual)
+            ),
+            Self::Cost { expected, actual } => {
+                write!(f, "cost mismatch: expected {expected} got {actual}")
+            }
+        }
+    }
+}
+
+impl core::error::Error for Mismatch {}
+
+fn hex_str(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+impl TestVector {
+    /// run `program` with `args` and record the result and cost
+    pub fn record(
+        a: &mut Allocator,
+        program: NodePtr,
+        args: NodePtr,
+        flags: u32,
+        max_cost: Cost,
+    ) -> io::Result<Self> {
+        let dialect = ChikDialect::new(flags);
+        let Reduction(cost, result) = run_program(a, &dialect, program, args, max_cost)?;
+        let result = tree_hash(a, result).or_else(|e| vector_err(&e.to_string()))?;
+        Ok(Self {
+            program: node_to_bytes(a, program)?,
+            args: node_to_bytes(a, args)?,
+            flags,
+            max_cost,
+            result,
+            cost,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(
+            TEST_VECTOR_MAGIC.len() + 4 + 6 * 5 + self.program.len() + self.args.len() + 52,
+        );
+        ret.extend_from_slice(TEST_VECTOR_MAGIC);
+        ret.extend_from_slice(&TEST_VECTOR_VERSION.to_le_bytes());
+        let records: [(u8, &[u8]); 6] = [
+            (TAG_PROGRAM, &self.program),
+            (TAG_ARGS, &self.args),
+            (TAG_FLAGS, &self.flags.to_le_bytes()),
+            (TAG_MAX_COST, &self.max_cost.to_le_bytes()),
+            (TAG_RESULT, &self.result),
+            (TAG_COST, &self.cost.to_le_bytes()),
+        ];
+        for (tag, value) in records {
+            ret.push(tag);
+            ret.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            ret.extend_from_slice(value);
+        }
+        ret
+    }
+
+    pub fn deserialize(mut buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < TEST_VECTOR_MAGIC.len() + 4 || !buf.starts_with(TEST_VECTOR_MAGIC) {
+            return vector_err("not a test vector");
+        }
+        let version = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        if version != TEST_VECTOR_VERSION {
+            return vector_err("unsupported test vector version");
+        }
+        buf = &buf[12..];
+
+        let mut fields: [Option<&[u8]>; 6] = [None; 6];
+        while !buf.is_empty() {
+            if buf.len() < 5 {
+                return vector_err("test vector truncated");
+            }
+            let tag = buf[0];
+            let len = u32::from_le_bytes(buf[1..5].try_into().unwrap()) as usize;
+            buf = &buf[5..];
+            if buf.len() < len {
+                return vector_err("test vector truncated");
+            }
+            let (value, rest) = buf.split_at(len);
+            buf = rest;
+            let Some(field) = (tag as usize)
+                .checked_sub(1)
+                .and_then(|idx| fields.get_mut(idx))
+            else {
+                return vector_err("unknown test vector field");
+            };
+            if field.replace(value).is_some() {
+                return vector_err("duplicate test vector field");
+            }
+        }
+
+        let [Some(program), Some(args), Some(flags), Some(max_cost), Some(result), Some(cost)] =
+            fields
+        else {
+            return vector_err("missing test vector field");
+        };
+        let (Ok(flags), Ok(max_cost), Ok(result), Ok(cost)) = (
+            <[u8; 4]>::try_from(flags),
+            <[u8; 8]>::try_from(max_cost),
+            <[u8; 32]>::try_from(result),
+            <[u8; 8]>::try_from(cost),
+        ) else {
+            return vector_err("invalid test vector field");
+        };
+        Ok(Self {
+            program: program.to_vec(),
+            args: args.to_vec(),
+            flags: u32::from_le_bytes(flags),
+            max_cost: Cost::from_le_bytes(max_cost),
+            result,
+            cost: Cost::from_le_bytes(cost),
+        })
+    }
+
+    /// run the program and check that the result and cost match the recorded
+    /// ones
+    pub fn run(&self, a: &mut Allocator) -> Result<(), Mismatch> {
+        let program =
+            node_from_bytes(a, &self.program).map_err(|e| Mismatch::Invalid(e.to_string()))?;
+        let args = node_from_bytes(a, &self.args).map_err(|e| Mismatch::Invalid(e.to_string()))?;
+        let dialect = ChikDialect::new(self.flags);
+        let Reduction(cost, result) = run_program(a, &dialect, program, args, self.max_cost)
+            .map_err(|e| Mismatch::Failed(e.1))?;
+        let actual = tree_hash(a, result).map_err(|e| Mismatch::Invalid(e.to_string()))?;
+        if actual != self.result {
+            return Err(Mismatch::Result {
+                expected: self.result,
+                actual,
+            });
+        }
+        if cost != self.cost {
+            return Err(Mismatch::Cost {
+                expected: self.cost,
+                actual: cost,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use crate::test_ops::parse_exp;
+
+#[test]
+fn test_vector_roundtrip() {
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, "(+ (q . 1) (q . 1))");
+    let nil = a.nil();
+    let v = TestVector::record(&mut a, program, nil, 0, 11000000000).unwrap();
+    assert_eq!(
+        v.program,
+        [0xff, 0x10, 0xff, 0xff, 1, 1, 0xff, 0xff, 1, 1, 0x80]
+    );
+    assert_eq!(v.args, [0x80]);
+    let two = a.new_small_number(2).unwrap();
+    assert_eq!(v.result, tree_hash(&a, two).unwrap());
+
+    let buf = v.serialize();
+    let v2 = TestVector::deserialize(&buf).unwrap();
+    assert_eq!(v, v2);
+
+    let mut a = Allocator::new();
+    v2.run(&mut a).unwrap();
+
+    // tamper with the expected cost
+    let mut tampered = v.clone();
+    tampered.cost += 1;
+    let tampered = TestVector::deserialize(&tampered.serialize()).unwrap();
+    assert_eq!(
+        tampered.run(&mut a).unwrap_err(),
+        Mismatch::Cost {
+            expected: v.cost + 1,
+            actual: v.cost
+        }
+    );
+
+    // and with the expected result
+    let mut tampered = v.clone();
+    tampered.result[0] ^= 1;
+    assert!(matches!(
+        tampered.run(&mut a).unwrap_err(),
+        Mismatch::Result { .. }
+    ));
+
+    // running out of cost
+    let mut tampered = v.clone();
+    tampered.max_cost = v.cost - 1;
+    assert_eq!(
+        tampered.run(&mut a).unwrap_err().to_string(),
+        "program failed: cost exceeded"
+    );
+}
+
+#[test]
+fn test_vector_invalid() {
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, "(+ (q . 1) (q . 1))");
+    let nil = a.nil();
+    let buf = TestVector::record(&mut a, program, nil, 0, 11000000000)
+        .unwrap()
+        .serialize();
+
+    let check = |buf: &[u8], msg: &str| {
+        assert_eq!(TestVector::deserialize(buf).unwrap_err().to_string(), msg);
+    };
+
+    check(b"KLVMSNAP\x01\0\0\0", "not a test vector");
+    check(b"KLVMTVEC\x02\0\0\0", "unsupported test vector version");
+    check(&buf[..buf.len() - 1], "test vector truncated");
+    check(&buf[..buf.len() - 10], "test vector truncated");
+    // drop the last field (the cost)
+    check(&buf[..buf.len() - 13], "missing test vector field");
+
+    let mut extra = buf.clone();
+    extra.extend_from_slice(&[7, 0, 0, 0, 0]);
+    check(&extra, "unknown test vector field");
+
+    let mut extra = buf.clone();
+    extra.extend_from_slice(&[6, 8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    check(&extra, "duplicate test vector field");
+
+    // the flags field has the wrong size
+    let mut v = buf[..12].to_vec();
+    for (tag, value) in [
+        (1_u8, &[0x80_u8][..]),
+        (2, &[0x80]),
+        (3, &[0, 0]),
+        (4, &[0; 8]),
+        (5, &[0; 32]),
+        (6, &[0; 8]),
+    ] {
+        v.push(tag);
+        v.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        v.extend_from_slice(value);
+    }
+    check(&v, "invalid test vector field");
+
+    // the program is not valid KLVM serialization
+    let v = TestVector {
+        program: vec![0xff],
+        args: vec![0x80],
+        flags: 0,
+        max_cost: 0,
+        result: [0; 32],
+        cost: 0,
+    };
+    assert!(matches!(v.run(&mut a), Err(Mismatch::Invalid(_))));
+}
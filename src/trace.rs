@@ -0,0 +1,497 @@
+use crate::allocator::{Allocator, NodePtr, SExp};
+use crate::reduction::{EvalErr, Reduction};
+use crate::run_program::{PostEval, PreEval};
+use crate::serde;
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::rc::Rc;
+
+// A trace file records every evaluation performed by run_program(), in the
+// order they complete. The layout is:
+//
+//   magic        10 bytes, "klvm-trace"
+//   version      2 bytes, big endian
+//   records      each record is a 4 byte, big endian, length followed by the
+//                record itself
+//
+// A record is:
+//
+//   op           a 4 byte, big endian, length followed by the operator atom.
+//                Empty if the program isn't an operator call (i.e. it's a path
+//                into the environment)
+//   program      the tree hash of the program (32 bytes)
+//   env          the tree hash of the environment (32 bytes)
+//   result       the tree hash of the result (32 bytes)
+//
+// Readers ignore any bytes at the end of a record, so later versions can add
+// fields.
+
+const MAGIC: &[u8; 10] = b"klvm-trace";
+pub const TRACE_VERSION: u16 = 1;
+
+// the length of a record, not counting the operator atom
+const FIXED_RECORD_LEN: usize = 4 + 3 * 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub op: Vec<u8>,
+    pub program: [u8; 32],
+    pub env: [u8; 32],
+    pub result: [u8; 32],
+}
+
+// the allocator may be rewound when exiting a softfork guard, so the hashes
+// can't be cached between calls
+fn tree_hash(a: &Allocator, node: NodePtr) -> std::result::Result<[u8; 32], EvalErr> {
+    serde::tree_hash(a, node).map_err(|e| EvalErr::new(node, e.to_string()))
+}
+
+fn write_record<W: Write>(out: &mut W, r: &TraceRecord) -> Result<()> {
+    let op_len: u32 =
+        r.op.len()
+            .try_into()
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "operator too large"))?;
+    out.write_all(&(FIXED_RECORD_LEN as u32 + op_len).to_be_bytes())?;
+    out.write_all(&op_len.to_be_bytes())?;
+    out.write_all(&r.op)?;
+    out.write_all(&r.program)?;
+    out.write_all(&r.env)?;
+    out.write_all(&r.result)
+}
+
+struct RecorderState<W: Write> {
+    out: W,
+    // the first write error. The post-eval callback can't fail, so errors are
+    // reported by finish()
+    error: Option<Error>,
+}
+
+/// Records a trace of every evaluation, by installing itself as the pre-eval
+/// and post-eval callbacks of run_program_with_pre_eval()
+pub struct TraceRecorder<W: Write> {
+    state: Rc<RefCell<RecorderState<W>>>,
+}
+
+impl<W: Write + 'static> TraceRecorder<W> {
+    pub fn new(mut out: W) -> Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&TRACE_VERSION.to_be_bytes())?;
+        Ok(Self {
+            state: Rc::new(RefCell::new(RecorderState { out, error: None })),
+        })
+    }
+
+    /// the callback to pass to run_program_with_pre_eval()
+    pub fn pre_eval(&self) -> PreEval {
+        let state = self.state.clone();
+        Box::new(
+            move |a: &mut Allocator,
+                  program: NodePtr,
+                  env: NodePtr|
+                  -> std::result::Result<Option<Box<PostEval>>, EvalErr> {
+                let op = match a.sexp(program) {
+                    SExp::Pair(op, _) if !op.is_pair() => a.atom(op).as_ref().to_vec(),
+                    _ => Vec::new(),
+                };
+                let program = tree_hash(a, program)?;
+                let env = tree_hash(a, env)?;
+                let state = state.clone();
+                let post_eval: Box<PostEval> = Box::new(move |a: &mut Allocator, result| {
+                    // the result is always set when evaluation succeeds
+                    let Some(result) = result else {
+                        return;
+                    };
+                    let mut state = state.borrow_mut();
+                    let result = match tree_hash(a, result) {
+                        Ok(result) => result,
+                        Err(e) => {
+                            state.error.get_or_insert(e.into());
+                            return;
+                        }
+                    };
+                    let record = TraceRecord {
+                        op: op.clone(),
+                        program,
+                        env,
+                        result,
+                    };
+                    if state.error.is_none() {
+                        if let Err(e) = write_record(&mut state.out, &record) {
+                            state.error = Some(e);
+                        }
+                    }
+                });
+                Ok(Some(post_eval))
+            },
+        )
+    }
+
+    /// flush the trace and return the underlying writer. This fails if any
+    /// record failed to be written, or if the callbacks are still alive
+    pub fn finish(self) -> Result<W> {
+        let state = Rc::try_unwrap(self.state)
+            .map_err(|_| Error::other("trace callbacks still in use"))?
+            .into_inner();
+        if let Some(e) = state.error {
+            return Err(e);
+        }
+        let mut out = state.out;
+        out.flush()?;
+        Ok(out)
+    }
+}
+
+/// A `raise` (operator 8) that aborted evaluation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaiseRecord {
+    /// the value passed to raise, i.e. the node of the "klvm raise" error
+    pub value: NodePtr,
+    /// the programs being evaluated when the raise happened, from the root
+    /// program down to the `(x ...)` call itself
+    pub path: Vec<NodePtr>,
+}
+
+/// Collects the raise sites hit by run_program_with_pre_eval(). The first
+/// raise aborts evaluation, so only the single path that failed is ever
+/// observed. Branches that weren't taken (e.g. the other side of an `i`) are
+/// not evaluated and can't be recorded. This means raises() returns at most
+/// one record, but it's a Vec to leave room for dialects that recover from
+/// a raise.
+#[derive(Default)]
+pub struct RaiseRecorder {
+    // the programs currently being evaluated. Each one is popped by its
+    // post-eval callback once it completes. Evaluations that fail are never
+    // popped, so after a failure this is the path to the failing program
+    stack: Rc<RefCell<Vec<NodePtr>>>,
+}
+
+impl RaiseRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the callback to pass to run_program_with_pre_eval()
+    pub fn pre_eval(&self) -> PreEval {
+        let stack = self.stack.clone();
+        Box::new(
+            move |_a: &mut Allocator,
+                  program: NodePtr,
+                  _env: NodePtr|
+                  -> std::result::Result<Option<Box<PostEval>>, EvalErr> {
+                stack.borrow_mut().push(program);
+                let stack = stack.clone();
+                let post_eval: Box<PostEval> = Box::new(move |_a: &mut Allocator, _result| {
+                    stack.borrow_mut().pop();
+                });
+                Ok(Some(post_eval))
+            },
+        )
+    }
+
+    /// the raises encountered by the run that returned `result`. This is
+    /// empty unless the program failed with "klvm raise"
+    pub fn raises(&self, result: &std::result::Result<Reduction, EvalErr>) -> Vec<RaiseRecord> {
+        match result {
+            Err(e) if e.raised().is_some() => vec![RaiseRecord {
+                value: e.0,
+                path: self.stack.borrow().clone(),
+            }],
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid trace: {msg}"))
+}
+
+/// Reads the records of a trace file
+pub struct TraceReader<R: Read> {
+    input: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// read and validate the header
+    pub fn new(mut input: R) -> Result<Self> {
+        let mut header = [0_u8; 12];
+        input.read_exact(&mut header)?;
+        if &header[..10] != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        if u16::from_be_bytes([header[10], header[11]]) != TRACE_VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        Ok(Self { input })
+    }
+
+    /// the next record, or None at the end of the trace
+    pub fn next_record(&mut self) -> Result<Option<TraceRecord>> {
+        let mut len = [0_u8; 4];
+        // distinguish the end of the trace from a truncated record
+        let mut read = 0;
+        while read < len.len() {
+            match self.input.read(&mut len[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => return Err(invalid("truncated record")),
+                n => read += n,
+            }
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len < FIXED_RECORD_LEN {
+            return Err(invalid("record too short"));
+        }
+        let mut buf = vec![0_u8; len];
+        self.input
+            .read_exact(&mut buf)
+            .map_err(|_| invalid("truncated record"))?;
+
+        let op_len = u32::from_be_bytes(buf[0..4].try_into().unwrap()) as usize;
+        if op_len > len - FIXED_RECORD_LEN {
+            return Err(invalid("bad operator length"));
+        }
+        let hashes = &buf[4 + op_len..];
+        Ok(Some(TraceRecord {
+            op: buf[4..4 + op_len].to_vec(),
+            program: hashes[0..32].try_into().unwrap(),
+            env: hashes[32..64].try_into().unwrap(),
+            result: hashes[64..96].try_into().unwrap(),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// The first record where two traces differ. A record of None means that
+/// trace ended before the other one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub index: usize,
+    pub a: Option<TraceRecord>,
+    pub b: Option<TraceRecord>,
+}
+
+/// compare two traces, record by record. Returns the first difference, or
+/// None if they are identical
+pub fn compare_traces<A: Read, B: Read>(
+    mut a: TraceReader<A>,
+    mut b: TraceReader<B>,
+) -> Result<Option<Divergence>> {
+    let mut index = 0;
+    loop {
+        let (ra, rb) = (a.next_record()?, b.next_record()?);
+        if ra.is_none() && rb.is_none() {
+            return Ok(None);
+        }
+        if ra != rb {
+            return Ok(Some(Divergence {
+                index,
+                a: ra,
+                b: rb,
+            }));
+        }
+        index += 1;
+    }
+}
+
+#[cfg(test)]
+use crate::chik_dialect::{ChikDialect, ENABLE_BLS_OPS_OUTSIDE_GUARD};
+#[cfg(test)]
+use crate::run_program::run_program_with_pre_eval;
+#[cfg(test)]
+use crate::test_ops::parse_exp;
+
+#[cfg(test)]
+fn record_trace(program: &str, flags: u32) -> Vec<u8> {
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(&mut a, program);
+    let recorder = TraceRecorder::new(Vec::new()).unwrap();
+    run_program_with_pre_eval(
+        &mut a,
+        &ChikDialect::new(flags),
+        program,
+        NodePtr::NIL,
+        100000,
+        Some(recorder.pre_eval()),
+    )
+    .unwrap();
+    recorder.finish().unwrap()
+}
+
+#[cfg(test)]
+fn reader(buf: &[u8]) -> TraceReader<&[u8]> {
+    TraceReader::new(buf).unwrap()
+}
+
+// the offsets where each record starts, and the end of the trace
+#[cfg(test)]
+fn record_offsets(trace: &[u8]) -> Vec<usize> {
+    let mut ret = vec![MAGIC.len() + 2];
+    let mut pos = ret[0];
+    while pos < trace.len() {
+        pos += 4 + u32::from_be_bytes(trace[pos..pos + 4].try_into().unwrap()) as usize;
+        ret.push(pos);
+    }
+    ret
+}
+
+#[test]
+fn test_trace_round_trip() {
+    let program = "(c (+ (q . 1) (q . 2)) (61 (q . 7) (q . 3)))";
+    let trace = record_trace(program, ENABLE_BLS_OPS_OUTSIDE_GUARD);
+    let records = reader(&trace).collect::<Result<Vec<_>>>().unwrap();
+
+    // the whole program completes last
+    let mut a = Allocator::new();
+    let (node, _) = parse_exp(&mut a, program);
+    let last = records.last().unwrap();
+    assert_eq!(last.op, [4]);
+    assert_eq!(last.program, tree_hash(&a, node).unwrap());
+    assert_eq!(last.env, tree_hash(&a, NodePtr::NIL).unwrap());
+    let (expected, _) = parse_exp(&mut a, "(3 . 1)");
+    assert_eq!(last.result, tree_hash(&a, expected).unwrap());
+
+    // c, +, mod and the 4 quotes
+    assert_eq!(records.len(), 7);
+}
+
+#[test]
+fn test_compare_traces() {
+    let program = "(c (+ (q . 1) (q . 2)) (61 (q . 7) (q . 3)))";
+
+    // the same program, on two fresh allocators
+    let trace1 = record_trace(program, ENABLE_BLS_OPS_OUTSIDE_GUARD);
+    let trace2 = record_trace(program, ENABLE_BLS_OPS_OUTSIDE_GUARD);
+    assert_eq!(
+        compare_traces(reader(&trace1), reader(&trace2)).unwrap(),
+        None
+    );
+
+    // without the BLS operators, mod (61) is an unknown operator, returning
+    // nil. The traces diverge at the mod call
+    let trace3 = record_trace(program, 0);
+    let divergence = compare_traces(reader(&trace1), reader(&trace3))
+        .unwrap()
+        .unwrap();
+
+    let mut a = Allocator::new();
+    let (mod_call, _) = parse_exp(&mut a, "(61 (q . 7) (q . 3))");
+    let mod_call = tree_hash(&a, mod_call).unwrap();
+    let expected_index = reader(&trace1)
+        .position(|r| r.unwrap().program == mod_call)
+        .unwrap();
+    assert_eq!(divergence.index, expected_index);
+    let (ra, rb) = (divergence.a.unwrap(), divergence.b.unwrap());
+    assert_eq!(ra.program, mod_call);
+    assert_eq!(rb.program, mod_call);
+    assert_eq!(rb.result, tree_hash(&a, NodePtr::NIL).unwrap());
+    assert!(ra.result != rb.result);
+
+    // a trace that ends early
+    let offsets = record_offsets(&trace1);
+    let truncated = &trace1[..offsets[5]];
+    let divergence = compare_traces(reader(&trace1), reader(truncated))
+        .unwrap()
+        .unwrap();
+    assert_eq!(divergence.index, 5);
+    assert!(divergence.a.is_some());
+    assert_eq!(divergence.b, None);
+}
+
+#[test]
+fn test_invalid_trace() {
+    let trace = record_trace("(+ (q . 1) (q . 2))", 0);
+    assert!(reader(&trace).all(|r| r.is_ok()));
+
+    // bad magic
+    let mut buf = trace.clone();
+    buf[0] = b'x';
+    assert!(TraceReader::new(&buf[..]).is_err());
+
+    // unsupported version
+    let mut buf = trace.clone();
+    buf[11] = 2;
+    assert!(TraceReader::new(&buf[..]).is_err());
+
+    // a truncated header
+    assert!(TraceReader::new(&trace[..11]).is_err());
+
+    // truncated records. A cut exactly between two records is a valid, but
+    // shorter, trace
+    let offsets = record_offsets(&trace);
+    for len in offsets[0]..trace.len() {
+        let records = reader(&trace[..len]).collect::<Result<Vec<_>>>();
+        assert_eq!(records.is_ok(), offsets.contains(&len));
+    }
+
+    // an operator length that doesn't fit in the record
+    let mut buf = trace;
+    buf[offsets[0] + 4 + 3] = 0xff;
+    assert!(reader(&buf).collect::<Result<Vec<_>>>().is_err());
+}
+
+#[test]
+fn test_raise_recorder() {
+    let mut a = Allocator::new();
+    let (program, _) = parse_exp(
+        &mut a,
+        "(a (i 1 (q . (x (q . 100))) (q . (x (q . 200) (q . 300)))) 1)",
+    );
+    let (taken, _) = parse_exp(&mut a, "(x (q . 100))");
+    let (not_taken, _) = parse_exp(&mut a, "(x (q . 200) (q . 300))");
+    let dialect = ChikDialect::new(0);
+
+    for (env, branch, value) in [(1, taken, "100"), (0, not_taken, "(200 300)")] {
+        let env = a.new_small_number(env).unwrap();
+        let recorder = RaiseRecorder::new();
+        let result = run_program_with_pre_eval(
+            &mut a,
+            &dialect,
+            program,
+            env,
+            100000,
+            Some(recorder.pre_eval()),
+        );
+        let raises = recorder.raises(&result);
+        assert_eq!(raises.len(), 1);
+        let raise = &raises[0];
+        assert_eq!(result.unwrap_err().0, raise.value);
+        assert_eq!(crate::disassemble::disassemble(&a, raise.value), value);
+        // the root program, and the branch that was applied by it
+        assert_eq!(raise.path.len(), 2);
+        assert_eq!(raise.path[0], program);
+        assert!(crate::test_ops::node_eq(&a, raise.path[1], branch));
+    }
+
+    // a program that doesn't raise records nothing
+    let (program, _) = parse_exp(&mut a, "(+ (q . 1) (q . 2))");
+    let recorder = RaiseRecorder::new();
+    let result = run_program_with_pre_eval(
+        &mut a,
+        &dialect,
+        program,
+        NodePtr::NIL,
+        100000,
+        Some(recorder.pre_eval()),
+    );
+    assert!(result.is_ok());
+    assert!(recorder.raises(&result).is_empty());
+
+    // neither does one failing for another reason
+    let (program, _) = parse_exp(&mut a, "(+ (q . 1) (q 1 2))");
+    let result = run_program_with_pre_eval(
+        &mut a,
+        &dialect,
+        program,
+        NodePtr::NIL,
+        100000,
+        Some(recorder.pre_eval()),
+    );
+    assert!(result.is_err());
+    assert!(recorder.raises(&result).is_empty());
+}
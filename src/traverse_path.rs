@@ -1,6 +1,12 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
 use crate::allocator::{Allocator, NodePtr, SExp};
 use crate::cost::Cost;
+use crate::number::Number;
 use crate::reduction::{EvalErr, Reduction, Response};
+use crate::run_program::EnvProvider;
+use num_bigint::Sign;
 
 // lowered from measured 147 per bit. It doesn't seem to take this long in
 // practice
@@ -55,7 +61,10 @@ pub fn traverse_path(allocator: &Allocator, node_index: &[u8], args: NodePtr) ->
         let is_bit_set: bool = (node_index[byte_idx] & bitmask) != 0;
         match allocator.sexp(arg_list) {
             SExp::Atom => {
-                return Err(EvalErr(arg_list, "path into atom".into()));
+                if arg_list.is_hash_stub() {
+                    return Err(EvalErr::new(arg_list, "descend into hash stub".into()));
+                }
+                return Err(EvalErr::new(arg_list, "path into atom".into()));
             }
             SExp::Pair(left, right) => {
                 arg_list = if is_bit_set { right } else { left };
@@ -89,7 +98,10 @@ pub fn traverse_path_fast(allocator: &Allocator, mut node_index: u32, args: Node
     let mut num_bits = 0;
     while node_index != 1 {
         let SExp::Pair(left, right) = allocator.sexp(arg_list) else {
-            return Err(EvalErr(arg_list, "path into atom".into()));
+            if arg_list.is_hash_stub() {
+                return Err(EvalErr::new(arg_list, "descend into hash stub".into()));
+            }
+            return Err(EvalErr::new(arg_list, "path into atom".into()));
         };
 
         let is_bit_set: bool = (node_index & 0x01) != 0;
@@ -108,6 +120,115 @@ pub fn traverse_path_fast(allocator: &Allocator, mut node_index: u32, args: Node
     Ok(Reduction(cost, arg_list))
 }
 
+// ask `provider` for the sub tree at `path` into a lazy environment. It may not
+// return another lazy environment, since that could make us go around in
+// circles
+pub(crate) fn lazy_env_lookup(
+    allocator: &mut Allocator,
+    lazy_env: NodePtr,
+    path: &[u8],
+    provider: &mut EnvProvider,
+) -> Result<NodePtr, EvalErr> {
+    let node = provider(allocator, path)?;
+    if allocator.is_lazy_env(node) {
+        return Err(EvalErr::new(
+            lazy_env,
+            "env provider returned a lazy env".into(),
+        ));
+    }
+    Ok(node)
+}
+
+/// Like `traverse_path()`, but if the path reaches a lazy environment (see
+/// `Allocator::new_lazy_env()`), the rest of the path is looked up by
+/// `provider`. It's passed the remaining path in the same form as
+/// `node_index`, i.e. `1` refers to the lazy environment itself. The cost is
+/// the same as if the sub tree had been there all along
+pub fn traverse_path_lazy(
+    allocator: &mut Allocator,
+    node_index: &[u8],
+    args: NodePtr,
+    provider: &mut EnvProvider,
+) -> Response {
+    let mut arg_list: NodePtr = args;
+
+    let first_bit_byte_index = first_non_zero(node_index);
+    if first_bit_byte_index >= node_index.len() {
+        return traverse_path(allocator, node_index, args);
+    }
+    let last_bitmask = msb_mask(node_index[first_bit_byte_index]);
+
+    // the cost only depends on the path, not on where it ends up
+    let num_bits = (node_index.len() - first_bit_byte_index - 1) as Cost * 8
+        + last_bitmask.trailing_zeros() as Cost;
+    let cost: Cost = TRAVERSE_BASE_COST
+        + (first_bit_byte_index as Cost) * TRAVERSE_COST_PER_ZERO_BYTE
+        + TRAVERSE_COST_PER_BIT
+        + num_bits * TRAVERSE_COST_PER_BIT;
+
+    let mut byte_idx = node_index.len() - 1;
+    let mut bitmask = 0x01;
+    let mut consumed = 0_usize;
+    loop {
+        if allocator.is_lazy_env(arg_list) {
+            let rest = Number::from_bytes_be(Sign::Plus, node_index) >> consumed;
+            let node = lazy_env_lookup(allocator, arg_list, &rest.to_signed_bytes_be(), provider)?;
+            return Ok(Reduction(cost, node));
+        }
+        if byte_idx == first_bit_byte_index && bitmask >= last_bitmask {
+            break;
+        }
+        let is_bit_set: bool = (node_index[byte_idx] & bitmask) != 0;
+        match allocator.sexp(arg_list) {
+            SExp::Atom => {
+                if arg_list.is_hash_stub() {
+                    return Err(EvalErr::new(arg_list, "descend into hash stub".into()));
+                }
+                return Err(EvalErr::new(arg_list, "path into atom".into()));
+            }
+            SExp::Pair(left, right) => {
+                arg_list = if is_bit_set { right } else { left };
+            }
+        }
+        if bitmask == 0x80 {
+            bitmask = 0x01;
+            byte_idx -= 1;
+        } else {
+            bitmask <<= 1;
+        }
+        consumed += 1;
+    }
+    Ok(Reduction(cost, arg_list))
+}
+
+/// Returns the path of every atom in the tree at `root` for which `pred`
+/// returns true, in left-to-right order. These are the paths `traverse_path()`
+/// (and environment lookups in a program) resolve back to the atom. Atoms
+/// more than 63 levels deep can't be addressed by a u64 and are skipped
+pub fn paths_to<F: Fn(&[u8]) -> bool>(allocator: &Allocator, root: NodePtr, pred: F) -> Vec<u64> {
+    let mut ret = Vec::new();
+    // the node, the bits of the path so far (the first step in the least
+    // significant bit) and the depth
+    let mut stack: Vec<(NodePtr, u64, u32)> = vec![(root, 0, 0)];
+    while let Some((node, bits, depth)) = stack.pop() {
+        match allocator.sexp(node) {
+            SExp::Pair(left, right) => {
+                if depth == 63 {
+                    continue;
+                }
+                stack.push((right, bits | (1 << depth), depth + 1));
+                stack.push((left, bits, depth + 1));
+            }
+            SExp::Atom => {
+                if pred(allocator.atom(node).as_ref()) {
+                    ret.push(bits | (1 << depth));
+                }
+            }
+        }
+    }
+    ret
+}
+
 #[test]
 fn test_msb_mask() {
     assert_eq!(msb_mask(0x0), 0x0);
@@ -183,23 +304,90 @@ fn test_traverse_path() {
     // errors
     assert_eq!(
         traverse_path(&a, &[0b1011], list).unwrap_err(),
-        EvalErr(nul, "path into atom".to_string())
+        EvalErr::new(nul, "path into atom".to_string())
     );
     assert_eq!(
         traverse_path(&a, &[0b1101], list).unwrap_err(),
-        EvalErr(n1, "path into atom".to_string())
+        EvalErr::new(n1, "path into atom".to_string())
     );
     assert_eq!(
         traverse_path(&a, &[0b1001], list).unwrap_err(),
-        EvalErr(n1, "path into atom".to_string())
+        EvalErr::new(n1, "path into atom".to_string())
     );
     assert_eq!(
         traverse_path(&a, &[0b1010], list).unwrap_err(),
-        EvalErr(n2, "path into atom".to_string())
+        EvalErr::new(n2, "path into atom".to_string())
     );
     assert_eq!(
         traverse_path(&a, &[0b1110], list).unwrap_err(),
-        EvalErr(n2, "path into atom".to_string())
+        EvalErr::new(n2, "path into atom".to_string())
+    );
+}
+
+#[test]
+fn test_traverse_path_lazy() {
+    use crate::allocator::Allocator;
+
+    let mut a = Allocator::new();
+    let n1 = a.new_atom(&[0, 1, 2]).unwrap();
+    let n2 = a.new_atom(&[4, 5, 6]).unwrap();
+    // a list long enough for paths into it to span several bytes
+    let mut sub = a.nil();
+    for i in 0..12 {
+        sub = a.new_pair(if i % 2 == 0 { n1 } else { n2 }, sub).unwrap();
+    }
+    let full = a.new_pair(n1, sub).unwrap();
+    let lazy_env = a.new_lazy_env().unwrap();
+    let lazy = a.new_pair(n1, lazy_env).unwrap();
+
+    // every path gives the same result, cost and error, whether the list is
+    // present or produced by the provider. Except for path 1, which is the
+    // environment itself
+    let mut calls = 0;
+    for i in (0..0x4000_u32).filter(|i| *i != 1) {
+        let path = Number::from(i).to_signed_bytes_be();
+        for path in [path.clone(), [&[0_u8][..], &path[..]].concat()] {
+            let expected = traverse_path(&a, &path, full);
+            let mut provider = |a: &mut Allocator, p: &[u8]| {
+                calls += 1;
+                traverse_path(a, p, sub).map(|r| r.1)
+            };
+            assert_eq!(
+                traverse_path_lazy(&mut a, &path, lazy, &mut provider),
+                expected
+            );
+        }
+    }
+    // the provider is called for every path going right at the top, i.e. all
+    // odd paths except 1, in both encodings
+    assert_eq!(calls, 2 * (0x2000 - 1));
+
+    // the provider is passed the rest of the path
+    let mut paths = Vec::new();
+    let mut provider = |a: &mut Allocator, p: &[u8]| -> Result<NodePtr, EvalErr> {
+        paths.push(p.to_vec());
+        Ok(a.nil())
+    };
+    for path in [&[0b11][..], &[0b111], &[0b1_1011], &[0x01, 0x01]] {
+        traverse_path_lazy(&mut a, path, lazy, &mut provider).unwrap();
+    }
+    assert_eq!(
+        paths,
+        [vec![0b1], vec![0b11], vec![0b1101], vec![0x00, 0x80]]
+    );
+
+    // the provider may fail, or not return another lazy environment
+    let mut provider = |_: &mut Allocator, _: &[u8]| -> Result<NodePtr, EvalErr> {
+        Err(EvalErr::new(n2, "no such coin".into()))
+    };
+    assert_eq!(
+        traverse_path_lazy(&mut a, &[0b11], lazy, &mut provider).unwrap_err(),
+        EvalErr::new(n2, "no such coin".into())
+    );
+    let mut provider = |_: &mut Allocator, _: &[u8]| -> Result<NodePtr, EvalErr> { Ok(lazy_env) };
+    assert_eq!(
+        traverse_path_lazy(&mut a, &[0b11], lazy, &mut provider).unwrap_err(),
+        EvalErr::new(lazy_env, "env provider returned a lazy env".into())
     );
 }
 
@@ -241,22 +429,69 @@ fn test_traverse_path_fast_fast() {
     // errors
     assert_eq!(
         traverse_path_fast(&a, 0b1011, list).unwrap_err(),
-        EvalErr(nul, "path into atom".to_string())
+        EvalErr::new(nul, "path into atom".to_string())
     );
     assert_eq!(
         traverse_path_fast(&a, 0b1101, list).unwrap_err(),
-        EvalErr(n1, "path into atom".to_string())
+        EvalErr::new(n1, "path into atom".to_string())
     );
     assert_eq!(
         traverse_path_fast(&a, 0b1001, list).unwrap_err(),
-        EvalErr(n1, "path into atom".to_string())
+        EvalErr::new(n1, "path into atom".to_string())
     );
     assert_eq!(
         traverse_path_fast(&a, 0b1010, list).unwrap_err(),
-        EvalErr(n2, "path into atom".to_string())
+        EvalErr::new(n2, "path into atom".to_string())
     );
     assert_eq!(
         traverse_path_fast(&a, 0b1110, list).unwrap_err(),
-        EvalErr(n2, "path into atom".to_string())
+        EvalErr::new(n2, "path into atom".to_string())
     );
 }
+
+#[test]
+fn test_paths_to() {
+    use crate::test_ops::parse_exp;
+
+    let mut a = Allocator::new();
+    let (tree, _) = parse_exp(&mut a, "(1 (2 . 0x1337) (3 (0x1337 4)) 0x1337 . 5)");
+
+    let paths = paths_to(&a, tree, |atom| atom == [0x13, 0x37]);
+    assert_eq!(paths, vec![0b1101, 0b1001011, 0b10111]);
+    for path in &paths {
+        let node = traverse_path(&a, &path.to_be_bytes(), tree).unwrap().1;
+        assert_eq!(a.atom(node).as_ref(), [0x13, 0x37]);
+        let node = traverse_path_fast(&a, *path as u32, tree).unwrap().1;
+        assert_eq!(a.atom(node).as_ref(), [0x13, 0x37]);
+    }
+
+    // nil terminators are atoms too
+    assert_eq!(
+        paths_to(&a, tree, |atom| atom.is_empty()),
+        vec![0b11101011, 0b111011]
+    );
+    assert_eq!(paths_to(&a, tree, |atom| atom == [6]), Vec::<u64>::new());
+    // the root itself
+    let atom = a.new_atom(&[1, 2, 3]).unwrap();
+    assert_eq!(paths_to(&a, atom, |_| true), vec![1]);
+}
+
+#[test]
+fn test_paths_to_deep() {
+    let mut a = Allocator::new();
+    let target = a.new_atom(&[0xff]).unwrap();
+    // a chain of pairs, each with the target as the right element
+    let mut tree = target;
+    for _ in 0..70 {
+        tree = a.new_pair(tree, target).unwrap();
+    }
+    let paths = paths_to(&a, tree, |atom| atom == [0xff]);
+    // only the ones up to 63 levels deep can be expressed
+    assert_eq!(paths.len(), 63);
+    assert_eq!(paths[62], 0b11);
+    assert_eq!(paths[0], 0b11 << 62);
+    for path in paths {
+        let node = traverse_path(&a, &path.to_be_bytes(), tree).unwrap().1;
+        assert_eq!(node, target);
+    }
+}
@@ -0,0 +1,230 @@
+// Runs the conformance fixtures in tests/conformance/ through run_program().
+//
+// Each fixture is a text file of "key: value" lines. Lines starting with ';'
+// are comments. The keys are:
+//
+// program: the serialized program (hex)
+// env: the serialized environment (hex)
+// flags: the ChikDialect flags (decimal or 0x-prefixed hex)
+// max_cost: (optional) the cost limit, defaults to 11000000000
+//
+// followed by the expected outcome, either:
+//
+// cost: the cost of running the program
+// result: the serialized result (hex)
+//
+// or:
+//
+// error: the error message
+//
+// The fixtures are meant to be consumed by other implementations too, so they
+// only rely on the serialization format and the error messages.
+//
+// Set KLVM_BLESS=1 to rewrite the expected outcome of every fixture with the
+// actual outcome.
+
+use klvmr::allocator::Allocator;
+use klvmr::chik_dialect::ChikDialect;
+use klvmr::cost::Cost;
+use klvmr::reduction::Reduction;
+use klvmr::run_program::run_program;
+use klvmr::serde::{node_from_bytes, node_to_bytes};
+use std::fs;
+use std::path::Path;
+
+const DEFAULT_MAX_COST: Cost = 11000000000;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Success { cost: Cost, result: Vec<u8> },
+    Error(String),
+}
+
+#[derive(Debug)]
+struct Fixture {
+    // the comments and the inputs, as they appear in the file. These are
+    // preserved when blessing
+    header: Vec<String>,
+    program: Vec<u8>,
+    env: Vec<u8>,
+    flags: u32,
+    max_cost: Cost,
+    expected: Option<Outcome>,
+}
+
+fn parse_int(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_fixture(text: &str) -> Result<Fixture, String> {
+    let mut header = Vec::new();
+    let mut program = None;
+    let mut env = None;
+    let mut flags = None;
+    let mut max_cost = DEFAULT_MAX_COST;
+    let mut cost = None;
+    let mut result = None;
+    let mut error = None;
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with(';') {
+            header.push(line.to_string());
+            continue;
+        }
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("invalid line: {line}"))?;
+        let value = value.trim();
+        let decode_hex = || hex::decode(value).map_err(|e| format!("invalid hex for {key}: {e}"));
+        let decode_int = || parse_int(value).ok_or_else(|| format!("invalid integer for {key}"));
+        match key.trim() {
+            "program" => program = Some(decode_hex()?),
+            "env" => env = Some(decode_hex()?),
+            "flags" => flags = Some(u32::try_from(decode_int()?).map_err(|e| e.to_string())?),
+            "max_cost" => max_cost = decode_int()?,
+            "cost" => cost = Some(decode_int()?),
+            "result" => result = Some(decode_hex()?),
+            "error" => error = Some(value.to_string()),
+            key => return Err(format!("unknown key: {key}")),
+        }
+        if matches!(key.trim(), "program" | "env" | "flags" | "max_cost") {
+            header.push(line.to_string());
+        }
+    }
+
+    let expected = match (cost, result, error) {
+        (Some(cost), Some(result), None) => Some(Outcome::Success { cost, result }),
+        (None, None, Some(error)) => Some(Outcome::Error(error)),
+        // a new fixture, waiting to be blessed
+        (None, None, None) => None,
+        _ => {
+            return Err("expected either cost and result, or error".to_string());
+        }
+    };
+
+    Ok(Fixture {
+        header,
+        program: program.ok_or("missing program")?,
+        env: env.ok_or("missing env")?,
+        flags: flags.ok_or("missing flags")?,
+        max_cost,
+        expected,
+    })
+}
+
+fn run_fixture(fixture: &Fixture) -> Outcome {
+    let mut a = Allocator::new();
+    let program = node_from_bytes(&mut a, &fixture.program).expect("invalid program");
+    let env = node_from_bytes(&mut a, &fixture.env).expect("invalid env");
+    let dialect = ChikDialect::new(fixture.flags);
+    match run_program(&mut a, &dialect, program, env, fixture.max_cost) {
+        Ok(Reduction(cost, result)) => Outcome::Success {
+            cost,
+            result: node_to_bytes(&a, result).expect("failed to serialize result"),
+        },
+        Err(e) => Outcome::Error(e.1),
+    }
+}
+
+fn bless(path: &Path, fixture: &Fixture, outcome: &Outcome) {
+    let mut text = fixture.header.join("\n");
+    text.push('\n');
+    match outcome {
+        Outcome::Success { cost, result } => {
+            text.push_str(&format!("cost: {cost}\nresult: {}\n", hex::encode(result)));
+        }
+        Outcome::Error(e) => {
+            text.push_str(&format!("error: {e}\n"));
+        }
+    }
+    fs::write(path, text).expect("failed to write fixture");
+}
+
+#[test]
+fn conformance() {
+    let blessing = std::env::var("KLVM_BLESS").is_ok_and(|v| v == "1");
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance");
+
+    let mut paths: Vec<_> = fs::read_dir(&dir)
+        .expect("failed to list fixtures")
+        .map(|entry| entry.expect("failed to list fixtures").path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no fixtures found in {}", dir.display());
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        let text = fs::read_to_string(path).expect("failed to read fixture");
+        let fixture = parse_fixture(&text)
+            .unwrap_or_else(|e| panic!("failed to parse {}: {e}", path.display()));
+        let outcome = run_fixture(&fixture);
+        if blessing {
+            if fixture.expected.as_ref() != Some(&outcome) {
+                bless(path, &fixture, &outcome);
+            }
+        } else if fixture.expected.as_ref() != Some(&outcome) {
+            failures.push(format!(
+                "{}: expected {:?} got {:?}",
+                path.display(),
+                fixture.expected,
+                outcome
+            ));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} fixtures failed:\n{}",
+        failures.len(),
+        paths.len(),
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn conformance_parse_fixture() {
+    let fixture = parse_fixture(
+        "; (c (q . 100) (q . ()))\n\
+         program: ff04ffff0164ffff018080\n\
+         env: 80\n\
+         flags: 0x20\n\
+         cost: 91\n\
+         result: ff6480\n",
+    )
+    .unwrap();
+    assert_eq!(fixture.flags, 0x20);
+    assert_eq!(fixture.max_cost, DEFAULT_MAX_COST);
+    assert_eq!(
+        fixture.expected,
+        Some(Outcome::Success {
+            cost: 91,
+            result: vec![0xff, 0x64, 0x80]
+        })
+    );
+    assert_eq!(run_fixture(&fixture), fixture.expected.unwrap());
+
+    let fixture = parse_fixture(
+        "program: ff05ffff010180\nenv: 80\nflags: 0\nmax_cost: 1000\nerror: first of non-cons",
+    )
+    .unwrap();
+    assert_eq!(fixture.max_cost, 1000);
+    assert_eq!(fixture.header.len(), 4);
+    assert_eq!(run_fixture(&fixture), fixture.expected.unwrap());
+
+    for invalid in [
+        "program: ff0501\nenv: 80\n",
+        "program: ff0501\nenv: 80\nflags: 0\ncost: 1\n",
+        "program: ff0501\nenv: 80\nflags: 0\ncost: 1\nresult: 80\nerror: foo\n",
+        "program: ff05zz\nenv: 80\nflags: 0\n",
+        "program: ff0501\nenv: 80\nflags: 0\nfoo: bar\n",
+    ] {
+        assert!(parse_fixture(invalid).is_err());
+    }
+}
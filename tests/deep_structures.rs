@@ -0,0 +1,177 @@
+// Runs the public tree traversals over pathologically deep trees, to make sure
+// none of them recurse on the call stack.
+//
+// Each tree is either left-deep ((((() . 1) . 1) . 1) ...), right-deep
+// (1 1 1 ...) or zig-zags between the two. The expected tree hash is computed
+// while building the tree, independently of the code under test.
+//
+// The default tests use 100,000 pairs, enough to overflow a test thread's stack
+// if a traversal recursed. The 1 million pair versions take most of a minute
+// in a debug build, so they're ignored by default. CI runs them in release
+// mode with: cargo test --release -- --include-ignored
+
+use klvmr::allocator::{find_atoms, Allocator, NodePtr};
+use klvmr::chik_dialect::ChikDialect;
+use klvmr::disassemble::{disassemble, disassemble_with_budget, DisassembleBudget};
+use klvmr::program::Program;
+use klvmr::run_program::run_program_output;
+use klvmr::serde::{
+    canonicalize_serialization, node_from_bytes, node_from_bytes_backrefs, node_to_bytes,
+    node_to_bytes_backrefs, node_to_bytes_limit, serialized_length_from_bytes,
+    tree_hash_from_stream, tree_hash_stream,
+};
+use klvmr::sha2::Sha256;
+use std::io::Cursor;
+
+const NUM_PAIRS: usize = 100_000;
+const NUM_PAIRS_1M: usize = 1_000_000;
+
+#[derive(Clone, Copy)]
+enum Shape {
+    LeftDeep,
+    RightDeep,
+    ZigZag,
+}
+
+fn hash_atom(buf: &[u8]) -> [u8; 32] {
+    let mut ctx = Sha256::new();
+    ctx.update([1_u8]);
+    ctx.update(buf);
+    ctx.finalize()
+}
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut ctx = Sha256::new();
+    ctx.update([2_u8]);
+    ctx.update(left);
+    ctx.update(right);
+    ctx.finalize()
+}
+
+// returns the tree along with its tree hash
+fn build_tree(a: &mut Allocator, shape: Shape, num_pairs: usize) -> (NodePtr, [u8; 32]) {
+    let one = a.one();
+    let one_hash = hash_atom(&[1]);
+    let mut node = a.nil();
+    let mut hash = hash_atom(&[]);
+    for i in 0..num_pairs {
+        let left_deep = match shape {
+            Shape::LeftDeep => true,
+            Shape::RightDeep => false,
+            Shape::ZigZag => i % 2 == 0,
+        };
+        if left_deep {
+            node = a.new_pair(node, one).expect("new_pair");
+            hash = hash_pair(&hash, &one_hash);
+        } else {
+            node = a.new_pair(one, node).expect("new_pair");
+            hash = hash_pair(&one_hash, &hash);
+        }
+    }
+    (node, hash)
+}
+
+fn check_traversals(shape: Shape, num_pairs: usize) {
+    let mut a = Allocator::new();
+    let (node, expected_hash) = build_tree(&mut a, shape, num_pairs);
+
+    // every pair is serialized as 0xff, every 1 as 0x01 and the single nil as
+    // 0x80
+    let expected_len = num_pairs * 2 + 1;
+    let bytes = node_to_bytes_limit(&a, node, expected_len).expect("node_to_bytes_limit");
+    assert_eq!(bytes.len(), expected_len);
+    assert!(node_to_bytes_limit(&a, node, expected_len - 1).is_err());
+    // the default limit of node_to_bytes() is 2 MB
+    assert_eq!(node_to_bytes(&a, node).is_ok(), expected_len <= 2_000_000);
+
+    // traversals of the serialized form
+    assert_eq!(
+        serialized_length_from_bytes(&bytes).expect("serialized_length_from_bytes"),
+        expected_len as u64
+    );
+    assert_eq!(
+        tree_hash_from_stream(&mut Cursor::new(&bytes[..])).expect("tree_hash_from_stream"),
+        expected_hash
+    );
+    assert_eq!(
+        tree_hash_stream(&bytes[..]).expect("tree_hash_stream"),
+        expected_hash
+    );
+    assert_eq!(
+        canonicalize_serialization(&bytes).expect("canonicalize_serialization"),
+        (bytes.clone(), true)
+    );
+    let program = Program::from_bytes_validated(&bytes).expect("from_bytes_validated");
+    assert_eq!(*program.tree_hash(), expected_hash);
+
+    // round trips through the deserializers
+    let parsed = node_from_bytes(&mut a, &bytes).expect("node_from_bytes");
+    assert!(a.structural_eq(node, parsed));
+    assert_eq!(a.structural_hash(node), a.structural_hash(parsed));
+
+    let br_bytes = node_to_bytes_backrefs(&a, node).expect("node_to_bytes_backrefs");
+    let parsed = node_from_bytes_backrefs(&mut a, &br_bytes).expect("node_from_bytes_backrefs");
+    assert!(a.structural_eq(node, parsed));
+
+    // copying the tree out of the allocator, and back in
+    let owned = a.to_owned_tree(node);
+    let copy = owned.to_allocator(&mut a).expect("to_allocator");
+    assert!(a.structural_eq(node, copy));
+    drop(owned);
+
+    // iterating over the atoms
+    assert_eq!(a.atoms_iter(node).count(), num_pairs + 1);
+    assert_eq!(find_atoms(&a, node, |atom| atom == [1]).count(), num_pairs);
+
+    // rendering
+    let text = disassemble(&a, node);
+    assert!(text.len() >= expected_len);
+    let budget = DisassembleBudget {
+        max_nodes: 1000,
+        max_output_len: 100,
+    };
+    let text = disassemble_with_budget(&a, node, budget);
+    assert!(text.ends_with("..."));
+    assert!(text.len() <= 103);
+
+    // quoting the tree and hashing the result
+    let quote = a.one();
+    let quoted = a.new_pair(quote, node).expect("new_pair");
+    let output = run_program_output(&mut a, &ChikDialect::new(0), quoted, quote, 11_000_000_000)
+        .expect("run_program_output");
+    assert!(a.structural_eq(output.result, node));
+    assert_eq!(*output.tree_hash(&a), expected_hash);
+}
+
+#[test]
+fn test_left_deep() {
+    check_traversals(Shape::LeftDeep, NUM_PAIRS);
+}
+
+#[test]
+fn test_right_deep() {
+    check_traversals(Shape::RightDeep, NUM_PAIRS);
+}
+
+#[test]
+fn test_zig_zag() {
+    check_traversals(Shape::ZigZag, NUM_PAIRS);
+}
+
+#[test]
+#[ignore = "slow in debug builds, run with --release -- --include-ignored"]
+fn test_left_deep_1m() {
+    check_traversals(Shape::LeftDeep, NUM_PAIRS_1M);
+}
+
+#[test]
+#[ignore = "slow in debug builds, run with --release -- --include-ignored"]
+fn test_right_deep_1m() {
+    check_traversals(Shape::RightDeep, NUM_PAIRS_1M);
+}
+
+#[test]
+#[ignore = "slow in debug builds, run with --release -- --include-ignored"]
+fn test_zig_zag_1m() {
+    check_traversals(Shape::ZigZag, NUM_PAIRS_1M);
+}
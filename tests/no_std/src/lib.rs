@@ -0,0 +1,64 @@
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use klvmr::core_ops::{op_cons, op_eq, op_first, op_if, op_listp, op_raise, op_rest};
+use klvmr::cost::Cost;
+use klvmr::dialect::{Dialect, OperatorSet};
+use klvmr::err_utils::err;
+use klvmr::reduction::Response;
+use klvmr::serde::{node_from_bytes, node_to_bytes};
+use klvmr::{run_program, Allocator, NodePtr};
+
+// ChikDialect isn't available without std (its BLS operators need it), so
+// this dialect only has the core operators. Anything else is an error
+pub struct CoreDialect;
+
+impl Dialect for CoreDialect {
+    fn quote_kw(&self) -> u32 {
+        1
+    }
+    fn apply_kw(&self) -> u32 {
+        2
+    }
+    fn softfork_kw(&self) -> u32 {
+        36
+    }
+    fn softfork_extension(&self, _ext: u32) -> OperatorSet {
+        OperatorSet::Default
+    }
+    fn op(
+        &self,
+        a: &mut Allocator,
+        op: NodePtr,
+        args: NodePtr,
+        max_cost: Cost,
+        _extension: OperatorSet,
+    ) -> Response {
+        let f = match a.atom(op).as_ref() {
+            [3] => op_if,
+            [4] => op_cons,
+            [5] => op_first,
+            [6] => op_rest,
+            [7] => op_listp,
+            [8] => op_raise,
+            [9] => op_eq,
+            _ => return err(op, "unimplemented operator"),
+        };
+        f(a, args, max_cost)
+    }
+    fn allow_unknown_ops(&self) -> bool {
+        false
+    }
+}
+
+/// run a serialized program against a serialized environment, returning the
+/// cost and the serialized result
+pub fn run_serialized(program: &[u8], env: &[u8], max_cost: u64) -> Option<(u64, Vec<u8>)> {
+    let mut a = Allocator::new();
+    let program = node_from_bytes(&mut a, program).ok()?;
+    let env = node_from_bytes(&mut a, env).ok()?;
+    let reduction = run_program(&mut a, &CoreDialect, program, env, max_cost).ok()?;
+    Some((reduction.0, node_to_bytes(&a, reduction.1).ok()?))
+}
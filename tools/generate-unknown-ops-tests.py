@@ -0,0 +1,101 @@
+# the cost rules for unknown operators, ported from default_unknown_op() in
+# the python implementation (klvm/more_ops.py)
+
+ARITH_BASE_COST = 99
+ARITH_COST_PER_ARG = 320
+ARITH_COST_PER_BYTE = 3
+
+MUL_BASE_COST = 92
+MUL_COST_PER_OP = 885
+MUL_LINEAR_COST_PER_BYTE = 6
+MUL_SQUARE_COST_PER_BYTE_DIVIDER = 128
+
+CONCAT_BASE_COST = 142
+CONCAT_COST_PER_ARG = 135
+CONCAT_COST_PER_BYTE = 3
+
+# each argument list is a tuple of (text, atom lengths). None means the
+# argument list contains a pair
+ARGS = [
+    ("", []),
+    ("1 0x0203", [1, 2]),
+    ("0x010203 0 5", [3, 0, 1]),
+    ("(1 . 2)", None),
+]
+
+def unknown_op(op: bytes, arg_lens):
+    if len(op) == 0 or op[:2] == b"\xff\xff":
+        return None
+
+    cost_function = (op[-1] & 0b11000000) >> 6
+    cost_multiplier = int.from_bytes(op[:-1], "big", signed=False) + 1
+    if len(op) > 5:
+        return None
+
+    if cost_function == 0:
+        cost = 1
+    elif arg_lens is None:
+        # the cost functions 1, 2 and 3 require all arguments to be atoms
+        return None
+    elif cost_function == 1:
+        cost = ARITH_BASE_COST
+        for l in arg_lens:
+            cost += ARITH_COST_PER_ARG + l * ARITH_COST_PER_BYTE
+    elif cost_function == 2:
+        cost = MUL_BASE_COST
+        if len(arg_lens) > 0:
+            l0 = arg_lens[0]
+            for l1 in arg_lens[1:]:
+                cost += MUL_COST_PER_OP
+                cost += (l0 + l1) * MUL_LINEAR_COST_PER_BYTE
+                cost += (l0 * l1) // MUL_SQUARE_COST_PER_BYTE_DIVIDER
+                l0 += l1
+    else:
+        cost = CONCAT_BASE_COST
+        for l in arg_lens:
+            cost += CONCAT_COST_PER_ARG + l * CONCAT_COST_PER_BYTE
+
+    cost *= cost_multiplier
+    if cost >= 2**32:
+        return None
+    return cost
+
+def write_case(f, op: bytes, args: str, arg_lens):
+    name = "0x" + op.hex() if len(op) > 0 else "0"
+    cost = unknown_op(op, arg_lens)
+    line = f"{name} {args}".rstrip()
+    if cost is None:
+        f.write(f"{line} => FAIL\n")
+    else:
+        f.write(f"{line} => 0 | {cost}\n")
+
+with open("../op-tests/test-unknown-ops.txt", "w+") as f:
+    f.write("; This file was generated by tools/generate-unknown-ops-tests.py\n\n")
+
+    # the empty atom is a reserved operator
+    for args, arg_lens in ARGS:
+        write_case(f, b"", args, arg_lens)
+
+    # every single-byte opcode
+    for op in range(256):
+        for args, arg_lens in ARGS:
+            write_case(f, bytes([op]), args, arg_lens)
+
+    # multi-byte opcodes. Quote and apply are only the canonical single bytes
+    # 0x01 and 0x02, the leading zero makes these unknown operators with a
+    # multiplier of 0
+    for op in [
+        b"\x00\x01",
+        b"\x00\x02",
+        b"\x00\x40",
+        b"\x01\x80",
+        b"\xff\x00",
+        b"\xff\xfe\xc0",
+        b"\xff\xff",
+        b"\xff\xff\x00",
+        b"\xff\xff\xff\xff\x00",
+        b"\x00\x00\x00\x00\x00",
+        b"\x00\x00\x00\x00\x00\x00",
+    ]:
+        for args, arg_lens in ARGS:
+            write_case(f, op, args, arg_lens)
@@ -1,11 +1,19 @@
+use clap::Parser;
 use hex_literal::hex;
+use klvm_rs_test_tools::corpus_pack::{entry_name, PackWriter};
+use klvmr::allocator::Allocator;
+use klvmr::chik_dialect::ChikDialect;
+use klvmr::cost::Cost;
+use klvmr::reduction::Reduction;
+use klvmr::run_program::run_program;
+use klvmr::serde::node_from_bytes;
 use klvmr::serde::write_atom::write_atom;
 use rand::rngs::StdRng;
 use rand::Rng;
 use rand::SeedableRng;
-use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
 use std::fs::{create_dir_all, File};
-use std::io::Write;
+use std::io::{BufWriter, Write};
 
 #[repr(u8)]
 #[derive(PartialEq, Clone, Copy, Debug)]
@@ -57,7 +65,7 @@ const fn op(opcode: u32, operands: &'static [Type], result: Type) -> OperatorInf
     }
 }
 
-const OPERATORS: [OperatorInfo; 83] = [
+const OPERATORS: [OperatorInfo; 85] = [
     // apply
     op(2, &[Type::Program, Type::Tree], Type::AnyAtom),
     // if
@@ -291,6 +299,13 @@ const OPERATORS: [OperatorInfo; 83] = [
     // mod
     op(61, &[Type::Int64, Type::Int64], Type::Int64),
     op(61, &[Type::Bytes32, Type::Bytes32], Type::Bytes32),
+    // sha256_tagged
+    op(63, &[Type::Bytes32, Type::AnyAtom], Type::Bytes32),
+    op(
+        63,
+        &[Type::AnyAtom, Type::AnyAtom, Type::AnyAtom],
+        Type::Bytes32,
+    ),
 ];
 
 const ZEROS: [u8; 96] = [0; 96];
@@ -371,7 +386,12 @@ fn write_int(buf: &mut Vec<u8>, val: u64) {
     write_atom(buf, slice).expect("write_atom failed");
 }
 
-fn generate_program<R: Rng>(op: &OperatorInfo, rng: &mut R, buffer: &mut Vec<u8>) {
+fn generate_program<R: Rng>(
+    op: &OperatorInfo,
+    rng: &mut R,
+    buffer: &mut Vec<u8>,
+    coverage: &mut Coverage,
+) {
     buffer.push(0xff); // cons
     write_int(buffer, op.opcode as u64);
     for arg in op.operands {
@@ -388,33 +408,55 @@ fn generate_program<R: Rng>(op: &OperatorInfo, rng: &mut R, buffer: &mut Vec<u8>
                 // quoted value
                 buffer.push(0xff); // cons
                 buffer.push(1); // quote
-                generate(*arg, rng, buffer);
+                generate(*arg, rng, buffer, coverage);
             } else {
                 let sub_op = sample(rng, &potential_ops);
-                generate_program(sub_op, rng, buffer);
+                coverage.add_sub_expression(sub_op.opcode);
+                generate_program(sub_op, rng, buffer, coverage);
             }
         } else {
             // quoted value
             buffer.push(0xff); // cons
             buffer.push(1); // quote
-            generate(*arg, rng, buffer);
+            generate(*arg, rng, buffer, coverage);
         }
     }
     buffer.push(0x80); // cons
 }
 
-fn generate_args<R: Rng>(op: &OperatorInfo, rng: &mut R, buffer: &mut Vec<u8>) {
+fn generate_args<R: Rng>(
+    op: &OperatorInfo,
+    rng: &mut R,
+    buffer: &mut Vec<u8>,
+    coverage: &mut Coverage,
+) {
     for arg in op.operands {
         buffer.push(0xff); // cons
                            // quoted value
         buffer.push(0xff); // cons
         buffer.push(1); // quote
-        generate(*arg, rng, buffer);
+        generate(*arg, rng, buffer, coverage);
     }
     buffer.push(0x80); // cons
 }
 
-fn generate<R: Rng>(t: Type, rng: &mut R, buffer: &mut Vec<u8>) {
+// the input to the apply_opcode fuzzer. The opcode byte followed by the
+// arguments, as they are passed to the operator (i.e. not quoted)
+fn generate_opcode_args<R: Rng>(
+    op: &OperatorInfo,
+    rng: &mut R,
+    buffer: &mut Vec<u8>,
+    coverage: &mut Coverage,
+) {
+    buffer.push(op.opcode as u8);
+    for arg in op.operands {
+        buffer.push(0xff); // cons
+        generate(*arg, rng, buffer, coverage);
+    }
+    buffer.push(0x80); // cons
+}
+
+fn generate<R: Rng>(t: Type, rng: &mut R, buffer: &mut Vec<u8>, coverage: &mut Coverage) {
     match t {
         Type::Tree => {
             buffer.push(0xff); // cons
@@ -429,20 +471,21 @@ fn generate<R: Rng>(t: Type, rng: &mut R, buffer: &mut Vec<u8>) {
             } else {
                 rand_atom_type(rng)
             };
-            generate(left_side, rng, buffer);
-            generate(right_side, rng, buffer);
+            generate(left_side, rng, buffer, coverage);
+            generate(right_side, rng, buffer, coverage);
         }
         Type::List => {
             let len = rng.gen_range(0..10);
             for _i in 0..len {
                 buffer.push(0xff); // cons
-                generate(rand_atom_type(rng), rng, buffer);
+                generate(rand_atom_type(rng), rng, buffer, coverage);
             }
             buffer.push(0x80); // NIL
         }
         Type::Program => {
             let op = sample(rng, &OPERATORS);
-            generate_program(op, rng, buffer);
+            coverage.add_sub_expression(op.opcode);
+            generate_program(op, rng, buffer, coverage);
         }
         Type::Bool => {
             if rng.gen_bool(0.5) {
@@ -496,44 +539,460 @@ fn generate<R: Rng>(t: Type, rng: &mut R, buffer: &mut Vec<u8>) {
             write_atom(buffer, &ZEROS[..96]).expect("write_atom failed");
         }
         Type::AnyAtom => {
-            generate(rand_atom_type(rng), rng, buffer);
+            generate(rand_atom_type(rng), rng, buffer, coverage);
+        }
+    }
+}
+
+// keeps track of which operators the generated programs exercise
+#[derive(Default)]
+struct Coverage {
+    // opcode -> number of programs whose top-level operator was that opcode
+    top_level: BTreeMap<u32, usize>,
+    // opcode -> number of times it was used in a sub-expression
+    sub_expressions: BTreeMap<u32, usize>,
+}
+
+impl Coverage {
+    fn add_top_level(&mut self, opcode: u32) {
+        *self.top_level.entry(opcode).or_default() += 1;
+    }
+
+    fn add_sub_expression(&mut self, opcode: u32) {
+        *self.sub_expressions.entry(opcode).or_default() += 1;
+    }
+
+    fn total_programs(&self) -> usize {
+        self.top_level.values().sum()
+    }
+
+    // operators that never appeared as a sub-expression
+    fn missing_sub_expressions(&self) -> Vec<u32> {
+        let mut ret: Vec<u32> = OPERATORS
+            .iter()
+            .map(|o| o.opcode)
+            .filter(|opcode| !self.sub_expressions.contains_key(opcode))
+            .collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+
+    fn print_report(&self) {
+        println!("{:>10} {:>10} {:>10}", "opcode", "top-level", "sub-expr");
+        for (opcode, count) in &self.top_level {
+            println!(
+                "{opcode:>10} {count:>10} {:>10}",
+                self.sub_expressions.get(opcode).unwrap_or(&0)
+            );
+        }
+        println!("total programs: {}", self.total_programs());
+        println!(
+            "never used as sub-expression: {:?}",
+            self.missing_sub_expressions()
+        );
+    }
+}
+
+// generate `count` programs, cycling through all operators for the top-level
+// operator. Each program is passed to `out`
+fn generate_programs<R: Rng, F: FnMut(&[u8])>(
+    count: usize,
+    rng: &mut R,
+    coverage: &mut Coverage,
+    mut out: F,
+) {
+    let mut buffer = Vec::<u8>::new();
+    for i in 0..count {
+        buffer.truncate(0);
+
+        let op = &OPERATORS[i % OPERATORS.len()];
+        coverage.add_top_level(op.opcode);
+        generate_program(op, rng, &mut buffer, coverage);
+        out(&buffer);
+    }
+}
+
+// the max_cost values the stress corpus is built around. Each stress program
+// is generated with one parameter just below and one just above each of these
+const STRESS_MAX_COSTS: [Cost; 3] = [1_000_000, 100_000_000, 11_000_000_000];
+
+// the number of arguments passed to the variadic operators
+const STRESS_VARIADIC_ARGS: usize = 300;
+
+// the atom doubled by the concat stress programs
+const STRESS_SEED: [u8; 32] = BYTES32[0];
+
+// the recursive function used by Stress::NestedApply. It's called with the
+// environment (F n) and returns n, computed as (+ 1 (F (- n 1))) so that every
+// level of recursion stays on the stack
+// (a (i 5 (q + (q . 1) (a 2 (c 2 (c (- 5 (q . 1)) ())))) (q q)) 1)
+const RECURSE: [u8; 55] = hex!("ff02ffff03ff05ffff01ff10ffff0101ffff02ff02ffff04ff02ffff04ffff11ff05ffff010180ff8080808080ffff01ff018080ff0180");
+
+// programs for the stress corpus. They don't use their environment, and their
+// cost is controlled by a single parameter
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Stress {
+    // recurse `param` levels deep
+    NestedApply,
+    // double STRESS_SEED `param` times, by concatenating the environment with
+    // itself
+    Concat,
+    // add and logand, with STRESS_VARIADIC_ARGS arguments. Each argument is
+    // the atom built by Concat
+    VariadicAdd,
+    VariadicLogand,
+}
+
+const STRESS_PROGRAMS: [Stress; 4] = [
+    Stress::NestedApply,
+    Stress::Concat,
+    Stress::VariadicAdd,
+    Stress::VariadicLogand,
+];
+
+impl Stress {
+    // whether the cost grows exponentially with the parameter, rather than
+    // linearly
+    fn exponential(self) -> bool {
+        self != Stress::NestedApply
+    }
+
+    fn write(self, buffer: &mut Vec<u8>, param: u32) {
+        match self {
+            Stress::NestedApply => {
+                // (a (q . F) (q F n))
+                buffer.extend_from_slice(&hex!("ff02ffff01"));
+                buffer.extend_from_slice(&RECURSE);
+                buffer.extend_from_slice(&hex!("ffff01ff"));
+                buffer.extend_from_slice(&RECURSE);
+                buffer.push(0xff); // cons
+                write_int(buffer, param as u64);
+                buffer.extend_from_slice(&hex!("8080"));
+            }
+            Stress::Concat => {
+                // (a (q concat 1 1) (a (q concat 1 1) ... (q . seed)))
+                for _ in 0..param {
+                    buffer.extend_from_slice(&hex!("ff02ffff01ff0eff01ff0180ff"));
+                }
+                buffer.extend_from_slice(&hex!("ff01"));
+                write_atom(buffer, &STRESS_SEED).expect("write_atom failed");
+                buffer.resize(buffer.len() + param as usize, 0x80);
+            }
+            Stress::VariadicAdd | Stress::VariadicLogand => {
+                // (a (q op 1 1 ...) <Concat program>)
+                let opcode = if self == Stress::VariadicAdd { 16 } else { 24 };
+                buffer.extend_from_slice(&[0xff, 0x02, 0xff, 0xff, 0x01, 0xff, opcode]);
+                for _ in 0..STRESS_VARIADIC_ARGS {
+                    buffer.extend_from_slice(&[0xff, 0x01]);
+                }
+                buffer.extend_from_slice(&[0x80, 0xff]);
+                Stress::Concat.write(buffer, param);
+                buffer.push(0x80);
+            }
+        }
+    }
+
+    // estimate the parameter that makes the program cost `target`, by
+    // measuring the cost at two small parameters and extrapolating
+    fn estimate_param(self, target: Cost) -> f64 {
+        let (lo, hi) = if self.exponential() { (8, 9) } else { (10, 20) };
+        let cost = |param: u32| {
+            let mut buffer = Vec::new();
+            self.write(&mut buffer, param);
+            run_stress(&buffer, Cost::MAX).expect("stress program failed") as f64
+        };
+        let (cost_lo, cost_hi) = (cost(lo), cost(hi));
+        let (unit_lo, unit_hi) = if self.exponential() {
+            (2_f64.powi(lo as i32), 2_f64.powi(hi as i32))
+        } else {
+            (lo as f64, hi as f64)
+        };
+        // cost = base + per_unit * unit
+        let per_unit = (cost_hi - cost_lo) / (unit_hi - unit_lo);
+        let base = cost_lo - per_unit * unit_lo;
+        let units = ((target as f64 - base) / per_unit).max(1.0);
+        if self.exponential() {
+            units.log2()
+        } else {
+            units
+        }
+    }
+
+    // a parameter whose cost is just below `target`, and one just above
+    fn straddle(self, target: Cost) -> [u32; 2] {
+        let param = self.estimate_param(target);
+        if self.exponential() {
+            let below = param.floor() as u32;
+            [below, below + 1]
+        } else {
+            [(param * 0.9) as u32, (param * 1.1).ceil() as u32]
         }
     }
 }
 
-fn filename(buffer: &[u8]) -> String {
-    let mut sha1 = Sha1::new();
-    sha1.update(buffer);
-    hex::encode(sha1.finalize())
+// (softfork (q . cost) (q . 0) (q . program) (q . ()))
+fn write_softfork(buffer: &mut Vec<u8>, cost: Cost, program: &[u8]) {
+    buffer.extend_from_slice(&hex!("ff24ffff01"));
+    write_int(buffer, cost);
+    buffer.extend_from_slice(&hex!("ffff0180ffff01"));
+    buffer.extend_from_slice(program);
+    buffer.extend_from_slice(&hex!("ffff018080"));
+}
+
+// run a stress program with a nil environment, returning its cost or the error
+// message
+fn run_stress(program: &[u8], max_cost: Cost) -> Result<Cost, String> {
+    let mut a = Allocator::new();
+    let program = node_from_bytes(&mut a, program).map_err(|e| e.to_string())?;
+    let nil = a.nil();
+    run_program(&mut a, &ChikDialect::new(0), program, nil, max_cost)
+        .map(|Reduction(cost, _)| cost)
+        .map_err(|e| e.1)
+}
+
+// generate the stress corpus: programs whose cost straddles each of
+// STRESS_MAX_COSTS, both by themselves and wrapped in a softfork guard that
+// declares the target cost. Each program is passed to `out`
+fn generate_stress<F: FnMut(&[u8])>(max_costs: &[Cost], mut out: F) {
+    let mut program = Vec::<u8>::new();
+    let mut buffer = Vec::<u8>::new();
+    for stress in STRESS_PROGRAMS {
+        for target in max_costs {
+            for param in stress.straddle(*target) {
+                program.truncate(0);
+                stress.write(&mut program, param);
+                out(&program);
+
+                buffer.truncate(0);
+                write_softfork(&mut buffer, *target, &program);
+                out(&buffer);
+            }
+        }
+    }
+}
+
+// where the corpus for one fuzz target is written. Either one file per entry,
+// named by its SHA-1, or a single corpus.pack file
+enum CorpusWriter {
+    Files(String),
+    Pack(PackWriter<BufWriter<File>>),
+}
+
+impl CorpusWriter {
+    fn new(target: &str, pack: bool) -> Self {
+        let dir = format!("../fuzz/corpus/{target}");
+        create_dir_all(&dir).expect("failed to create directory");
+        if pack {
+            let out = File::create(format!("{dir}/corpus.pack")).expect("failed to open file");
+            Self::Pack(PackWriter::new(BufWriter::new(out)).expect("failed to write file"))
+        } else {
+            Self::Files(dir)
+        }
+    }
+
+    fn write(&mut self, buffer: &[u8]) {
+        match self {
+            Self::Files(dir) => {
+                let mut out = File::create(format!("{dir}/{}", entry_name(buffer)))
+                    .expect("failed to open file");
+                out.write_all(buffer).expect("failed to write file");
+            }
+            Self::Pack(w) => w.add(buffer).expect("failed to write file"),
+        }
+    }
+
+    fn finish(self) {
+        if let Self::Pack(w) = self {
+            w.finish().expect("failed to write file");
+        }
+    }
+}
+
+/// Generate a fuzz corpus for the fuzz_run_program, operators and apply_opcode
+/// fuzzers
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// print a report of which operators the generated programs exercise
+    #[arg(short, long, default_value_t = false)]
+    report: bool,
+
+    /// write a single corpus.pack file per fuzz target, instead of one file
+    /// per entry. Use klvm-corpus to extract the entries
+    #[arg(short, long, default_value_t = false)]
+    pack: bool,
 }
 
 pub fn main() {
+    let args = Args::parse();
     let mut buffer = Vec::<u8>::new();
     let mut rng = StdRng::seed_from_u64(0x1337);
 
-    create_dir_all("../fuzz/corpus/fuzz_run_program").expect("failed to create directory");
-    create_dir_all("../fuzz/corpus/operators").expect("failed to create directory");
+    let mut out = CorpusWriter::new("fuzz_run_program", args.pack);
+    let mut coverage = Coverage::default();
+    generate_programs(40000, &mut rng, &mut coverage, |buffer| out.write(buffer));
+    out.finish();
 
+    // the operator arguments aren't programs, so they are not included in the
+    // coverage report
+    let mut out = CorpusWriter::new("operators", args.pack);
+    let mut args_coverage = Coverage::default();
     for i in 0..40000 {
         buffer.truncate(0);
 
         let op = &OPERATORS[i % OPERATORS.len()];
-        generate_program(op, &mut rng, &mut buffer);
-        let mut out = File::create(format!(
-            "../fuzz/corpus/fuzz_run_program/{}",
-            filename(&buffer)
-        ))
-        .expect("failed to open file");
-        out.write_all(&buffer).expect("failed to write file");
+        generate_args(op, &mut rng, &mut buffer, &mut args_coverage);
+        out.write(&buffer);
     }
+    out.finish();
 
+    // the operators with a single byte opcode, with their arguments
+    let mut out = CorpusWriter::new("apply_opcode", args.pack);
+    let single_byte_ops: Vec<&OperatorInfo> =
+        OPERATORS.iter().filter(|op| op.opcode <= 0xff).collect();
     for i in 0..40000 {
         buffer.truncate(0);
 
-        let op = &OPERATORS[i % OPERATORS.len()];
-        generate_args(op, &mut rng, &mut buffer);
-        let mut out = File::create(format!("../fuzz/corpus/operators/{}", filename(&buffer)))
-            .expect("failed to open file");
-        out.write_all(&buffer).expect("failed to write file");
+        let op = single_byte_ops[i % single_byte_ops.len()];
+        generate_opcode_args(op, &mut rng, &mut buffer, &mut args_coverage);
+        out.write(&buffer);
     }
+    out.finish();
+
+    // the stress programs are meant for the fuzz_run_program fuzzer, but they
+    // are kept separate since they are slow to run
+    let mut out = CorpusWriter::new("stress", args.pack);
+    generate_stress(&STRESS_MAX_COSTS, |buffer| out.write(buffer));
+    out.finish();
+
+    if args.report {
+        coverage.print_report();
+    }
+}
+
+#[test]
+fn test_coverage_report() {
+    let mut rng = StdRng::seed_from_u64(0x1337);
+    let mut coverage = Coverage::default();
+    let mut programs = 0;
+    generate_programs(1000, &mut rng, &mut coverage, |_| programs += 1);
+    assert_eq!(programs, 1000);
+    assert_eq!(coverage.total_programs(), 1000);
+
+    // every operator is used as the top-level operator at least once
+    for op in &OPERATORS {
+        assert!(coverage.top_level.contains_key(&op.opcode));
+    }
+
+    for opcode in coverage.missing_sub_expressions() {
+        assert!(!coverage.sub_expressions.contains_key(&opcode));
+    }
+}
+
+#[test]
+fn test_opcode_args() {
+    use klvmr::chik_dialect::{ENABLE_BLS_OPS_OUTSIDE_GUARD, ENABLE_SHA256_TAGGED, NO_UNKNOWN_OPS};
+    use klvmr::dialect::OperatorSet;
+
+    // the seeds for apply_opcode are well formed, and name operators the
+    // dialect knows about. apply (2) and softfork (36) are handled by the
+    // interpreter, not by the dialect
+    let mut rng = StdRng::seed_from_u64(0x1337);
+    let mut coverage = Coverage::default();
+    let dialect =
+        ChikDialect::new(NO_UNKNOWN_OPS | ENABLE_BLS_OPS_OUTSIDE_GUARD | ENABLE_SHA256_TAGGED);
+    let mut buffer = Vec::new();
+    for op in OPERATORS
+        .iter()
+        .filter(|op| op.opcode <= 0xff && op.opcode != 2 && op.opcode != 36)
+    {
+        buffer.truncate(0);
+        generate_opcode_args(op, &mut rng, &mut buffer, &mut coverage);
+        assert_eq!(buffer[0] as u32, op.opcode);
+        let mut a = Allocator::new();
+        let args = node_from_bytes(&mut a, &buffer[1..]).unwrap();
+        assert_eq!(a.list_len(args), Some(op.operands.len()));
+        if let Err(e) = dialect.apply_opcode(
+            &mut a,
+            op.opcode,
+            args,
+            11_000_000_000,
+            OperatorSet::Default,
+        ) {
+            assert_ne!(e.1, "unimplemented operator");
+        }
+    }
+}
+
+#[test]
+fn test_stress_programs() {
+    // the programs compute what we expect
+    let mut buffer = Vec::new();
+    Stress::NestedApply.write(&mut buffer, 10);
+    let mut a = Allocator::new();
+    let program = node_from_bytes(&mut a, &buffer).unwrap();
+    let nil = a.nil();
+    let Reduction(_, result) =
+        run_program(&mut a, &ChikDialect::new(0), program, nil, 11_000_000_000).unwrap();
+    assert_eq!(a.small_number(result), Some(10));
+
+    buffer.truncate(0);
+    Stress::Concat.write(&mut buffer, 3);
+    let mut a = Allocator::new();
+    let program = node_from_bytes(&mut a, &buffer).unwrap();
+    let nil = a.nil();
+    let Reduction(_, result) =
+        run_program(&mut a, &ChikDialect::new(0), program, nil, 11_000_000_000).unwrap();
+    assert_eq!(a.atom(result).as_ref(), STRESS_SEED.repeat(8).as_slice());
+
+    for stress in [Stress::VariadicAdd, Stress::VariadicLogand] {
+        buffer.truncate(0);
+        stress.write(&mut buffer, 2);
+        assert!(run_stress(&buffer, 11_000_000_000).is_ok());
+    }
+
+    // the parameters straddle the target cost
+    for stress in STRESS_PROGRAMS {
+        let [below, above] = stress.straddle(1_000_000);
+        assert!(below < above);
+        buffer.truncate(0);
+        stress.write(&mut buffer, above);
+        assert_eq!(run_stress(&buffer, 1_000_000).unwrap_err(), "cost exceeded");
+    }
+}
+
+#[test]
+fn test_stress_cost_exceeded() {
+    // every program in the stress corpus fails cleanly when run with a small
+    // max_cost
+    let mut programs = 0;
+    generate_stress(&STRESS_MAX_COSTS, |buffer| {
+        programs += 1;
+        assert_eq!(run_stress(buffer, 100_000).unwrap_err(), "cost exceeded");
+    });
+    assert_eq!(programs, STRESS_PROGRAMS.len() * STRESS_MAX_COSTS.len() * 4);
+}
+
+#[test]
+fn test_pack_corpus() {
+    use klvm_rs_test_tools::corpus_pack::Pack;
+
+    // packing the corpus, and unpacking it again, produces the same files as
+    // writing one file per entry
+    let mut rng = StdRng::seed_from_u64(0x1337);
+    let mut coverage = Coverage::default();
+    let mut files = BTreeMap::<String, Vec<u8>>::new();
+    let mut pack = PackWriter::new(Vec::new()).unwrap();
+    generate_programs(1000, &mut rng, &mut coverage, |buffer| {
+        files.insert(entry_name(buffer), buffer.to_vec());
+        pack.add(buffer).unwrap();
+    });
+    let pack = Pack::from_bytes(pack.finish().unwrap()).unwrap();
+    let unpacked: BTreeMap<String, Vec<u8>> = pack
+        .index()
+        .iter()
+        .map(|e| (e.name(), pack.entry(e).to_vec()))
+        .collect();
+    assert_eq!(unpacked, files);
 }
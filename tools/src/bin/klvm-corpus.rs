@@ -0,0 +1,74 @@
+use clap::{Parser, Subcommand};
+use klvm_rs_test_tools::corpus_pack::Pack;
+use std::fs::{create_dir_all, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// Inspect and extract fuzz corpus pack files, as written by
+/// generate-fuzz-corpus --pack
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// print the name, offset and length of every entry
+    List {
+        /// the pack file
+        pack: PathBuf,
+    },
+    /// write entries to a directory, one file per entry, named by its SHA-1
+    Unpack {
+        /// the pack file
+        pack: PathBuf,
+
+        /// the directory to write the entries to
+        dir: PathBuf,
+
+        /// the names of the entries to extract. All entries are extracted if
+        /// none are specified
+        names: Vec<String>,
+    },
+}
+
+fn unpack(pack: &Pack, dir: &Path, names: &[String]) -> std::io::Result<usize> {
+    if names.is_empty() {
+        return pack.unpack(dir);
+    }
+    create_dir_all(dir)?;
+    for name in names {
+        let Some(entry) = pack.get(name) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no entry named {name}"),
+            ));
+        };
+        File::create(dir.join(name))?.write_all(entry)?;
+    }
+    Ok(names.len())
+}
+
+pub fn main() -> ExitCode {
+    let args = Args::parse();
+    let result = match &args.command {
+        Command::List { pack } => Pack::open(pack).map(|pack| {
+            for e in pack.index() {
+                println!("{} {} {}", e.name(), e.offset, e.len);
+            }
+        }),
+        Command::Unpack { pack, dir, names } => Pack::open(pack)
+            .and_then(|pack| unpack(&pack, dir, names))
+            .map(|count| println!("extracted {count} entries")),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
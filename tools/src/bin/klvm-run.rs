@@ -0,0 +1,104 @@
+use clap::Parser;
+use klvmr::allocator::Allocator;
+use klvmr::chik_dialect::ChikDialect;
+use klvmr::cost::Cost;
+use klvmr::reduction::{EvalErr, Reduction};
+use klvmr::run_program::run_program;
+use klvmr::serde::{node_from_bytes, node_to_bytes, tree_hash_from_stream};
+use serde_json::json;
+use std::io::Cursor;
+use std::process::ExitCode;
+
+// the exit codes are part of the interface, scripts depend on them
+const EXIT_EVAL_ERROR: u8 = 1;
+const EXIT_USAGE_ERROR: u8 = 2;
+
+/// Run a serialized KLVM program
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// the serialized program, hex encoded
+    program: String,
+
+    /// the serialized environment, hex encoded
+    #[arg(default_value = "80")]
+    env: String,
+
+    /// the cost limit
+    #[arg(short, long, default_value_t = 11000000000)]
+    max_cost: Cost,
+
+    /// the ChikDialect flags
+    #[arg(short, long, default_value_t = 0)]
+    flags: u32,
+
+    /// print the outcome as a single JSON object
+    #[arg(short, long, default_value_t = false)]
+    json: bool,
+}
+
+// an error in the input, rather than in running the program
+fn usage_error(json: bool, message: String) -> ExitCode {
+    if json {
+        println!(
+            "{}",
+            json!({"error_kind": "invalid_input", "message": message, "node_hex": null})
+        );
+    } else {
+        eprintln!("error: {message}");
+    }
+    ExitCode::from(EXIT_USAGE_ERROR)
+}
+
+fn eval_error(json: bool, a: &Allocator, e: &EvalErr) -> ExitCode {
+    if json {
+        let node_hex = node_to_bytes(a, e.0).ok().map(hex::encode);
+        println!(
+            "{}",
+            json!({"error_kind": e.kind().as_str(), "message": e.display(a), "node_hex": node_hex})
+        );
+    } else {
+        eprintln!("error: {}", e.display(a));
+    }
+    ExitCode::from(EXIT_EVAL_ERROR)
+}
+
+pub fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let mut a = Allocator::new();
+    let mut parse = |name: &str, value: &str| {
+        let buf = hex::decode(value).map_err(|e| format!("invalid hex for {name}: {e}"))?;
+        node_from_bytes(&mut a, &buf).map_err(|e| format!("invalid {name}: {e}"))
+    };
+    let program = match parse("program", &args.program) {
+        Ok(n) => n,
+        Err(message) => return usage_error(args.json, message),
+    };
+    let env = match parse("env", &args.env) {
+        Ok(n) => n,
+        Err(message) => return usage_error(args.json, message),
+    };
+
+    let dialect = ChikDialect::new(args.flags);
+    let Reduction(cost, result) = match run_program(&mut a, &dialect, program, env, args.max_cost) {
+        Ok(r) => r,
+        Err(e) => return eval_error(args.json, &a, &e),
+    };
+    let result = match node_to_bytes(&a, result) {
+        Ok(buf) => buf,
+        Err(e) => return usage_error(args.json, format!("failed to serialize result: {e}")),
+    };
+    if args.json {
+        let tree_hash = tree_hash_from_stream(&mut Cursor::new(result.as_slice()))
+            .expect("result is a valid serialization");
+        println!(
+            "{}",
+            json!({"cost": cost, "result_hex": hex::encode(&result), "tree_hash": hex::encode(tree_hash)})
+        );
+    } else {
+        println!("cost: {cost}");
+        println!("result: {}", hex::encode(&result));
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,285 @@
+// A pack file stores a fuzz corpus as a single file, rather than one file per
+// entry. The layout is:
+//
+//   magic        8 bytes, "klvmpack"
+//   entries      each entry is a 4 byte, big endian, length followed by the
+//                entry itself
+//   index        one record per entry: the SHA-1 of the entry (20 bytes), the
+//                offset of the entry itself, after its length prefix (8 bytes)
+//                and its length (4 bytes). All integers are big endian
+//   footer       the offset of the index (8 bytes), the number of entries (4
+//                bytes) and the magic (8 bytes)
+//
+// Entries are named by the hex encoded SHA-1 of their contents, which is also
+// the file name used when the corpus is written one file per entry.
+
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::fs::{create_dir_all, File};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"klvmpack";
+const INDEX_RECORD_LEN: usize = 20 + 8 + 4;
+const FOOTER_LEN: usize = 8 + 4 + MAGIC.len();
+
+/// The name of a corpus entry, the hex encoded SHA-1 of its contents
+pub fn entry_name(buf: &[u8]) -> String {
+    hex::encode(sha1(buf))
+}
+
+fn sha1(buf: &[u8]) -> [u8; 20] {
+    let mut ctx = Sha1::new();
+    ctx.update(buf);
+    ctx.finalize().into()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub sha1: [u8; 20],
+    /// the offset of the entry in the pack file, not including its length
+    /// prefix
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl IndexEntry {
+    pub fn name(&self) -> String {
+        hex::encode(self.sha1)
+    }
+}
+
+/// Writes entries to a pack file. Entries with the same contents are only
+/// stored once, just like they would end up in the same file when written
+/// one file per entry. The index is written by `finish()`
+pub struct PackWriter<W: Write> {
+    out: W,
+    offset: u64,
+    index: Vec<IndexEntry>,
+    seen: HashSet<[u8; 20]>,
+}
+
+impl<W: Write> PackWriter<W> {
+    pub fn new(mut out: W) -> Result<Self> {
+        out.write_all(MAGIC)?;
+        Ok(Self {
+            out,
+            offset: MAGIC.len() as u64,
+            index: Vec::new(),
+            seen: HashSet::new(),
+        })
+    }
+
+    pub fn add(&mut self, entry: &[u8]) -> Result<()> {
+        let sha1 = sha1(entry);
+        if !self.seen.insert(sha1) {
+            return Ok(());
+        }
+        let len: u32 = entry
+            .len()
+            .try_into()
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "corpus entry too large"))?;
+        self.out.write_all(&len.to_be_bytes())?;
+        self.out.write_all(entry)?;
+        self.index.push(IndexEntry {
+            sha1,
+            offset: self.offset + 4,
+            len,
+        });
+        self.offset += 4 + len as u64;
+        Ok(())
+    }
+
+    /// write the index and footer, and return the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        let index_offset = self.offset;
+        for e in &self.index {
+            self.out.write_all(&e.sha1)?;
+            self.out.write_all(&e.offset.to_be_bytes())?;
+            self.out.write_all(&e.len.to_be_bytes())?;
+        }
+        let count: u32 = self
+            .index
+            .len()
+            .try_into()
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "too many corpus entries"))?;
+        self.out.write_all(&index_offset.to_be_bytes())?;
+        self.out.write_all(&count.to_be_bytes())?;
+        self.out.write_all(MAGIC)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid pack file: {msg}"))
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> u64 {
+    u64::from_be_bytes(buf[pos..pos + 8].try_into().unwrap())
+}
+
+/// A pack file loaded into memory
+pub struct Pack {
+    buf: Vec<u8>,
+    index: Vec<IndexEntry>,
+}
+
+impl Pack {
+    /// parse and validate a pack file. Every index entry is checked to be in
+    /// bounds and to agree with the length prefix of the entry
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self> {
+        if buf.len() < MAGIC.len() + FOOTER_LEN || !buf.starts_with(MAGIC) || !buf.ends_with(MAGIC)
+        {
+            return Err(invalid("bad magic"));
+        }
+        let footer = buf.len() - FOOTER_LEN;
+        let index_offset = read_u64(&buf, footer);
+        let count = read_u32(&buf, footer + 8) as usize;
+        let index_len = count
+            .checked_mul(INDEX_RECORD_LEN)
+            .ok_or_else(|| invalid("bad entry count"))?;
+        if index_offset < MAGIC.len() as u64
+            || index_offset.checked_add(index_len as u64) != Some(footer as u64)
+        {
+            return Err(invalid("bad index offset"));
+        }
+        let index_offset = index_offset as usize;
+
+        let mut index = Vec::with_capacity(count);
+        for i in 0..count {
+            let pos = index_offset + i * INDEX_RECORD_LEN;
+            let e = IndexEntry {
+                sha1: buf[pos..pos + 20].try_into().unwrap(),
+                offset: read_u64(&buf, pos + 20),
+                len: read_u32(&buf, pos + 28),
+            };
+            if e.offset < (MAGIC.len() + 4) as u64
+                || e.offset.saturating_add(e.len as u64) > index_offset as u64
+                || read_u32(&buf, e.offset as usize - 4) != e.len
+            {
+                return Err(invalid("bad index entry"));
+            }
+            index.push(e);
+        }
+        Ok(Self { buf, index })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    pub fn index(&self) -> &[IndexEntry] {
+        &self.index
+    }
+
+    pub fn entry(&self, e: &IndexEntry) -> &[u8] {
+        &self.buf[e.offset as usize..(e.offset + e.len as u64) as usize]
+    }
+
+    /// look up an entry by its name (the hex encoded SHA-1)
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.index
+            .iter()
+            .find(|e| e.name() == name)
+            .map(|e| self.entry(e))
+    }
+
+    /// write every entry to `dir`, one file per entry, named by its SHA-1.
+    /// Returns the number of files written
+    pub fn unpack<P: AsRef<Path>>(&self, dir: P) -> Result<usize> {
+        let dir = dir.as_ref();
+        create_dir_all(dir)?;
+        for e in &self.index {
+            File::create(dir.join(e.name()))?.write_all(self.entry(e))?;
+        }
+        Ok(self.index.len())
+    }
+}
+
+#[cfg(test)]
+fn test_pack(entries: &[&[u8]]) -> Vec<u8> {
+    let mut w = PackWriter::new(Vec::new()).unwrap();
+    for e in entries {
+        w.add(e).unwrap();
+    }
+    w.finish().unwrap()
+}
+
+#[test]
+fn test_entry_name() {
+    // the same names generate-fuzz-corpus has always used for its files
+    assert_eq!(entry_name(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(
+        entry_name(&[0xff, 0x01, 0x80]),
+        hex::encode(Sha1::digest([0xff, 0x01, 0x80]))
+    );
+}
+
+#[test]
+fn test_pack_round_trip() {
+    let entries: [&[u8]; 4] = [&[0xff, 0x01, 0x80], &[0x80], &[], &[0x42; 1000]];
+    let pack = Pack::from_bytes(test_pack(&entries)).unwrap();
+    assert_eq!(pack.index().len(), entries.len());
+    for (e, expected) in pack.index().iter().zip(entries) {
+        assert_eq!(pack.entry(e), expected);
+        assert_eq!(e.name(), entry_name(expected));
+        assert_eq!(pack.get(&e.name()), Some(expected));
+    }
+    assert_eq!(pack.get(&entry_name(b"missing")), None);
+
+    // an empty pack
+    let pack = Pack::from_bytes(test_pack(&[])).unwrap();
+    assert!(pack.index().is_empty());
+}
+
+#[test]
+fn test_pack_duplicates() {
+    let pack = Pack::from_bytes(test_pack(&[&[1, 2, 3], &[4], &[1, 2, 3]])).unwrap();
+    assert_eq!(pack.index().len(), 2);
+}
+
+#[test]
+fn test_unpack() {
+    let entries: [&[u8]; 3] = [&[0xff, 0x01, 0x80], &[0x80], &[0x42; 1000]];
+    let pack = Pack::from_bytes(test_pack(&entries)).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("klvm-corpus-test-{}", std::process::id()));
+    assert_eq!(pack.unpack(&dir).unwrap(), entries.len());
+    for e in entries {
+        assert_eq!(std::fs::read(dir.join(entry_name(e))).unwrap(), e);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_invalid_pack() {
+    let valid = test_pack(&[&[0xff, 0x01, 0x80], &[0x80]]);
+    assert!(Pack::from_bytes(valid.clone()).is_ok());
+
+    // truncated
+    for len in 0..valid.len() {
+        assert!(Pack::from_bytes(valid[..len].to_vec()).is_err());
+    }
+
+    // an entry length that doesn't match its prefix
+    let mut buf = valid.clone();
+    buf[MAGIC.len() + 3] = 4;
+    assert!(Pack::from_bytes(buf).is_err());
+
+    // the entry count doesn't match the index
+    let mut buf = valid.clone();
+    let count = buf.len() - FOOTER_LEN + 8 + 3;
+    buf[count] = 3;
+    assert!(Pack::from_bytes(buf).is_err());
+
+    // an index entry pointing past the entries
+    let mut buf = valid;
+    let index_offset = read_u64(&buf, buf.len() - FOOTER_LEN) as usize;
+    buf[index_offset + 20 + 7] = 0xff;
+    assert!(Pack::from_bytes(buf).is_err());
+}
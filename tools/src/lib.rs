@@ -0,0 +1 @@
+pub mod corpus_pack;
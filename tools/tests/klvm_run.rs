@@ -0,0 +1,81 @@
+use assert_cmd::Command;
+use serde_json::Value;
+
+// runs klvm-run with --json and returns the exit code and the parsed output
+fn run_json(args: &[&str]) -> (i32, Value) {
+    let output = Command::cargo_bin("klvm-run")
+        .unwrap()
+        .arg("--json")
+        .args(args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let value: Value = serde_json::from_str(&stdout).unwrap();
+    (output.status.code().unwrap(), value)
+}
+
+fn keys(value: &Value) -> Vec<&str> {
+    let mut keys: Vec<&str> = value
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    keys.sort();
+    keys
+}
+
+#[test]
+fn klvm_run_success() {
+    // (c (q . 100) (q . ()))
+    let (code, value) = run_json(&["ff04ffff0164ffff018080"]);
+    assert_eq!(code, 0);
+    assert_eq!(keys(&value), ["cost", "result_hex", "tree_hash"]);
+    assert_eq!(value["cost"], 91);
+    assert_eq!(value["result_hex"], "ff6480");
+    assert_eq!(
+        value["tree_hash"],
+        "ca502c9ffd42d5dd8ca736f3f8eed70ed804554c3e94aff2d76830cdd94ed2b9"
+    );
+}
+
+#[test]
+fn klvm_run_raise() {
+    // (x (q . "oops"))
+    let (code, value) = run_json(&["ff08ffff01846f6f707380"]);
+    assert_eq!(code, 1);
+    assert_eq!(keys(&value), ["error_kind", "message", "node_hex"]);
+    assert_eq!(value["error_kind"], "raise");
+    assert_eq!(value["message"], "klvm raise: \"oops\"");
+    assert_eq!(value["node_hex"], "846f6f7073");
+}
+
+#[test]
+fn klvm_run_cost_exceeded() {
+    let (code, value) = run_json(&["ff04ffff0164ffff018080", "80", "--max-cost", "10"]);
+    assert_eq!(code, 1);
+    assert_eq!(keys(&value), ["error_kind", "message", "node_hex"]);
+    assert_eq!(value["error_kind"], "cost_exceeded");
+    assert!(value["message"]
+        .as_str()
+        .unwrap()
+        .ends_with("cost exceeded"));
+}
+
+#[test]
+fn klvm_run_malformed_hex() {
+    for args in [["ff04zz", "80"], ["ff04ffff0164ffff018080", "8"]] {
+        let (code, value) = run_json(&args);
+        assert_eq!(code, 2);
+        assert_eq!(keys(&value), ["error_kind", "message", "node_hex"]);
+        assert_eq!(value["error_kind"], "invalid_input");
+        assert_eq!(value["node_hex"], Value::Null);
+    }
+
+    // without --json, usage errors are reported by clap, also with exit code 2
+    Command::cargo_bin("klvm-run")
+        .unwrap()
+        .arg("--no-such-flag")
+        .assert()
+        .code(2);
+}